@@ -0,0 +1,121 @@
+//! Async FTP Module
+//!
+//! Awaitable wrappers around the blocking FTP backend, for embedding
+//! rustftpfs in an async service (for example one that serves FTP content
+//! over HTTP) instead of mounting it with FUSE. Only built with the `tokio`
+//! feature.
+//!
+//! Each call runs the blocking suppaftp operation on tokio's blocking thread
+//! pool with `spawn_blocking`, so the async executor is never stalled by a
+//! slow server. The FUSE filesystem does not use this module.
+
+use std::sync::{Arc, Mutex};
+
+use crate::ftp::{ConnectOptions, FtpBackend, FtpConnection, FtpError, FtpFileInfo, Result};
+
+/// Shared FTP connection usable from async code
+///
+/// Clones share the same connection, like the clones of `FtpFs`. Calls made
+/// concurrently are served one at a time in the order they take the lock;
+/// open several `AsyncFtp`s to transfer in parallel.
+pub struct AsyncFtp<B: FtpBackend + Send + 'static = FtpConnection> {
+    conn: Arc<Mutex<B>>,
+}
+
+impl<B: FtpBackend + Send + 'static> Clone for AsyncFtp<B> {
+    fn clone(&self) -> Self {
+        AsyncFtp {
+            conn: Arc::clone(&self.conn),
+        }
+    }
+}
+
+impl AsyncFtp {
+    /// Connect and log in without blocking the executor
+    pub async fn connect(
+        server: String,
+        username: String,
+        password: String,
+        port: Option<u16>,
+        options: ConnectOptions,
+    ) -> Result<Self> {
+        let conn = tokio::task::spawn_blocking(move || {
+            FtpConnection::with_options(server, username, password, port, options)
+        })
+        .await
+        .map_err(join_error)??;
+        Ok(Self::from_backend(conn))
+    }
+}
+
+impl<B: FtpBackend + Send + 'static> AsyncFtp<B> {
+    /// Wrap an already connected backend
+    pub fn from_backend(conn: B) -> Self {
+        AsyncFtp {
+            conn: Arc::new(Mutex::new(conn)),
+        }
+    }
+
+    /// List a directory
+    pub async fn list_dir(&self, path: &str) -> Result<Vec<FtpFileInfo>> {
+        let path = path.to_string();
+        self.run(move |conn| conn.list_dir(&path)).await
+    }
+
+    /// Download a whole file
+    pub async fn retrieve(&self, path: &str) -> Result<Vec<u8>> {
+        let path = path.to_string();
+        self.run(move |conn| conn.retrieve(&path)).await
+    }
+
+    /// Upload `data`, replacing the file if it exists
+    pub async fn store(&self, path: &str, data: Vec<u8>) -> Result<()> {
+        let path = path.to_string();
+        self.run(move |conn| conn.store(&path, &data)).await
+    }
+
+    /// Run `op` with the connection on the blocking thread pool
+    async fn run<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut B) -> Result<T> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || op(&mut conn.lock().unwrap()))
+            .await
+            .map_err(join_error)?
+    }
+}
+
+/// A blocking task that panicked or was cancelled with the runtime
+fn join_error(e: tokio::task::JoinError) -> FtpError {
+    FtpError::Protocol(format!("FTP task failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+
+    #[test]
+    fn test_calls_run_on_the_blocking_pool() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let ftp = AsyncFtp::from_backend(MockBackend::default());
+
+        runtime.block_on(async {
+            ftp.store("/a.txt", b"hello".to_vec()).await.unwrap();
+            assert_eq!(ftp.clone().retrieve("/a.txt").await.unwrap(), b"hello");
+
+            let files = ftp.list_dir("/").await.unwrap();
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0].size, 5);
+
+            assert!(matches!(
+                ftp.retrieve("/missing").await,
+                Err(FtpError::NotFound)
+            ));
+        });
+    }
+}
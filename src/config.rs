@@ -0,0 +1,164 @@
+//! Configuration File Module
+//!
+//! Loads named connection profiles from a TOML file so that long command
+//! lines don't have to be repeated for every mount.
+//!
+//! ```toml
+//! [hosts.work]
+//! host = "ftp.example.com"
+//! user = "alice"
+//! password = "secret"
+//! port = 2121
+//! tls = true
+//! mode = "passive"
+//! cache_size = "256M"
+//! timeout = 30
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::ftp::DataMode;
+use crate::throttle::parse_size;
+
+/// Top-level layout of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Named connection profiles
+    #[serde(default)]
+    pub hosts: HashMap<String, HostProfile>,
+}
+
+/// Connection defaults for a single named host
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostProfile {
+    pub host: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub port: Option<u16>,
+    pub tls: Option<bool>,
+    pub mode: Option<ProfileMode>,
+    /// Bytes of file contents kept in memory (`--cache-size`)
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub cache_size: Option<u64>,
+    /// Connect/read timeout in seconds
+    pub timeout: Option<u64>,
+}
+
+/// Data channel mode as spelled in the configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileMode {
+    Passive,
+    Active,
+}
+
+/// Byte count written as a number or with a K, M or G suffix (`"256M"`)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(text) => parse_size(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+impl From<ProfileMode> for DataMode {
+    fn from(mode: ProfileMode) -> Self {
+        match mode {
+            ProfileMode::Passive => DataMode::Passive,
+            ProfileMode::Active => DataMode::Active,
+        }
+    }
+}
+
+impl Config {
+    /// Parse configuration from TOML text
+    pub fn parse(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|e| anyhow::anyhow!("{}", e))
+    }
+
+    /// Load configuration from a file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).context(format!("Failed to read config file {:?}", path))?;
+        Self::parse(&text).context(format!("Invalid config file {:?}", path))
+    }
+
+    /// Look up a named profile
+    pub fn profile(&self, name: &str) -> Result<&HostProfile> {
+        self.hosts
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("Profile '{}' not found in config file", name))
+    }
+}
+
+/// Default configuration file location (`~/.config/rustftpfs.toml`)
+pub fn default_config_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/rustftpfs.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() {
+        let config = Config::parse(
+            r#"
+            [hosts.work]
+            host = "ftp.example.com"
+            user = "alice"
+            port = 2121
+            tls = true
+            mode = "active"
+            cache_size = "64M"
+            timeout = 30
+
+            [hosts.home]
+            host = "ftp.example.org"
+            cache_size = 1048576
+            "#,
+        )
+        .unwrap();
+
+        let profile = config.profile("work").unwrap();
+        assert_eq!(profile.host.as_deref(), Some("ftp.example.com"));
+        assert_eq!(profile.user.as_deref(), Some("alice"));
+        assert_eq!(profile.port, Some(2121));
+        assert_eq!(profile.tls, Some(true));
+        assert_eq!(profile.mode, Some(ProfileMode::Active));
+        assert_eq!(profile.cache_size, Some(64 * 1024 * 1024));
+        assert_eq!(profile.timeout, Some(30));
+        assert_eq!(config.profile("home").unwrap().cache_size, Some(1048576));
+        assert!(config.profile("office").is_err());
+    }
+
+    #[test]
+    fn test_unknown_key_is_reported() {
+        let err = Config::parse(
+            r#"
+            [hosts.work]
+            hots = "ftp.example.com"
+            "#,
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("hots"));
+    }
+}
@@ -2,89 +2,491 @@
 //!
 //! Implementación optimizada del filesystem FUSE para montar servidores FTP.
 //! Características de rendimiento:
-//! - Caché de listados de directorio con TTL de 30 segundos
+//! - Caché de listados de directorio (60 segundos por defecto)
 //! - Caché de atributos de archivos para evitar consultas repetidas
-//! - TTL extendido de FUSE (10 segundos) para reducir getattr() calls
+//! - Tipo y tamaño de las entradas tomados de los listados (menos MLST/CWD/SIZE)
+//! - TTL extendido de FUSE (30 segundos por defecto) para reducir getattr() calls
+//! - Todos los TTL configurables; 0 desactiva la caché correspondiente
 //! - Prefetching básico de directorios comunes
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
+use std::os::unix::fs::FileExt;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{Context, Result};
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyOpen, ReplyWrite, Request,
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
 };
-use libc::{EIO, EISDIR, ENOENT, ENOTDIR};
+use libc::{EACCES, EILSEQ, EINVAL, EIO, EISDIR, ENOENT, ENOSPC, ENOTDIR, ENOTTY, ENXIO, EROFS};
 use log::{debug, error, info, trace, warn};
 
-use crate::ftp::{FtpConnection, FtpFileInfo};
+use crate::ftp::{
+    is_ascii_path, is_entry_name, join_ftp_path, normalize_ftp_path, FtpBackend, FtpConnection,
+    FtpError, FtpFileInfo,
+};
+use crate::lock_order::OrderedMutex;
+use crate::locks::{Lock, LockTable};
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, Exposition};
+use crate::mirror::Mirror;
+
+/// Valor de un `Result<T, i32>`, o responder con el errno y salir del
+/// callback de FUSE
+macro_rules! try_reply {
+    ($result:expr, $reply:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(errno) => {
+                $reply.error(errno);
+                return;
+            }
+        }
+    };
+}
 
 /// Inode number for the root directory
 const ROOT_INODE: u64 = 1;
 
-/// TTL extendido para atributos FUSE (30 segundos - optimizado para VS Code)
+/// Inodo reservado para el archivo de estadísticas en la raíz del montaje
+const STATS_INODE: u64 = 2;
+
+/// Nombre del archivo de estadísticas (solo lectura, nunca llega al servidor)
+const STATS_FILE_NAME: &str = ".rustftpfs-stats";
+
+/// ioctl para vaciar todas las cachés: `_IO('R', 1)`, sobre cualquier archivo
+/// o directorio del montaje
+pub const IOCTL_FLUSH_CACHES: u32 = 0x5201;
+
+/// ioctl para vaciar la caché de un directorio: `_IO('R', 2)`, sobre el
+/// propio directorio
+pub const IOCTL_FLUSH_DIR: u32 = 0x5202;
+
+/// ioctl para forzar una reconexión al servidor: `_IO('R', 3)`
+pub const IOCTL_RECONNECT: u32 = 0x5203;
+
+/// TTL de FUSE por defecto para entradas y atributos (30 segundos - optimizado para VS Code)
 const TTL: Duration = Duration::from_secs(30);
 
-/// TTL para caché de directorios (60 segundos - reduce readdir frecuentes)
+/// TTL por defecto para caché de directorios (60 segundos - reduce readdir frecuentes)
 const DIR_CACHE_TTL: Duration = Duration::from_secs(60);
 
-/// TTL para caché de atributos de archivos (120 segundos - reduce getattr)
+/// TTL por defecto para caché de atributos de archivos (120 segundos - reduce getattr)
 const ATTR_CACHE_TTL: Duration = Duration::from_secs(120);
 
-/// Patrones de archivos temporales a ignorar (optimización para editores)
-const TEMP_FILE_PATTERNS: &[&str] = &[
-    ".attach_pid", // Java debugger
-    ".swp",
-    ".swo",
-    ".swn", // vim swap files
-    "~",
-    ".tmp",
-    ".temp", // archivos temporales
-    ".git",
-    ".svn",
-    ".hg", // control de versiones
-    ".vscode",
-    ".idea", // configuración de IDEs
-    "__pycache__",
-    ".pyc",
-    ".pyo", // Python cache
-    ".DS_Store",
-    ".directory", // archivos de sistema
-    ".nfs",
-    ".lock",
-    ".pid", // lock files
-];
+/// TTL para caché de tipos (archivo/directorio) vistos en listados
+const KIND_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// TTL para caché de tamaños vistos en listados o con SIZE
+const SIZE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Cada cuánto se vuelve a pedir el tamaño de un archivo abierto, para que
+/// un log que crece se pueda seguir con `tail -f`
+const OPEN_SIZE_TTL: Duration = Duration::from_secs(1);
+
+/// TTL por defecto de la caché negativa de `lookup`: corto, para que un
+/// archivo creado por otro cliente no quede oculto mucho tiempo
+const NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Tiempo que se confía en el espacio libre de AVBL antes de volver a preguntar
+const FREE_SPACE_TTL: Duration = Duration::from_secs(10);
+
+/// Rutas inexistentes recordadas antes de purgar las caducadas
+const MAX_NEGATIVE_ENTRIES: usize = 1024;
+
+/// Unidad de `st_blocks`: el kernel siempre cuenta bloques de 512 bytes,
+/// independientemente del `blksize` que se anuncie
+const BLOCK_UNIT: u64 = 512;
+
+/// `blksize` por defecto anunciado a las aplicaciones (tamaño de E/S preferido)
+const DEFAULT_BLKSIZE: u32 = 128 * 1024;
+
+/// errno para un error de la capa FTP: EIO salvo que lleve un [`FtpError`]
+/// que indique algo más preciso
+fn errno_for(error: &anyhow::Error) -> i32 {
+    match error.downcast_ref::<FtpError>() {
+        Some(FtpError::NotFound) => ENOENT,
+        Some(FtpError::PermissionDenied) => EACCES,
+        Some(FtpError::NotADirectory) => ENOTDIR,
+        Some(FtpError::IsADirectory) => EISDIR,
+        Some(FtpError::NoSpace) => ENOSPC,
+        Some(FtpError::InvalidName) => libc::EILSEQ,
+        Some(FtpError::Io(e)) if e.kind() == io::ErrorKind::CrossesDevices => libc::EXDEV,
+        _ => EIO,
+    }
+}
+
+/// Responder a getxattr/listxattr: con `size` 0 solo se pide la longitud
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if (size as usize) < data.len() {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// Destino de un enlace simbólico `link_path` tal como debe verlo el kernel
+///
+/// Los destinos relativos se devuelven intactos: el kernel los resuelve
+/// dentro del montaje. Los absolutos son rutas del servidor, que el kernel
+/// resolvería desde la raíz local; si caen dentro de `root` (la ruta del
+/// servidor montada) se reescriben relativos al directorio del enlace. Los
+/// que apuntan fuera del montaje no tienen equivalente y se dejan igual.
+fn mount_link_target(root: &str, link_path: &str, target: &str) -> String {
+    if !target.starts_with('/') {
+        return target.to_string();
+    }
+
+    // Resolver `.` y `..` del destino antes de compararlo con la raíz
+    let mut resolved: Vec<&str> = Vec::new();
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                resolved.pop();
+            }
+            name => resolved.push(name),
+        }
+    }
+
+    let root: Vec<&str> = root.split('/').filter(|c| !c.is_empty()).collect();
+    if !resolved.starts_with(&root) {
+        debug!(
+            "Symlink {} points outside the mount ({}), leaving it as is",
+            link_path, target
+        );
+        return target.to_string();
+    }
+
+    let link_dir: Vec<&str> = link_path.split('/').filter(|c| !c.is_empty()).collect();
+    let link_dir = &link_dir[..link_dir.len().saturating_sub(1)];
+    let common = link_dir
+        .iter()
+        .zip(&resolved)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative: Vec<&str> = vec![".."; link_dir.len() - common];
+    relative.extend(&resolved[common..]);
+    if relative.is_empty() {
+        ".".to_string()
+    } else {
+        relative.join("/")
+    }
+}
+
+/// Ruta absoluta en el servidor a la que apunta el enlace `link_path`
+///
+/// Los destinos relativos parten del directorio del enlace; `.` y `..` se
+/// resuelven sin preguntar al servidor.
+fn resolve_link_path(link_path: &str, target: &str) -> String {
+    let base = if target.starts_with('/') {
+        ""
+    } else {
+        link_path.rsplit_once('/').map_or("", |(dir, _)| dir)
+    };
+
+    let mut resolved: Vec<&str> = Vec::new();
+    for component in base.split('/').chain(target.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                resolved.pop();
+            }
+            name => resolved.push(name),
+        }
+    }
+    format!("/{}", resolved.join("/"))
+}
+
+/// Crear `path` en el servidor con los directorios intermedios que falten
+///
+/// Falla si alguno de ellos existe como archivo.
+fn create_dir_all<B: FtpBackend>(conn: &mut B, path: &str) -> Result<()> {
+    let mut current = String::new();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        current = join_ftp_path(&current, component);
+        if conn.is_dir(&current)? {
+            continue;
+        }
+        if conn.size(&current).is_ok() {
+            anyhow::bail!("Cannot create mount path {}: {} is a file", path, current);
+        }
+        conn.mkdir(&current)
+            .with_context(|| format!("Failed to create {}", current))?;
+        info!("Created {} on the server", current);
+    }
+    Ok(())
+}
+
+/// Número de bloques de 512 bytes ocupados por `size` bytes
+fn blocks_for(size: u64) -> u64 {
+    size.div_ceil(BLOCK_UNIT)
+}
+
+/// Tamaño a partir del cual el buffer de escritura se vuelca a un fichero temporal
+const WRITE_BUFFER_SPILL_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Tamaño máximo de un archivo que se descarga entero al abrirlo para
+/// escribir; los mayores se escriben directamente en el servidor
+const WRITE_PRELOAD_MAX: u64 = 16 * 1024 * 1024;
+
+/// Tamaño de cada REST + STOR al reanudar una subida interrumpida
+const UPLOAD_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Ventana de read-ahead por defecto para lecturas secuenciales
+const DEFAULT_READ_AHEAD: usize = 1024 * 1024;
+
+/// Máximo de directorios pendientes en la cola de prefetch recursivo
+const MAX_DIR_PREFETCH_QUEUE: usize = 256;
+
+/// Espera del prefetch de directorios mientras la conexión está en uso
+const DIR_PREFETCH_BACKOFF: Duration = Duration::from_millis(20);
+
+/// Máximo de ventanas de read-ahead pendientes de descarga
+const MAX_READ_AHEAD_QUEUE: usize = 16;
+
+/// Máximo de archivos abiertos pendientes de descarga en segundo plano
+const MAX_FILE_PREFETCH_QUEUE: usize = 64;
+
+/// Bytes que puede ocupar `read_cache` antes de dejar de descargar archivos
+/// al abrirlos
+const FILE_PREFETCH_BUDGET: u64 = 256 * 1024 * 1024;
+
+/// Enlaces encadenados que `--follow-symlinks` sigue antes de dar por hecho un bucle
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// Niveles de subdirectorios que `--recursive-rmdir` borra antes de rendirse
+/// (protege de bucles de enlaces en el servidor)
+const MAX_RMDIR_DEPTH: usize = 32;
+
+/// Atributo extendido de solo lectura con la ruta del inodo en el servidor
+const FTP_PATH_XATTR: &str = "user.rustftpfs.ftp_path";
+
+/// errno de "atributo inexistente" (macOS lo llama ENOATTR)
+#[cfg(target_os = "macos")]
+const ENOATTR: i32 = libc::ENOATTR;
+#[cfg(not(target_os = "macos"))]
+const ENOATTR: i32 = libc::ENODATA;
+
+/// Patrones ignorados por defecto: solo archivos swap/backup de editores
+pub const DEFAULT_IGNORE_PATTERNS: &[&str] = &[".*.swp", ".*.swo", ".*.swn", "*~", ".#*"];
+
+/// Lista de patrones glob de archivos temporales a ignorar
+///
+/// Los nombres que coinciden no aparecen en `readdir`, `lookup` devuelve
+/// ENOENT, `create` los rechaza y `unlink` los da por borrados.
+#[derive(Debug, Clone)]
+pub struct IgnoreList {
+    patterns: Vec<glob::Pattern>,
+}
+
+impl IgnoreList {
+    /// Compilar una lista de patrones glob
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|p| {
+                glob::Pattern::new(p.as_ref())
+                    .context(format!("Invalid ignore pattern '{}'", p.as_ref()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(IgnoreList { patterns })
+    }
+
+    /// Lista vacía (no se ignora nada)
+    pub fn none() -> Self {
+        IgnoreList {
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Verifica si un nombre de archivo es temporal/ignorable
+    ///
+    /// El patrón debe cubrir el nombre completo: `*.tmp` ignora `a.tmp` pero no
+    /// `notes.tmplate`, y `.DS_Store` solo coincide con ese nombre exacto.
+    pub fn matches(&self, name: &str) -> bool {
+        self.patterns.iter().any(|p| p.matches(name))
+    }
+}
+
+impl Default for IgnoreList {
+    fn default() -> Self {
+        Self::new(DEFAULT_IGNORE_PATTERNS).expect("default ignore patterns are valid")
+    }
+}
+
+/// Patrones glob de rutas FTP que nunca se cachean (`--no-cache-glob`)
+///
+/// Sus listados, atributos y datos se piden siempre al servidor, para
+/// subárboles que cambian constantemente (p. ej. `/logs`) sin renunciar a
+/// TTL largos en el resto.
+#[derive(Debug, Clone, Default)]
+pub struct CacheExclusions {
+    patterns: Vec<glob::Pattern>,
+}
+
+impl CacheExclusions {
+    /// Compilar una lista de patrones glob
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|p| {
+                glob::Pattern::new(p.as_ref())
+                    .context(format!("Invalid no-cache pattern '{}'", p.as_ref()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(CacheExclusions { patterns })
+    }
 
-/// Verifica si un nombre de archivo es temporal/ignorable
-fn is_temp_file(name: &str) -> bool {
-    // Verificar si empieza con punto y contiene algún patrón temporal
-    if name.starts_with('.') {
-        // Archivos que empiezan con .attach_pid
-        if name.starts_with(".attach_pid") {
-            return true;
-        }
-        // Archivos que terminan en ~ (backups)
-        if name.ends_with('~') {
-            return true;
-        }
-        // Otros archivos ocultos temporales
-        for pattern in TEMP_FILE_PATTERNS {
-            if name.contains(pattern) {
+    /// Verifica si una ruta FTP completa queda fuera de las cachés
+    ///
+    /// Basta con que coincida la ruta o uno de sus directorios: `/logs`
+    /// excluye también todo lo que hay debajo. `*` cruza `/`, así que
+    /// `*.log` cubre esos archivos en cualquier directorio.
+    pub fn matches(&self, path: &str) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+        let mut path = path;
+        loop {
+            if self.patterns.iter().any(|p| p.matches(path)) {
                 return true;
             }
+            match path.rsplit_once('/') {
+                Some((parent, _)) if !parent.is_empty() => path = parent,
+                _ => return false,
+            }
         }
     }
+}
+
+/// Comprobar `mask` (R_OK/W_OK/X_OK) contra los bits de permiso del inodo
+///
+/// Se usan los bits de propietario, grupo u otros según `uid` y los grupos
+/// del proceso (el principal y los suplementarios). Root tiene lectura y
+/// escritura siempre, y ejecución si algún bit x está activo.
+fn check_access(attr: &FileAttr, uid: u32, gids: &[u32], mask: i32) -> bool {
+    let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+
+    if uid == 0 {
+        return mask & libc::X_OK as u16 == 0 || attr.perm & 0o111 != 0;
+    }
+
+    let granted = if uid == attr.uid {
+        attr.perm >> 6
+    } else if gids.contains(&attr.gid) {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    } & 0o7;
+
+    mask & !granted == 0
+}
+
+/// Grupos suplementarios de `pid`, de la línea `Groups:` de
+/// `/proc/<pid>/status`
+///
+/// FUSE solo pasa el gid principal. Si el proceso ya terminó o no hay
+/// `/proc`, no hay grupos que añadir.
+fn supplementary_groups(pid: u32) -> Vec<u32> {
+    std::fs::read_to_string(format!("/proc/{}/status", pid))
+        .map(|status| parse_groups(&status))
+        .unwrap_or_default()
+}
+
+fn parse_groups(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|gid| gid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
 
-    // Archivos que terminan en ~ (backups)
-    if name.ends_with('~') {
-        return true;
+/// Nueva posición de `lseek` en un archivo de `size` bytes, o el errno
+///
+/// FTP no sabe de huecos: todo el archivo es datos y el único hueco es el
+/// implícito al final. SEEK_CUR llega ya resuelto por el kernel como posición
+/// absoluta, así que se trata igual que SEEK_SET.
+fn seek_position(offset: i64, whence: i32, size: u64) -> Result<i64, i32> {
+    let size = i64::try_from(size).map_err(|_| EINVAL)?;
+    let position = match whence {
+        libc::SEEK_SET | libc::SEEK_CUR => offset,
+        libc::SEEK_END => size.checked_add(offset).ok_or(EINVAL)?,
+        libc::SEEK_DATA | libc::SEEK_HOLE if offset < 0 => return Err(EINVAL),
+        libc::SEEK_DATA | libc::SEEK_HOLE if offset >= size => return Err(ENXIO),
+        libc::SEEK_DATA => offset,
+        libc::SEEK_HOLE => size,
+        _ => return Err(EINVAL),
+    };
+    if position < 0 {
+        return Err(EINVAL);
     }
+    Ok(position)
+}
+
+/// Rango `[inicio, fin)` de una lectura de `size` bytes en `offset`, o el errno
+///
+/// Un offset negativo es EINVAL. El final se satura en lugar de desbordarse:
+/// lo que quede más allá del fin del archivo simplemente se lee vacío.
+fn read_range(offset: i64, size: u32) -> Result<(u64, u64), i32> {
+    let start = u64::try_from(offset).map_err(|_| EINVAL)?;
+    Ok((start, start.saturating_add(size as u64)))
+}
+
+/// Rango `[inicio, fin)` de una escritura de `len` bytes en `offset`, o el errno
+///
+/// Un offset negativo es EINVAL, y un final que no cabe en `st_size` (más
+/// allá de `i64::MAX`) es EFBIG, como en un sistema de archivos local.
+fn write_range(offset: i64, len: usize) -> Result<(u64, u64), i32> {
+    let start = u64::try_from(offset).map_err(|_| EINVAL)?;
+    let end = i64::try_from(len)
+        .ok()
+        .and_then(|len| offset.checked_add(len))
+        .ok_or(libc::EFBIG)?;
+    Ok((start, end as u64))
+}
+
+/// Porción `[start, end)` de `data`, recortada a su longitud
+fn slice_range(data: &[u8], start: u64, end: u64) -> &[u8] {
+    let len = data.len() as u64;
+    let start = start.min(len);
+    &data[start as usize..end.clamp(start, len) as usize]
+}
+
+/// Buscar una entrada de un listado por nombre exacto
+///
+/// Se comparan los bytes tal cual los devolvió el servidor (sin normalizar),
+/// de modo que lo que muestra `readdir` es exactamente lo que encuentra `lookup`.
+fn find_entry<'a>(files: &'a [FtpFileInfo], name: &str) -> Option<&'a FtpFileInfo> {
+    files.iter().find(|f| f.name.as_bytes() == name.as_bytes())
+}
 
-    false
+/// Nombre recibido del kernel como UTF-8, o EILSEQ si no lo es
+///
+/// Los nombres que no son UTF-8 no pueden enviarse al servidor sin
+/// alterarlos, así que todas las operaciones los rechazan con el mismo error.
+fn utf8_name(name: &OsStr) -> Result<&str, i32> {
+    name.to_str().ok_or(EILSEQ)
 }
 
 /// Representa un inodo de archivo o directorio
@@ -95,6 +497,8 @@ struct Inode {
     name: String,
     attr: FileAttr,
     ftp_path: String,
+    /// Destino de un enlace simbólico tal como lo lista el servidor
+    link_target: Option<String>,
 }
 
 /// Entrada de caché de directorio con timestamp
@@ -104,6 +508,166 @@ struct DirCacheEntry {
     timestamp: Instant,
 }
 
+/// Caché ruta -> dato visto en un listado de directorio reciente
+///
+/// Se usa para el tipo (evita el sondeo de `is_dir`: MLST o LIST del padre +
+/// CWD) y para el tamaño (evita un SIZE por cada `getattr`) en el camino de
+/// consulta individual.
+#[derive(Debug)]
+struct ListingCache<T> {
+    entries: HashMap<String, (T, Instant)>,
+    ttl: Duration,
+    /// Consultas resueltas desde la caché
+    hits: u64,
+    /// Consultas que tuvieron que preguntar al servidor
+    misses: u64,
+}
+
+/// Límites superiores (segundos) de los cubos de los histogramas de latencia
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Histograma de latencias de una operación FTP
+#[derive(Default)]
+struct Histogram {
+    /// Observaciones por cubo (sin acumular); las mayores solo cuentan en `count`
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    /// Suma de las latencias en microsegundos
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| secs <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Cubos acumulados como los espera Prometheus: (límite, observaciones <= límite)
+    #[cfg(feature = "metrics")]
+    fn cumulative(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        LATENCY_BUCKETS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(&le, n)| {
+                total += n.load(Ordering::Relaxed);
+                (le, total)
+            })
+            .collect()
+    }
+}
+
+/// Contadores que se publican en el archivo de estadísticas
+#[derive(Default)]
+struct Stats {
+    /// Listados servidos desde `dir_cache`
+    dir_cache_hits: AtomicU64,
+    /// Listados que tuvieron que pedirse al servidor
+    dir_cache_misses: AtomicU64,
+    /// Archivos descartados de la caché de lectura
+    read_cache_evictions: AtomicU64,
+    /// Bytes descargados del servidor
+    bytes_down: AtomicU64,
+    /// Bytes subidos al servidor
+    bytes_up: AtomicU64,
+    /// Reconexiones completadas
+    reconnects: AtomicU64,
+    /// Duración de los listados pedidos al servidor (reintentos incluidos)
+    list_latency: Histogram,
+    /// Duración de las descargas, completas o por ventanas
+    retrieve_latency: Histogram,
+    /// Duración de las subidas de buffers de escritura
+    store_latency: Histogram,
+}
+
+/// Valores de las estadísticas en un momento dado, leídos sin tocar la red
+struct StatsSnapshot {
+    dir_entries: usize,
+    attr_entries: usize,
+    /// (aciertos, fallos) de cada caché
+    dir: (u64, u64),
+    kind: (u64, u64),
+    size: (u64, u64),
+    negative: (u64, u64),
+    read_files: usize,
+    read_bytes: usize,
+    read_evictions: u64,
+    open_handles: usize,
+    bytes_down: u64,
+    bytes_up: u64,
+    reconnects: u64,
+}
+
+impl Stats {
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Copy> ListingCache<T> {
+    fn new(ttl: Duration) -> Self {
+        ListingCache {
+            entries: HashMap::new(),
+            ttl,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// (aciertos, fallos) hasta ahora
+    fn hits_misses(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    /// Valor reciente de `path`, sin contarlo en las estadísticas
+    fn peek(&self, path: &str) -> Option<T> {
+        self.entries
+            .get(path)
+            .filter(|(_, timestamp)| timestamp.elapsed() < self.ttl)
+            .map(|&(value, _)| value)
+    }
+
+    /// Valor reciente de `path`, contando aciertos y fallos
+    fn get(&mut self, path: &str) -> Option<T> {
+        let cached = self.peek(path);
+        if cached.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        cached
+    }
+
+    fn insert(&mut self, path: &str, value: T) {
+        self.entries
+            .insert(path.to_string(), (value, Instant::now()));
+    }
+
+    /// Olvidar las entradas contenidas directamente en `dir`
+    fn forget_children(&mut self, dir: &str) {
+        self.entries
+            .retain(|p, _| Path::new(p).parent() != Some(Path::new(dir)));
+    }
+
+    /// Descartar las entradas caducadas
+    fn prune(&mut self) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, (_, timestamp)| timestamp.elapsed() < ttl);
+    }
+}
+
 /// Entrada de caché de atributos con timestamp
 #[derive(Debug, Clone)]
 struct AttrCacheEntry {
@@ -111,51 +675,677 @@ struct AttrCacheEntry {
     timestamp: Instant,
 }
 
+/// Almacenamiento del buffer de escritura
+#[derive(Debug)]
+enum BufferData {
+    /// Contenido en memoria (archivos pequeños)
+    Memory(Vec<u8>),
+    /// Contenido volcado a un fichero temporal anónimo (archivos grandes)
+    Disk { file: File, len: u64 },
+}
+
 /// Buffer de escritura para lazy write
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct WriteBuffer {
-    data: Vec<u8>,
+    data: BufferData,
     dirty: bool,
     last_modified: Instant,
+    /// El contenido no se cargó (archivo demasiado grande): cada escritura se
+    /// sube en su offset al momento, como con `--write-through`
+    direct: bool,
+}
+
+impl WriteBuffer {
+    fn new() -> Self {
+        WriteBuffer {
+            data: BufferData::Memory(Vec::new()),
+            dirty: false,
+            last_modified: Instant::now(),
+            direct: false,
+        }
+    }
+
+    /// Tamaño actual del contenido
+    fn len(&self) -> u64 {
+        match &self.data {
+            BufferData::Memory(data) => data.len() as u64,
+            BufferData::Disk { len, .. } => *len,
+        }
+    }
+
+    /// Escribir datos en el offset indicado, volcando a disco si se supera el umbral
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EFBIG))?;
+
+        if let BufferData::Memory(buffer) = &self.data {
+            if end > WRITE_BUFFER_SPILL_THRESHOLD {
+                debug!(
+                    "Write buffer exceeds {} bytes, spilling to temp file",
+                    WRITE_BUFFER_SPILL_THRESHOLD
+                );
+                let file = tempfile::tempfile()?;
+                file.write_all_at(buffer, 0)?;
+                let len = buffer.len() as u64;
+                self.data = BufferData::Disk { file, len };
+            }
+        }
+
+        match &mut self.data {
+            BufferData::Memory(buffer) => {
+                let (offset, end) = (offset as usize, end as usize);
+                if end > buffer.len() {
+                    buffer.resize(end, 0);
+                }
+                buffer[offset..end].copy_from_slice(data);
+            }
+            BufferData::Disk { file, len } => {
+                // Los huecos de un fichero disperso se leen como ceros
+                file.write_all_at(data, offset)?;
+                *len = (*len).max(end);
+            }
+        }
+
+        self.dirty = true;
+        self.last_modified = Instant::now();
+        Ok(())
+    }
+
+    /// Truncar o extender (con ceros) el contenido a `len` bytes
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if len > self.len() {
+            // El último byte es cero; el hueco intermedio también se rellena con ceros
+            return self.write_at(len - 1, &[0]);
+        }
+
+        match &mut self.data {
+            BufferData::Memory(buffer) => buffer.truncate(len as usize),
+            BufferData::Disk { file, len: current } => {
+                file.set_len(len)?;
+                *current = len;
+            }
+        }
+        // Vacío, el contenido vuelve a ser completo
+        if len == 0 {
+            self.direct = false;
+        }
+        self.dirty = true;
+        self.last_modified = Instant::now();
+        Ok(())
+    }
+
+    /// Poner a cero `[offset, end)`, sin pasar de `end`
+    fn zero_range(&mut self, offset: u64, end: u64) -> io::Result<()> {
+        static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+        let mut pos = offset;
+        while pos < end {
+            let n = (end - pos).min(ZEROS.len() as u64) as usize;
+            self.write_at(pos, &ZEROS[..n])?;
+            pos += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Reservar memoria para `len` bytes sin cambiar el tamaño
+    fn reserve(&mut self, len: u64) {
+        if let BufferData::Memory(buffer) = &mut self.data {
+            if len <= WRITE_BUFFER_SPILL_THRESHOLD {
+                buffer.reserve((len as usize).saturating_sub(buffer.len()));
+            }
+        }
+    }
+
+    /// Leer un rango del contenido
+    fn read_at(&self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let len = self.len();
+        if offset >= len {
+            return Ok(Vec::new());
+        }
+        let end = len.min(offset.saturating_add(size as u64));
+
+        match &self.data {
+            BufferData::Memory(data) => Ok(data[offset as usize..end as usize].to_vec()),
+            BufferData::Disk { file, .. } => {
+                let mut out = vec![0; (end - offset) as usize];
+                file.read_exact_at(&mut out, offset)?;
+                Ok(out)
+            }
+        }
+    }
+
+    /// Copia del contenido, para subirla sin bloquear las escrituras
+    fn snapshot(&self) -> io::Result<WriteBuffer> {
+        let data = match &self.data {
+            BufferData::Memory(data) => BufferData::Memory(data.clone()),
+            BufferData::Disk { file, len } => {
+                // Las escrituras usan offsets explícitos: mover el cursor no les afecta
+                let mut source = file.try_clone()?;
+                source.seek(SeekFrom::Start(0))?;
+                let mut copy = tempfile::tempfile()?;
+                io::copy(&mut Read::take(source, *len), &mut copy)?;
+                BufferData::Disk {
+                    file: copy,
+                    len: *len,
+                }
+            }
+        };
+        Ok(WriteBuffer {
+            data,
+            dirty: self.dirty,
+            last_modified: self.last_modified,
+            direct: self.direct,
+        })
+    }
+
+    /// Lector secuencial del contenido completo para subirlo al servidor
+    fn reader(&mut self) -> io::Result<Box<dyn Read + '_>> {
+        match &mut self.data {
+            BufferData::Memory(data) => Ok(Box::new(io::Cursor::new(data.as_slice()))),
+            BufferData::Disk { file, len } => {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(Read::take(file, *len)))
+            }
+        }
+    }
+}
+
+/// Lector que cuenta los bytes que entrega, para saber hasta dónde pudo
+/// llegar una subida cortada
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Cola de trabajo de hilos en segundo plano, compartida por todos los
+/// clones del filesystem para que `close` la cierre para todos
+///
+/// Los hilos no guardan el emisor, así que al soltarlo `close` su `recv`
+/// termina en lugar de bloquearse para siempre.
+struct WorkQueue<T> {
+    sender: Mutex<Option<SyncSender<T>>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl<T> WorkQueue<T> {
+    /// Cola cerrada, sin hilos: todo `try_send` falla
+    fn closed() -> Self {
+        WorkQueue {
+            sender: Mutex::new(None),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Abrir la cola con `sender` para los hilos que la atienden
+    fn open(&self, sender: SyncSender<T>) {
+        *self.sender.lock().unwrap() = Some(sender);
+    }
+
+    /// Registrar un hilo que atiende la cola, para esperarlo al cerrarla
+    fn add_thread(&self, handle: JoinHandle<()>) {
+        self.threads.lock().unwrap().push(handle);
+    }
+
+    /// Si la cola acepta trabajo
+    fn is_open(&self) -> bool {
+        self.sender.lock().unwrap().is_some()
+    }
+
+    /// Encolar sin esperar; una cola cerrada responde `Disconnected`
+    fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        match &*self.sender.lock().unwrap() {
+            Some(sender) => sender.try_send(item),
+            None => Err(TrySendError::Disconnected(item)),
+        }
+    }
+
+    /// Soltar el emisor y esperar a que terminen los hilos
+    fn close(&self) {
+        self.sender.lock().unwrap().take();
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for handle in threads {
+            // Un hilo de la cola no puede esperarse a sí mismo
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
 }
 
 /// Información de handle de archivo abierto
 #[derive(Debug, Clone)]
 struct FileHandle {
     ino: u64,
-    write_buffer: Option<WriteBuffer>,
+    write_buffer: Option<Arc<Mutex<WriteBuffer>>>,
+    /// Offset donde terminó la última lectura (detección de acceso secuencial)
+    next_read: u64,
+}
+
+/// Estado observado de un archivo vigilado: (tamaño en el listado, MDTM)
+type WatchState = (Option<u64>, Option<SystemTime>);
+
+/// Ventanas de read-ahead de un archivo: índice de ventana -> datos
+type AheadChunks = BTreeMap<u64, Arc<Vec<u8>>>;
+
+/// Opciones de comportamiento del filesystem
+#[derive(Debug, Clone)]
+pub struct FsOptions {
+    /// Subir cada `write` al servidor inmediatamente (REST + STOR) en lugar de
+    /// acumularlo hasta flush/release. Más lento, pero no pierde datos ante un
+    /// fallo y otros clientes ven el contenido al momento.
+    pub write_through: bool,
+    /// Intervalo de sondeo para detectar cambios externos en archivos abiertos
+    /// o cacheados (None o cero = desactivado)
+    pub poll_interval: Option<Duration>,
+    /// Subir los buffers sucios que lleven este tiempo sin escribirse, aunque
+    /// el archivo siga abierto (None = solo en flush/fsync/release)
+    pub flush_interval: Option<Duration>,
+    /// Reintentos (con reconexión) ante fallos transitorios de transferencia
+    pub retries: u32,
+    /// Enviar NOOP tras este tiempo sin actividad para que el servidor no
+    /// cierre la conexión de control (None = desactivado)
+    pub keepalive: Option<Duration>,
+    /// Tamaño de bloque preferido anunciado en `st_blksize`
+    pub blksize: u32,
+    /// Archivos temporales a ocultar y no enviar al servidor
+    pub ignore: IgnoreList,
+    /// Crear los archivos de `ignore` en un área local en memoria en lugar de
+    /// rechazarlos; renombrarlos a un nombre normal los sube al servidor
+    pub shadow_ignored: bool,
+    /// Montaje de solo lectura: toda operación que modifique algo falla con
+    /// EROFS sin llegar al servidor, aunque el kernel ya no suela pedirla
+    pub read_only: bool,
+    /// Tamaño de la ventana de read-ahead para lecturas secuenciales
+    /// (0 = desactivado, se descarga el archivo completo). Con `mirror` no se
+    /// usa: el espejo guarda archivos completos
+    pub read_ahead: usize,
+    /// Bytes de contenido de archivos que `read_cache` guarda en memoria; al
+    /// pasarse se descartan otros archivos (None = sin límite)
+    pub cache_size: Option<u64>,
+    /// Archivos más grandes que esto nunca se cargan enteros en memoria: se
+    /// leen por ventanas y no se puede escribir más allá (None = sin límite)
+    pub max_file_size: Option<u64>,
+    /// Propietario de todos los archivos (None = el usuario que monta)
+    pub uid: Option<u32>,
+    /// Grupo de todos los archivos (None = el grupo del usuario que monta)
+    pub gid: Option<u32>,
+    /// Bits que se quitan a los permisos que anuncia el servidor
+    pub umask: u16,
+    /// Modo para los archivos nuevos cuando el cliente pide el habitual
+    /// (0666) o ninguno (`--file-mode`)
+    pub file_mode: Option<u32>,
+    /// Modo para los directorios nuevos cuando el cliente pide el habitual
+    /// (0777) o ninguno (`--dir-mode`)
+    pub dir_mode: Option<u32>,
+    /// Ruta del servidor que se muestra como raíz del montaje
+    pub root: String,
+    /// Crear `root` (y los directorios intermedios) si no existe
+    pub mkdir_root: bool,
+    /// Niveles de subdirectorios a listar en segundo plano tras un `readdir`
+    /// (0 = desactivado)
+    pub prefetch_depth: usize,
+    /// Hilos que descargan en segundo plano el contenido de los archivos al
+    /// abrirlos, para que el primer `read` ya lo encuentre (0 = desactivado)
+    pub prefetch_workers: usize,
+    /// Tiempo que el kernel guarda entradas (y sus atributos) sin preguntar
+    pub entry_ttl: Duration,
+    /// Vigencia de los listados de directorio cacheados; también acota las
+    /// cachés de tipo y tamaño que salen de ellos
+    pub dir_ttl: Duration,
+    /// Vigencia de los atributos cacheados; el kernel los guarda como mucho
+    /// `entry_ttl`
+    pub attr_ttl: Duration,
+    /// Tiempo que `lookup` recuerda que un nombre no existe (0 = desactivado)
+    pub negative_ttl: Duration,
+    /// Consistencia estricta (`--consistency strict`): los listados y cada
+    /// `getattr` actualizan tamaño y fecha de los inodos ya conocidos, y los
+    /// datos cacheados se descartan al abrir o si algo cambió
+    pub strict: bool,
+    /// Rutas cuyos listados, atributos y datos no se cachean
+    pub no_cache: CacheExclusions,
+    /// Copia local de archivos y listados para seguir leyendo sin conexión
+    /// y encolar las subidas que fallen (None = desactivada)
+    pub mirror: Option<Mirror>,
+    /// Servir solo desde `mirror`, sin contactar con el servidor
+    pub offline: bool,
+    /// Mostrar el archivo de estadísticas en los listados de la raíz
+    /// (siempre se puede abrir por su nombre)
+    pub show_control: bool,
+    /// Presentar los enlaces simbólicos como su destino (directorio o
+    /// archivo) en lugar de como enlaces
+    pub follow_symlinks: bool,
+    /// Tratar `File.txt` y `file.txt` como el mismo archivo, como hacen los
+    /// servidores sobre Windows; los nombres se siguen mostrando tal cual
+    pub case_insensitive: bool,
+    /// Dirección en la que servir las estadísticas para Prometheus
+    #[cfg(feature = "metrics")]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Si RMD falla, borrar el contenido del directorio en el servidor y
+    /// reintentar (`--recursive-rmdir`)
+    pub recursive_rmdir: bool,
+    /// Bytes del espacio libre (AVBL) que no se anuncian en `statfs` ni se
+    /// dejan ocupar a las escrituras, que fallan antes con ENOSPC
+    /// (None = sin comprobar el espacio al escribir)
+    pub quota_reserve: Option<u64>,
 }
 
+impl Default for FsOptions {
+    fn default() -> Self {
+        FsOptions {
+            write_through: false,
+            poll_interval: None,
+            flush_interval: None,
+            retries: 3,
+            keepalive: Some(Duration::from_secs(60)),
+            blksize: DEFAULT_BLKSIZE,
+            ignore: IgnoreList::default(),
+            shadow_ignored: false,
+            read_only: false,
+            read_ahead: DEFAULT_READ_AHEAD,
+            cache_size: None,
+            max_file_size: None,
+            uid: None,
+            gid: None,
+            umask: 0,
+            file_mode: None,
+            dir_mode: None,
+            root: "/".to_string(),
+            mkdir_root: false,
+            prefetch_depth: 0,
+            prefetch_workers: 0,
+            entry_ttl: TTL,
+            dir_ttl: DIR_CACHE_TTL,
+            attr_ttl: ATTR_CACHE_TTL,
+            negative_ttl: NEGATIVE_CACHE_TTL,
+            strict: false,
+            no_cache: CacheExclusions::default(),
+            mirror: None,
+            offline: false,
+            show_control: false,
+            follow_symlinks: false,
+            case_insensitive: false,
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+            recursive_rmdir: false,
+            quota_reserve: None,
+        }
+    }
+}
+
+/// Rangos en el orden global de bloqueo de los mutex de `FtpFs` que se
+/// toman anidados: con uno tomado solo se puede tomar otro de rango mayor
+///
+/// Los buffers de escritura van entre `open_files` y `ftp_conn` (la
+/// conexión se toma con el buffer retenido durante la subida); quien tiene
+/// la conexión solo puede probarlos con `try_lock`. Los mutex sin rango
+/// (contadores, `watch_state`, `avbl`, `stats_snapshots`...) se toman
+/// solos: nunca se tiene otro al tomarlos ni se toma otro con ellos.
+const RANK_OPEN_FILES: u8 = 10;
+const RANK_FTP_CONN: u8 = 20;
+const RANK_SHADOW_FILES: u8 = 30;
+const RANK_DEFERRED_UNLINKS: u8 = 35;
+const RANK_PENDING_CREATES: u8 = 40;
+const RANK_CHMOD_CHECKED: u8 = 45;
+const RANK_PATH_TO_INODE: u8 = 50;
+const RANK_DIR_CACHE: u8 = 55;
+const RANK_INODES: u8 = 60;
+const RANK_ATTR_CACHE: u8 = 70;
+const RANK_KIND_CACHE: u8 = 80;
+const RANK_SIZE_CACHE: u8 = 85;
+const RANK_NEGATIVE_CACHE: u8 = 90;
+const RANK_READ_CACHE: u8 = 100;
+const RANK_FILE_PREFETCHING: u8 = 105;
+const RANK_AHEAD_CACHE: u8 = 110;
+
 /// Implementación del filesystem FUSE para FTP (Optimizado)
-pub struct FtpFs {
-    ftp_conn: Arc<Mutex<FtpConnection>>,
-    inodes: Arc<Mutex<HashMap<u64, Inode>>>,
-    path_to_inode: Arc<Mutex<HashMap<String, u64>>>,
+///
+/// Todo el estado está detrás de `Arc`, así que los clones comparten cachés y
+/// conexión (se usan para los hilos en segundo plano). Es genérico sobre el
+/// backend para poder probarlo con un servidor simulado en memoria.
+///
+/// Los mutex que se anidan siguen el orden de los `RANK_*`, comprobado en
+/// las compilaciones de depuración.
+pub struct FtpFs<B: FtpBackend = FtpConnection> {
+    ftp_conn: Arc<OrderedMutex<B>>,
+    inodes: Arc<OrderedMutex<HashMap<u64, Inode>>>,
+    path_to_inode: Arc<OrderedMutex<HashMap<String, u64>>>,
     next_inode: Arc<Mutex<u64>>,
-    read_cache: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
+    read_cache: Arc<OrderedMutex<HashMap<u64, Vec<u8>>>>,
     /// Caché de listados de directorio: path -> (archivos, timestamp)
-    dir_cache: Arc<Mutex<HashMap<String, DirCacheEntry>>>,
+    dir_cache: Arc<OrderedMutex<HashMap<String, DirCacheEntry>>>,
+    /// Tipos vistos en listados recientes: path -> (es directorio, timestamp)
+    kind_cache: Arc<OrderedMutex<ListingCache<bool>>>,
+    /// Tamaños vistos en listados recientes o con SIZE: path -> (tamaño, timestamp)
+    size_cache: Arc<OrderedMutex<ListingCache<u64>>>,
     /// Caché de atributos: ino -> (atributos, timestamp)
-    attr_cache: Arc<Mutex<HashMap<u64, AttrCacheEntry>>>,
+    attr_cache: Arc<OrderedMutex<HashMap<u64, AttrCacheEntry>>>,
+    /// Rutas que `lookup` encontró inexistentes: path -> ((), timestamp)
+    negative_cache: Arc<OrderedMutex<ListingCache<()>>>,
     /// Handles de archivos abiertos: fh -> FileHandle
-    open_files: Arc<Mutex<HashMap<u64, FileHandle>>>,
+    open_files: Arc<OrderedMutex<HashMap<u64, FileHandle>>>,
     /// Contador para generar file handles únicos
     next_fh: Arc<Mutex<u64>>,
+    /// Último (tamaño, mtime) observado por el sondeo de cambios: ino -> estado
+    watch_state: Arc<Mutex<HashMap<u64, WatchState>>>,
+    /// Ventanas descargadas por read-ahead: ino -> ventanas
+    ahead_cache: Arc<OrderedMutex<HashMap<u64, AheadChunks>>>,
+    /// Ventanas que se están descargando en segundo plano: (ino, ventana)
+    prefetching: Arc<Mutex<HashSet<(u64, u64)>>>,
+    /// Cola del hilo de read-ahead: (ino, ruta, ventana)
+    read_ahead: Arc<WorkQueue<(u64, String, u64)>>,
+    /// Archivos por encima de `max_file_size` de los que ya se avisó al abrirlos
+    oversized_warned: Arc<Mutex<HashSet<u64>>>,
+    /// Último SIZE de cada archivo abierto: ino -> momento
+    size_checked: Arc<Mutex<HashMap<u64, Instant>>>,
+    /// Cola del hilo de prefetch recursivo: (directorio, niveles restantes)
+    dir_prefetch: Arc<WorkQueue<(String, usize)>>,
+    /// Último AVBL de la raíz, menos lo escrito desde entonces: (bytes
+    /// libres o None si el servidor no lo dice, momento de la consulta)
+    avbl: Arc<Mutex<Option<(Option<u64>, Instant)>>>,
+    /// Cola de los hilos de prefetch de archivos abiertos: (ino, ruta)
+    file_prefetch: Arc<WorkQueue<(u64, String)>>,
+    /// Archivos encolados o descargándose por `file_prefetch`; cerrar el
+    /// último handle los quita y cancela así su descarga
+    file_prefetching: Arc<OrderedMutex<HashSet<u64>>>,
+    /// Archivos borrados mientras seguían abiertos: ino -> ruta a borrar en el
+    /// servidor al cerrarse el último handle
+    deferred_unlinks: Arc<OrderedMutex<HashMap<u64, String>>>,
+    /// Archivos temporales que solo existen en local: ino -> contenido
+    shadow_files: Arc<OrderedMutex<HashMap<u64, Arc<Mutex<WriteBuffer>>>>>,
+    /// Archivos creados que aún no se han subido: ino -> modo a aplicar
+    pending_creates: Arc<OrderedMutex<HashMap<u64, u32>>>,
+    /// Resultado de comprobar en el listado el modo de lo creado con
+    /// `--file-mode`/`--dir-mode`: (directorio, es directorio) -> None si
+    /// SITE CHMOD se aplicó, o el modo con el que el servidor crea si lo ignora
+    chmod_checked: Arc<OrderedMutex<HashMap<(String, bool), Option<u32>>>>,
+    /// Contadores de caché y transferencias
+    stats: Arc<Stats>,
+    /// Contenido del archivo de estadísticas fijado al abrirlo: fh -> JSON
+    stats_snapshots: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
+    /// Bloqueos de `fcntl` tomados a través de este montaje
+    locks: Arc<LockTable>,
+    /// Si ya se llamó a `shutdown`; los hilos de fondo terminan al verlo
+    shut_down: Arc<AtomicBool>,
+    /// Servidor de `--metrics-addr`, que `shutdown` detiene
+    #[cfg(feature = "metrics")]
+    metrics_server: Arc<Mutex<Option<metrics::Server>>>,
+    /// Extensiones de los archivos que el backend transfiere en modo ASCII;
+    /// como los bytes no coinciden con SIZE ni con los offsets de REST, se
+    /// leen siempre enteros y el tamaño que se anuncia es el de lo descargado
+    ascii_extensions: Arc<[String]>,
+    /// Si es el handle que devolvió `with_options` y no un clon: solo ese
+    /// cierra el filesystem al destruirse
+    owner: bool,
+    /// Opciones de comportamiento
+    options: FsOptions,
+}
+
+impl<B: FtpBackend> Clone for FtpFs<B> {
+    fn clone(&self) -> Self {
+        FtpFs {
+            ftp_conn: Arc::clone(&self.ftp_conn),
+            inodes: Arc::clone(&self.inodes),
+            path_to_inode: Arc::clone(&self.path_to_inode),
+            next_inode: Arc::clone(&self.next_inode),
+            read_cache: Arc::clone(&self.read_cache),
+            dir_cache: Arc::clone(&self.dir_cache),
+            kind_cache: Arc::clone(&self.kind_cache),
+            size_cache: Arc::clone(&self.size_cache),
+            attr_cache: Arc::clone(&self.attr_cache),
+            negative_cache: Arc::clone(&self.negative_cache),
+            open_files: Arc::clone(&self.open_files),
+            next_fh: Arc::clone(&self.next_fh),
+            watch_state: Arc::clone(&self.watch_state),
+            ahead_cache: Arc::clone(&self.ahead_cache),
+            prefetching: Arc::clone(&self.prefetching),
+            read_ahead: Arc::clone(&self.read_ahead),
+            oversized_warned: Arc::clone(&self.oversized_warned),
+            size_checked: Arc::clone(&self.size_checked),
+            dir_prefetch: Arc::clone(&self.dir_prefetch),
+            avbl: Arc::clone(&self.avbl),
+            file_prefetch: Arc::clone(&self.file_prefetch),
+            file_prefetching: Arc::clone(&self.file_prefetching),
+            deferred_unlinks: Arc::clone(&self.deferred_unlinks),
+            shadow_files: Arc::clone(&self.shadow_files),
+            pending_creates: Arc::clone(&self.pending_creates),
+            chmod_checked: Arc::clone(&self.chmod_checked),
+            stats: Arc::clone(&self.stats),
+            stats_snapshots: Arc::clone(&self.stats_snapshots),
+            locks: Arc::clone(&self.locks),
+            shut_down: Arc::clone(&self.shut_down),
+            #[cfg(feature = "metrics")]
+            metrics_server: Arc::clone(&self.metrics_server),
+            ascii_extensions: Arc::clone(&self.ascii_extensions),
+            owner: false,
+            options: self.options.clone(),
+        }
+    }
 }
 
-impl FtpFs {
+impl<B: FtpBackend> FtpFs<B> {
     /// Crear un nuevo filesystem FTP
-    pub fn new(ftp_conn: FtpConnection) -> Result<Self> {
-        let fs = FtpFs {
-            ftp_conn: Arc::new(Mutex::new(ftp_conn)),
-            inodes: Arc::new(Mutex::new(HashMap::new())),
-            path_to_inode: Arc::new(Mutex::new(HashMap::new())),
-            next_inode: Arc::new(Mutex::new(2)), // Empieza en 2, 1 está reservado para root
-            read_cache: Arc::new(Mutex::new(HashMap::new())),
-            dir_cache: Arc::new(Mutex::new(HashMap::new())),
-            attr_cache: Arc::new(Mutex::new(HashMap::new())),
-            open_files: Arc::new(Mutex::new(HashMap::new())),
+    pub fn new(ftp_conn: B) -> Result<Self> {
+        Self::with_options(ftp_conn, FsOptions::default())
+    }
+
+    /// Crear un nuevo filesystem FTP con opciones explícitas
+    pub fn with_options(mut ftp_conn: B, options: FsOptions) -> Result<Self> {
+        // Una raíz inexistente se mostraría como un directorio vacío: fallar antes
+        let root_path = normalize_ftp_path(&options.root);
+        if root_path != "/" && !ftp_conn.is_dir(&root_path)? {
+            if ftp_conn.size(&root_path).is_ok() {
+                anyhow::bail!("Mount path {} is not a directory", root_path);
+            }
+            if !options.mkdir_root {
+                anyhow::bail!(
+                    "Mount path {} does not exist on the server (use --mkdir-root to create it)",
+                    root_path
+                );
+            }
+            create_dir_all(&mut ftp_conn, &root_path)?;
+        }
+
+        let ascii_extensions = ftp_conn.ascii_extensions().into();
+        let mut fs = FtpFs {
+            ftp_conn: Arc::new(OrderedMutex::new(RANK_FTP_CONN, "ftp_conn", ftp_conn)),
+            inodes: Arc::new(OrderedMutex::new(RANK_INODES, "inodes", HashMap::new())),
+            path_to_inode: Arc::new(OrderedMutex::new(
+                RANK_PATH_TO_INODE,
+                "path_to_inode",
+                HashMap::new(),
+            )),
+            next_inode: Arc::new(Mutex::new(3)), // 1 es root y 2 el archivo de estadísticas
+            read_cache: Arc::new(OrderedMutex::new(
+                RANK_READ_CACHE,
+                "read_cache",
+                HashMap::new(),
+            )),
+            dir_cache: Arc::new(OrderedMutex::new(
+                RANK_DIR_CACHE,
+                "dir_cache",
+                HashMap::new(),
+            )),
+            kind_cache: Arc::new(OrderedMutex::new(
+                RANK_KIND_CACHE,
+                "kind_cache",
+                ListingCache::new(KIND_CACHE_TTL.min(options.dir_ttl)),
+            )),
+            size_cache: Arc::new(OrderedMutex::new(
+                RANK_SIZE_CACHE,
+                "size_cache",
+                ListingCache::new(SIZE_CACHE_TTL.min(options.dir_ttl)),
+            )),
+            attr_cache: Arc::new(OrderedMutex::new(
+                RANK_ATTR_CACHE,
+                "attr_cache",
+                HashMap::new(),
+            )),
+            negative_cache: Arc::new(OrderedMutex::new(
+                RANK_NEGATIVE_CACHE,
+                "negative_cache",
+                ListingCache::new(options.negative_ttl),
+            )),
+            open_files: Arc::new(OrderedMutex::new(
+                RANK_OPEN_FILES,
+                "open_files",
+                HashMap::new(),
+            )),
             next_fh: Arc::new(Mutex::new(1)), // File handles empiezan en 1
+            watch_state: Arc::new(Mutex::new(HashMap::new())),
+            ahead_cache: Arc::new(OrderedMutex::new(
+                RANK_AHEAD_CACHE,
+                "ahead_cache",
+                HashMap::new(),
+            )),
+            prefetching: Arc::new(Mutex::new(HashSet::new())),
+            read_ahead: Arc::new(WorkQueue::closed()),
+            oversized_warned: Arc::new(Mutex::new(HashSet::new())),
+            size_checked: Arc::new(Mutex::new(HashMap::new())),
+            dir_prefetch: Arc::new(WorkQueue::closed()),
+            avbl: Arc::new(Mutex::new(None)),
+            file_prefetch: Arc::new(WorkQueue::closed()),
+            file_prefetching: Arc::new(OrderedMutex::new(
+                RANK_FILE_PREFETCHING,
+                "file_prefetching",
+                HashSet::new(),
+            )),
+            deferred_unlinks: Arc::new(OrderedMutex::new(
+                RANK_DEFERRED_UNLINKS,
+                "deferred_unlinks",
+                HashMap::new(),
+            )),
+            shadow_files: Arc::new(OrderedMutex::new(
+                RANK_SHADOW_FILES,
+                "shadow_files",
+                HashMap::new(),
+            )),
+            pending_creates: Arc::new(OrderedMutex::new(
+                RANK_PENDING_CREATES,
+                "pending_creates",
+                HashMap::new(),
+            )),
+            chmod_checked: Arc::new(OrderedMutex::new(
+                RANK_CHMOD_CHECKED,
+                "chmod_checked",
+                HashMap::new(),
+            )),
+            stats: Arc::new(Stats::default()),
+            stats_snapshots: Arc::new(Mutex::new(HashMap::new())),
+            locks: Arc::new(LockTable::default()),
+            shut_down: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "metrics")]
+            metrics_server: Arc::new(Mutex::new(None)),
+            ascii_extensions,
+            owner: true,
+            options,
         };
 
         // Crear inodo raíz
@@ -168,13 +1358,13 @@ impl FtpFs {
             ctime: SystemTime::now(),
             crtime: SystemTime::now(),
             kind: FileType::Directory,
-            perm: 0o755,
+            perm: 0o755 & !fs.options.umask,
             nlink: 2,
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
+            uid: fs.owner_uid(),
+            gid: fs.owner_gid(),
             rdev: 0,
             flags: 0,
-            blksize: 512,
+            blksize: fs.options.blksize,
         };
 
         let root_inode = Inode {
@@ -182,14 +1372,16 @@ impl FtpFs {
             parent: ROOT_INODE,
             name: "/".to_string(),
             attr: root_attr,
-            ftp_path: "/".to_string(),
+            ftp_path: root_path.clone(),
+            link_target: None,
         };
 
         fs.inodes.lock().unwrap().insert(ROOT_INODE, root_inode);
+        let root_key = fs.path_key(&root_path).into_owned();
         fs.path_to_inode
             .lock()
             .unwrap()
-            .insert("/".to_string(), ROOT_INODE);
+            .insert(root_key, ROOT_INODE);
 
         // Cachear atributos del root
         fs.attr_cache.lock().unwrap().insert(
@@ -200,69 +1392,635 @@ impl FtpFs {
             },
         );
 
+        // Con intervalo cero el hilo sondearía sin pausa
+        if let Some(interval) = fs.options.poll_interval.filter(|i| !i.is_zero()) {
+            fs.spawn_change_poller(interval);
+        }
+        if let Some(interval) = fs.options.keepalive {
+            fs.spawn_keepalive(interval);
+        }
+        if let Some(interval) = fs.options.flush_interval {
+            fs.spawn_flusher(interval);
+        }
+        // Los archivos de más de --max-file-size van por ventanas aunque no haya read-ahead
+        if fs.options.read_ahead > 0 || fs.options.max_file_size.is_some() {
+            fs.spawn_read_ahead();
+        }
+        if fs.options.prefetch_depth > 0 {
+            fs.spawn_dir_prefetcher();
+        }
+        if fs.options.prefetch_workers > 0 {
+            fs.spawn_file_prefetchers(fs.options.prefetch_workers);
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = fs.options.metrics_addr {
+            let source = fs.clone();
+            let server = metrics::serve(addr, move || source.metrics_text())
+                .context(format!("Failed to serve metrics on {}", addr))?;
+            *fs.metrics_server.lock().unwrap() = Some(server);
+        }
+        // Sin AVBL la reserva no puede cumplirse: avisar al montar, no al llenarse
+        if fs.options.quota_reserve.is_some() && fs.server_free_space().is_none() {
+            warn!(
+                "--quota-reserve has no effect: the server does not report its free space (AVBL)"
+            );
+        }
+        fs.replay_pending_uploads();
+
         info!("Created optimized FtpFs with caching enabled");
 
         Ok(fs)
     }
 
-    /// Asignar un nuevo número de inodo
-    fn allocate_inode(&self) -> u64 {
-        let mut next = self.next_inode.lock().unwrap();
-        let ino = *next;
-        *next += 1;
-        ino
+    /// Si el filesystem ya se cerró con `shutdown`
+    fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::Relaxed)
     }
 
-    /// Obtener o crear inodo para información de archivo FTP
-    fn get_or_create_inode(&self, parent: u64, file_info: &FtpFileInfo) -> Inode {
-        let path = file_info.path.clone();
+    /// Tiempo que el kernel puede guardar los atributos de un `getattr`
+    fn kernel_attr_ttl(&self) -> Duration {
+        self.options.entry_ttl.min(self.options.attr_ttl)
+    }
 
-        // Verificar si el inodo ya existe
-        if let Some(&ino) = self.path_to_inode.lock().unwrap().get(&path) {
-            if let Some(inode) = self.inodes.lock().unwrap().get(&ino).cloned() {
-                return inode;
-            }
+    /// Tiempo que el kernel puede guardar los atributos de una entrada de
+    /// `readdirplus`: los mismos que daría `getattr`
+    fn listed_attr_ttl(&self, ino: u64) -> Duration {
+        if ino == STATS_INODE {
+            // El archivo de estadísticas cambia con cada lectura
+            Duration::ZERO
+        } else if self.open_count(ino) > 0 {
+            // Un archivo abierto puede estar creciendo
+            OPEN_SIZE_TTL.min(self.kernel_attr_ttl())
+        } else {
+            self.kernel_attr_ttl()
         }
+    }
 
-        // Crear nuevo inodo
-        let ino = self.allocate_inode();
+    /// UID con el que se anuncian los archivos
+    fn owner_uid(&self) -> u32 {
+        self.options
+            .uid
+            .unwrap_or_else(|| unsafe { libc::getuid() })
+    }
+
+    /// GID con el que se anuncian los archivos
+    fn owner_gid(&self) -> u32 {
+        self.options
+            .gid
+            .unwrap_or_else(|| unsafe { libc::getgid() })
+    }
+
+    /// Lanzar el hilo que sondea cambios externos cada `interval`
+    fn spawn_change_poller(&self, interval: Duration) {
+        let fs = self.clone();
+        let spawned = thread::Builder::new()
+            .name("rustftpfs-poll".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                if fs.is_shut_down() {
+                    break;
+                }
+                fs.poll_external_changes();
+            });
+
+        match spawned {
+            Ok(_) => info!("Polling for external changes every {:?}", interval),
+            Err(e) => warn!("Failed to start change poller: {}", e),
+        }
+    }
+
+    /// Lanzar el hilo que descarga las ventanas de read-ahead encoladas por
+    /// `prefetch_after`
+    ///
+    /// Con una sola conexión no sirve de nada más de un hilo; la cola está
+    /// acotada y `shutdown` la cierra y espera al hilo.
+    fn spawn_read_ahead(&self) {
+        let (sender, receiver) = mpsc::sync_channel::<(u64, String, u64)>(MAX_READ_AHEAD_QUEUE);
+
+        let fs = self.clone();
+        let spawned = thread::Builder::new()
+            .name("rustftpfs-readahead".to_string())
+            .spawn(move || {
+                for (ino, ftp_path, index) in receiver {
+                    if !fs.is_shut_down() {
+                        if let Err(e) = fs.fetch_chunk(ino, &ftp_path, index) {
+                            debug!("Read-ahead of {} failed: {}", ftp_path, e);
+                        }
+                    }
+                    fs.prefetching.lock().unwrap().remove(&(ino, index));
+                }
+            });
+
+        match spawned {
+            Ok(handle) => {
+                self.read_ahead.open(sender);
+                self.read_ahead.add_thread(handle);
+            }
+            Err(e) => warn!("Failed to start read-ahead: {}", e),
+        }
+    }
+
+    /// Lanzar el hilo que lista en segundo plano los subdirectorios encolados
+    /// por `readdir`, calentando `dir_cache` para recorridos como `find`
+    ///
+    /// `shutdown` cierra la cola y espera al hilo.
+    fn spawn_dir_prefetcher(&self) {
+        let (sender, receiver) = mpsc::sync_channel::<(String, usize)>(MAX_DIR_PREFETCH_QUEUE);
+
+        let fs = self.clone();
+        let spawned = thread::Builder::new()
+            .name("rustftpfs-dirprefetch".to_string())
+            .spawn(move || {
+                for (path, depth) in receiver {
+                    if fs.is_shut_down() {
+                        break;
+                    }
+                    fs.prefetch_dir(&path, depth);
+                }
+            });
+
+        match spawned {
+            Ok(handle) => {
+                self.dir_prefetch.open(sender);
+                self.dir_prefetch.add_thread(handle);
+                info!(
+                    "Prefetching directory listings {} level(s) deep",
+                    self.options.prefetch_depth
+                );
+            }
+            Err(e) => warn!("Failed to start directory prefetcher: {}", e),
+        }
+    }
+
+    /// Listar en segundo plano un directorio encolado y encolar sus
+    /// subdirectorios con un nivel menos
+    ///
+    /// Cede la conexión a las operaciones del usuario: solo lista cuando
+    /// nadie la está usando, así un `ls` no espera detrás de la cola.
+    fn prefetch_dir(&self, path: &str, depth: usize) {
+        while self.ftp_conn.try_lock().is_none() {
+            if self.is_shut_down() {
+                return;
+            }
+            thread::sleep(DIR_PREFETCH_BACKOFF);
+        }
+        if self.dir_cache_fresh(path) {
+            return;
+        }
+        match self.list_ftp_directory_cached(path) {
+            Ok(files) => {
+                trace!("Prefetched listing of {}", path);
+                self.prefetch_subdirs(&files, depth - 1);
+            }
+            Err(e) => debug!("Prefetch of {} failed: {}", path, e),
+        }
+    }
+
+    /// Encolar los subdirectorios de un listado para listarlos en segundo
+    /// plano, con `depth` niveles por debajo de ellos incluidos
+    ///
+    /// Si la cola está llena se descartan: el prefetch es solo una optimización.
+    fn prefetch_subdirs(&self, files: &[FtpFileInfo], depth: usize) {
+        if depth == 0 || !self.dir_prefetch.is_open() {
+            return;
+        }
+
+        for file in files.iter().filter(|f| f.is_dir) {
+            // Un listado que no se cachea no sirve de nada por adelantado
+            if self.options.ignore.matches(&file.name)
+                || self.options.no_cache.matches(&file.path)
+                || self.dir_cache_fresh(&file.path)
+            {
+                continue;
+            }
+            match self.dir_prefetch.try_send((file.path.clone(), depth)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    trace!("Directory prefetch queue full, skipping the rest");
+                    return;
+                }
+                Err(TrySendError::Disconnected(_)) => return,
+            }
+        }
+    }
+
+    /// Lanzar los hilos que descargan en segundo plano los archivos
+    /// encolados por `open`
+    ///
+    /// Todos comparten la conexión FTP: `workers` limita cuántas descargas
+    /// esperan turno a la vez, no cuántas van en paralelo por la red.
+    /// `shutdown` cierra la cola y espera a los hilos.
+    fn spawn_file_prefetchers(&self, workers: usize) {
+        let (sender, receiver) = mpsc::sync_channel::<(u64, String)>(MAX_FILE_PREFETCH_QUEUE);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let mut started = 0;
+        for i in 0..workers {
+            let fs = self.clone();
+            let receiver = Arc::clone(&receiver);
+            let spawned = thread::Builder::new()
+                .name(format!("rustftpfs-prefetch-{}", i))
+                .spawn(move || loop {
+                    // El receptor se suelta antes de descargar, para que otro hilo tome el siguiente
+                    let next = receiver.lock().unwrap().recv();
+                    let Ok((ino, ftp_path)) = next else {
+                        break;
+                    };
+                    if fs.is_shut_down() {
+                        break;
+                    }
+                    fs.prefetch_file(ino, &ftp_path);
+                });
+            match spawned {
+                Ok(handle) => {
+                    self.file_prefetch.add_thread(handle);
+                    started += 1;
+                }
+                Err(e) => warn!("Failed to start file prefetcher: {}", e),
+            }
+        }
+
+        if started > 0 {
+            self.file_prefetch.open(sender);
+            info!("Prefetching opened files with {} worker(s)", started);
+        }
+    }
+
+    /// Encolar la descarga en segundo plano de un archivo recién abierto
+    ///
+    /// Solo archivos normales que se abren para leer, que el servidor ya
+    /// tiene, no cacheados y por debajo de `--max-file-size`, mientras
+    /// `read_cache` no pase de `FILE_PREFETCH_BUDGET` (ni de `--cache-size`).
+    /// Si la cola está llena se descartan: el prefetch es solo una
+    /// optimización.
+    fn queue_file_prefetch(&self, ino: u64, flags: i32) {
+        if !self.file_prefetch.is_open() {
+            return;
+        }
+        if flags & libc::O_ACCMODE == libc::O_WRONLY || flags & libc::O_TRUNC != 0 {
+            return;
+        }
+        let Some(inode) = self.inodes.lock().unwrap().get(&ino).cloned() else {
+            return;
+        };
+        let size = self
+            .get_attr_cached(ino)
+            .map_or(inode.attr.size, |attr| attr.size);
+        if inode.attr.kind != FileType::RegularFile
+            || self.oversized(size)
+            || self.shadow_buffer(ino).is_some()
+            || self.dirty_write_buffer(ino).is_some()
+            || self.pending_creates.lock().unwrap().contains_key(&ino)
+        {
+            return;
+        }
+        {
+            let cache = self.read_cache.lock().unwrap();
+            let cached: u64 = cache.values().map(|data| data.len() as u64).sum();
+            let budget = self
+                .options
+                .cache_size
+                .map_or(FILE_PREFETCH_BUDGET, |limit| {
+                    limit.min(FILE_PREFETCH_BUDGET)
+                });
+            if cache.contains_key(&ino) || cached.saturating_add(size) > budget {
+                return;
+            }
+        }
+        if !self.file_prefetching.lock().unwrap().insert(ino) {
+            return;
+        }
+        if self.file_prefetch.try_send((ino, inode.ftp_path)).is_err() {
+            trace!("File prefetch queue full, not prefetching inode {}", ino);
+            self.file_prefetching.lock().unwrap().remove(&ino);
+        }
+    }
+
+    /// Descargar a `read_cache` un archivo encolado, salvo que ya se cerrara
+    ///
+    /// El inodo sigue en `file_prefetching` hasta que termina la descarga,
+    /// para que `read` la espere en vez de pedir ventanas y para que volver
+    /// a abrirlo no encole otra.
+    fn prefetch_file(&self, ino: u64, ftp_path: &str) {
+        if self.open_count(ino) == 0 {
+            trace!("Prefetch of {} cancelled, the file was closed", ftp_path);
+        } else {
+            match self.load_file_data(ino, ftp_path) {
+                Ok(data) => trace!("Prefetched {} ({} bytes)", ftp_path, data.len()),
+                Err(e) => debug!("Prefetch of {} failed: {:#}", ftp_path, e),
+            }
+            // Cerrado a mitad de descarga: `release` ya no tenía nada que vaciar
+            if self.open_count(ino) == 0 {
+                self.evict_file_data(ino);
+            }
+        }
+        self.file_prefetching.lock().unwrap().remove(&ino);
+    }
+
+    /// Lanzar el hilo que mantiene viva la conexión de control con NOOP
+    ///
+    /// Con la conexión recuperada, reintenta también las subidas encoladas.
+    fn spawn_keepalive(&self, interval: Duration) {
+        let fs = self.clone();
+        let spawned = thread::Builder::new()
+            .name("rustftpfs-keepalive".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                // Tras QUIT, un NOOP fallido volvería a conectar
+                if fs.is_shut_down() {
+                    break;
+                }
+
+                let mut conn = fs.ftp_conn.lock().unwrap();
+                // Si hay tráfico reciente no hace falta NOOP
+                if conn.idle_time() < interval {
+                    continue;
+                }
+                trace!("Sending keepalive NOOP");
+                if let Err(e) = conn.noop() {
+                    warn!("Keepalive failed, reconnecting: {}", e);
+                    if let Err(e) = fs.reconnect(&mut conn) {
+                        warn!("Reconnect failed: {}", e);
+                        continue;
+                    }
+                }
+                drop(conn);
+                fs.replay_pending_uploads();
+            });
+
+        if let Err(e) = spawned {
+            warn!("Failed to start keepalive thread: {}", e);
+        }
+    }
+
+    /// Lanzar el hilo que sube cada `interval` los buffers que lleven ese
+    /// tiempo sucios y sin escrituras, para acotar lo que se pierde si el
+    /// proceso muere con archivos abiertos (p. ej. logs que nunca se cierran)
+    fn spawn_flusher(&self, interval: Duration) {
+        let fs = self.clone();
+        let spawned = thread::Builder::new()
+            .name("rustftpfs-flush".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                if fs.is_shut_down() {
+                    break;
+                }
+                fs.flush_idle_buffers(interval);
+            });
+
+        match spawned {
+            Ok(_) => info!("Flushing idle write buffers every {:?}", interval),
+            Err(e) => warn!("Failed to start write buffer flusher: {}", e),
+        }
+    }
+
+    /// Subir los buffers sucios sin escrituras desde hace `idle`, sin cerrar
+    /// sus handles. Devuelve cuántos se subieron
+    fn flush_idle_buffers(&self, idle: Duration) -> usize {
+        let mut buffers: Vec<(u64, Arc<Mutex<WriteBuffer>>)> = Vec::new();
+        for handle in self.open_files.lock().unwrap().values() {
+            if let Some(buffer) = &handle.write_buffer {
+                if !buffers.iter().any(|(_, b)| Arc::ptr_eq(b, buffer)) {
+                    buffers.push((handle.ino, Arc::clone(buffer)));
+                }
+            }
+        }
+
+        let mut flushed = 0;
+        for (ino, buffer) in buffers {
+            match self.flush_buffer_snapshot(ino, &buffer, idle) {
+                Ok(true) => flushed += 1,
+                Ok(false) => {}
+                Err(e) => warn!("Periodic flush of inode {} failed: {:#}", ino, e),
+            }
+        }
+        flushed
+    }
+
+    /// Subir una copia de `buffer` si lleva `idle` sucio sin escrituras
+    ///
+    /// La copia se sube sin retener el buffer, así que las escrituras no
+    /// esperan a la red; el buffer solo queda limpio si ninguna escritura
+    /// llegó mientras tanto. Devuelve si se subió.
+    fn flush_buffer_snapshot(
+        &self,
+        ino: u64,
+        buffer: &Mutex<WriteBuffer>,
+        idle: Duration,
+    ) -> Result<bool> {
+        // Los temporales locales no se suben y los borrados se eliminarán al cerrarse
+        if self.shadow_files.lock().unwrap().contains_key(&ino)
+            || self.deferred_unlinks.lock().unwrap().contains_key(&ino)
+        {
+            return Ok(false);
+        }
+        let ftp_path = match self.inodes.lock().unwrap().get(&ino) {
+            Some(inode) => inode.ftp_path.clone(),
+            None => return Ok(false),
+        };
+
+        // La conexión se toma antes que el buffer, al revés que en `write` y
+        // `sync_write_buffer`: con el buffer ocupado se deja para la próxima
+        // vuelta. Retenerla hasta acabar evita que esta copia llegue al
+        // servidor después de un release con datos más nuevos
+        let mut conn = self.ftp_conn.lock().unwrap();
+        let mut snapshot = match buffer.try_lock() {
+            Ok(buffer) if buffer.dirty && buffer.last_modified.elapsed() >= idle => {
+                buffer.snapshot().context("Failed to copy write buffer")?
+            }
+            _ => return Ok(false),
+        };
+        let len = snapshot.len();
+        self.upload_buffer(&mut conn, &ftp_path, &mut snapshot)
+            .context("Failed to store file to FTP")?;
+        drop(conn);
+        Stats::add(&self.stats.bytes_up, len);
+
+        let mut buffer = buffer.lock().unwrap();
+        if buffer.last_modified == snapshot.last_modified {
+            buffer.dirty = false;
+        }
+        drop(buffer);
+
+        self.forget_cached_path(&ftp_path);
+        debug!("Flushed {} ({} bytes) while still open", ftp_path, len);
+        Ok(true)
+    }
+
+    /// Comparar tamaño/MDTM de los archivos abiertos o cacheados con el servidor
+    /// y descartar las cachés de los que hayan cambiado
+    fn poll_external_changes(&self) {
+        let mut watched: HashSet<u64> = self
+            .open_files
+            .lock()
+            .unwrap()
+            .values()
+            .map(|handle| handle.ino)
+            .collect();
+        watched.extend(self.read_cache.lock().unwrap().keys().copied());
+        watched.extend(self.ahead_cache.lock().unwrap().keys().copied());
+
+        // Agrupar por directorio padre para listar cada directorio una sola vez
+        let mut by_dir: HashMap<String, Vec<Inode>> = HashMap::new();
+        {
+            let inodes = self.inodes.lock().unwrap();
+            for ino in &watched {
+                if let Some(inode) = inodes.get(ino) {
+                    if inode.attr.kind != FileType::RegularFile {
+                        continue;
+                    }
+                    if let Some(parent) = inodes.get(&inode.parent) {
+                        by_dir
+                            .entry(parent.ftp_path.clone())
+                            .or_default()
+                            .push(inode.clone());
+                    }
+                }
+            }
+        }
+
+        for (dir, files) in by_dir {
+            let listing = match self.ftp_conn.lock().unwrap().list_dir(&dir) {
+                Ok(listing) => listing,
+                Err(e) => {
+                    debug!("poll: failed to list {}: {}", dir, e);
+                    continue;
+                }
+            };
+            self.cache_listing(&dir, &listing);
+
+            for inode in files {
+                let size = listing
+                    .iter()
+                    .find(|f| f.path == inode.ftp_path)
+                    .map(|f| f.size);
+                let mtime = self.ftp_conn.lock().unwrap().mdtm(&inode.ftp_path).ok();
+
+                let previous = self
+                    .watch_state
+                    .lock()
+                    .unwrap()
+                    .insert(inode.ino, (size, mtime));
+
+                let changed = matches!(previous, Some(previous) if previous != (size, mtime));
+                if changed && self.dirty_write_buffer(inode.ino).is_none() {
+                    debug!(
+                        "poll: {} changed on server, evicting caches",
+                        inode.ftp_path
+                    );
+                    self.evict_file_data(inode.ino);
+                    self.attr_cache.lock().unwrap().remove(&inode.ino);
+                }
+            }
+        }
 
-        let kind = if file_info.is_dir {
+        // Olvidar el estado de los archivos que ya no se vigilan
+        self.watch_state
+            .lock()
+            .unwrap()
+            .retain(|ino, _| watched.contains(ino));
+    }
+
+    /// Asignar un nuevo número de inodo
+    fn allocate_inode(&self) -> u64 {
+        let mut next = self.next_inode.lock().unwrap();
+        let ino = *next;
+        *next += 1;
+        ino
+    }
+
+    /// Obtener o crear inodo para información de archivo FTP
+    fn get_or_create_inode(&self, parent: u64, file_info: &FtpFileInfo) -> Inode {
+        let key = self
+            .path_key(&normalize_ftp_path(&file_info.path))
+            .into_owned();
+
+        // Verificar si el inodo ya existe
+        let existing = self.path_to_inode.lock().unwrap().get(&key).copied();
+        if let Some(ino) = existing {
+            let inode = self.inodes.lock().unwrap().get(&ino).cloned();
+            match inode {
+                Some(inode) if !self.kind_changed(&inode, file_info) => {
+                    return self.refresh_from_listing(inode, file_info);
+                }
+                // Borrado y vuelto a crear con otro tipo: el kernel no puede
+                // seguir viendo el inodo viejo con el tipo anterior
+                Some(inode) => {
+                    debug!(
+                        "{} changed type on the server, replacing inode {}",
+                        file_info.path, ino
+                    );
+                    self.evict_inode(&key, &inode);
+                }
+                None => {}
+            }
+        }
+
+        // Con --follow-symlinks el inodo es el del destino, pero con el nombre
+        // y la entrada del enlace; lecturas y listados usan la ruta resuelta
+        let target = if self.options.follow_symlinks && file_info.link_target.is_some() {
+            self.follow_symlink(file_info)
+        } else {
+            None
+        };
+        let name = file_info.name.clone();
+        let file_info = target.as_ref().unwrap_or(file_info);
+
+        // Crear nuevo inodo
+        let ino = self.allocate_inode();
+
+        let kind = if file_info.link_target.is_some() {
+            FileType::Symlink
+        } else if file_info.is_dir {
             FileType::Directory
         } else {
             FileType::RegularFile
         };
 
-        let nlink = if file_info.is_dir { 2 } else { 1 };
+        let nlink = if file_info.is_dir {
+            self.dir_nlink(&file_info.path).unwrap_or(2)
+        } else {
+            1
+        };
 
         let attr = FileAttr {
             ino,
             size: file_info.size,
-            blocks: (file_info.size + 511) / 512,
+            blocks: blocks_for(file_info.size),
             atime: file_info.modified_time.unwrap_or(SystemTime::now()),
             mtime: file_info.modified_time.unwrap_or(SystemTime::now()),
             ctime: file_info.modified_time.unwrap_or(SystemTime::now()),
             crtime: file_info.modified_time.unwrap_or(SystemTime::now()),
             kind,
-            perm: (file_info.permissions & 0o777) as u16,
+            perm: (file_info.permissions & 0o777) as u16 & !self.options.umask,
             nlink,
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
+            uid: self.owner_uid(),
+            gid: self.owner_gid(),
             rdev: 0,
             flags: 0,
-            blksize: 512,
+            blksize: self.options.blksize,
         };
 
         let inode = Inode {
             ino,
             parent,
-            name: file_info.name.clone(),
+            name,
             attr,
-            ftp_path: path.clone(),
+            ftp_path: file_info.path.clone(),
+            link_target: file_info.link_target.clone(),
         };
 
-        self.inodes.lock().unwrap().insert(ino, inode.clone());
-        self.path_to_inode.lock().unwrap().insert(path, ino);
+        // Otro hilo puede haber creado el inodo de la misma ruta mientras
+        // tanto: se comprueba e inserta con ambas tablas tomadas
+        {
+            let mut path_to_inode = self.path_to_inode.lock().unwrap();
+            let mut inodes = self.inodes.lock().unwrap();
+            if let Some(existing) = path_to_inode.get(&key).and_then(|ino| inodes.get(ino)) {
+                return existing.clone();
+            }
+            inodes.insert(ino, inode.clone());
+            path_to_inode.insert(key, ino);
+        }
 
         // Cachear atributos
         self.attr_cache.lock().unwrap().insert(
@@ -276,67 +2034,503 @@ impl FtpFs {
         inode
     }
 
+    /// Si un listado muestra `file_info` con otro tipo que el de `inode`
+    ///
+    /// Con `--follow-symlinks` el inodo de un enlace tiene el tipo de su
+    /// destino, que el listado no dice: los enlaces no se comparan.
+    fn kind_changed(&self, inode: &Inode, file_info: &FtpFileInfo) -> bool {
+        let listed = if file_info.link_target.is_some() {
+            if self.options.follow_symlinks {
+                return false;
+            }
+            FileType::Symlink
+        } else if file_info.is_dir {
+            FileType::Directory
+        } else {
+            FileType::RegularFile
+        };
+        inode.attr.kind != listed
+    }
+
+    /// En modo estricto, poner al día un inodo conocido con su entrada del
+    /// listado; si no, el inodo se devuelve tal cual
+    fn refresh_from_listing(&self, inode: Inode, file_info: &FtpFileInfo) -> Inode {
+        let refreshable = self.options.strict
+            && inode.attr.kind == FileType::RegularFile
+            && file_info.link_target.is_none()
+            && !self.is_ascii(&inode.ftp_path)
+            && self.shadow_buffer(inode.ino).is_none()
+            && self.dirty_write_buffer(inode.ino).is_none();
+        if !refreshable {
+            return inode;
+        }
+        match self.apply_remote_attr(inode.ino, file_info.size, file_info.modified_time) {
+            Some(attr) => Inode { attr, ..inode },
+            None => inode,
+        }
+    }
+
+    /// Aplicar al inodo el tamaño y la fecha que da ahora el servidor
+    ///
+    /// Si alguno cambió, los datos cacheados ya no sirven y se descartan.
+    fn apply_remote_attr(
+        &self,
+        ino: u64,
+        size: u64,
+        mtime: Option<SystemTime>,
+    ) -> Option<FileAttr> {
+        let (attr, changed) = {
+            let mut inodes = self.inodes.lock().unwrap();
+            let inode = inodes.get_mut(&ino)?;
+            let changed =
+                inode.attr.size != size || mtime.is_some_and(|mtime| mtime != inode.attr.mtime);
+            inode.attr.size = size;
+            inode.attr.blocks = blocks_for(size);
+            if let Some(mtime) = mtime {
+                inode.attr.mtime = mtime;
+                inode.attr.ctime = mtime;
+            }
+            (inode.attr, changed)
+        };
+        if changed {
+            debug!("Inode {} changed on the server, evicting its data", ino);
+            self.evict_file_data(ino);
+        }
+        self.update_attr_cache(ino, attr);
+        Some(attr)
+    }
+
+    /// Olvidar el inodo de `path`, que ya es otra cosa en el servidor
+    ///
+    /// Los handles abiertos lo conservan hasta su `release`, como tras un
+    /// `rename` que lo sustituye.
+    fn evict_inode(&self, path: &str, inode: &Inode) {
+        let ino = inode.ino;
+        self.path_to_inode
+            .lock()
+            .unwrap()
+            .remove(self.path_key(path).as_ref());
+        if self.open_count(ino) == 0 {
+            self.inodes.lock().unwrap().remove(&ino);
+        }
+        self.attr_cache.lock().unwrap().remove(&ino);
+        self.evict_file_data(ino);
+        if inode.attr.kind == FileType::Directory {
+            self.dir_cache
+                .lock()
+                .unwrap()
+                .remove(self.path_key(&inode.ftp_path).as_ref());
+        }
+    }
+
+    /// Resolver un enlace simbólico hasta algo que no lo sea
+    ///
+    /// Devuelve None si el destino no existe o hay más de `MAX_SYMLINK_DEPTH`
+    /// saltos (un bucle), y entonces se sigue mostrando como enlace.
+    fn follow_symlink(&self, link: &FtpFileInfo) -> Option<FtpFileInfo> {
+        let mut current = link.clone();
+        for _ in 0..MAX_SYMLINK_DEPTH {
+            let target = match &current.link_target {
+                Some(target) => resolve_link_path(&current.path, target),
+                None => return Some(current),
+            };
+
+            current = match self.link_target_info(&target) {
+                Ok(info) => info,
+                Err(e) => {
+                    debug!(
+                        "Symlink {} points to {} which can't be resolved: {:#}",
+                        link.path, target, e
+                    );
+                    return None;
+                }
+            };
+        }
+
+        warn!(
+            "Symlink {} is nested more than {} levels deep, showing it as a link",
+            link.path, MAX_SYMLINK_DEPTH
+        );
+        None
+    }
+
+    /// Información del destino de un enlace
+    ///
+    /// Se busca en el listado (cacheado) de su directorio; si ese directorio no
+    /// se puede listar, se sondea con CWD y SIZE, exigiendo que SIZE responda
+    /// para no tomar un destino inexistente por un archivo vacío.
+    fn link_target_info(&self, target: &str) -> Result<FtpFileInfo> {
+        let (dir, name) = target.rsplit_once('/').unwrap_or(("", target));
+        if name.is_empty() {
+            return self.get_ftp_file_info("/");
+        }
+        let dir = if dir.is_empty() { "/" } else { dir };
+
+        match self.list_ftp_directory_cached(dir) {
+            Ok(files) => match self.listed_entry(&files, name) {
+                Some(info) => Ok(info.clone()),
+                None => Err(FtpError::NotFound.into()),
+            },
+            Err(e) => {
+                debug!("Can't list {} to resolve a symlink: {:#}", dir, e);
+                let info = self.get_ftp_file_info(target)?;
+                if !info.is_dir {
+                    self.remote_size(target)?;
+                }
+                Ok(info)
+            }
+        }
+    }
+
+    /// `nlink` de un directorio: 2 más el número de subdirectorios
+    ///
+    /// Es aproximado: solo se conoce cuando el directorio está en `dir_cache`.
+    /// Hasta que se lista se anuncia 2, y puede quedar desfasado si otro
+    /// cliente crea o borra subdirectorios antes del siguiente listado.
+    fn dir_nlink(&self, path: &str) -> Option<u32> {
+        let cache = self.dir_cache.lock().unwrap();
+        let entry = cache.get(self.path_key(path).as_ref())?;
+        Some(2 + entry.files.iter().filter(|f| f.is_dir).count() as u32)
+    }
+
+    /// Actualizar el `nlink` del inodo de `path` tras listar el directorio
+    fn refresh_dir_nlink(&self, path: &str) {
+        let nlink = match self.dir_nlink(path) {
+            Some(nlink) => nlink,
+            None => return,
+        };
+        let ino = match self
+            .path_to_inode
+            .lock()
+            .unwrap()
+            .get(self.path_key(path).as_ref())
+        {
+            Some(&ino) => ino,
+            None => return,
+        };
+
+        if let Some(inode) = self.inodes.lock().unwrap().get_mut(&ino) {
+            inode.attr.nlink = nlink;
+        }
+        if let Some(entry) = self.attr_cache.lock().unwrap().get_mut(&ino) {
+            entry.attr.nlink = nlink;
+        }
+    }
+
     /// Obtener listado de directorio con caché
     fn list_ftp_directory_cached(&self, path: &str) -> Result<Vec<FtpFileInfo>> {
+        let path = &normalize_ftp_path(path);
+
         // Verificar caché primero
-        {
+        if !self.options.no_cache.matches(path) {
             let cache = self.dir_cache.lock().unwrap();
-            if let Some(entry) = cache.get(path) {
-                if entry.timestamp.elapsed() < DIR_CACHE_TTL {
+            if let Some(entry) = cache.get(self.path_key(path).as_ref()) {
+                if entry.timestamp.elapsed() < self.options.dir_ttl {
                     trace!("Directory cache hit for: {}", path);
+                    Stats::add(&self.stats.dir_cache_hits, 1);
                     return Ok(entry.files.clone());
                 }
             }
         }
 
+        // Un listado reciente ya dijo que es un archivo: no hace falta preguntar
+        if self.kind_cache.lock().unwrap().peek(path) == Some(false) {
+            return Err(anyhow::Error::new(FtpError::NotADirectory)
+                .context(format!("Failed to list {}", path)));
+        }
+
         // Caché miss - consultar servidor FTP
         trace!("Directory cache miss for: {}", path);
+        Stats::add(&self.stats.dir_cache_misses, 1);
         let mut conn = self.ftp_conn.lock().unwrap();
 
-        let files = match conn.list_dir(path) {
-            Ok(files) => files,
-            Err(e) => {
-                warn!("Failed to list directory, attempting reconnect: {}", e);
-                conn.reconnect()?;
-                conn.list_dir(path)?
+        // Un listado cortado a medias es un error, nunca un directorio vacío
+        // o incompleto: se reintenta y no se guarda nada en caché
+        let started = Instant::now();
+        let mut attempt = 0;
+        let files = loop {
+            match conn.list_dir(path) {
+                Ok(files) => break files,
+                // Reconectar no hace aparecer un directorio que no existe
+                Err(
+                    e @ (FtpError::NotFound | FtpError::NotADirectory | FtpError::PermissionDenied),
+                ) => return Err(e.into()),
+                Err(e) if attempt < self.options.retries.max(1) => {
+                    attempt += 1;
+                    warn!("Failed to list {}, attempting reconnect: {}", path, e);
+                    if let Err(e) = self.reconnect(&mut conn) {
+                        warn!("Reconnect failed: {}", e);
+                    }
+                }
+                Err(e) => match self
+                    .options
+                    .mirror
+                    .as_ref()
+                    .and_then(|m| m.load_listing(path))
+                {
+                    Some(files) => {
+                        warn!("Server unreachable, listing {} from the local mirror", path);
+                        break files;
+                    }
+                    None => return Err(e.into()),
+                },
             }
         };
 
+        self.stats.list_latency.observe(started.elapsed());
+
+        // Los borrados diferidos siguen en el servidor, pero ya no existen
+        let mut files = files;
+        {
+            let deferred = self.deferred_unlinks.lock().unwrap();
+            if !deferred.is_empty() {
+                files.retain(|f| !deferred.values().any(|path| *path == f.path));
+            }
+        }
+
         // Guardar en caché
-        self.dir_cache.lock().unwrap().insert(
-            path.to_string(),
-            DirCacheEntry {
-                files: files.clone(),
-                timestamp: Instant::now(),
-            },
-        );
+        self.cache_listing(path, &files);
 
         Ok(files)
     }
 
-    /// Invalidar caché de directorio (llamar después de operaciones de escritura)
-    fn invalidate_dir_cache(&self, path: &str) {
-        self.dir_cache.lock().unwrap().remove(path);
-        debug!("Invalidated directory cache for: {}", path);
-    }
+    /// Guardar un listado en `dir_cache`, y el tipo y tamaño de cada entrada
+    /// en `kind_cache` y `size_cache`
+    ///
+    /// Con `mirror` el listado también se guarda en disco.
+    fn cache_listing(&self, path: &str, files: &[FtpFileInfo]) {
+        if let Some(mirror) = self
+            .options
+            .mirror
+            .as_ref()
+            .filter(|_| !self.options.offline)
+        {
+            if let Err(e) = mirror.store_listing(path, files) {
+                debug!("Failed to mirror listing of {}: {}", path, e);
+            }
+        }
+        let uncached = self.options.no_cache.matches(path);
+        if !uncached {
+            self.dir_cache.lock().unwrap().insert(
+                self.path_key(&normalize_ftp_path(path)).into_owned(),
+                DirCacheEntry {
+                    files: files.to_vec(),
+                    timestamp: Instant::now(),
+                },
+            );
+        }
 
-    /// Obtener atributos con caché
-    fn get_attr_cached(&self, ino: u64) -> Option<FileAttr> {
-        let cache = self.attr_cache.lock().unwrap();
-        if let Some(entry) = cache.get(&ino) {
-            if entry.timestamp.elapsed() < ATTR_CACHE_TTL {
-                return Some(entry.attr);
+        let mut kinds = self.kind_cache.lock().unwrap();
+        let mut sizes = self.size_cache.lock().unwrap();
+        for file in files {
+            kinds.insert(&file.path, file.is_dir);
+            if !file.is_dir && !uncached {
+                sizes.insert(&file.path, file.size);
             }
         }
-        None
+        drop(sizes);
+        drop(kinds);
+
+        self.refresh_dir_nlink(path);
     }
 
-    /// Actualizar caché de atributos
-    fn update_attr_cache(&self, ino: u64, attr: FileAttr) {
-        self.attr_cache.lock().unwrap().insert(
-            ino,
-            AttrCacheEntry {
-                attr,
+    /// Tamaño de un archivo: del último listado si es reciente, si no con SIZE
+    fn remote_size(&self, path: &str) -> Result<u64> {
+        if self.options.no_cache.matches(path) {
+            return Ok(self.ftp_conn.lock().unwrap().size(path)?);
+        }
+        if let Some(size) = self.size_cache.lock().unwrap().get(path) {
+            return Ok(size);
+        }
+
+        let size = self.ftp_conn.lock().unwrap().size(path)?;
+        let mut sizes = self.size_cache.lock().unwrap();
+        sizes.insert(path, size);
+        debug!(
+            "SIZE {}: {} bytes ({} SIZE commands, {} answered from listings)",
+            path, size, sizes.misses, sizes.hits
+        );
+        Ok(size)
+    }
+
+    /// Si el listado de `path` está en caché y no ha caducado
+    fn dir_cache_fresh(&self, path: &str) -> bool {
+        self.dir_cache
+            .lock()
+            .unwrap()
+            .get(self.path_key(path).as_ref())
+            .is_some_and(|entry| entry.timestamp.elapsed() < self.options.dir_ttl)
+    }
+
+    /// Invalidar caché de directorio (llamar después de operaciones de escritura)
+    ///
+    /// También olvida el tipo y tamaño de sus entradas, que pueden haber cambiado.
+    fn invalidate_dir_cache(&self, path: &str) {
+        let key = self.path_key(path);
+        self.dir_cache.lock().unwrap().remove(key.as_ref());
+        self.kind_cache.lock().unwrap().forget_children(path);
+        self.size_cache.lock().unwrap().forget_children(path);
+        self.negative_cache.lock().unwrap().forget_children(&key);
+        debug!("Invalidated directory cache for: {}", path);
+    }
+
+    /// Invalidar el listado del directorio que contiene `path`
+    ///
+    /// La caché de listados va por ruta FTP, no por número de inodo.
+    fn invalidate_parent_dir(&self, path: &str) {
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            self.invalidate_dir_cache(if parent.is_empty() { "/" } else { parent });
+        }
+    }
+
+    /// Recordar que `path` no existe durante `negative_ttl`
+    fn remember_missing(&self, path: &str) {
+        if self.options.negative_ttl.is_zero() {
+            return;
+        }
+        let mut negative = self.negative_cache.lock().unwrap();
+        if negative.entries.len() >= MAX_NEGATIVE_ENTRIES {
+            negative.prune();
+        }
+        negative.insert(&self.path_key(path), ());
+    }
+
+    /// Obtener atributos con caché
+    fn get_attr_cached(&self, ino: u64) -> Option<FileAttr> {
+        if self.uncached_inode(ino) {
+            return None;
+        }
+        let cache = self.attr_cache.lock().unwrap();
+        if let Some(entry) = cache.get(&ino) {
+            if entry.timestamp.elapsed() < self.options.attr_ttl {
+                return Some(entry.attr);
+            }
+        }
+        None
+    }
+
+    /// Si la ruta del inodo está excluida de las cachés (`--no-cache-glob`)
+    fn uncached_inode(&self, ino: u64) -> bool {
+        if self.options.no_cache.patterns.is_empty() {
+            return false;
+        }
+        self.inodes
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .is_some_and(|inode| self.options.no_cache.matches(&inode.ftp_path))
+    }
+
+    /// Atributos de `.` o `..` sacados de la tabla de inodos, sin red
+    ///
+    /// El padre de la raíz es la propia raíz.
+    fn dot_entry_attr(&self, dir: &Inode, name: &str) -> Option<FileAttr> {
+        let ino = match name {
+            "." => dir.ino,
+            ".." if dir.ino == ROOT_INODE => ROOT_INODE,
+            ".." => dir.parent,
+            _ => return None,
+        };
+        self.get_attr_cached(ino).or_else(|| {
+            self.inodes
+                .lock()
+                .unwrap()
+                .get(&ino)
+                .map(|inode| inode.attr)
+        })
+    }
+
+    /// Entradas de un directorio para `readdir` y `readdirplus`: `.`, `..`,
+    /// el listado sin los archivos ignorados y los temporales locales
+    fn dir_entries(&self, dir: &Inode, offset: i64) -> Result<Vec<(u64, FileType, String)>, i32> {
+        // Recolectar entradas con strings propios
+        let mut entries: Vec<(u64, FileType, String)> = vec![
+            (dir.ino, FileType::Directory, ".".to_string()),
+            (dir.parent, FileType::Directory, "..".to_string()),
+        ];
+
+        // Usar caché de directorio (evita consulta FTP repetida)
+        // Filtrar archivos temporales
+        match self.list_ftp_directory_cached(&dir.ftp_path) {
+            Ok(files) => {
+                let filtered_count = files.len();
+                for file_info in &files {
+                    // Un nombre vacío o `.`/`..` pisaría las entradas sintéticas
+                    if !is_entry_name(&file_info.name) {
+                        warn!("readdir: skipping entry named {:?}", file_info.name);
+                        continue;
+                    }
+                    // Ignorar archivos temporales en el listado
+                    if self.options.ignore.matches(&file_info.name) {
+                        trace!("readdir: filtering temp file {}", file_info.name);
+                        continue;
+                    }
+                    let file_inode = self.get_or_create_inode(dir.ino, file_info);
+                    entries.push((
+                        file_inode.ino,
+                        file_inode.attr.kind,
+                        file_inode.name.clone(),
+                    ));
+                }
+                trace!(
+                    "readdir: filtered {} temp files from {}",
+                    filtered_count - entries.len() + 2,
+                    filtered_count
+                ); // +2 por . y ..
+
+                for (shadow_ino, name) in self.shadow_entries(dir.ino) {
+                    entries.push((shadow_ino, FileType::RegularFile, name));
+                }
+                for (pending_ino, name) in self.pending_entries(dir.ino) {
+                    if !entries.iter().any(|(_, listed, _)| *listed == name) {
+                        entries.push((pending_ino, FileType::RegularFile, name));
+                    }
+                }
+                if dir.ino == ROOT_INODE && self.options.show_control {
+                    entries.push((
+                        STATS_INODE,
+                        FileType::RegularFile,
+                        STATS_FILE_NAME.to_string(),
+                    ));
+                }
+
+                // Solo en la primera llamada: las siguientes continúan el mismo listado
+                if offset == 0 {
+                    self.prefetch_subdirs(&files, self.options.prefetch_depth);
+                }
+            }
+            Err(e) => {
+                error!("readdir: failed to list directory: {:#}", e);
+                return Err(errno_for(&e));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Atributos de una entrada de `dir_entries`
+    fn entry_attr(&self, dir: &Inode, ino: u64, name: &str) -> Option<FileAttr> {
+        if ino == STATS_INODE {
+            return Some(self.stats_attr(self.stats_json().len()));
+        }
+        self.dot_entry_attr(dir, name)
+            .or_else(|| self.shadow_attr(ino))
+            .or_else(|| self.get_attr_cached(ino))
+            .or_else(|| {
+                self.inodes
+                    .lock()
+                    .unwrap()
+                    .get(&ino)
+                    .map(|inode| inode.attr)
+            })
+    }
+
+    /// Actualizar caché de atributos
+    fn update_attr_cache(&self, ino: u64, attr: FileAttr) {
+        self.attr_cache.lock().unwrap().insert(
+            ino,
+            AttrCacheEntry {
+                attr,
                 timestamp: Instant::now(),
             },
         );
@@ -344,29 +2538,73 @@ impl FtpFs {
 
     /// Obtener información de archivo FTP (solo para archivos no cacheados)
     fn get_ftp_file_info(&self, path: &str) -> Result<FtpFileInfo> {
+        // El tipo suele conocerse por el listado del padre; solo se sondea si no
+        let cached = self.kind_cache.lock().unwrap().get(path);
+
         let mut conn = self.ftp_conn.lock().unwrap();
 
         // Verificar si es directorio
-        let is_dir = conn.is_dir(path)?;
+        let is_dir = match cached {
+            Some(is_dir) => is_dir,
+            None => conn.is_dir(path)?,
+        };
+        if cached.is_none() {
+            self.kind_cache.lock().unwrap().insert(path, is_dir);
+        }
 
-        let size = if is_dir {
-            0
+        // Igual con el tamaño: SIZE solo si ningún listado reciente lo trae
+        let cached_size = if is_dir {
+            Some(0)
         } else {
-            conn.size(path).unwrap_or(0)
+            self.size_cache.lock().unwrap().get(path)
         };
+        let size = match cached_size {
+            Some(size) => size,
+            None => match conn.size(path) {
+                Ok(size) => {
+                    self.size_cache.lock().unwrap().insert(path, size);
+                    size
+                }
+                Err(_) => 0,
+            },
+        };
+
+        // MDTM da la hora exacta; si el servidor no lo soporta se deja en None
+        let modified_time = if is_dir { None } else { conn.mdtm(path).ok() };
 
         let name = Path::new(path)
             .file_name()
             .map(|n| n.to_string_lossy().to_string())
             .unwrap_or_else(|| path.to_string());
 
+        // MDTM para archivos, más el sondeo de tipo y SIZE si no estaban en caché
+        let round_trips = usize::from(cached.is_none())
+            + usize::from(cached_size.is_none())
+            + usize::from(!is_dir);
+        let kinds = self.kind_cache.lock().unwrap();
+        let sizes = self.size_cache.lock().unwrap();
+        trace!(
+            "get_ftp_file_info({}): {} round trips (is_dir cache: {} hits, {} probes; \
+             size cache: {} hits, {} SIZE commands)",
+            path,
+            round_trips,
+            kinds.hits,
+            kinds.misses,
+            sizes.hits,
+            sizes.misses
+        );
+        drop(sizes);
+        drop(kinds);
+
         Ok(FtpFileInfo {
             name,
             path: path.to_string(),
             size,
             is_dir,
             permissions: if is_dir { 0o755 } else { 0o644 },
-            modified_time: None,
+            modified_time,
+            link_target: None,
+            raw_name: None,
         })
     }
 
@@ -380,879 +2618,5488 @@ impl FtpFs {
 
     /// Sincronizar buffer de escritura al servidor FTP
     fn sync_write_buffer(&self, fh: u64) -> Result<()> {
-        if let Some(file_handle) = self.open_files.lock().unwrap().get(&fh).cloned() {
-            if let Some(ref write_buffer) = file_handle.write_buffer {
-                if write_buffer.dirty {
-                    let inode = self
-                        .inodes
-                        .lock()
-                        .unwrap()
-                        .get(&file_handle.ino)
-                        .cloned()
-                        .ok_or_else(|| anyhow::anyhow!("Inode not found"))?;
-
-                    trace!(
-                        "Syncing write buffer for inode {} ({} bytes)",
-                        file_handle.ino,
-                        write_buffer.data.len()
-                    );
-
-                    let mut conn = self.ftp_conn.lock().unwrap();
-                    conn.store(&inode.ftp_path, &write_buffer.data)
-                        .context("Failed to store file to FTP")?;
+        let file_handle = match self.open_files.lock().unwrap().get(&fh).cloned() {
+            Some(file_handle) => file_handle,
+            None => return Ok(()),
+        };
+        let write_buffer = match file_handle.write_buffer {
+            Some(write_buffer) => write_buffer,
+            None => return Ok(()),
+        };
 
-                    // Actualizar caché de lectura con los nuevos datos
-                    self.read_cache
-                        .lock()
-                        .unwrap()
-                        .insert(file_handle.ino, write_buffer.data.clone());
+        let mut write_buffer = write_buffer.lock().unwrap();
+        if !write_buffer.dirty {
+            return Ok(());
+        }
+        // Los temporales locales no se suben nunca
+        if self
+            .shadow_files
+            .lock()
+            .unwrap()
+            .contains_key(&file_handle.ino)
+        {
+            return Ok(());
+        }
+        // Un archivo ya borrado no se sube: se eliminará al cerrarse
+        if self
+            .deferred_unlinks
+            .lock()
+            .unwrap()
+            .contains_key(&file_handle.ino)
+        {
+            write_buffer.dirty = false;
+            return Ok(());
+        }
 
-                    // Actualizar tamaño en caché de atributos
-                    if let Some(entry) = self.attr_cache.lock().unwrap().get_mut(&file_handle.ino) {
-                        entry.attr.size = write_buffer.data.len() as u64;
-                        entry.attr.blocks = (write_buffer.data.len() as u64 + 511) / 512;
-                    }
+        let inode = self
+            .inodes
+            .lock()
+            .unwrap()
+            .get(&file_handle.ino)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Inode not found"))?;
 
-                    // Invalidar caché de directorio padre
-                    self.invalidate_dir_cache(&inode.parent.to_string());
+        let len = write_buffer.len();
+        trace!(
+            "Syncing write buffer for inode {} ({} bytes)",
+            file_handle.ino,
+            len
+        );
 
-                    trace!("Write buffer synced successfully");
-                }
+        let stored = {
+            let mut conn = self.ftp_conn.lock().unwrap();
+            let started = Instant::now();
+            let stored = self.upload_buffer(&mut conn, &inode.ftp_path, &mut write_buffer);
+            self.stats.store_latency.observe(started.elapsed());
+            if stored.is_ok() {
+                self.finish_pending_create(&mut conn, file_handle.ino, &inode.ftp_path);
+            }
+            stored
+        };
+        match (stored, &self.options.mirror) {
+            (Ok(()), _) => {
+                Stats::add(&self.stats.bytes_up, len);
+                debug!(
+                    event = "store", ino = file_handle.ino, path = inode.ftp_path.as_str(), bytes = len;
+                    "Stored {} ({} bytes)", inode.ftp_path, len
+                );
+            }
+            // Sin conexión: guardar los datos para subirlos al reconectar
+            (Err(e), Some(mirror)) if e.is_transient() => {
+                let mut reader = write_buffer
+                    .reader()
+                    .context("Failed to read write buffer")?;
+                mirror
+                    .queue_upload(&inode.ftp_path, &mut reader)
+                    .context("Failed to queue upload in the local mirror")?;
+                warn!(
+                    "Upload of {} failed ({}), queued in the local mirror",
+                    inode.ftp_path, e
+                );
+            }
+            (Err(e), _) => {
+                return Err(anyhow::Error::new(e).context("Failed to store file to FTP"));
             }
         }
-        Ok(())
-    }
+        write_buffer.dirty = false;
 
-    /// Cargar datos de archivo con prefetching opcional
-    fn load_file_data(&self, ino: u64, ftp_path: &str, prefetch: bool) -> Result<Vec<u8>> {
-        // Verificar caché primero
-        if let Some(data) = self.read_cache.lock().unwrap().get(&ino).cloned() {
-            trace!("File data cache hit for inode {}", ino);
-            return Ok(data);
+        // Actualizar caché de lectura con los nuevos datos (solo si caben en memoria)
+        match &write_buffer.data {
+            BufferData::Memory(data) => {
+                self.ahead_cache.lock().unwrap().remove(&file_handle.ino);
+                self.cache_file_data(file_handle.ino, data.clone());
+            }
+            BufferData::Disk { .. } => {
+                self.evict_file_data(file_handle.ino);
+            }
         }
 
-        // Cargar desde FTP
-        trace!(
-            "Loading file data for inode {} (prefetch: {})",
-            ino,
-            prefetch
-        );
-        let mut conn = self.ftp_conn.lock().unwrap();
-        let data = conn
-            .retrieve(ftp_path)
-            .context("Failed to retrieve file from FTP")?;
+        // Actualizar tamaño en caché de atributos
+        if let Some(entry) = self.attr_cache.lock().unwrap().get_mut(&file_handle.ino) {
+            entry.attr.size = len;
+            entry.attr.blocks = blocks_for(len);
+        }
 
-        // Guardar en caché
-        self.read_cache.lock().unwrap().insert(ino, data.clone());
+        // Invalidar caché de directorio padre
+        self.invalidate_parent_dir(&inode.ftp_path);
 
-        trace!("File data loaded: {} bytes", data.len());
-        Ok(data)
+        trace!("Write buffer synced successfully");
+        Ok(())
     }
-}
 
-impl Filesystem for FtpFs {
-    /// Obtener atributos de archivo (optimizado con caché extendido)
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        trace!("getattr called for inode {}", ino);
+    /// Subir `buffer` a `ftp_path` y comprobar con SIZE que llegó completo
+    ///
+    /// Una subida interrumpida por un fallo transitorio se reanuda, tras
+    /// reconectar, desde lo que ya tiene el servidor, en trozos de
+    /// [`UPLOAD_CHUNK_SIZE`] con REST + STOR para que otro corte no obligue a
+    /// empezar de cero. Solo cuenta lo que esta subida llegó a enviar: si el
+    /// STOR falló antes de mandar nada, lo que haya en el servidor es el
+    /// archivo anterior y se empieza de cero. Si el tamaño final no coincide
+    /// se repite entera; tras
+    /// `retries` intentos se devuelve el error. Los servidores sin SIZE no
+    /// permiten verificar y la subida se da por buena.
+    fn upload_buffer(
+        &self,
+        conn: &mut B,
+        ftp_path: &str,
+        buffer: &mut WriteBuffer,
+    ) -> Result<(), FtpError> {
+        let len = buffer.len();
+        let mut offset = 0;
+        let mut attempt = 0;
+        loop {
+            // Bytes de este intento que pudieron llegar al servidor
+            let mut sent = offset;
+            let stored = if offset == 0 {
+                let mut reader = CountingReader {
+                    inner: buffer.reader()?,
+                    count: 0,
+                };
+                let stored = conn.store_from(ftp_path, &mut reader);
+                sent = reader.count;
+                stored
+            } else {
+                debug!("Resuming upload of {} at offset {}", ftp_path, offset);
+                Self::upload_from(conn, ftp_path, buffer, &mut sent)
+            };
+
+            let error = match stored {
+                // En ASCII SIZE no mide lo subido: no hay nada que comprobar
+                Ok(()) if self.is_ascii(ftp_path) => return Ok(()),
+                Ok(()) => match conn.size(ftp_path) {
+                    Ok(size) if size == len => {
+                        debug!("Verified upload of {}: {} bytes", ftp_path, size);
+                        return Ok(());
+                    }
+                    Ok(size) => {
+                        warn!(
+                            "Upload of {} incomplete: server has {} of {} bytes",
+                            ftp_path, size, len
+                        );
+                        FtpError::Protocol(format!(
+                            "Upload of {} incomplete: server has {} of {} bytes",
+                            ftp_path, size, len
+                        ))
+                    }
+                    Err(e) => {
+                        debug!("Could not verify upload of {}: {}", ftp_path, e);
+                        return Ok(());
+                    }
+                },
+                Err(e) if e.is_transient() => e,
+                Err(e) => return Err(e),
+            };
 
-        // Para root, siempre usar caché rápida
-        if ino == ROOT_INODE {
-            if let Some(attr) = self.get_attr_cached(ino) {
-                reply.attr(&TTL, &attr);
-                return;
+            if attempt >= self.options.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            offset = 0;
+            if error.is_transient() {
+                warn!("Upload of {} interrupted: {}", ftp_path, error);
+                if let Err(e) = self.reconnect(conn) {
+                    warn!("Reconnect failed: {}", e);
+                    continue;
+                }
+                // Lo recibido es un prefijo de lo subido solo hasta donde se envió
+                offset = match conn.size(ftp_path) {
+                    Ok(size) if size < len && !self.is_ascii(ftp_path) => size.min(sent),
+                    _ => 0,
+                };
             }
         }
+    }
 
-        // Intentar obtener de caché primero
-        if let Some(attr) = self.get_attr_cached(ino) {
-            reply.attr(&TTL, &attr);
-            return;
+    /// Subir `buffer` desde `*pos` en trozos con REST + STOR, avanzando `pos`
+    /// con cada trozo completado
+    fn upload_from(
+        conn: &mut B,
+        ftp_path: &str,
+        buffer: &WriteBuffer,
+        pos: &mut u64,
+    ) -> Result<(), FtpError> {
+        let len = buffer.len();
+        while *pos < len {
+            let chunk = buffer.read_at(*pos, UPLOAD_CHUNK_SIZE as usize)?;
+            conn.store_at(ftp_path, *pos, &chunk)?;
+            *pos += chunk.len() as u64;
         }
+        Ok(())
+    }
 
-        // Si no está en caché, obtener del inodo
-        if let Some(inode) = self.inodes.lock().unwrap().get(&ino) {
-            // Para archivos regulares, actualizar tamaño ocasionalmente (no cada vez)
-            if inode.attr.kind == FileType::RegularFile {
-                // Solo actualizar si no hay caché o ha pasado mucho tiempo
-                let should_update = {
-                    let cache = self.attr_cache.lock().unwrap();
-                    if let Some(entry) = cache.get(&ino) {
-                        entry.timestamp.elapsed() > ATTR_CACHE_TTL
-                    } else {
-                        true
-                    }
-                };
+    /// Contenido de un archivo temporal local (None si el inodo es remoto)
+    fn shadow_buffer(&self, ino: u64) -> Option<Arc<Mutex<WriteBuffer>>> {
+        self.shadow_files.lock().unwrap().get(&ino).cloned()
+    }
 
-                if should_update {
-                    if let Ok(info) = self.get_ftp_file_info(&inode.ftp_path) {
-                        let mut updated_attr = inode.attr.clone();
-                        updated_attr.size = info.size;
-                        self.update_attr_cache(ino, updated_attr);
-                        reply.attr(&TTL, &updated_attr);
-                        return;
-                    }
-                }
-            }
+    /// Atributos de un archivo temporal local, con el tamaño de su contenido
+    fn shadow_attr(&self, ino: u64) -> Option<FileAttr> {
+        let buffer = self.shadow_buffer(ino)?;
+        let mut attr = self.inodes.lock().unwrap().get(&ino)?.attr;
+        attr.size = buffer.lock().unwrap().len();
+        attr.blocks = blocks_for(attr.size);
+        Some(attr)
+    }
 
-            // Usar atributos cacheados del inodo
-            self.update_attr_cache(ino, inode.attr);
-            reply.attr(&TTL, &inode.attr);
-            return;
-        }
+    /// Archivos temporales locales de un directorio: (ino, nombre)
+    fn shadow_entries(&self, parent: u64) -> Vec<(u64, String)> {
+        let shadow = self.shadow_files.lock().unwrap();
+        let inodes = self.inodes.lock().unwrap();
+        shadow
+            .keys()
+            .filter_map(|ino| inodes.get(ino))
+            .filter(|inode| inode.parent == parent)
+            .map(|inode| (inode.ino, inode.name.clone()))
+            .collect()
+    }
 
-        error!("getattr: inode {} not found", ino);
-        reply.error(ENOENT);
+    /// Crear un archivo temporal que nunca se sube al servidor
+    fn create_shadow(&self, parent: u64, name: &str, ftp_path: &str, mode: u32) -> Inode {
+        // Un inodo anterior con la misma ruta (p. ej. un temporal ya borrado) se descarta
+        self.path_to_inode
+            .lock()
+            .unwrap()
+            .remove(self.path_key(ftp_path).as_ref());
+        let file_info = FtpFileInfo {
+            name: name.to_string(),
+            path: ftp_path.to_string(),
+            size: 0,
+            is_dir: false,
+            permissions: mode & 0o777,
+            modified_time: Some(SystemTime::now()),
+            link_target: None,
+            raw_name: None,
+        };
+        let inode = self.get_or_create_inode(parent, &file_info);
+        self.shadow_files
+            .lock()
+            .unwrap()
+            .insert(inode.ino, Arc::new(Mutex::new(WriteBuffer::new())));
+        debug!("Created local-only temp file {}", ftp_path);
+        inode
     }
 
-    /// Buscar archivo por nombre (usando caché de directorio)
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let name_str = name.to_string_lossy().to_string();
-        trace!("lookup called for parent={}, name={}", parent, name_str);
+    /// Subir un archivo temporal local a `new_path` al renombrarlo a un nombre
+    /// normal; desde entonces es un archivo remoto más
+    fn materialize_shadow(&self, ino: u64, new_path: &str) -> Result<()> {
+        let buffer = match self.shadow_buffer(ino) {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        };
+        let mut buffer = buffer.lock().unwrap();
+        {
+            let mut reader = buffer.reader().context("Failed to read temp file")?;
+            self.ftp_conn
+                .lock()
+                .unwrap()
+                .store_from(new_path, &mut reader)
+                .context(format!("Failed to upload {}", new_path))?;
+        }
+        // Los handles abiertos siguen escribiendo en el mismo buffer, que ahora
+        // se sincroniza como cualquier otro
+        buffer.dirty = false;
+        let len = buffer.len();
+        drop(buffer);
+        Stats::add(&self.stats.bytes_up, len);
+
+        self.shadow_files.lock().unwrap().remove(&ino);
+        self.set_cached_size(ino, len);
+        debug!("Uploaded local temp file as {} ({} bytes)", new_path, len);
+        Ok(())
+    }
 
-        // OPTIMIZACIÓN VS Code: Ignorar archivos temporales inmediatamente
-        if is_temp_file(&name_str) {
-            trace!("lookup: ignoring temp file {}", name_str);
-            reply.error(ENOENT);
-            return;
+    /// Renombrar en el servidor y mover el inodo a su nueva ruta
+    ///
+    /// Lo escrito en handles abiertos del origen se sube antes: los editores
+    /// guardan escribiendo `a.tmp` y renombrándolo sobre `a`, y sin esto el
+    /// servidor renombraría el temporal aún vacío. El inodo que ocupaba el
+    /// destino queda reemplazado y sus datos cacheados se descartan.
+    fn rename_remote(
+        &self,
+        old_path: &str,
+        new_path: &str,
+        newparent: u64,
+        newname: &str,
+    ) -> Result<()> {
+        let (old_key, new_key) = (self.path_key(old_path), self.path_key(new_path));
+        let source = self
+            .path_to_inode
+            .lock()
+            .unwrap()
+            .get(old_key.as_ref())
+            .copied();
+        if let Some(ino) = source {
+            self.sync_inode_buffers(ino)
+                .context("Failed to upload pending writes before renaming")?;
         }
 
-        // Obtener inodo padre
-        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
-            Some(inode) => inode.clone(),
-            None => {
-                error!("lookup: parent inode {} not found", parent);
-                reply.error(ENOENT);
-                return;
+        self.ftp_conn
+            .lock()
+            .unwrap()
+            .rename(old_path, new_path)
+            .with_context(|| format!("Failed to rename {} to {}", old_path, new_path))?;
+        if let Some(mirror) = &self.options.mirror {
+            mirror.forget(old_path);
+            mirror.forget(new_path);
+        }
+
+        // Actualizar caché de inodos
+        let replaced = {
+            let mut path_to_inode = self.path_to_inode.lock().unwrap();
+            path_to_inode.remove(old_key.as_ref());
+            match source {
+                Some(ino) => path_to_inode.insert(new_key.into_owned(), ino),
+                None => path_to_inode.remove(new_key.as_ref()),
             }
         };
-
-        // Entradas especiales
-        if name_str == "." {
-            reply.entry(&TTL, &parent_inode.attr, 0);
-            return;
+        if let Some(ino) = source {
+            if let Some(inode) = self.inodes.lock().unwrap().get_mut(&ino) {
+                inode.ftp_path = new_path.to_string();
+                inode.name = newname.to_string();
+                inode.parent = newparent;
+            }
         }
-        if name_str == ".." {
-            let parent_parent = parent_inode.parent;
-            if let Some(attr) = self.get_attr_cached(parent_parent) {
-                reply.entry(&TTL, &attr, 0);
-                return;
+        if let Some(replaced) = replaced.filter(|&replaced| Some(replaced) != source) {
+            if self.open_count(replaced) == 0 {
+                self.inodes.lock().unwrap().remove(&replaced);
+                self.attr_cache.lock().unwrap().remove(&replaced);
             }
+            self.evict_file_data(replaced);
         }
+        Ok(())
+    }
 
-        // Construir ruta FTP
-        let ftp_path = if parent_inode.ftp_path == "/" {
-            format!("/{}", name_str)
-        } else {
-            format!("{}/{}", parent_inode.ftp_path, name_str)
-        };
-
-        // Verificar caché de inodo primero
-        if let Some(&ino) = self.path_to_inode.lock().unwrap().get(&ftp_path) {
-            if let Some(attr) = self.get_attr_cached(ino) {
-                reply.entry(&TTL, &attr, 0);
-                return;
-            }
+    /// Subir los buffers con datos pendientes de todos los handles de un inodo
+    fn sync_inode_buffers(&self, ino: u64) -> Result<()> {
+        let handles: Vec<u64> = self
+            .open_files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, handle)| handle.ino == ino)
+            .map(|(&fh, _)| fh)
+            .collect();
+        for fh in handles {
+            self.sync_write_buffer(fh)?;
         }
+        Ok(())
+    }
 
-        // Verificar caché de directorio primero (evita consulta FTP individual)
-        match self.list_ftp_directory_cached(&parent_inode.ftp_path) {
-            Ok(files) => {
-                if let Some(file_info) = files.iter().find(|f| f.name == name_str) {
-                    let inode = self.get_or_create_inode(parent, file_info);
-                    reply.entry(&TTL, &inode.attr, 0);
-                    return;
-                }
-            }
-            Err(e) => {
-                debug!("lookup: failed to list parent directory: {}", e);
+    /// Fijar en el servidor la fecha de modificación de `ino` (MFMT)
+    ///
+    /// Lo pendiente de subir se sube antes, o la subida pisaría la fecha; si
+    /// se pide la hora actual, esa subida ya la deja puesta. Un servidor sin
+    /// MFMT ni SITE UTIME da ENOTSUP en lugar de fingir que se aplicó.
+    fn set_remote_mtime(&self, ino: u64, time: SystemTime, now: bool) -> Result<(), i32> {
+        if self.shadow_buffer(ino).is_some() {
+            return Ok(());
+        }
+        let ftp_path = match self.inodes.lock().unwrap().get(&ino) {
+            Some(inode) => inode.ftp_path.clone(),
+            None => return Err(ENOENT),
+        };
+        if self.dirty_write_buffer(ino).is_some() {
+            self.sync_inode_buffers(ino).map_err(|e| {
+                error!("setattr: failed to sync {} before MFMT: {:#}", ftp_path, e);
+                errno_for(&e)
+            })?;
+            if now {
+                return Ok(());
             }
         }
 
-        // Fallback: consulta directa al FTP
-        match self.get_ftp_file_info(&ftp_path) {
-            Ok(file_info) => {
-                let inode = self.get_or_create_inode(parent, &file_info);
-                reply.entry(&TTL, &inode.attr, 0);
+        let mut conn = self.ftp_conn.lock().unwrap();
+        match conn.set_mtime(&ftp_path, time) {
+            Ok(()) => {
+                drop(conn);
+                self.invalidate_parent_dir(&ftp_path);
+                Ok(())
             }
-            Err(_) => {
-                reply.error(ENOENT);
+            Err(FtpError::Protocol(message)) => {
+                debug!(
+                    "Cannot set the modification time of {}: {}",
+                    ftp_path, message
+                );
+                Err(libc::ENOTSUP)
+            }
+            Err(e) => {
+                error!(
+                    "setattr: failed to set the modification time of {}: {}",
+                    ftp_path, e
+                );
+                Err(errno_for(&e.into()))
             }
         }
     }
 
-    /// Leer contenido de directorio (optimizado con caché)
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        trace!("readdir called for inode {} with offset {}", ino, offset);
+    /// Reconectar contando la reconexión en las estadísticas
+    fn reconnect(&self, conn: &mut B) -> Result<(), FtpError> {
+        conn.reconnect()?;
+        Stats::add(&self.stats.reconnects, 1);
+        Ok(())
+    }
 
-        let inode = match self.inodes.lock().unwrap().get(&ino) {
-            Some(inode) => inode.clone(),
-            None => {
-                error!("readdir: inode {} not found", ino);
-                reply.error(ENOENT);
-                return;
-            }
+    /// Modo con el que crear un archivo o directorio, ya enmascarado
+    ///
+    /// Con `--file-mode`/`--dir-mode` se usa el configurado (sin umask)
+    /// salvo que el cliente pida explícitamente otro distinto del habitual.
+    fn created_mode(&self, mode: u32, umask: u32, is_dir: bool) -> u32 {
+        let (default, usual) = if is_dir {
+            (self.options.dir_mode, 0o777)
+        } else {
+            (self.options.file_mode, 0o666)
         };
+        let requested = mode & 0o7777;
+        match default {
+            Some(default) if requested == 0 || requested == usual => default & 0o7777,
+            _ => mode & !umask & 0o7777,
+        }
+    }
 
-        if inode.attr.kind != FileType::Directory {
-            reply.error(ENOTDIR);
-            return;
+    /// Aplicar en el servidor el modo pedido al crear `ftp_path`
+    ///
+    /// SITE CHMOD no es estándar: si el servidor no lo admite, los permisos
+    /// quedan solo en el inodo local. Devuelve el modo ya enmascarado; con
+    /// `--file-mode` (archivos) o `--dir-mode` (directorios), el que muestra
+    /// el listado del servidor.
+    ///
+    /// Listar el padre tras cada creación haría cuadrático extraer un tar:
+    /// se comprueba una vez por directorio y tipo, y el resultado sirve
+    /// para las siguientes creaciones en él.
+    fn chmod_created(
+        &self,
+        conn: &mut B,
+        ftp_path: &str,
+        mode: u32,
+        umask: u32,
+        is_dir: bool,
+    ) -> u32 {
+        let mode = mode & !umask & 0o7777;
+        if let Err(e) = conn.chmod(ftp_path, mode) {
+            debug!(
+                "SITE CHMOD {:o} {} failed, keeping permissions local: {}",
+                mode, ftp_path, e
+            );
+            return mode;
+        }
+        let default = match is_dir {
+            true => self.options.dir_mode,
+            false => self.options.file_mode,
+        };
+        if default.is_none() {
+            return mode;
         }
 
-        // Recolectar entradas con strings propios
-        let mut entries: Vec<(u64, FileType, String)> = vec![
-            (inode.ino, FileType::Directory, ".".to_string()),
-            (inode.parent, FileType::Directory, "..".to_string()),
-        ];
+        let parent = match ftp_path.rsplit_once('/') {
+            Some(("", _)) | None => "/",
+            Some((parent, _)) => parent,
+        };
+        let key = (parent.to_string(), is_dir);
+        if let Some(&checked) = self.chmod_checked.lock().unwrap().get(&key) {
+            return checked.unwrap_or(mode);
+        }
+        let Some(listed) = self.listed_mode(conn, ftp_path) else {
+            return mode;
+        };
+        let checked = (listed != mode).then_some(listed);
+        self.chmod_checked.lock().unwrap().insert(key, checked);
+        listed
+    }
 
-        // Usar caché de directorio (evita consulta FTP repetida)
-        // OPTIMIZACIÓN VS Code: Filtrar archivos temporales
-        match self.list_ftp_directory_cached(&inode.ftp_path) {
-            Ok(files) => {
-                let filtered_count = files.len();
-                for file_info in files {
-                    // Ignorar archivos temporales en el listado
-                    if is_temp_file(&file_info.name) {
-                        trace!("readdir: filtering temp file {}", file_info.name);
-                        continue;
-                    }
-                    let file_inode = self.get_or_create_inode(ino, &file_info);
-                    entries.push((
-                        file_inode.ino,
-                        file_inode.attr.kind,
-                        file_inode.name.clone(),
-                    ));
-                }
-                trace!(
-                    "readdir: filtered {} temp files from {}",
-                    filtered_count - entries.len() + 2,
-                    filtered_count
-                ); // +2 por . y ..
-            }
+    /// Permisos de `ftp_path` según el listado de su directorio
+    ///
+    /// Algunos servidores aceptan SITE CHMOD sin aplicarlo; se avisa si el
+    /// listado no muestra el modo pedido.
+    fn listed_mode(&self, conn: &mut B, ftp_path: &str) -> Option<u32> {
+        let (parent, name) = ftp_path.rsplit_once('/')?;
+        let parent = if parent.is_empty() { "/" } else { parent };
+        let files = match conn.list_dir(parent) {
+            Ok(files) => files,
             Err(e) => {
-                error!("readdir: failed to list directory: {}", e);
-                reply.error(EIO);
-                return;
+                debug!("Could not list {} to check permissions: {}", parent, e);
+                return None;
             }
-        }
+        };
+        let listed = self.listed_entry(&files, name)?.permissions & 0o7777;
+        debug!("{} is listed with mode {:o}", ftp_path, listed);
+        Some(listed)
+    }
 
-        // Enviar entradas empezando desde offset
-        for (i, (entry_ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
-            let buffer_full = reply.add(*entry_ino, (i + 1) as i64, *kind, name.as_str());
-            if buffer_full {
-                break;
+    /// Completar la creación de `ftp_path` tras su primera subida
+    ///
+    /// `create` no sube nada: el archivo vacío se sube con el primer flush,
+    /// junto con lo que se haya escrito, así que `touch` o crear y escribir
+    /// cuestan un solo STOR. Hasta entonces solo existe en local.
+    fn finish_pending_create(&self, conn: &mut B, ino: u64, ftp_path: &str) {
+        let mode = match self.pending_creates.lock().unwrap().remove(&ino) {
+            Some(mode) => mode,
+            None => return,
+        };
+        let applied = self.chmod_created(conn, ftp_path, mode, 0, false);
+        if applied != mode {
+            warn!(
+                "{} was created with mode {:o} instead of {:o}",
+                ftp_path, applied, mode
+            );
+            if let Some(inode) = self.inodes.lock().unwrap().get_mut(&ino) {
+                inode.attr.perm = applied as u16 & !self.options.umask;
             }
+            self.attr_cache.lock().unwrap().remove(&ino);
         }
+        debug!("Created {} on the server", ftp_path);
+    }
 
-        reply.ok();
+    /// Crear el inodo de un archivo nuevo que se subirá en su primer flush
+    fn create_pending(&self, parent: u64, name: &str, ftp_path: &str, mode: u32) -> Inode {
+        self.cancel_deferred_unlink(ftp_path);
+        self.negative_cache
+            .lock()
+            .unwrap()
+            .entries
+            .remove(self.path_key(ftp_path).as_ref());
+        let file_info = FtpFileInfo {
+            name: name.to_string(),
+            path: ftp_path.to_string(),
+            size: 0,
+            is_dir: false,
+            permissions: mode & 0o777,
+            modified_time: Some(SystemTime::now()),
+            link_target: None,
+            raw_name: None,
+        };
+        let inode = self.get_or_create_inode(parent, &file_info);
+        self.pending_creates.lock().unwrap().insert(inode.ino, mode);
+        inode
     }
 
-    /// Abrir archivo (con write buffer para lazy write)
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
-        trace!("open called for inode {} flags {}", ino, flags);
+    /// Mover en local el inodo `ino`, que el servidor no conoce, de la clave
+    /// `old_key` a `new_path`
+    ///
+    /// El inodo que ocupaba el destino queda reemplazado.
+    fn move_local(
+        &self,
+        ino: u64,
+        old_key: &str,
+        new_path: String,
+        newparent: u64,
+        newname: String,
+    ) {
+        let mut path_to_inode = self.path_to_inode.lock().unwrap();
+        path_to_inode.remove(old_key);
+        let new_key = self.path_key(&new_path).into_owned();
+        if let Some(replaced) = path_to_inode.insert(new_key, ino) {
+            drop(path_to_inode);
+            if self.open_count(replaced) == 0 {
+                self.inodes.lock().unwrap().remove(&replaced);
+                self.attr_cache.lock().unwrap().remove(&replaced);
+            }
+            self.evict_file_data(replaced);
+        }
+        if let Some(inode) = self.inodes.lock().unwrap().get_mut(&ino) {
+            inode.ftp_path = new_path;
+            inode.name = newname;
+            inode.parent = newparent;
+        }
+        self.attr_cache.lock().unwrap().remove(&ino);
+    }
 
-        let fh = self.allocate_fh();
+    /// Archivos creados en `parent` que aún no se han subido: (ino, nombre)
+    fn pending_entries(&self, parent: u64) -> Vec<(u64, String)> {
+        let pending = self.pending_creates.lock().unwrap();
+        let inodes = self.inodes.lock().unwrap();
+        pending
+            .keys()
+            .filter_map(|ino| inodes.get(ino))
+            .filter(|inode| inode.parent == parent && inode.attr.nlink > 0)
+            .map(|inode| (inode.ino, inode.name.clone()))
+            .collect()
+    }
 
-        // Verificar si es modo escritura (flags & O_WRONLY o O_RDWR)
-        let is_write_mode = (flags & 0o1) != 0 || (flags & 0o2) != 0;
+    /// Instantánea JSON de los contadores internos (sin tocar la red)
+    fn stats_json(&self) -> String {
+        let s = self.stats_snapshot();
+        format!(
+            "{{\n  \"dir_cache\": {{\"entries\": {}, \"hits\": {}, \"misses\": {}}},\n  \
+             \"attr_cache\": {{\"entries\": {}}},\n  \
+             \"kind_cache\": {{\"hits\": {}, \"misses\": {}}},\n  \
+             \"size_cache\": {{\"hits\": {}, \"misses\": {}}},\n  \
+             \"negative_cache\": {{\"hits\": {}, \"misses\": {}}},\n  \
+             \"read_cache\": {{\"files\": {}, \"bytes\": {}, \"evictions\": {}}},\n  \
+             \"open_handles\": {},\n  \
+             \"bytes_down\": {},\n  \
+             \"bytes_up\": {},\n  \
+             \"reconnects\": {}\n}}\n",
+            s.dir_entries,
+            s.dir.0,
+            s.dir.1,
+            s.attr_entries,
+            s.kind.0,
+            s.kind.1,
+            s.size.0,
+            s.size.1,
+            s.negative.0,
+            s.negative.1,
+            s.read_files,
+            s.read_bytes,
+            s.read_evictions,
+            s.open_handles,
+            s.bytes_down,
+            s.bytes_up,
+            s.reconnects,
+        )
+    }
 
-        let file_handle = FileHandle {
-            ino,
-            write_buffer: if is_write_mode {
-                Some(WriteBuffer {
-                    data: Vec::new(),
-                    dirty: false,
-                    last_modified: Instant::now(),
-                })
-            } else {
-                None
-            },
+    /// Leer los contadores y el tamaño de las cachés
+    fn stats_snapshot(&self) -> StatsSnapshot {
+        let (read_files, read_bytes) = {
+            let read_cache = self.read_cache.lock().unwrap();
+            let bytes: usize = read_cache.values().map(Vec::len).sum();
+            (read_cache.len(), bytes)
         };
+        let stats = &self.stats;
+        StatsSnapshot {
+            dir_entries: self.dir_cache.lock().unwrap().len(),
+            attr_entries: self.attr_cache.lock().unwrap().len(),
+            dir: (
+                Stats::get(&stats.dir_cache_hits),
+                Stats::get(&stats.dir_cache_misses),
+            ),
+            kind: self.kind_cache.lock().unwrap().hits_misses(),
+            size: self.size_cache.lock().unwrap().hits_misses(),
+            negative: self.negative_cache.lock().unwrap().hits_misses(),
+            read_files,
+            read_bytes,
+            read_evictions: Stats::get(&stats.read_cache_evictions),
+            open_handles: self.open_files.lock().unwrap().len(),
+            bytes_down: Stats::get(&stats.bytes_down),
+            bytes_up: Stats::get(&stats.bytes_up),
+            reconnects: Stats::get(&stats.reconnects),
+        }
+    }
 
-        self.open_files.lock().unwrap().insert(fh, file_handle);
-        trace!(
-            "Opened file handle {} for inode {} (write mode: {})",
-            fh,
-            ino,
-            is_write_mode
+    /// Las estadísticas en formato de texto de Prometheus (`--metrics-addr`)
+    #[cfg(feature = "metrics")]
+    fn metrics_text(&self) -> String {
+        let s = self.stats_snapshot();
+        let mut metrics = Exposition::default();
+        let caches = |pick: fn(&(u64, u64)) -> u64| {
+            [
+                ("cache=\"dir\"", pick(&s.dir)),
+                ("cache=\"kind\"", pick(&s.kind)),
+                ("cache=\"size\"", pick(&s.size)),
+                ("cache=\"negative\"", pick(&s.negative)),
+            ]
+        };
+        metrics.counter(
+            "rustftpfs_cache_hits_total",
+            "Lookups answered from a cache",
+            &caches(|c| c.0),
+        );
+        metrics.counter(
+            "rustftpfs_cache_misses_total",
+            "Lookups that had to ask the server",
+            &caches(|c| c.1),
+        );
+        metrics.gauge(
+            "rustftpfs_cache_entries",
+            "Entries currently cached",
+            &[
+                ("cache=\"dir\"", s.dir_entries as u64),
+                ("cache=\"attr\"", s.attr_entries as u64),
+                ("cache=\"read\"", s.read_files as u64),
+            ],
+        );
+        metrics.gauge(
+            "rustftpfs_read_cache_bytes",
+            "File contents held in the read cache",
+            &[("", s.read_bytes as u64)],
+        );
+        metrics.counter(
+            "rustftpfs_read_cache_evictions_total",
+            "Files dropped from the read cache",
+            &[("", s.read_evictions)],
+        );
+        metrics.gauge(
+            "rustftpfs_open_handles",
+            "Open file handles",
+            &[("", s.open_handles as u64)],
+        );
+        metrics.counter(
+            "rustftpfs_transferred_bytes_total",
+            "Bytes transferred to and from the server",
+            &[
+                ("direction=\"down\"", s.bytes_down),
+                ("direction=\"up\"", s.bytes_up),
+            ],
+        );
+        metrics.counter(
+            "rustftpfs_reconnects_total",
+            "Reconnections to the server",
+            &[("", s.reconnects)],
         );
 
-        reply.opened(fh, 0);
+        let name = "rustftpfs_operation_duration_seconds";
+        metrics.histogram_header(name, "Duration of FTP operations, retries included");
+        for (op, histogram) in [
+            ("list", &self.stats.list_latency),
+            ("retrieve", &self.stats.retrieve_latency),
+            ("store", &self.stats.store_latency),
+        ] {
+            metrics.histogram(
+                name,
+                &format!("operation=\"{}\"", op),
+                &histogram.cumulative(),
+                Stats::get(&histogram.sum_micros) as f64 / 1e6,
+                Stats::get(&histogram.count),
+            );
+        }
+        metrics.into_string()
     }
 
-    /// Leer datos de archivo (con caché y prefetching)
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        trace!(
-            "read called for inode {} offset {} size {}",
-            ino,
-            offset,
-            size
-        );
+    /// Atributos del archivo de estadísticas, con el tamaño de `len`
+    fn stats_attr(&self, len: usize) -> FileAttr {
+        let now = SystemTime::now();
+        FileAttr {
+            ino: STATS_INODE,
+            size: len as u64,
+            blocks: blocks_for(len as u64),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            uid: self.owner_uid(),
+            gid: self.owner_gid(),
+            rdev: 0,
+            flags: 0,
+            blksize: self.options.blksize,
+        }
+    }
 
-        let inode = match self.inodes.lock().unwrap().get(&ino) {
-            Some(inode) => inode.clone(),
-            None => {
-                error!("read: inode {} not found", ino);
-                reply.error(ENOENT);
-                return;
+    /// Descartar un archivo temporal local (el inodo dura hasta su último release)
+    fn remove_shadow(&self, ino: u64) {
+        self.shadow_files.lock().unwrap().remove(&ino);
+        let inode = self.inodes.lock().unwrap().get(&ino).cloned();
+        if let Some(inode) = inode {
+            let key = self.path_key(&inode.ftp_path);
+            let mut path_to_inode = self.path_to_inode.lock().unwrap();
+            if path_to_inode.get(key.as_ref()) == Some(&ino) {
+                path_to_inode.remove(key.as_ref());
             }
-        };
+        }
+        if self.open_count(ino) == 0 {
+            self.inodes.lock().unwrap().remove(&ino);
+            self.attr_cache.lock().unwrap().remove(&ino);
+        }
+    }
 
-        if inode.attr.kind == FileType::Directory {
-            reply.error(EISDIR);
-            return;
+    /// Valor de un atributo extendido sintético (None si no existe)
+    fn synthetic_xattr(&self, ino: u64, name: &OsStr) -> Option<Vec<u8>> {
+        if name != FTP_PATH_XATTR {
+            return None;
         }
+        let inodes = self.inodes.lock().unwrap();
+        inodes
+            .get(&ino)
+            .map(|inode| inode.ftp_path.clone().into_bytes())
+    }
 
-        // Cargar datos con prefetching
-        match self.load_file_data(ino, &inode.ftp_path, true) {
-            Ok(data) => {
-                let offset = offset as usize;
-                let size = size as usize;
+    /// Número de handles abiertos sobre un inodo
+    fn open_count(&self, ino: u64) -> usize {
+        self.open_files
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|handle| handle.ino == ino)
+            .count()
+    }
 
-                if offset >= data.len() {
-                    reply.data(&[]);
-                    return;
-                }
+    /// EROFS en montajes de solo lectura; las operaciones que modifican algo
+    /// lo comprueban antes de tocar la red o las cachés
+    fn check_writable(&self) -> Result<(), i32> {
+        if self.options.read_only {
+            Err(EROFS)
+        } else {
+            Ok(())
+        }
+    }
 
-                let end = std::cmp::min(offset + size, data.len());
-                reply.data(&data[offset..end]);
-            }
-            Err(e) => {
-                error!("read: failed to load file data: {}", e);
-                reply.error(EIO);
+    /// Borrar el archivo `ftp_path` del directorio `parent_path`
+    ///
+    /// Si sigue abierto, el inodo y el archivo en el servidor se conservan
+    /// hasta el último `release` para que los handles puedan seguir leyendo;
+    /// la ruta desaparece de los listados desde ya.
+    fn remove_file(&self, parent_path: &str, ftp_path: &str) -> Result<()> {
+        let ino = self
+            .path_to_inode
+            .lock()
+            .unwrap()
+            .remove(self.path_key(ftp_path).as_ref());
+        if let Some(ino) = ino {
+            if self.open_count(ino) > 0 {
+                debug!("{} is still open, deferring its deletion", ftp_path);
+                self.deferred_unlinks
+                    .lock()
+                    .unwrap()
+                    .insert(ino, ftp_path.to_string());
+                if let Some(inode) = self.inodes.lock().unwrap().get_mut(&ino) {
+                    inode.attr.nlink = 0;
+                }
+                self.attr_cache.lock().unwrap().remove(&ino);
+                self.invalidate_dir_cache(parent_path);
+                return Ok(());
             }
+            self.inodes.lock().unwrap().remove(&ino);
+            self.evict_file_data(ino);
+            self.attr_cache.lock().unwrap().remove(&ino);
         }
-    }
+        self.invalidate_dir_cache(parent_path);
 
-    /// Escribir datos en archivo (con write buffer - lazy write)
-    fn write(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        trace!(
-            "write called for inode {} fh {} offset {} size {}",
-            ino,
-            fh,
-            offset,
-            data.len()
-        );
+        self.delete_remote_file(ftp_path)
+    }
 
-        let inode = match self.inodes.lock().unwrap().get(&ino) {
-            Some(inode) => inode.clone(),
-            None => {
-                error!("write: inode {} not found", ino);
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    /// Borrar un archivo en el servidor (y en `mirror`); que no exista no es
+    /// un error
+    fn delete_remote_file(&self, ftp_path: &str) -> Result<()> {
+        let mut conn = self.ftp_conn.lock().unwrap();
+        if !conn.exists(ftp_path).unwrap_or(false) {
+            trace!("unlink: file does not exist: {}", ftp_path);
+            return Ok(());
+        }
+        conn.delete(ftp_path)
+            .context(format!("Failed to delete {}", ftp_path))?;
+        drop(conn);
 
-        if inode.attr.kind == FileType::Directory {
-            reply.error(EISDIR);
-            return;
+        if let Some(mirror) = &self.options.mirror {
+            mirror.forget(ftp_path);
         }
+        Ok(())
+    }
 
-        // Obtener o crear el file handle
-        let mut open_files = self.open_files.lock().unwrap();
-        let file_handle = open_files.get_mut(&fh);
+    /// Anular el borrado diferido de `ftp_path` porque se ha vuelto a crear
+    ///
+    /// El inodo antiguo queda huérfano y se libera en su último `release`.
+    fn cancel_deferred_unlink(&self, ftp_path: &str) {
+        self.deferred_unlinks.lock().unwrap().retain(|ino, path| {
+            let cancel = path == ftp_path;
+            if cancel {
+                debug!(
+                    "{} recreated, cancelling deferred delete of inode {}",
+                    path, ino
+                );
+            }
+            !cancel
+        });
+    }
 
-        if let Some(file_handle) = file_handle {
-            if let Some(ref mut write_buffer) = file_handle.write_buffer {
-                // Redimensionar buffer si es necesario
-                let offset = offset as usize;
-                let end = offset + data.len();
-                if end > write_buffer.data.len() {
-                    write_buffer.data.resize(end, 0);
-                }
+    /// Cerrar un handle: subir su buffer y, si era el último del inodo,
+    /// liberar sus datos y completar un borrado diferido
+    fn release_handle(&self, ino: u64, fh: u64) -> Result<()> {
+        self.sync_write_buffer(fh)?;
+        self.open_files.lock().unwrap().remove(&fh);
 
-                // Escribir datos en el buffer
-                write_buffer.data[offset..end].copy_from_slice(data);
-                write_buffer.dirty = true;
-                write_buffer.last_modified = Instant::now();
+        // Limpiar caché de lectura para ahorrar memoria
+        // (pero solo si no hay otros handles abiertos para este archivo)
+        if self.open_count(ino) > 0 {
+            return Ok(());
+        }
+        // Una descarga en segundo plano que aún espera ya no se hará, y la que
+        // esté en curso vacía su caché al terminar
+        self.evict_file_data(ino);
+        self.size_checked.lock().unwrap().remove(&ino);
+
+        let deferred = self.deferred_unlinks.lock().unwrap().remove(&ino);
+        let ftp_path = self
+            .inodes
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .map(|inode| inode.ftp_path.clone());
+        let orphaned = ftp_path.is_some_and(|path| {
+            let key = self.path_key(&path);
+            self.path_to_inode.lock().unwrap().get(key.as_ref()) != Some(&ino)
+        });
+        if deferred.is_none() && !orphaned {
+            return Ok(());
+        }
 
-                // Actualizar caché de lectura para mantener consistencia
-                self.read_cache
-                    .lock()
-                    .unwrap()
-                    .insert(ino, write_buffer.data.clone());
+        self.inodes.lock().unwrap().remove(&ino);
+        self.attr_cache.lock().unwrap().remove(&ino);
+        self.pending_creates.lock().unwrap().remove(&ino);
+        if let Some(path) = deferred {
+            debug!("Last handle of {} closed, deleting it", path);
+            // unlink ya respondió con éxito: un fallo aquí solo se registra
+            if let Err(e) = self.delete_remote_file(&path) {
+                error!("Deferred delete of {} failed: {:#}", path, e);
+            }
+        }
+        Ok(())
+    }
 
-                trace!(
-                    "Write buffered: {} bytes at offset {} (total: {})",
-                    data.len(),
-                    offset,
-                    write_buffer.data.len()
-                );
+    /// Subir las escrituras que quedaron encoladas en `mirror` sin conexión
+    ///
+    /// Se detiene en el primer fallo; el resto se reintenta más tarde.
+    /// Devuelve cuántas se subieron.
+    fn replay_pending_uploads(&self) -> usize {
+        // Sin conexión no hay adónde subirlas: esperan al próximo montaje
+        let mirror = match &self.options.mirror {
+            Some(mirror) if !self.options.offline => mirror,
+            _ => return 0,
+        };
 
-                reply.written(data.len() as u32);
-                return;
+        let mut replayed = 0;
+        for path in mirror.pending() {
+            let stored = mirror
+                .open_pending(&path)
+                .map_err(FtpError::from)
+                .and_then(|mut file| {
+                    let len = file.metadata().map_or(0, |m| m.len());
+                    self.ftp_conn
+                        .lock()
+                        .unwrap()
+                        .store_from(&path, &mut file)
+                        .map(|()| len)
+                });
+            match stored {
+                Ok(len) => Stats::add(&self.stats.bytes_up, len),
+                Err(e) => {
+                    warn!("Replaying queued upload of {} failed: {}", path, e);
+                    break;
+                }
+            }
+            if let Err(e) = mirror.finish_upload(&path) {
+                warn!("Failed to remove queued upload of {}: {}", path, e);
             }
+            info!("Uploaded {} queued while offline", path);
+            self.forget_cached_path(&path);
+            replayed += 1;
         }
-
-        // Fallback si no hay write buffer (modo read-only o error)
-        error!("write: no write buffer available for fh {}", fh);
-        reply.error(EIO);
+        replayed
     }
 
-    /// Crear archivo (invalida caché de directorio)
-    fn create(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        _umask: u32,
-        _flags: i32,
-        reply: ReplyCreate,
-    ) {
-        let name_str = name.to_string_lossy().to_string();
-        trace!(
-            "create called for parent={} name={} mode={}",
-            parent,
-            name_str,
-            mode
-        );
-
-        // OPTIMIZACIÓN VS Code: No crear archivos temporales en el servidor
-        if is_temp_file(&name_str) {
-            trace!("create: ignoring temp file {}", name_str);
-            // Devolver un error que VS Code interpretará como "no soportado"
-            // pero no interrumpirá el flujo de trabajo
-            reply.error(libc::EOPNOTSUPP);
-            return;
+    /// Borrar `path` con todo su contenido en el servidor (`--recursive-rmdir`)
+    ///
+    /// Los archivos y enlaces se borran con DELE y los subdirectorios con esta
+    /// misma función, hasta `depth` niveles. Los enlaces no se siguen.
+    fn remove_tree(&self, conn: &mut B, path: &str, depth: usize) -> Result<()> {
+        if depth == 0 {
+            anyhow::bail!("{} is nested too deeply to remove", path);
+        }
+        for entry in conn.list_dir(path)? {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            let child = join_ftp_path(path, &entry.name);
+            if entry.is_dir && entry.link_target.is_none() {
+                self.remove_tree(conn, &child, depth - 1)?;
+            } else {
+                conn.delete(&child)?;
+            }
         }
+        conn.rmdir(path)?;
+        info!(event = "remove_tree", path = path; "Removed {} and its contents", path);
+        Ok(())
+    }
 
-        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
-            Some(inode) => inode.clone(),
-            None => {
-                error!("create: parent inode {} not found", parent);
-                reply.error(ENOENT);
-                return;
+    /// Borrar el directorio `path` del servidor
+    ///
+    /// Muchos servidores responden a RMD con un 550 genérico, así que solo
+    /// un listado con entradas confirma que el directorio no está vacío: en
+    /// ese caso es ENOTEMPTY o, con `--recursive-rmdir`, se borra todo con
+    /// [`Self::remove_tree`].
+    fn remove_dir(&self, conn: &mut B, path: &str) -> Result<(), i32> {
+        let error = match conn.rmdir(path) {
+            Ok(()) => return Ok(()),
+            Err(e @ (FtpError::NotFound | FtpError::PermissionDenied | FtpError::Transient)) => {
+                debug!("rmdir: RMD {} failed: {}", path, e);
+                return Err(errno_for(&e.into()));
             }
+            Err(e) => e,
         };
+        let not_empty = match conn.list_dir(path) {
+            Ok(entries) => entries.iter().any(|e| e.name != "." && e.name != ".."),
+            Err(e) => {
+                debug!("rmdir: could not list {}: {}", path, e);
+                false
+            }
+        };
+        if !not_empty {
+            error!("rmdir: failed to remove directory {}: {}", path, error);
+            return Err(errno_for(&error.into()));
+        }
+        if !self.options.recursive_rmdir {
+            return Err(libc::ENOTEMPTY);
+        }
 
-        let ftp_path = if parent_inode.ftp_path == "/" {
-            format!("/{}", name_str)
-        } else {
-            format!("{}/{}", parent_inode.ftp_path, name_str)
+        debug!("rmdir: {} is not empty, removing its contents", path);
+        let removed = self.remove_tree(conn, path, MAX_RMDIR_DEPTH);
+        self.forget_tree(path);
+        removed.map_err(|e| {
+            error!("rmdir: failed to remove directory: {:#}", e);
+            errno_for(&e)
+        })
+    }
+
+    /// Olvidar los inodos y cachés de `dir` y de todo lo que contenía
+    fn forget_tree(&self, dir: &str) {
+        // Compara por clave: vale tanto para las cachés indexadas por clave
+        // como para las indexadas por ruta
+        let key = self.path_key(dir);
+        let prefix = format!("{}/", key.trim_end_matches('/'));
+        let under = |path: &str| {
+            let path = self.path_key(path);
+            path == key || path.starts_with(&prefix)
         };
 
-        // Crear archivo vacío en FTP
-        let mut conn = self.ftp_conn.lock().unwrap();
-        match conn.store(&ftp_path, &[]) {
-            Ok(_) => {
-                drop(conn); // Liberar lock
+        let removed: Vec<u64> = {
+            let mut path_to_inode = self.path_to_inode.lock().unwrap();
+            let paths: Vec<String> = path_to_inode.keys().filter(|p| under(p)).cloned().collect();
+            paths
+                .iter()
+                .filter_map(|path| path_to_inode.remove(path))
+                .collect()
+        };
+        for ino in removed {
+            self.inodes.lock().unwrap().remove(&ino);
+            self.attr_cache.lock().unwrap().remove(&ino);
+            self.evict_file_data(ino);
+        }
+        self.dir_cache
+            .lock()
+            .unwrap()
+            .retain(|path, _| !under(path));
+        self.kind_cache
+            .lock()
+            .unwrap()
+            .entries
+            .retain(|path, _| !under(path));
+        self.size_cache
+            .lock()
+            .unwrap()
+            .entries
+            .retain(|path, _| !under(path));
+        self.negative_cache
+            .lock()
+            .unwrap()
+            .entries
+            .retain(|path, _| !under(path));
+        self.invalidate_parent_dir(dir);
+    }
 
-                // Invalidar caché del directorio padre
-                self.invalidate_dir_cache(&parent_inode.ftp_path);
+    /// Descartar lo cacheado de `path` tras cambiarlo por detrás del inodo
+    fn forget_cached_path(&self, path: &str) {
+        let ino = self
+            .path_to_inode
+            .lock()
+            .unwrap()
+            .get(self.path_key(path).as_ref())
+            .copied();
+        if let Some(ino) = ino {
+            self.evict_file_data(ino);
+            self.attr_cache.lock().unwrap().remove(&ino);
+        }
+        self.invalidate_parent_dir(path);
+    }
 
-                // Crear inodo para el nuevo archivo
-                let file_info = FtpFileInfo {
-                    name: name_str,
-                    path: ftp_path,
-                    size: 0,
-                    is_dir: false,
-                    permissions: (mode & 0o777) as u32,
-                    modified_time: Some(SystemTime::now()),
-                };
+    /// Vaciar todas las cachés de listados, atributos y datos
+    ///
+    /// Los inodos, los handles abiertos y los buffers de escritura se
+    /// conservan: lo siguiente que se consulte se vuelve a pedir al servidor.
+    fn flush_caches(&self) {
+        self.dir_cache.lock().unwrap().clear();
+        self.kind_cache.lock().unwrap().entries.clear();
+        self.size_cache.lock().unwrap().entries.clear();
+        self.negative_cache.lock().unwrap().entries.clear();
+        self.attr_cache.lock().unwrap().clear();
+        self.read_cache.lock().unwrap().clear();
+        self.ahead_cache.lock().unwrap().clear();
+        self.size_checked.lock().unwrap().clear();
+        info!(event = "cache_flush"; "Flushed all caches");
+    }
 
-                let inode = self.get_or_create_inode(parent, &file_info);
-                reply.created(&TTL, &inode.attr, 0, 0, 0);
+    /// Vaciar la caché del directorio `ino` y los datos de sus archivos
+    fn flush_dir_cache(&self, ino: u64) -> Result<(), i32> {
+        let dir = match self.inodes.lock().unwrap().get(&ino) {
+            Some(inode) if inode.attr.kind == FileType::Directory => inode.ftp_path.clone(),
+            Some(_) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        };
+        let key = self.path_key(&dir);
+        let children: Vec<u64> = self
+            .path_to_inode
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(path, _)| {
+                path.rsplit_once('/')
+                    .is_some_and(|(parent, _)| parent == key || (parent.is_empty() && key == "/"))
+            })
+            .map(|(_, &child)| child)
+            .collect();
+        for child in children {
+            self.evict_file_data(child);
+            self.attr_cache.lock().unwrap().remove(&child);
+        }
+        self.attr_cache.lock().unwrap().remove(&ino);
+        self.invalidate_dir_cache(&dir);
+        info!(event = "cache_flush", path = dir.as_str(); "Flushed cache of {}", dir);
+        Ok(())
+    }
+
+    /// Atender los comandos `IOCTL_*`; el resto responde ENOTTY
+    fn handle_ioctl(&self, ino: u64, cmd: u32) -> Result<(), i32> {
+        match cmd {
+            IOCTL_FLUSH_CACHES => {
+                self.flush_caches();
+                Ok(())
             }
-            Err(e) => {
-                error!("create: failed to create file: {}", e);
-                reply.error(EIO);
+            IOCTL_FLUSH_DIR => self.flush_dir_cache(ino),
+            IOCTL_RECONNECT => {
+                let mut conn = self.ftp_conn.lock().unwrap();
+                self.reconnect(&mut conn).map_err(|e| {
+                    error!("ioctl: reconnect failed: {}", e);
+                    EIO
+                })
             }
+            _ => Err(ENOTTY),
         }
     }
 
-    /// Eliminar archivo (invalida cachés)
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let name_str = name.to_string_lossy().to_string();
-        trace!("unlink called for parent={} name={}", parent, name_str);
-
-        // OPTIMIZACIÓN VS Code: Ignorar completamente archivos temporales
-        if is_temp_file(&name_str) {
-            trace!("unlink: ignoring temp file {}", name_str);
-            reply.ok();
-            return;
+    /// Subir todos los buffers de escritura pendientes (p. ej. antes de desmontar)
+    ///
+    /// Devuelve cuántos buffers se sincronizaron; los fallos se registran y no
+    /// detienen el resto.
+    pub fn flush_all(&self) -> usize {
+        let mut flushed = 0;
+        for fh in self.dirty_handles() {
+            match self.sync_write_buffer(fh) {
+                Ok(()) => flushed += 1,
+                Err(e) => error!("Failed to flush write buffer for fh {}: {}", fh, e),
+            }
         }
+        flushed
+    }
 
-        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
-            Some(inode) => inode.clone(),
-            None => {
-                error!("unlink: parent inode {} not found", parent);
-                reply.error(ENOENT);
-                return;
+    /// Handles abiertos con el buffer de escritura sucio
+    fn dirty_handles(&self) -> Vec<u64> {
+        self.open_files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, handle)| {
+                handle
+                    .write_buffer
+                    .as_ref()
+                    .is_some_and(|buffer| buffer.lock().unwrap().dirty)
+            })
+            .map(|(&fh, _)| fh)
+            .collect()
+    }
+
+    /// Cerrar el filesystem de forma ordenada: subir los buffers de escritura
+    /// sucios y las subidas encoladas sin conexión, detener los hilos de
+    /// fondo, cerrar la sesión con QUIT y vaciar las cachés
+    ///
+    /// Las colas de prefetch y read-ahead se cierran y se espera a sus hilos
+    /// (y a la descarga que tengan en curso), y el servidor de métricas deja
+    /// de escuchar. Los hilos que duermen entre rondas (sondeo, keepalive,
+    /// flusher) salen al despertar y ver `shut_down`.
+    ///
+    /// El binario la llama al desmontar (en `destroy`), y el `Drop` del handle
+    /// que devolvió `with_options` también, para quien use la biblioteca sin
+    /// llamarla. Solo la primera llamada tiene efecto. Si algún buffer no se
+    /// pudo subir devuelve error, pero la sesión se cierra igualmente.
+    pub fn shutdown(&self) -> Result<()> {
+        if self.shut_down.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.dir_prefetch.close();
+        self.read_ahead.close();
+        self.file_prefetch.close();
+        #[cfg(feature = "metrics")]
+        {
+            let server = self.metrics_server.lock().unwrap().take();
+            if let Some(server) = server {
+                server.stop();
             }
-        };
+        }
+        let flushed = self.flush_all();
+        let unflushed = self.dirty_handles().len();
+        self.replay_pending_uploads();
+        info!(
+            "Shutting down: flushed {} dirty write buffer(s), closing the FTP session",
+            flushed
+        );
 
-        let ftp_path = if parent_inode.ftp_path == "/" {
-            format!("/{}", name_str)
-        } else {
-            format!("{}/{}", parent_inode.ftp_path, name_str)
-        };
+        let quit = self.ftp_conn.lock().unwrap().quit();
+        self.flush_caches();
 
-        // Eliminar de cachés
-        if let Some(&ino) = self.path_to_inode.lock().unwrap().get(&ftp_path) {
-            self.inodes.lock().unwrap().remove(&ino);
-            self.read_cache.lock().unwrap().remove(&ino);
-            self.attr_cache.lock().unwrap().remove(&ino);
+        if unflushed > 0 {
+            return Err(anyhow::anyhow!(
+                "{} dirty write buffer(s) could not be uploaded",
+                unflushed
+            ));
         }
-        self.path_to_inode.lock().unwrap().remove(&ftp_path);
-        self.invalidate_dir_cache(&parent_inode.ftp_path);
+        quit.context("Failed to close the FTP session")
+    }
 
-        // Verificar si el archivo existe antes de intentar borrarlo
-        let exists = {
-            let mut conn = self.ftp_conn.lock().unwrap();
-            conn.exists(&ftp_path).unwrap_or(false)
-        };
+    /// Buffer de escritura a modificar para `fh`, o el pendiente del inodo
+    fn write_buffer_for(&self, fh: Option<u64>, ino: u64) -> Option<Arc<Mutex<WriteBuffer>>> {
+        fh.and_then(|fh| {
+            self.open_files
+                .lock()
+                .unwrap()
+                .get(&fh)
+                .and_then(|handle| handle.write_buffer.clone())
+        })
+        .or_else(|| self.dirty_write_buffer(ino))
+        .or_else(|| self.shadow_buffer(ino))
+    }
 
-        if !exists {
-            trace!("unlink: file does not exist: {}", ftp_path);
-            reply.ok();
-            return;
+    /// Buffer de escritura de un archivo que se abre para escribir
+    ///
+    /// Sin O_TRUNC el buffer empieza con el contenido del servidor, para que
+    /// escribir en un offset modifique el archivo en vez de subir ceros en
+    /// lugar de lo que había antes. Por encima de [`WRITE_PRELOAD_MAX`] no
+    /// se descarga: las escrituras van directas al servidor en su offset.
+    fn open_write_buffer(&self, ino: u64, truncate: bool) -> Result<WriteBuffer> {
+        let mut buffer = WriteBuffer::new();
+        let inode = match self.inodes.lock().unwrap().get(&ino) {
+            Some(inode) => inode.clone(),
+            None => return Ok(buffer),
+        };
+        if inode.attr.kind != FileType::RegularFile
+            || self.pending_creates.lock().unwrap().contains_key(&ino)
+        {
+            return Ok(buffer);
         }
 
-        // Eliminar de FTP
-        let mut conn = self.ftp_conn.lock().unwrap();
-        match conn.delete(&ftp_path) {
-            Ok(_) => {
-                reply.ok();
+        // El truncado se sube al cerrar aunque no se escriba nada
+        if truncate {
+            buffer.dirty = true;
+            self.evict_file_data(ino);
+            if let Some(inode) = self.inodes.lock().unwrap().get_mut(&ino) {
+                inode.attr.size = 0;
+                inode.attr.blocks = 0;
+                self.update_attr_cache(ino, inode.attr);
             }
-            Err(e) => {
-                error!("unlink: failed to delete file: {}", e);
-                reply.error(EIO);
+            return Ok(buffer);
+        }
+
+        let size = self.remote_size(&inode.ftp_path).unwrap_or(inode.attr.size);
+        if size == 0 {
+            return Ok(buffer);
+        }
+        if size > WRITE_PRELOAD_MAX {
+            debug!(
+                "{} is {} bytes, writing to it in place on the server",
+                inode.ftp_path, size
+            );
+            buffer.direct = true;
+            // Las escrituras miden contra este tamaño lo que crece el archivo
+            if let Some(inode) = self.inodes.lock().unwrap().get_mut(&ino) {
+                inode.attr.size = size;
+                inode.attr.blocks = blocks_for(size);
             }
+            return Ok(buffer);
         }
-    }
 
-    /// Crear directorio (invalida caché)
-    fn mkdir(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        _umask: u32,
-        reply: ReplyEntry,
-    ) {
-        let name_str = name.to_string_lossy().to_string();
+        let data = self.load_file_data(ino, &inode.ftp_path)?;
+        buffer.write_at(0, &data)?;
+        // Coincide con el servidor: no hay nada que subir todavía
+        buffer.dirty = false;
         trace!(
-            "mkdir called for parent={} name={} mode={}",
-            parent,
-            name_str,
-            mode
+            "Loaded {} bytes of {} for writing",
+            data.len(),
+            inode.ftp_path
         );
+        Ok(buffer)
+    }
 
-        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
-            Some(inode) => inode.clone(),
-            None => {
-                error!("mkdir: parent inode {} not found", parent);
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let ftp_path = if parent_inode.ftp_path == "/" {
-            format!("/{}", name_str)
-        } else {
-            format!("{}/{}", parent_inode.ftp_path, name_str)
-        };
-
-        // Crear directorio en FTP
-        let mut conn = self.ftp_conn.lock().unwrap();
-        match conn.mkdir(&ftp_path) {
-            Ok(_) => {
-                drop(conn); // Liberar lock
+    /// Tamaño actual de un archivo abierto para `lseek`
+    ///
+    /// Los datos pendientes mandan; si no, la caché de atributos, y si ha
+    /// caducado se refresca como en `getattr`.
+    fn file_size(&self, ino: u64, fh: u64) -> Result<u64> {
+        if ino == STATS_INODE {
+            let snapshot = self.stats_snapshots.lock().unwrap().get(&fh).cloned();
+            return Ok(snapshot.map_or_else(|| self.stats_json().len(), |data| data.len()) as u64);
+        }
+        if let Some(write_buffer) = self.write_buffer_for(Some(fh), ino) {
+            return Ok(write_buffer.lock().unwrap().len());
+        }
+        if let Some(attr) = self.get_attr_cached(ino) {
+            return Ok(attr.size);
+        }
 
-                // Invalidar caché
-                self.invalidate_dir_cache(&parent_inode.ftp_path);
+        let inode = self
+            .inodes
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .cloned()
+            .ok_or(FtpError::NotFound)?;
+        let size = self.remote_size(&inode.ftp_path)?;
+        let mut attr = inode.attr;
+        attr.size = size;
+        attr.blocks = blocks_for(size);
+        self.update_attr_cache(ino, attr);
+        Ok(size)
+    }
 
-                // Crear inodo para el nuevo directorio
-                let file_info = FtpFileInfo {
-                    name: name_str,
-                    path: ftp_path,
-                    size: 0,
-                    is_dir: true,
-                    permissions: (mode & 0o777) as u32,
-                    modified_time: Some(SystemTime::now()),
-                };
+    /// Volver a pedir el tamaño de un archivo con un solo SIZE, sin listar
+    ///
+    /// Si cambió, los datos cacheados ya no sirven: se descartan para que la
+    /// próxima lectura vea el final nuevo.
+    fn refresh_size(&self, ino: u64) -> Result<u64> {
+        let inode = self
+            .inodes
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .cloned()
+            .ok_or(FtpError::NotFound)?;
+        let size = self.ftp_conn.lock().unwrap().size(&inode.ftp_path)?;
+        self.size_cache
+            .lock()
+            .unwrap()
+            .insert(&inode.ftp_path, size);
+
+        let mut attr = self.get_attr_cached(ino).unwrap_or(inode.attr);
+        if attr.size != size {
+            debug!(
+                "{} changed size: {} -> {} bytes",
+                inode.ftp_path, attr.size, size
+            );
+            self.set_cached_size(ino, size);
+            self.evict_file_data(ino);
+        }
+        attr.size = size;
+        attr.blocks = blocks_for(size);
+        self.update_attr_cache(ino, attr);
+        Ok(size)
+    }
 
-                let inode = self.get_or_create_inode(parent, &file_info);
-                reply.entry(&TTL, &inode.attr, 0);
+    /// Atributos de un archivo abierto sin escrituras pendientes, con el
+    /// tamaño comprobado como mucho cada `OPEN_SIZE_TTL` (o `attr_ttl` si es
+    /// menor)
+    fn open_file_attr(&self, ino: u64) -> Option<FileAttr> {
+        if self.open_count(ino) == 0 || self.dirty_write_buffer(ino).is_some() {
+            return None;
+        }
+        let inode = self.inodes.lock().unwrap().get(&ino)?.clone();
+        // SIZE no sirve para los archivos ASCII: no mide lo que se descarga
+        if inode.attr.kind != FileType::RegularFile || self.is_ascii(&inode.ftp_path) {
+            return None;
+        }
+        let attr = inode.attr;
+
+        let due = {
+            let mut checked = self.size_checked.lock().unwrap();
+            match checked.get(&ino) {
+                Some(at) if at.elapsed() < OPEN_SIZE_TTL.min(self.options.attr_ttl) => false,
+                _ => {
+                    checked.insert(ino, Instant::now());
+                    true
+                }
             }
-            Err(e) => {
-                error!("mkdir: failed to create directory: {}", e);
-                reply.error(EIO);
+        };
+        if due {
+            if let Err(e) = self.refresh_size(ino) {
+                debug!("Failed to refresh size of inode {}: {:#}", ino, e);
             }
         }
+        Some(self.get_attr_cached(ino).unwrap_or(attr))
     }
 
-    /// Eliminar directorio (invalida caché)
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let name_str = name.to_string_lossy().to_string();
-        trace!("rmdir called for parent={} name={}", parent, name_str);
+    /// Actualizar el tamaño en el inodo y en la caché de atributos
+    fn set_cached_size(&self, ino: u64, size: u64) {
+        if let Some(inode) = self.inodes.lock().unwrap().get_mut(&ino) {
+            inode.attr.size = size;
+            inode.attr.blocks = blocks_for(size);
+        }
+        if let Some(entry) = self.attr_cache.lock().unwrap().get_mut(&ino) {
+            entry.attr.size = size;
+            entry.attr.blocks = blocks_for(size);
+        }
+    }
 
-        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
-            Some(inode) => inode.clone(),
-            None => {
-                error!("rmdir: parent inode {} not found", parent);
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    /// Buscar un buffer de escritura con datos pendientes para el inodo
+    fn dirty_write_buffer(&self, ino: u64) -> Option<Arc<Mutex<WriteBuffer>>> {
+        self.open_files
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|handle| handle.ino == ino)
+            .filter_map(|handle| handle.write_buffer.clone())
+            .find(|buffer| buffer.lock().unwrap().dirty)
+    }
 
-        let ftp_path = if parent_inode.ftp_path == "/" {
-            format!("/{}", name_str)
-        } else {
-            format!("{}/{}", parent_inode.ftp_path, name_str)
+    /// Guardar el contenido de un archivo en `read_cache`, descartando el de
+    /// otros archivos mientras la caché pase de `--cache-size`
+    fn cache_file_data(&self, ino: u64, data: Vec<u8>) {
+        let mut cache = self.read_cache.lock().unwrap();
+        cache.insert(ino, data);
+        let Some(limit) = self.options.cache_size else {
+            return;
         };
+        let mut cached: u64 = cache.values().map(|data| data.len() as u64).sum();
+        while cached > limit {
+            let Some(&victim) = cache.keys().find(|&&other| other != ino) else {
+                break;
+            };
+            if let Some(data) = cache.remove(&victim) {
+                cached -= data.len() as u64;
+                Stats::add(&self.stats.read_cache_evictions, 1);
+                debug!(event = "cache_evict", ino = victim; "Evicted cached data of inode {}", victim);
+            }
+        }
+    }
 
-        // Eliminar de cachés
-        if let Some(&ino) = self.path_to_inode.lock().unwrap().get(&ftp_path) {
-            self.inodes.lock().unwrap().remove(&ino);
-            self.attr_cache.lock().unwrap().remove(&ino);
-            self.dir_cache.lock().unwrap().remove(&ftp_path);
+    /// Descartar los datos cacheados de un archivo (completo y read-ahead)
+    fn evict_file_data(&self, ino: u64) {
+        if self.read_cache.lock().unwrap().remove(&ino).is_some() {
+            Stats::add(&self.stats.read_cache_evictions, 1);
+            debug!(event = "cache_evict", ino = ino; "Evicted cached data of inode {}", ino);
         }
-        self.path_to_inode.lock().unwrap().remove(&ftp_path);
-        self.invalidate_dir_cache(&parent_inode.ftp_path);
+        self.ahead_cache.lock().unwrap().remove(&ino);
+    }
 
-        // Eliminar directorio de FTP
-        let mut conn = self.ftp_conn.lock().unwrap();
-        match conn.rmdir(&ftp_path) {
-            Ok(_) => {
-                reply.ok();
-            }
-            Err(e) => {
-                error!("rmdir: failed to remove directory: {}", e);
-                reply.error(EIO);
+    /// Registrar una lectura en el handle y decidir si el acceso es secuencial
+    ///
+    /// Es secuencial si empieza donde terminó la lectura anterior del mismo
+    /// handle. La primera lectura no lo es, aunque empiece en 0: un solo
+    /// `read` no dice aún cómo se recorre el archivo.
+    fn track_sequential_read(&self, fh: u64, offset: u64, size: u32) -> bool {
+        match self.open_files.lock().unwrap().get_mut(&fh) {
+            Some(handle) => {
+                let sequential = handle.next_read > 0 && handle.next_read == offset;
+                handle.next_read = offset.saturating_add(size as u64);
+                sequential
             }
+            None => false,
         }
     }
 
-    /// Renombrar archivo o directorio (invalida cachés)
-    fn rename(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        newparent: u64,
-        newname: &OsStr,
-        _flags: u32,
-        reply: ReplyEmpty,
-    ) {
-        let name_str = name.to_string_lossy().to_string();
-        let newname_str = newname.to_string_lossy().to_string();
-        trace!(
-            "rename called: parent={} name={} newparent={} newname={}",
-            parent,
-            name_str,
-            newparent,
-            newname_str
-        );
+    /// Obtener una ventana de read-ahead, descargándola si no está en caché
+    fn fetch_chunk(&self, ino: u64, ftp_path: &str, index: u64) -> Result<Arc<Vec<u8>>> {
+        let cached = |fs: &Self| {
+            fs.ahead_cache
+                .lock()
+                .unwrap()
+                .get(&ino)
+                .and_then(|chunks| chunks.get(&index).cloned())
+        };
+        if let Some(chunk) = cached(self) {
+            return Ok(chunk);
+        }
 
-        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
-            Some(inode) => inode.clone(),
-            None => {
-                error!("rename: parent inode {} not found", parent);
-                reply.error(ENOENT);
-                return;
+        let mut conn = self.ftp_conn.lock().unwrap();
+        // El hilo de read-ahead puede haberla descargado mientras esperábamos la conexión
+        if let Some(chunk) = cached(self) {
+            return Ok(chunk);
+        }
+
+        let window = self.read_window();
+        let offset = index * window as u64;
+        trace!(
+            "Fetching read-ahead window {} of {} ({} bytes at {})",
+            index,
+            ftp_path,
+            window,
+            offset
+        );
+        let started = Instant::now();
+        let mut attempt = 0;
+        let data = loop {
+            match conn.retrieve_range(ftp_path, offset, window) {
+                Ok(data) => break data,
+                Err(e) if attempt < self.options.retries && e.is_transient() => {
+                    attempt += 1;
+                    warn!("Ranged download of {} interrupted: {}", ftp_path, e);
+                    if let Err(e) = self.reconnect(&mut conn) {
+                        warn!("Reconnect failed: {}", e);
+                    }
+                }
+                Err(e) => {
+                    return Err(
+                        anyhow::Error::new(e).context("Failed to retrieve file range from FTP")
+                    )
+                }
             }
         };
+        drop(conn);
+        self.stats.retrieve_latency.observe(started.elapsed());
+        Stats::add(&self.stats.bytes_down, data.len() as u64);
 
-        let newparent_inode = match self.inodes.lock().unwrap().get(&newparent) {
-            Some(inode) => inode.clone(),
-            None => {
-                error!("rename: newparent inode {} not found", newparent);
-                reply.error(ENOENT);
-                return;
+        let chunk = Arc::new(data);
+        self.ahead_cache
+            .lock()
+            .unwrap()
+            .entry(ino)
+            .or_default()
+            .insert(index, Arc::clone(&chunk));
+        Ok(chunk)
+    }
+
+    /// Leer `size` bytes en `offset` usando ventanas de read-ahead
+    ///
+    /// Devuelve los datos y si se llegó al final del archivo.
+    fn read_chunked(
+        &self,
+        ino: u64,
+        ftp_path: &str,
+        offset: u64,
+        size: usize,
+    ) -> Result<(Vec<u8>, bool)> {
+        let window = self.read_window() as u64;
+        let first = offset / window;
+        let mut data = Vec::with_capacity(size);
+        let mut index = first;
+        let eof = loop {
+            let chunk = self.fetch_chunk(ino, ftp_path, index)?;
+            let start = (offset.max(index * window) - index * window) as usize;
+            if start >= chunk.len() {
+                break true;
+            }
+            let end = chunk.len().min(start + size - data.len());
+            data.extend_from_slice(&chunk[start..end]);
+            if (chunk.len() as u64) < window {
+                break end == chunk.len();
             }
+            if data.len() == size {
+                break false;
+            }
+            index += 1;
         };
 
-        let old_path = if parent_inode.ftp_path == "/" {
-            format!("/{}", name_str)
-        } else {
-            format!("{}/{}", parent_inode.ftp_path, name_str)
-        };
+        // Las ventanas ya consumidas no se volverán a leer en acceso secuencial
+        if let Some(chunks) = self.ahead_cache.lock().unwrap().get_mut(&ino) {
+            chunks.retain(|&i, _| i >= first);
+        }
 
-        let new_path = if newparent_inode.ftp_path == "/" {
-            format!("/{}", newname_str)
-        } else {
-            format!("{}/{}", newparent_inode.ftp_path, newname_str)
+        Ok((data, eof))
+    }
+
+    /// Si una lectura en `start` se sirve por ventanas en lugar de
+    /// descargar el archivo entero
+    ///
+    /// Van por ventanas las lecturas secuenciales y la primera desde 0 de un
+    /// archivo mayor que una ventana; uno más pequeño se baja con un solo
+    /// RETR. Los que superan `--max-file-size` van siempre por ventanas.
+    fn read_windowed(&self, inode: &Inode, start: u64, sequential: bool) -> bool {
+        // Lo que el prefetch de `open` está descargando entero se espera
+        let fully_cached = self.read_cache.lock().unwrap().contains_key(&inode.ino)
+            || self.file_prefetching.lock().unwrap().contains(&inode.ino);
+        // El espejo guarda archivos completos, así que con él no hay ventanas
+        let mirrored = self.options.mirror.is_some();
+        let known_size = self
+            .get_attr_cached(inode.ino)
+            .map_or(inode.attr.size, |attr| attr.size);
+        let oversized = self.oversized(known_size);
+        let large = start == 0 && known_size > self.read_window() as u64;
+        let windowed = self.options.read_ahead > 0 && (sequential || large) && !mirrored;
+        // En ASCII los offsets no coinciden con el archivo: siempre completo
+        let ascii = self.is_ascii(&inode.ftp_path);
+        (windowed || oversized) && !fully_cached && !ascii
+    }
+
+    /// Tamaño de las ventanas de lectura
+    ///
+    /// Con `--read-ahead 0` los archivos grandes se siguen leyendo por
+    /// ventanas, del tamaño por defecto.
+    fn read_window(&self) -> usize {
+        match self.options.read_ahead {
+            0 => DEFAULT_READ_AHEAD,
+            window => window,
+        }
+    }
+
+    /// Espacio libre en el servidor según AVBL (None si no lo dice)
+    ///
+    /// Se pregunta como mucho cada `FREE_SPACE_TTL`; entre medias se
+    /// descuenta lo que reservan las escrituras con `claim_space`.
+    fn server_free_space(&self) -> Option<u64> {
+        let cached = *self.avbl.lock().unwrap();
+        if let Some((bytes, checked)) = cached {
+            if checked.elapsed() < FREE_SPACE_TTL {
+                return bytes;
+            }
+        }
+
+        let root = self.inodes.lock().unwrap()[&ROOT_INODE].ftp_path.clone();
+        let result = self.ftp_conn.lock().unwrap().available_space(&root);
+        let bytes = match result {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                debug!("Free space of {} is unknown: {}", root, e);
+                None
+            }
         };
+        *self.avbl.lock().unwrap() = Some((bytes, Instant::now()));
+        bytes
+    }
 
-        // Actualizar caché de inodos
-        if let Some(&ino) = self.path_to_inode.lock().unwrap().get(&old_path) {
-            if let Some(inode) = self.inodes.lock().unwrap().get_mut(&ino) {
-                inode.ftp_path = new_path.clone();
-                inode.name = newname_str.clone();
-                inode.parent = newparent;
+    /// Apartar `bytes` del espacio libre para una escritura, o ENOSPC si
+    /// ocuparían parte de `--quota-reserve`
+    ///
+    /// Sin `--quota-reserve` o sin AVBL no se comprueba nada: el servidor
+    /// responderá 552 a la subida si se queda sin espacio.
+    fn claim_space(&self, bytes: u64) -> Result<(), i32> {
+        let Some(reserve) = self.options.quota_reserve else {
+            return Ok(());
+        };
+        if bytes == 0 {
+            return Ok(());
+        }
+        match self.server_free_space() {
+            Some(free) if bytes > free.saturating_sub(reserve) => Err(ENOSPC),
+            Some(_) => {
+                if let Some((Some(free), _)) = self.avbl.lock().unwrap().as_mut() {
+                    *free = free.saturating_sub(bytes);
+                }
+                Ok(())
             }
-            self.path_to_inode.lock().unwrap().remove(&old_path);
-            self.path_to_inode
-                .lock()
-                .unwrap()
-                .insert(new_path.clone(), ino);
+            None => Ok(()),
         }
+    }
 
-        // Invalidar cachés de directorios afectados
-        self.invalidate_dir_cache(&parent_inode.ftp_path);
-        if parent_inode.ftp_path != newparent_inode.ftp_path {
-            self.invalidate_dir_cache(&newparent_inode.ftp_path);
+    /// Aplicar una escritura de `data` en `start` al buffer de un archivo
+    /// abierto y, con `--write-through` o sin el contenido cargado, subirla
+    /// en su offset al momento
+    fn write_buffered(
+        &self,
+        inode: &Inode,
+        write_buffer: &Mutex<WriteBuffer>,
+        start: u64,
+        data: &[u8],
+    ) -> Result<(), i32> {
+        let ino = inode.ino;
+        let end = start + data.len() as u64;
+        let mut write_buffer = write_buffer.lock().unwrap();
+        // Sin el contenido cargado, el tamaño conocido es el del inodo
+        let current = if write_buffer.direct {
+            inode.attr.size
+        } else {
+            write_buffer.len()
+        };
+        // Solo lo que crece el archivo ocupa espacio nuevo en el servidor
+        if let Err(errno) = self.claim_space(end.saturating_sub(current)) {
+            debug!("write: {} would eat into --quota-reserve", inode.ftp_path);
+            return Err(errno);
+        }
+        // Las escrituras en su sitio van solo al servidor: copiarlas a un
+        // buffer vacío lo rellenaría de ceros hasta `start`
+        if !write_buffer.direct {
+            if let Err(e) = write_buffer.write_at(start, data) {
+                error!("write: failed to buffer data: {}", e);
+                return Err(EIO);
+            }
         }
 
-        // Renombrar en FTP
-        let mut conn = self.ftp_conn.lock().unwrap();
-        match conn.rename(&old_path, &new_path) {
-            Ok(_) => {
-                reply.ok();
+        // Los lectores consultan el buffer mientras esté sucio
+        self.evict_file_data(ino);
+
+        // Modo write-through: subir el fragmento en su offset inmediatamente
+        let local_only = self.shadow_files.lock().unwrap().contains_key(&ino);
+        if (self.options.write_through || write_buffer.direct) && !local_only {
+            let mut conn = self.ftp_conn.lock().unwrap();
+            if let Err(e) = conn.store_at(&inode.ftp_path, start, data) {
+                error!("write: write-through upload failed: {}", e);
+                return Err(errno_for(&e.into()));
             }
-            Err(e) => {
-                error!("rename: failed to rename: {}", e);
-                reply.error(EIO);
+            drop(conn);
+            Stats::add(&self.stats.bytes_up, data.len() as u64);
+
+            let len = if write_buffer.direct {
+                let len = current.max(end);
+                if let Some(inode) = self.inodes.lock().unwrap().get_mut(&ino) {
+                    inode.attr.size = len;
+                    inode.attr.blocks = blocks_for(len);
+                }
+                len
+            } else {
+                write_buffer.dirty = false;
+                write_buffer.len()
+            };
+            if let Some(entry) = self.attr_cache.lock().unwrap().get_mut(&ino) {
+                entry.attr.size = entry.attr.size.max(len);
+                entry.attr.blocks = blocks_for(entry.attr.size);
             }
         }
+
+        trace!(
+            "Write buffered: {} bytes at offset {} (total: {})",
+            data.len(),
+            start,
+            write_buffer.len()
+        );
+        Ok(())
     }
 
-    /// Establecer atributos de archivo (simplificado)
-    fn setattr(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        trace!("setattr called for inode {}", ino);
+    /// Clave de `path` en `path_to_inode`, `dir_cache` y `negative_cache`
+    ///
+    /// Con `--case-insensitive` es la ruta en minúsculas, para que las
+    /// variantes de un mismo nombre den con el mismo inodo.
+    fn path_key<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if self.options.case_insensitive {
+            Cow::Owned(path.to_lowercase())
+        } else {
+            Cow::Borrowed(path)
+        }
+    }
 
-        let mut inodes = self.inodes.lock().unwrap();
+    /// Borrar la entrada `name` de `parent` (cuerpo de `unlink`)
+    fn unlink_entry(&self, parent: u64, name: &OsStr) -> Result<(), i32> {
+        self.check_writable()?;
 
-        if let Some(inode) = inodes.get_mut(&ino) {
-            if let Some(mode) = mode {
-                inode.attr.perm = mode as u16;
-            }
-            if let Some(uid) = uid {
-                inode.attr.uid = uid;
+        let name_str = utf8_name(name)?.to_string();
+        trace!("unlink called for parent={} name={}", parent, name_str);
+
+        // Ignorar completamente archivos temporales (los locales se descartan)
+        if self.options.ignore.matches(&name_str) {
+            trace!("unlink: ignoring temp file {}", name_str);
+            let shadow = self
+                .shadow_entries(parent)
+                .into_iter()
+                .find(|(_, name)| *name == name_str);
+            if let Some((ino, _)) = shadow {
+                self.remove_shadow(ino);
             }
-            if let Some(gid) = gid {
-                inode.attr.gid = gid;
+            return Ok(());
+        }
+
+        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("unlink: parent inode {} not found", parent);
+                return Err(ENOENT);
             }
-            if let Some(size) = size {
-                inode.attr.size = size;
+        };
+
+        let ftp_path = join_ftp_path(&parent_inode.ftp_path, &name_str);
+
+        self.remove_file(&parent_inode.ftp_path, &ftp_path)
+            .map_err(|e| {
+                error!("unlink: failed to delete file: {:#}", e);
+                EIO
+            })
+    }
+
+    /// Atributos de la entrada `name` de `parent` y el TTL con que se
+    /// entregan al kernel (cuerpo de `lookup`)
+    fn lookup_entry(&self, parent: u64, name: &OsStr) -> Result<(Duration, FileAttr), i32> {
+        let name_str = utf8_name(name)?.to_string();
+        trace!("lookup called for parent={}, name={}", parent, name_str);
+
+        if parent == ROOT_INODE && name_str == STATS_FILE_NAME {
+            let attr = self.stats_attr(self.stats_json().len());
+            return Ok((Duration::ZERO, attr));
+        }
+
+        // Ignorar archivos temporales inmediatamente (salvo los locales)
+        if self.options.ignore.matches(&name_str) {
+            trace!("lookup: ignoring temp file {}", name_str);
+            let shadow = self
+                .shadow_entries(parent)
+                .into_iter()
+                .find(|(_, name)| *name == name_str)
+                .and_then(|(ino, _)| self.shadow_attr(ino));
+            return shadow
+                .map(|attr| (self.options.entry_ttl, attr))
+                .ok_or(ENOENT);
+        }
+
+        // Obtener inodo padre
+        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("lookup: parent inode {} not found", parent);
+                return Err(ENOENT);
             }
+        };
 
-            // Actualizar caché de atributos
-            self.update_attr_cache(ino, inode.attr);
-            reply.attr(&TTL, &inode.attr);
-        } else {
-            error!("setattr: inode {} not found", ino);
-            reply.error(ENOENT);
+        // Entradas especiales: nunca se consultan al servidor
+        if name_str == "." || name_str == ".." {
+            return self
+                .dot_entry_attr(&parent_inode, &name_str)
+                .map(|attr| (self.options.entry_ttl, attr))
+                .ok_or(ENOENT);
         }
-    }
 
-    /// Liberar handle de archivo (sincroniza write buffer y limpia caché)
-    fn release(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: ReplyEmpty,
-    ) {
-        trace!("release called for inode {} fh {}", ino, fh);
+        // Construir ruta FTP
+        let ftp_path = join_ftp_path(&parent_inode.ftp_path, &name_str);
 
-        // Sincronizar write buffer si existe y está dirty
-        match self.sync_write_buffer(fh) {
-            Ok(_) => {
-                // Remover file handle de archivos abiertos
-                self.open_files.lock().unwrap().remove(&fh);
+        // Verificar caché de inodo primero
+        let key = self.path_key(&ftp_path);
+        let known = self
+            .path_to_inode
+            .lock()
+            .unwrap()
+            .get(key.as_ref())
+            .copied();
+        if let Some(ino) = known {
+            if let Some(attr) = self.get_attr_cached(ino) {
+                return Ok((self.options.entry_ttl, attr));
+            }
+            // Recién creado y sin subir: el servidor aún no lo conoce
+            if self.pending_creates.lock().unwrap().contains_key(&ino) {
+                if let Some(inode) = self.inodes.lock().unwrap().get(&ino) {
+                    return Ok((self.options.entry_ttl, inode.attr));
+                }
+            }
+        }
 
-                // Limpiar caché de lectura para ahorrar memoria
-                // (pero solo si no hay otros handles abiertos para este archivo)
-                let has_other_handles = self
-                    .open_files
-                    .lock()
-                    .unwrap()
-                    .values()
-                    .any(|handle| handle.ino == ino);
-                if !has_other_handles {
-                    self.read_cache.lock().unwrap().remove(&ino);
+        // Nombres que se acaban de buscar sin éxito (.git, .editorconfig...)
+        if self.negative_cache.lock().unwrap().get(&key).is_some() {
+            trace!("lookup: {} is known not to exist", ftp_path);
+            return Err(ENOENT);
+        }
+
+        // Verificar caché de directorio primero (evita consulta FTP individual)
+        let mut listed = false;
+        match self.list_ftp_directory_cached(&parent_inode.ftp_path) {
+            Ok(files) => {
+                if let Some(file_info) = self.listed_entry(&files, &name_str) {
+                    let inode = self.get_or_create_inode(parent, file_info);
+                    return Ok((self.options.entry_ttl, inode.attr));
+                }
+                listed = true;
+            }
+            Err(e) => {
+                debug!("lookup: failed to list parent directory: {:#}", e);
+                // Si el padre ya no existe o no es un directorio, no hay nada que buscar
+                let errno = errno_for(&e);
+                if errno != EIO {
+                    return Err(errno);
                 }
+            }
+        }
 
-                trace!("File handle {} released successfully", fh);
-                reply.ok();
+        // Fallback: consulta directa al FTP
+        match self.get_ftp_file_info(&ftp_path) {
+            Ok(file_info) => {
+                let inode = self.get_or_create_inode(parent, &file_info);
+                Ok((self.options.entry_ttl, inode.attr))
             }
             Err(e) => {
-                error!("release: failed to sync write buffer: {}", e);
-                reply.error(EIO);
+                // Con el servidor respondiendo, ni el listado ni la consulta lo vieron
+                let transient = e
+                    .downcast_ref::<FtpError>()
+                    .is_some_and(FtpError::is_transient);
+                if listed && !transient {
+                    self.remember_missing(&ftp_path);
+                }
+                Err(ENOENT)
             }
         }
     }
 
-    /// Sincronizar archivo (fuerza sync del write buffer)
-    fn fsync(&mut self, _req: &Request, _ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        trace!("fsync called for fh {}", fh);
+    /// Entrada `name` de un listado; con `--case-insensitive`, si no hay una
+    /// con esos bytes exactos, la que coincida sin distinguir mayúsculas
+    fn listed_entry<'a>(&self, files: &'a [FtpFileInfo], name: &str) -> Option<&'a FtpFileInfo> {
+        if let Some(entry) = find_entry(files, name) {
+            return Some(entry);
+        }
+        if !self.options.case_insensitive {
+            return None;
+        }
+        let name = name.to_lowercase();
+        files.iter().find(|f| f.name.to_lowercase() == name)
+    }
 
-        match self.sync_write_buffer(fh) {
-            Ok(_) => reply.ok(),
-            Err(e) => {
-                error!("fsync: failed to sync: {}", e);
-                reply.error(EIO);
-            }
+    /// Si `ftp_path` se transfiere en modo ASCII (`--ascii-ext`)
+    fn is_ascii(&self, ftp_path: &str) -> bool {
+        is_ascii_path(&self.ascii_extensions, ftp_path)
+    }
+
+    /// Tamaño de un archivo ASCII ya descargado (None si no es ASCII o no
+    /// está en caché)
+    fn ascii_data_size(&self, ino: u64, ftp_path: &str) -> Option<u64> {
+        if !self.is_ascii(ftp_path) {
+            return None;
         }
+        self.read_cache
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .map(|data| data.len() as u64)
     }
 
-    /// Verificar permisos de acceso (siempre permite para simplificar)
-    fn access(&mut self, _req: &Request, _ino: u64, _mask: i32, reply: ReplyEmpty) {
-        trace!("access called");
-        reply.ok();
+    /// Si un archivo de `size` bytes supera `--max-file-size`
+    fn oversized(&self, size: u64) -> bool {
+        self.options.max_file_size.is_some_and(|max| size > max)
     }
 
-    /// Liberar datos pendientes (sincroniza write buffer)
-    fn flush(&mut self, _req: &Request, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        trace!("flush called for fh {}", fh);
+    /// Aplicar `--max-file-size` al abrir `ino`
+    ///
+    /// Uno demasiado grande se leerá por ventanas (se avisa una vez por
+    /// archivo). Uno ASCII no admite ventanas, porque sus offsets no
+    /// coinciden con el servidor: se rechaza con EFBIG en lugar de
+    /// descargarlo entero.
+    fn check_size_limit(&self, ino: u64) -> Result<(), i32> {
+        let Some(inode) = self.inodes.lock().unwrap().get(&ino).cloned() else {
+            return Ok(());
+        };
+        let size = self
+            .get_attr_cached(ino)
+            .map_or(inode.attr.size, |attr| attr.size);
+        if !self.oversized(size) {
+            return Ok(());
+        }
+        if self.is_ascii(&inode.ftp_path) {
+            warn!(
+                "{} is {} bytes, over --max-file-size, and ASCII files can't be read in ranges",
+                inode.ftp_path, size
+            );
+            return Err(libc::EFBIG);
+        }
+        if self.oversized_warned.lock().unwrap().insert(ino) {
+            warn!(
+                "{} is {} bytes, over --max-file-size: reading it in ranges instead of caching it whole",
+                inode.ftp_path, size
+            );
+        }
+        Ok(())
+    }
 
-        match self.sync_write_buffer(fh) {
-            Ok(_) => reply.ok(),
-            Err(e) => {
-                error!("flush: failed to sync: {}", e);
-                reply.error(EIO);
+    /// Encolar la descarga en segundo plano de la ventana que sigue a la
+    /// lectura terminada en `end`
+    ///
+    /// Si la cola está llena se descarta: la lectura la pedirá al llegar.
+    fn prefetch_after(&self, ino: u64, ftp_path: &str, end: u64) {
+        let index = end.div_ceil(self.read_window() as u64);
+        let already_cached = self
+            .ahead_cache
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .is_some_and(|chunks| chunks.contains_key(&index));
+        if already_cached || !self.prefetching.lock().unwrap().insert((ino, index)) {
+            return;
+        }
+
+        if let Err(e) = self.read_ahead.try_send((ino, ftp_path.to_string(), index)) {
+            if let TrySendError::Full(_) = e {
+                trace!("Read-ahead queue full, not prefetching window {}", index);
+            }
+            self.prefetching.lock().unwrap().remove(&(ino, index));
+        }
+    }
+
+    /// Cargar los datos de un archivo, de la caché o del servidor
+    fn load_file_data(&self, ino: u64, ftp_path: &str) -> Result<Vec<u8>> {
+        // Una ruta sin caché solo se guarda mientras esté abierta: cada
+        // apertura la descarga de nuevo, pero no cada `read`
+        let keep = !self.options.no_cache.matches(ftp_path) || self.open_count(ino) > 0;
+
+        // Verificar caché primero
+        if let Some(data) = self.read_cache.lock().unwrap().get(&ino).cloned() {
+            trace!("File data cache hit for inode {}", ino);
+            return Ok(data);
+        }
+
+        // Cargar desde FTP
+        trace!("Loading file data for inode {}", ino);
+        let mut conn = self.ftp_conn.lock().unwrap();
+        // El prefetch puede haberlo descargado mientras esperábamos la conexión
+        if let Some(data) = self.read_cache.lock().unwrap().get(&ino).cloned() {
+            trace!("File data cache hit for inode {}", ino);
+            return Ok(data);
+        }
+
+        // Una copia en disco descargada con el mismo MDTM sigue siendo válida
+        let mtime = match &self.options.mirror {
+            Some(mirror) => {
+                let mtime = conn.mdtm(ftp_path).ok();
+                if let Some(data) = mtime.and_then(|m| mirror.load_file(ftp_path, Some(m))) {
+                    trace!("File data for {} served from the local mirror", ftp_path);
+                    drop(conn);
+                    if keep {
+                        self.cache_file_data(ino, data.clone());
+                    }
+                    return Ok(data);
+                }
+                mtime
+            }
+            None => None,
+        };
+
+        let started = Instant::now();
+        let mut data = Vec::new();
+        let mut attempt = 0;
+        loop {
+            match conn.retrieve_into(ftp_path, &mut data) {
+                Ok(()) => break,
+                Err(e) if attempt < self.options.retries && e.is_transient() => {
+                    attempt += 1;
+                    warn!("Download of {} interrupted: {}", ftp_path, e);
+                    debug!(
+                        "Resuming download of {} at offset {} (attempt {}/{})",
+                        ftp_path,
+                        data.len(),
+                        attempt,
+                        self.options.retries
+                    );
+                    if let Err(e) = self.reconnect(&mut conn) {
+                        warn!("Reconnect failed: {}", e);
+                    }
+                }
+                Err(e) => {
+                    // Sin servidor, una copia posiblemente antigua es mejor que EIO
+                    let stale = self
+                        .options
+                        .mirror
+                        .as_ref()
+                        .and_then(|m| m.load_file(ftp_path, None));
+                    if let Some(data) = stale {
+                        warn!(
+                            "Download of {} failed ({}), serving the local mirror copy",
+                            ftp_path, e
+                        );
+                        return Ok(data);
+                    }
+                    return Err(anyhow::Error::new(e).context("Failed to retrieve file from FTP"));
+                }
+            }
+        }
+        drop(conn);
+        self.stats.retrieve_latency.observe(started.elapsed());
+        Stats::add(&self.stats.bytes_down, data.len() as u64);
+        debug!(
+            event = "retrieve", ino = ino, path = ftp_path, bytes = data.len() as u64;
+            "Retrieved {} ({} bytes)", ftp_path, data.len()
+        );
+
+        if let Some(mirror) = self
+            .options
+            .mirror
+            .as_ref()
+            .filter(|_| !self.options.offline)
+        {
+            if let Err(e) = mirror.store_file(ftp_path, mtime, &data) {
+                debug!("Failed to mirror {}: {}", ftp_path, e);
             }
         }
+
+        // En ASCII lo descargado no mide lo que dice el servidor: anunciar
+        // el tamaño real para que las lecturas no se corten
+        if self.is_ascii(ftp_path) {
+            self.set_cached_size(ino, data.len() as u64);
+        }
+
+        // Guardar en caché
+        if keep {
+            self.cache_file_data(ino, data.clone());
+        }
+
+        trace!("File data loaded: {} bytes", data.len());
+        Ok(data)
+    }
+}
+
+impl<B: FtpBackend> Drop for FtpFs<B> {
+    /// Los clones de los hilos de fondo no cierran nada al destruirse
+    fn drop(&mut self) {
+        // Con un pánico en curso los mutex pueden estar envenenados
+        if !self.owner || thread::panicking() {
+            return;
+        }
+        if let Err(e) = self.shutdown() {
+            warn!("Failed to shut down the FTP filesystem: {:#}", e);
+        }
+    }
+}
+
+impl<B: FtpBackend> Filesystem for FtpFs<B> {
+    /// Pedir al kernel que liste con `readdirplus` (sus entradas llevan los
+    /// atributos); si no lo admite, se sigue usando `readdir`
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), libc::c_int> {
+        let wanted = fuser::consts::FUSE_DO_READDIRPLUS | fuser::consts::FUSE_READDIRPLUS_AUTO;
+        if let Err(unsupported) = config.add_capabilities(wanted) {
+            debug!(
+                "Kernel does not support readdirplus (flags {:#x})",
+                unsupported
+            );
+        }
+        // Que open reciba O_TRUNC: así no se descarga un archivo que se va a
+        // vaciar (sin esto el kernel manda un setattr después de abrir)
+        if let Err(unsupported) = config.add_capabilities(fuser::consts::FUSE_ATOMIC_O_TRUNC) {
+            debug!(
+                "Kernel does not support atomic O_TRUNC (flags {:#x})",
+                unsupported
+            );
+        }
+        // Sin esto el kernel resuelve los bloqueos por su cuenta y nunca llama
+        // a getlk/setlk
+        if let Err(unsupported) = config.add_capabilities(fuser::consts::FUSE_POSIX_LOCKS) {
+            debug!(
+                "Kernel does not support POSIX locks (flags {:#x})",
+                unsupported
+            );
+        }
+        Ok(())
+    }
+
+    /// Al desmontar: subir lo pendiente y cerrar la sesión FTP
+    fn destroy(&mut self) {
+        if let Err(e) = self.shutdown() {
+            error!("Failed to shut down the FTP filesystem: {:#}", e);
+        }
+    }
+
+    /// Obtener atributos de archivo (optimizado con caché extendido)
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        trace!("getattr called for inode {}", ino);
+
+        // El contenido cambia con cada lectura: que el kernel no guarde el tamaño
+        if ino == STATS_INODE {
+            let attr = self.stats_attr(self.stats_json().len());
+            reply.attr(&Duration::ZERO, &attr);
+            return;
+        }
+
+        // Para root, siempre usar caché rápida
+        if ino == ROOT_INODE {
+            if let Some(attr) = self.get_attr_cached(ino) {
+                reply.attr(&self.kernel_attr_ttl(), &attr);
+                return;
+            }
+        }
+
+        // Los temporales locales nunca preguntan al servidor
+        if let Some(attr) = self.shadow_attr(ino) {
+            reply.attr(&self.kernel_attr_ttl(), &attr);
+            return;
+        }
+
+        // Un archivo abierto puede estar creciendo: su tamaño caduca antes
+        if let Some(attr) = self.open_file_attr(ino) {
+            reply.attr(&OPEN_SIZE_TTL.min(self.kernel_attr_ttl()), &attr);
+            return;
+        }
+
+        // Intentar obtener de caché primero
+        if let Some(attr) = self.get_attr_cached(ino) {
+            reply.attr(&self.kernel_attr_ttl(), &attr);
+            return;
+        }
+
+        // Si no está en caché, obtener del inodo (sin retener el lock durante la red)
+        let inode = self.inodes.lock().unwrap().get(&ino).cloned();
+        if let Some(inode) = inode {
+            // Para archivos regulares, actualizar tamaño ocasionalmente (no cada vez).
+            // Con escrituras pendientes el servidor aún tiene el tamaño viejo
+            if inode.attr.kind == FileType::RegularFile && self.dirty_write_buffer(ino).is_none() {
+                // Solo actualizar si no hay caché o ha pasado mucho tiempo
+                let should_update = {
+                    let cache = self.attr_cache.lock().unwrap();
+                    if let Some(entry) = cache.get(&ino) {
+                        entry.timestamp.elapsed() >= self.options.attr_ttl
+                    } else {
+                        true
+                    }
+                };
+
+                if should_update {
+                    // El tamaño suele venir del último listado; si no, un SIZE.
+                    // En ASCII manda lo descargado, que no mide lo mismo
+                    let size = match self.ascii_data_size(ino, &inode.ftp_path) {
+                        Some(size) => Ok(size),
+                        None => self.remote_size(&inode.ftp_path),
+                    };
+                    if let Ok(size) = size {
+                        // Estricto: también la fecha, para que rsync no se salte
+                        // archivos cambiados con el mismo tamaño
+                        if self.options.strict {
+                            let mtime = self.ftp_conn.lock().unwrap().mdtm(&inode.ftp_path).ok();
+                            if let Some(attr) = self.apply_remote_attr(ino, size, mtime) {
+                                reply.attr(&self.kernel_attr_ttl(), &attr);
+                                return;
+                            }
+                        }
+                        let mut updated_attr = inode.attr;
+                        updated_attr.size = size;
+                        updated_attr.blocks = blocks_for(size);
+                        self.update_attr_cache(ino, updated_attr);
+                        reply.attr(&self.kernel_attr_ttl(), &updated_attr);
+                        return;
+                    }
+                }
+            }
+
+            // Usar atributos cacheados del inodo
+            self.update_attr_cache(ino, inode.attr);
+            reply.attr(&self.kernel_attr_ttl(), &inode.attr);
+            return;
+        }
+
+        error!("getattr: inode {} not found", ino);
+        reply.error(ENOENT);
+    }
+
+    /// Buscar archivo por nombre (usando caché de directorio)
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_entry(parent, name) {
+            Ok((ttl, attr)) => reply.entry(&ttl, &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Leer contenido de directorio (optimizado con caché)
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        trace!("readdir called for inode {} with offset {}", ino, offset);
+
+        let inode = match self.inodes.lock().unwrap().get(&ino) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("readdir: inode {} not found", ino);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if inode.attr.kind != FileType::Directory {
+            reply.error(ENOTDIR);
+            return;
+        }
+
+        let entries = match self.dir_entries(&inode, offset) {
+            Ok(entries) => entries,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        // Enviar entradas empezando desde offset
+        for (i, (entry_ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            let buffer_full = reply.add(*entry_ino, (i + 1) as i64, *kind, name.as_str());
+            if buffer_full {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    /// Listar directorio con los atributos de cada entrada (readdirplus)
+    ///
+    /// El listado ya trae tamaño, tipo y fecha: devolverlos aquí llena la
+    /// caché de entradas del kernel y evita un `lookup` por archivo en `ls -l`.
+    fn readdirplus(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        trace!(
+            "readdirplus called for inode {} with offset {}",
+            ino,
+            offset
+        );
+
+        let inode = match self.inodes.lock().unwrap().get(&ino) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("readdirplus: inode {} not found", ino);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if inode.attr.kind != FileType::Directory {
+            reply.error(ENOTDIR);
+            return;
+        }
+
+        let entries = match self.dir_entries(&inode, offset) {
+            Ok(entries) => entries,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        for (i, (entry_ino, _, name)) in entries.iter().enumerate().skip(offset as usize) {
+            let Some(attr) = self.entry_attr(&inode, *entry_ino, name) else {
+                trace!("readdirplus: no attributes for {}, skipping", name);
+                continue;
+            };
+            let ttl = self.listed_attr_ttl(*entry_ino);
+            let buffer_full = reply.add(*entry_ino, (i + 1) as i64, name.as_str(), &ttl, &attr, 0);
+            if buffer_full {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    /// Abrir archivo (con write buffer para lazy write)
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        trace!("open called for inode {} flags {}", ino, flags);
+
+        // Cada apertura lee una instantánea fija, sin pasar por la caché de páginas
+        if ino == STATS_INODE {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                reply.error(EACCES);
+                return;
+            }
+            let fh = self.allocate_fh();
+            let snapshot = self.stats_json().into_bytes();
+            self.stats_snapshots.lock().unwrap().insert(fh, snapshot);
+            reply.opened(fh, fuser::consts::FOPEN_DIRECT_IO);
+            return;
+        }
+
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            try_reply!(self.check_writable(), reply);
+        }
+
+        // Truncándolo ya no hay nada que descargar
+        if flags & libc::O_TRUNC == 0 {
+            try_reply!(self.check_size_limit(ino), reply);
+        }
+
+        let fh = self.allocate_fh();
+
+        // Estricto (o una ruta sin caché): cada apertura sin otros handles
+        // vuelve a leer del servidor
+        if (self.options.strict || self.uncached_inode(ino))
+            && self.open_count(ino) == 0
+            && self.shadow_buffer(ino).is_none()
+        {
+            self.evict_file_data(ino);
+        }
+
+        // Verificar si es modo escritura (flags & O_WRONLY o O_RDWR)
+        let is_write_mode = (flags & 0o1) != 0 || (flags & 0o2) != 0;
+
+        // Un temporal local se abre siempre sobre su único contenido, y un
+        // archivo con escrituras sin subir, sobre el buffer que las tiene
+        let write_buffer = match self.shadow_buffer(ino) {
+            Some(buffer) => Some(buffer),
+            None if is_write_mode => match self.dirty_write_buffer(ino) {
+                Some(buffer) => Some(buffer),
+                None => match self.open_write_buffer(ino, flags & libc::O_TRUNC != 0) {
+                    Ok(buffer) => Some(Arc::new(Mutex::new(buffer))),
+                    Err(e) => {
+                        error!("open: failed to load inode {} for writing: {:#}", ino, e);
+                        reply.error(errno_for(&e));
+                        return;
+                    }
+                },
+            },
+            None => None,
+        };
+        let file_handle = FileHandle {
+            ino,
+            write_buffer,
+            next_read: 0,
+        };
+
+        self.open_files.lock().unwrap().insert(fh, file_handle);
+        trace!(
+            "Opened file handle {} for inode {} (write mode: {})",
+            fh,
+            ino,
+            is_write_mode
+        );
+        self.queue_file_prefetch(ino, flags);
+
+        // El tamaño de un archivo ASCII no se conoce hasta descargarlo: que
+        // el kernel no corte las lecturas en el que anuncia el servidor
+        let ascii = self
+            .inodes
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .is_some_and(|inode| self.is_ascii(&inode.ftp_path));
+        let open_flags = if ascii {
+            fuser::consts::FOPEN_DIRECT_IO
+        } else {
+            0
+        };
+        reply.opened(fh, open_flags);
+    }
+
+    /// Leer el destino de un enlace simbólico (del listado, sin red)
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        trace!("readlink called for inode {}", ino);
+
+        let inodes = self.inodes.lock().unwrap();
+        let inode = match inodes.get(&ino) {
+            Some(inode) => inode,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let target = match &inode.link_target {
+            Some(target) => target,
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        let root = &inodes[&ROOT_INODE].ftp_path;
+
+        reply.data(mount_link_target(root, &inode.ftp_path, target).as_bytes());
+    }
+
+    /// Leer datos de archivo (con caché y prefetching)
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        trace!(
+            "read called for inode {} offset {} size {}",
+            ino,
+            offset,
+            size
+        );
+
+        let (start, end) = match read_range(offset, size) {
+            Ok(range) => range,
+            Err(errno) => {
+                debug!("read: invalid offset {} for inode {}", offset, ino);
+                reply.error(errno);
+                return;
+            }
+        };
+
+        if ino == STATS_INODE {
+            let snapshot = self.stats_snapshots.lock().unwrap().get(&fh).cloned();
+            let data = snapshot.unwrap_or_else(|| self.stats_json().into_bytes());
+            reply.data(slice_range(&data, start, end));
+            return;
+        }
+
+        let inode = match self.inodes.lock().unwrap().get(&ino) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("read: inode {} not found", ino);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if inode.attr.kind == FileType::Directory {
+            reply.error(EISDIR);
+            return;
+        }
+
+        // Los datos aún no sincronizados se sirven desde el buffer de escritura
+        let pending = self
+            .shadow_buffer(ino)
+            .or_else(|| self.dirty_write_buffer(ino));
+        if let Some(write_buffer) = pending {
+            match write_buffer.lock().unwrap().read_at(start, size as usize) {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    error!("read: failed to read write buffer: {}", e);
+                    reply.error(EIO);
+                }
+            }
+            return;
+        }
+
+        // Lectura secuencial de un archivo no cacheado: servir por ventanas y
+        // descargar la siguiente en segundo plano en lugar de bajar todo el archivo
+        let sequential = self.track_sequential_read(fh, start, size);
+        if self.read_windowed(&inode, start, sequential) {
+            match self.read_chunked(ino, &inode.ftp_path, start, size as usize) {
+                Ok((data, eof)) => {
+                    reply.data(&data);
+                    if !eof && sequential {
+                        self.prefetch_after(ino, &inode.ftp_path, start + data.len() as u64);
+                    }
+                }
+                Err(e) => {
+                    error!("read: failed to load file range: {}", e);
+                    reply.error(EIO);
+                }
+            }
+            return;
+        }
+
+        // Acceso aleatorio: cargar el archivo completo
+        match self.load_file_data(ino, &inode.ftp_path) {
+            Ok(data) => reply.data(slice_range(&data, start, end)),
+            Err(e) => {
+                error!("read: failed to load file data: {}", e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    /// Escribir datos en archivo (con write buffer - lazy write)
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        trace!(
+            "write called for inode {} fh {} offset {} size {}",
+            ino,
+            fh,
+            offset,
+            data.len()
+        );
+        try_reply!(self.check_writable(), reply);
+
+        let (start, end) = match write_range(offset, data.len()) {
+            Ok(range) => range,
+            Err(errno) => {
+                debug!("write: invalid offset {} for inode {}", offset, ino);
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let inode = match self.inodes.lock().unwrap().get(&ino) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("write: inode {} not found", ino);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if inode.attr.kind == FileType::Directory {
+            reply.error(EISDIR);
+            return;
+        }
+
+        // Obtener el buffer de escritura del file handle
+        let write_buffer = self
+            .open_files
+            .lock()
+            .unwrap()
+            .get(&fh)
+            .and_then(|handle| handle.write_buffer.clone());
+
+        if self.oversized(end) {
+            debug!("write: {} would grow past --max-file-size", inode.ftp_path);
+            reply.error(libc::EFBIG);
+            return;
+        }
+
+        if let Some(write_buffer) = write_buffer {
+            match self.write_buffered(&inode, &write_buffer, start, data) {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(errno) => reply.error(errno),
+            }
+            return;
+        }
+
+        // Fallback si no hay write buffer (modo read-only o error)
+        error!("write: no write buffer available for fh {}", fh);
+        reply.error(EIO);
+    }
+
+    /// Crear archivo (invalida caché de directorio)
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        try_reply!(self.check_writable(), reply);
+
+        let name_str = try_reply!(utf8_name(name), reply).to_string();
+        trace!(
+            "create called for parent={} name={} mode={}",
+            parent,
+            name_str,
+            mode
+        );
+
+        // No crear archivos temporales en el servidor
+        let temp = self.options.ignore.matches(&name_str);
+        if temp && !self.options.shadow_ignored {
+            trace!("create: ignoring temp file {}", name_str);
+            // Devolver un error que VS Code interpretará como "no soportado"
+            // pero no interrumpirá el flujo de trabajo
+            reply.error(libc::EOPNOTSUPP);
+            return;
+        }
+
+        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("create: parent inode {} not found", parent);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let ftp_path = join_ftp_path(&parent_inode.ftp_path, &name_str);
+
+        // Con --shadow-ignored el temporal vive solo en local
+        if temp {
+            let inode = self.create_shadow(parent, &name_str, &ftp_path, mode);
+            let fh = self.allocate_fh();
+            self.open_files.lock().unwrap().insert(
+                fh,
+                FileHandle {
+                    ino: inode.ino,
+                    write_buffer: self.shadow_buffer(inode.ino),
+                    next_read: 0,
+                },
+            );
+            reply.created(&self.options.entry_ttl, &inode.attr, 0, fh, 0);
+            return;
+        }
+
+        // El archivo vacío se sube con el primer flush, junto con lo escrito
+        let mode = self.created_mode(mode, umask, false);
+        let inode = self.create_pending(parent, &name_str, &ftp_path, mode);
+
+        // El handle devuelto debe admitir escrituras como uno de `open`
+        let fh = self.allocate_fh();
+        let mut buffer = WriteBuffer::new();
+        buffer.dirty = true;
+        self.open_files.lock().unwrap().insert(
+            fh,
+            FileHandle {
+                ino: inode.ino,
+                write_buffer: Some(Arc::new(Mutex::new(buffer))),
+                next_read: 0,
+            },
+        );
+        // Write-through promete que el servidor lo ve todo en el acto
+        if self.options.write_through {
+            if let Err(e) = self.sync_write_buffer(fh) {
+                error!("create: failed to create file: {:#}", e);
+                self.open_files.lock().unwrap().remove(&fh);
+                self.pending_creates.lock().unwrap().remove(&inode.ino);
+                self.path_to_inode
+                    .lock()
+                    .unwrap()
+                    .remove(self.path_key(&ftp_path).as_ref());
+                reply.error(EIO);
+                return;
+            }
+        }
+        reply.created(&self.options.entry_ttl, &inode.attr, 0, fh, 0);
+    }
+
+    /// Eliminar archivo (invalida cachés)
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.unlink_entry(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Crear directorio (invalida caché)
+    fn mkdir(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        try_reply!(self.check_writable(), reply);
+
+        let name_str = try_reply!(utf8_name(name), reply).to_string();
+        trace!(
+            "mkdir called for parent={} name={} mode={}",
+            parent,
+            name_str,
+            mode
+        );
+
+        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("mkdir: parent inode {} not found", parent);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let ftp_path = join_ftp_path(&parent_inode.ftp_path, &name_str);
+
+        // Crear directorio en FTP
+        let mut conn = self.ftp_conn.lock().unwrap();
+        match conn.mkdir(&ftp_path) {
+            Ok(_) => {
+                let wanted = self.created_mode(mode, umask, true);
+                let mode = self.chmod_created(&mut conn, &ftp_path, wanted, 0, true);
+                if mode != wanted {
+                    warn!(
+                        "{} was created with mode {:o} instead of {:o}",
+                        ftp_path, mode, wanted
+                    );
+                }
+                drop(conn); // Liberar lock
+
+                // Invalidar caché
+                self.invalidate_dir_cache(&parent_inode.ftp_path);
+
+                // Crear inodo para el nuevo directorio
+                let file_info = FtpFileInfo {
+                    name: name_str,
+                    path: ftp_path,
+                    size: 0,
+                    is_dir: true,
+                    permissions: mode & 0o777,
+                    modified_time: Some(SystemTime::now()),
+                    link_target: None,
+                    raw_name: None,
+                };
+
+                let inode = self.get_or_create_inode(parent, &file_info);
+                reply.entry(&self.options.entry_ttl, &inode.attr, 0);
+            }
+            Err(e) => {
+                error!("mkdir: failed to create directory: {}", e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    /// Eliminar directorio (invalida caché)
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        try_reply!(self.check_writable(), reply);
+
+        let name_str = try_reply!(utf8_name(name), reply).to_string();
+        trace!("rmdir called for parent={} name={}", parent, name_str);
+
+        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("rmdir: parent inode {} not found", parent);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let ftp_path = join_ftp_path(&parent_inode.ftp_path, &name_str);
+
+        // Eliminar de cachés
+        let key = self.path_key(&ftp_path);
+        if let Some(ino) = self.path_to_inode.lock().unwrap().remove(key.as_ref()) {
+            self.inodes.lock().unwrap().remove(&ino);
+            self.attr_cache.lock().unwrap().remove(&ino);
+            self.dir_cache.lock().unwrap().remove(key.as_ref());
+        }
+        self.invalidate_dir_cache(&parent_inode.ftp_path);
+
+        // Eliminar directorio de FTP
+        let mut conn = self.ftp_conn.lock().unwrap();
+        let removed = self.remove_dir(&mut conn, &ftp_path);
+        drop(conn);
+        match removed {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Renombrar archivo o directorio (invalida cachés)
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        try_reply!(self.check_writable(), reply);
+
+        let name_str = try_reply!(utf8_name(name), reply).to_string();
+        let newname_str = try_reply!(utf8_name(newname), reply).to_string();
+        trace!(
+            "rename called: parent={} name={} newparent={} newname={}",
+            parent,
+            name_str,
+            newparent,
+            newname_str
+        );
+
+        let parent_inode = match self.inodes.lock().unwrap().get(&parent) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("rename: parent inode {} not found", parent);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let newparent_inode = match self.inodes.lock().unwrap().get(&newparent) {
+            Some(inode) => inode.clone(),
+            None => {
+                error!("rename: newparent inode {} not found", newparent);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let old_path = join_ftp_path(&parent_inode.ftp_path, &name_str);
+
+        let new_path = join_ftp_path(&newparent_inode.ftp_path, &newname_str);
+
+        // Un temporal local no existe en el servidor: renombrarlo a otro
+        // temporal es solo local, y a un nombre normal lo sube
+        let old_key = self.path_key(&old_path).into_owned();
+        let known = self.path_to_inode.lock().unwrap().get(&old_key).copied();
+        let shadow_ino = known.filter(|ino| self.shadow_files.lock().unwrap().contains_key(ino));
+        if let Some(ino) = shadow_ino {
+            if !self.options.ignore.matches(&newname_str) {
+                if let Err(e) = self.materialize_shadow(ino, &new_path) {
+                    error!("rename: {:#}", e);
+                    reply.error(EIO);
+                    return;
+                }
+                self.invalidate_dir_cache(&newparent_inode.ftp_path);
+                self.cancel_deferred_unlink(&new_path);
+            }
+            self.move_local(ino, &old_key, new_path, newparent, newname_str);
+            reply.ok();
+            return;
+        }
+
+        // Creado y aún sin subir: el servidor no lo conoce, así que solo cambia
+        // en local y el primer flush lo sube ya con el nombre nuevo
+        let pending_ino =
+            known.filter(|ino| self.pending_creates.lock().unwrap().contains_key(ino));
+        if let Some(ino) = pending_ino {
+            self.cancel_deferred_unlink(&new_path);
+            self.move_local(ino, &old_key, new_path, newparent, newname_str);
+            reply.ok();
+            return;
+        }
+
+        // El destino reemplaza a un archivo borrado pero aún abierto
+        self.cancel_deferred_unlink(&new_path);
+
+        let result = self.rename_remote(&old_path, &new_path, newparent, &newname_str);
+
+        // Invalidar cachés de directorios afectados
+        self.invalidate_dir_cache(&parent_inode.ftp_path);
+        if parent_inode.ftp_path != newparent_inode.ftp_path {
+            self.invalidate_dir_cache(&newparent_inode.ftp_path);
+        }
+
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("rename: {:#}", e);
+                reply.error(errno_for(&e));
+            }
+        }
+    }
+
+    /// Establecer atributos de archivo (simplificado)
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        trace!("setattr called for inode {}", ino);
+        try_reply!(self.check_writable(), reply);
+
+        // Truncar/extender también el buffer de escritura (igual que fallocate),
+        // para que el contenido subido coincida con el tamaño anunciado
+        if let Some(size) = size {
+            if self.oversized(size) {
+                reply.error(libc::EFBIG);
+                return;
+            }
+            if let Some(write_buffer) = self.write_buffer_for(fh, ino) {
+                let mut write_buffer = write_buffer.lock().unwrap();
+                // Sin el contenido cargado solo se puede vaciar el archivo
+                if write_buffer.direct && size > 0 {
+                    warn!(
+                        "setattr: cannot resize inode {} opened for in-place writes",
+                        ino
+                    );
+                    reply.error(libc::EOPNOTSUPP);
+                    return;
+                }
+                if let Err(e) = write_buffer.set_len(size) {
+                    error!("setattr: failed to resize write buffer: {}", e);
+                    reply.error(EIO);
+                    return;
+                }
+            }
+            self.evict_file_data(ino);
+        }
+
+        // FTP no guarda atime: solo se aplica la fecha de modificación
+        let mtime = mtime.map(|time| match time {
+            fuser::TimeOrNow::SpecificTime(time) => (time, false),
+            fuser::TimeOrNow::Now => (SystemTime::now(), true),
+        });
+        if let Some((time, now)) = mtime {
+            if let Err(errno) = self.set_remote_mtime(ino, time, now) {
+                reply.error(errno);
+                return;
+            }
+        }
+
+        let mut inodes = self.inodes.lock().unwrap();
+
+        if let Some(inode) = inodes.get_mut(&ino) {
+            if let Some(mode) = mode {
+                inode.attr.perm = mode as u16;
+            }
+            if let Some((time, _)) = mtime {
+                inode.attr.mtime = time;
+            }
+            if let Some(uid) = uid {
+                inode.attr.uid = uid;
+            }
+            if let Some(gid) = gid {
+                inode.attr.gid = gid;
+            }
+            if let Some(size) = size {
+                inode.attr.size = size;
+                inode.attr.blocks = blocks_for(size);
+            }
+
+            // Actualizar caché de atributos
+            self.update_attr_cache(ino, inode.attr);
+            reply.attr(&self.kernel_attr_ttl(), &inode.attr);
+        } else {
+            error!("setattr: inode {} not found", ino);
+            reply.error(ENOENT);
+        }
+    }
+
+    /// Reservar o poner a cero espacio en un archivo abierto para escritura
+    ///
+    /// Solo afecta al buffer de escritura; el servidor ve el resultado al
+    /// sincronizar. Con FALLOC_FL_KEEP_SIZE el tamaño anunciado no cambia.
+    fn fallocate(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        trace!(
+            "fallocate called for inode {} offset {} length {} mode {:#x}",
+            ino,
+            offset,
+            length,
+            mode
+        );
+        try_reply!(self.check_writable(), reply);
+
+        if offset < 0 || length <= 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        let supported =
+            libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_ZERO_RANGE;
+        if mode & !supported != 0 {
+            reply.error(libc::EOPNOTSUPP);
+            return;
+        }
+
+        let write_buffer = match self.write_buffer_for(Some(fh), ino) {
+            Some(write_buffer) => write_buffer,
+            None => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        };
+        // Sin el contenido cargado no hay dónde aplicarlo
+        if write_buffer.lock().unwrap().direct {
+            reply.error(libc::EOPNOTSUPP);
+            return;
+        }
+
+        let Some(end) = offset.checked_add(length) else {
+            reply.error(libc::EFBIG);
+            return;
+        };
+        let (offset, end) = (offset as u64, end as u64);
+        let keep_size = mode & libc::FALLOC_FL_KEEP_SIZE != 0;
+        if !keep_size && self.oversized(end) {
+            reply.error(libc::EFBIG);
+            return;
+        }
+        let zero = mode & (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_ZERO_RANGE) != 0;
+
+        let mut buffer = write_buffer.lock().unwrap();
+        let len = buffer.len();
+        let result = if keep_size {
+            buffer.reserve(end);
+            if zero {
+                buffer.zero_range(offset, end.min(len))
+            } else {
+                Ok(())
+            }
+        } else {
+            let grow = if end > len {
+                buffer.set_len(end)
+            } else {
+                Ok(())
+            };
+            grow.and_then(|_| {
+                if zero {
+                    buffer.zero_range(offset, end)
+                } else {
+                    Ok(())
+                }
+            })
+        };
+        let new_len = buffer.len();
+        drop(buffer);
+
+        match result {
+            Ok(()) => {
+                if new_len != len {
+                    self.evict_file_data(ino);
+                    self.set_cached_size(ino, new_len);
+                }
+                reply.ok();
+            }
+            Err(e) => {
+                error!("fallocate: failed to resize write buffer: {}", e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    /// Reposicionar el offset; sin información de huecos todo el archivo es datos
+    fn lseek(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        trace!(
+            "lseek called for inode {} offset {} whence {}",
+            ino,
+            offset,
+            whence
+        );
+
+        let size = match self.file_size(ino, fh) {
+            Ok(size) => size,
+            Err(e) => {
+                error!("lseek: failed to get size of inode {}: {}", ino, e);
+                reply.error(errno_for(&e));
+                return;
+            }
+        };
+
+        match seek_position(offset, whence, size) {
+            Ok(position) => reply.offset(position),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Bloque físico de un bloque del archivo: siempre 0, "sin asignar"
+    ///
+    /// Los archivos viven en el servidor FTP, no en un dispositivo local, así
+    /// que no hay bloques que mapear. Responder 0 (como un hueco en FIBMAP)
+    /// en vez de ENOSYS evita errores en herramientas que lo consultan.
+    fn bmap(&mut self, _req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        trace!(
+            "bmap called for inode {} block {} (size {})",
+            ino,
+            idx,
+            blocksize
+        );
+        reply.bmap(0);
+    }
+
+    /// Liberar handle de archivo (sincroniza write buffer y limpia caché)
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        trace!("release called for inode {} fh {}", ino, fh);
+
+        if let Some(owner) = lock_owner {
+            self.locks.release_owner(ino, owner);
+        }
+
+        if ino == STATS_INODE {
+            self.stats_snapshots.lock().unwrap().remove(&fh);
+            reply.ok();
+            return;
+        }
+
+        match self.release_handle(ino, fh) {
+            Ok(()) => {
+                trace!("File handle {} released successfully", fh);
+                reply.ok();
+            }
+            Err(e) => {
+                error!("release: failed to sync write buffer: {}", e);
+                reply.error(errno_for(&e));
+            }
+        }
+    }
+
+    /// Sincronizar archivo (fuerza sync del write buffer)
+    fn fsync(&mut self, _req: &Request, _ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        trace!("fsync called for fh {}", fh);
+
+        match self.sync_write_buffer(fh) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                error!("fsync: failed to sync: {}", e);
+                reply.error(errno_for(&e));
+            }
+        }
+    }
+
+    /// Espacio del filesystem para `df`: AVBL como total, y como libre lo
+    /// que queda tras `--quota-reserve`
+    ///
+    /// FTP no informa de la capacidad total ni de inodos. Sin AVBL se
+    /// responde como si no hubiera información, todo a cero.
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let bsize = self.options.blksize;
+        let Some(free) = self.server_free_space() else {
+            reply.statfs(0, 0, 0, 0, 0, bsize, 255, bsize);
+            return;
+        };
+        let reserve = self.options.quota_reserve.unwrap_or(0);
+        let blocks = free / bsize as u64;
+        let available = free.saturating_sub(reserve) / bsize as u64;
+        reply.statfs(blocks, available, available, 0, 0, bsize, 255, bsize);
+    }
+
+    /// Verificar permisos de acceso contra el dueño y los bits reportados
+    ///
+    /// Solo responde a access(2); con `--default-permissions` el kernel
+    /// comprueba él mismo cada operación y no llega a llamarlo.
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        trace!("access called for inode {} mask {:o}", ino, mask);
+
+        let attr = match self.get_attr_cached(ino) {
+            Some(attr) => attr,
+            None if ino == STATS_INODE => self.stats_attr(0),
+            None => match self.inodes.lock().unwrap().get(&ino) {
+                Some(inode) => inode.attr,
+                None => {
+                    reply.error(ENOENT);
+                    return;
+                }
+            },
+        };
+
+        // Camino rápido: solo se pregunta si existe
+        if mask == libc::F_OK {
+            reply.ok();
+            return;
+        }
+
+        if mask & libc::W_OK != 0 {
+            try_reply!(self.check_writable(), reply);
+        }
+
+        // Los grupos suplementarios solo hacen falta si no decide el dueño
+        // ni el grupo principal
+        let mut gids = vec![req.gid()];
+        if req.uid() != 0 && req.uid() != attr.uid && req.gid() != attr.gid {
+            gids.extend(supplementary_groups(req.pid()));
+        }
+        if check_access(&attr, req.uid(), &gids, mask) {
+            reply.ok();
+        } else {
+            reply.error(EACCES);
+        }
+    }
+
+    /// Liberar datos pendientes (sincroniza write buffer)
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        trace!("flush called for fh {}", fh);
+
+        // Cerrar cualquier descriptor suelta los bloqueos POSIX del proceso
+        self.locks.release_owner(ino, lock_owner);
+
+        match self.sync_write_buffer(fh) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                error!("flush: failed to sync: {}", e);
+                reply.error(errno_for(&e));
+            }
+        }
+    }
+
+    /// Comandos de control sobre cualquier archivo o directorio del montaje
+    ///
+    /// Permiten vaciar cachés o reconectar sin desmontar (ver `IOCTL_*`).
+    /// Las cachés del propio kernel caducan con su TTL habitual.
+    fn ioctl(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        _in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        debug!("ioctl called for inode {} cmd {:#x}", ino, cmd);
+
+        match self.handle_ioctl(ino, cmd) {
+            Ok(()) => reply.ioctl(0, &[]),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Consultar bloqueos (F_GETLK): el primero que impediría tomar el pedido
+    ///
+    /// Solo se ven los bloqueos tomados a través de este montaje; otros
+    /// montajes y clientes FTP no los comparten.
+    fn getlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        trace!("getlk called for inode {} range {}-{}", ino, start, end);
+
+        let lock = Lock {
+            start,
+            end,
+            typ,
+            pid,
+            owner: lock_owner,
+        };
+        match self.locks.conflict(ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, held.typ, held.pid),
+            None => reply.locked(start, end, libc::F_UNLCK, 0),
+        }
+    }
+
+    /// Tomar o soltar un bloqueo (F_SETLK/F_SETLKW)
+    ///
+    /// F_SETLKW espera en otro hilo: bloquear este impediría atender el
+    /// desbloqueo que lo despierta. Cerrar el archivo (`release_owner`)
+    /// cancela la espera con EINTR, así el hilo no queda aparcado para
+    /// siempre si el proceso muere o recibe una señal.
+    fn setlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        trace!(
+            "setlk called for inode {} range {}-{} type {} (wait: {})",
+            ino,
+            start,
+            end,
+            typ,
+            sleep
+        );
+
+        if ![libc::F_RDLCK, libc::F_WRLCK, libc::F_UNLCK].contains(&typ) {
+            reply.error(EINVAL);
+            return;
+        }
+        let lock = Lock {
+            start,
+            end,
+            typ,
+            pid,
+            owner: lock_owner,
+        };
+        match self.locks.set(ino, lock) {
+            Ok(()) => reply.ok(),
+            Err(held) if sleep => {
+                debug!(
+                    "Inode {} range {}-{} locked by pid {}, waiting",
+                    ino, start, end, held.pid
+                );
+                let locks = Arc::clone(&self.locks);
+                thread::spawn(move || match locks.set_wait(ino, lock) {
+                    true => reply.ok(),
+                    false => reply.error(libc::EINTR),
+                });
+            }
+            Err(_) => reply.error(libc::EAGAIN),
+        }
+    }
+
+    /// Leer un atributo extendido: solo existen los sintéticos, sin red
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        trace!("getxattr called for inode {} name {:?}", ino, name);
+
+        if !self.inodes.lock().unwrap().contains_key(&ino) {
+            reply.error(ENOENT);
+            return;
+        }
+        match self.synthetic_xattr(ino, name) {
+            Some(value) => reply_xattr(reply, size, &value),
+            None => reply.error(ENOATTR),
+        }
+    }
+
+    /// Listar atributos extendidos (los nombres, separados por NUL)
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        trace!("listxattr called for inode {}", ino);
+
+        if !self.inodes.lock().unwrap().contains_key(&ino) {
+            reply.error(ENOENT);
+            return;
+        }
+        let names = format!("{}\0", FTP_PATH_XATTR);
+        reply_xattr(reply, size, names.as_bytes());
+    }
+
+    /// FTP no guarda atributos extendidos
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        _value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        trace!("setxattr called for inode {} name {:?}", ino, name);
+        match self.check_writable() {
+            Err(errno) => reply.error(errno),
+            Ok(()) => reply.error(libc::ENOTSUP),
+        }
+    }
+
+    /// FTP no guarda atributos extendidos
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        trace!("removexattr called for inode {} name {:?}", ino, name);
+        match self.check_writable() {
+            Err(errno) => reply.error(errno),
+            Ok(()) => reply.error(libc::ENOTSUP),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn mock_fs(backend: &MockBackend) -> FtpFs<MockBackend> {
+        let options = FsOptions {
+            keepalive: None,
+            ..FsOptions::default()
+        };
+        FtpFs::with_options(backend.clone(), options).unwrap()
+    }
+
+    /// Abrir un handle de `ino` con `buffer` como buffer de escritura
+    fn open_handle(fs: &FtpFs<MockBackend>, ino: u64, buffer: Option<WriteBuffer>) -> u64 {
+        let fh = fs.allocate_fh();
+        fs.open_files.lock().unwrap().insert(
+            fh,
+            FileHandle {
+                ino,
+                write_buffer: buffer.map(|buffer| Arc::new(Mutex::new(buffer))),
+                next_read: 0,
+            },
+        );
+        fh
+    }
+
+    #[test]
+    fn test_directory_listing_is_cached() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let fs = mock_fs(&backend);
+
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "a.txt");
+        assert_eq!(files[0].size, 5);
+
+        fs.list_ftp_directory_cached("/").unwrap();
+        assert_eq!(backend.state.lock().unwrap().list_calls, 1);
+
+        fs.invalidate_dir_cache("/");
+        fs.list_ftp_directory_cached("/").unwrap();
+        assert_eq!(backend.state.lock().unwrap().list_calls, 2);
+
+        assert!(fs.list_ftp_directory_cached("/missing").is_err());
+    }
+
+    #[test]
+    fn test_ioctl_commands_flush_caches_and_reconnect() {
+        let backend = MockBackend::default()
+            .with_file("/a.txt", b"hello")
+            .with_file("/sub/b.txt", b"world");
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .dirs
+            .insert("/sub".to_string());
+        let fs = mock_fs(&backend);
+
+        let root = fs.list_ftp_directory_cached("/").unwrap();
+        fs.list_ftp_directory_cached("/sub").unwrap();
+        assert_eq!(backend.state.lock().unwrap().list_calls, 2);
+
+        // Vaciar un directorio solo afecta a ese listado
+        let sub = fs.get_or_create_inode(ROOT_INODE, find_entry(&root, "sub").unwrap());
+        assert_eq!(fs.handle_ioctl(sub.ino, IOCTL_FLUSH_DIR), Ok(()));
+        assert!(fs.dir_cache.lock().unwrap().contains_key("/"));
+        assert!(!fs.dir_cache.lock().unwrap().contains_key("/sub"));
+        assert_eq!(fs.handle_ioctl(ROOT_INODE, IOCTL_FLUSH_CACHES), Ok(()));
+        assert!(fs.dir_cache.lock().unwrap().is_empty());
+
+        assert_eq!(fs.handle_ioctl(ROOT_INODE, IOCTL_RECONNECT), Ok(()));
+        assert_eq!(Stats::get(&fs.stats.reconnects), 1);
+        assert_eq!(fs.handle_ioctl(ROOT_INODE, 0x5400), Err(ENOTTY));
+    }
+
+    #[test]
+    fn test_recursive_rmdir_removes_contents_and_cached_inodes() {
+        let backend = MockBackend::default()
+            .with_file("/d/a.txt", b"a")
+            .with_file("/d/sub/b.txt", b"b")
+            .with_file("/keep.txt", b"k");
+        {
+            let mut state = backend.state.lock().unwrap();
+            state.dirs.insert("/d".to_string());
+            state.dirs.insert("/d/sub".to_string());
+        }
+        let fs = mock_fs(&backend);
+        let listing = fs.list_ftp_directory_cached("/d").unwrap();
+        let a = fs.get_or_create_inode(ROOT_INODE, find_entry(&listing, "a.txt").unwrap());
+
+        let mut conn = backend.clone();
+        fs.remove_tree(&mut conn, "/d", MAX_RMDIR_DEPTH).unwrap();
+        fs.forget_tree("/d");
+
+        let state = backend.state.lock().unwrap();
+        assert!(state.dirs.is_empty());
+        assert_eq!(state.files.keys().collect::<Vec<_>>(), ["/keep.txt"]);
+        drop(state);
+        assert!(!fs.inodes.lock().unwrap().contains_key(&a.ino));
+        assert!(!fs.dir_cache.lock().unwrap().contains_key("/d"));
+
+        // Sin niveles disponibles no se borra nada
+        let backend = MockBackend::default().with_file("/d/a.txt", b"a");
+        backend.state.lock().unwrap().dirs.insert("/d".to_string());
+        let fs = mock_fs(&backend);
+        let mut conn = backend.clone();
+        assert!(fs.remove_tree(&mut conn, "/d", 0).is_err());
+        assert!(backend.state.lock().unwrap().files.contains_key("/d/a.txt"));
+    }
+
+    #[test]
+    fn test_rmdir_recurses_only_into_directories_that_are_not_empty() {
+        let backend = MockBackend::default().with_file("/d/a.txt", b"a");
+        backend.state.lock().unwrap().dirs.insert("/d".to_string());
+        let fs = mock_fs(&backend);
+        let mut conn = backend.clone();
+        assert_eq!(fs.remove_dir(&mut conn, "/d"), Err(libc::ENOTEMPTY));
+        assert_eq!(fs.remove_dir(&mut conn, "/missing"), Err(ENOENT));
+
+        let options = FsOptions {
+            keepalive: None,
+            recursive_rmdir: true,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        // Un directorio que no existe no se lista ni se recorre
+        let lists = backend.state.lock().unwrap().list_calls;
+        assert_eq!(fs.remove_dir(&mut conn, "/missing"), Err(ENOENT));
+        assert_eq!(backend.state.lock().unwrap().list_calls, lists);
+
+        assert_eq!(fs.remove_dir(&mut conn, "/d"), Ok(()));
+        let state = backend.state.lock().unwrap();
+        assert!(state.dirs.is_empty());
+        assert!(state.files.is_empty());
+    }
+
+    #[test]
+    fn test_missing_names_are_remembered_until_the_directory_changes() {
+        let backend = MockBackend::default();
+        let fs = mock_fs(&backend);
+
+        fs.remember_missing("/.editorconfig");
+        fs.remember_missing("/sub/.git");
+        assert!(fs
+            .negative_cache
+            .lock()
+            .unwrap()
+            .peek("/.editorconfig")
+            .is_some());
+
+        // Crear o renombrar algo en `/` invalida su listado y lo olvida
+        fs.invalidate_dir_cache("/");
+        assert!(fs
+            .negative_cache
+            .lock()
+            .unwrap()
+            .peek("/.editorconfig")
+            .is_none());
+        assert!(fs
+            .negative_cache
+            .lock()
+            .unwrap()
+            .peek("/sub/.git")
+            .is_some());
+
+        let options = FsOptions {
+            keepalive: None,
+            negative_ttl: Duration::ZERO,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend, options).unwrap();
+        fs.remember_missing("/.editorconfig");
+        assert!(fs.negative_cache.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_zero_poll_interval_starts_no_poller() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let options = FsOptions {
+            keepalive: None,
+            poll_interval: Some(Duration::ZERO),
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+        open_handle(&fs, inode.ino, None);
+
+        // Un sondeo sin pausa volvería a listar `/` por el archivo abierto
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(backend.state.lock().unwrap().list_calls, 1);
+    }
+
+    #[test]
+    fn test_zero_ttls_disable_caching() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let options = FsOptions {
+            keepalive: None,
+            entry_ttl: Duration::ZERO,
+            dir_ttl: Duration::ZERO,
+            attr_ttl: Duration::ZERO,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        fs.list_ftp_directory_cached("/").unwrap();
+        assert_eq!(backend.state.lock().unwrap().list_calls, 2);
+
+        // Sin caché de listados tampoco se reutilizan tipo ni tamaño
+        assert_eq!(fs.kind_cache.lock().unwrap().peek("/a.txt"), None);
+        assert_eq!(fs.size_cache.lock().unwrap().peek("/a.txt"), None);
+
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+        fs.update_attr_cache(inode.ino, inode.attr);
+        assert!(fs.get_attr_cached(inode.ino).is_none());
+        assert_eq!(fs.kernel_attr_ttl(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_created_mode_is_applied_with_site_chmod() {
+        let backend = MockBackend::default();
+        let fs = mock_fs(&backend);
+
+        let mut conn = backend.clone();
+        assert_eq!(
+            fs.chmod_created(&mut conn, "/key", 0o100666, 0o066, false),
+            0o600
+        );
+        assert_eq!(
+            fs.chmod_created(&mut conn, "/private", 0o40777, 0o077, true),
+            0o700
+        );
+        assert_eq!(
+            backend.state.lock().unwrap().chmod_calls,
+            vec![("/key".to_string(), 0o600), ("/private".to_string(), 0o700)]
+        );
+
+        // Sin SITE CHMOD el modo se queda en local sin fallar
+        backend.state.lock().unwrap().down = true;
+        assert_eq!(
+            fs.chmod_created(&mut conn, "/other", 0o644, 0o022, false),
+            0o644
+        );
+        assert_eq!(backend.state.lock().unwrap().chmod_calls.len(), 2);
+    }
+
+    #[test]
+    fn test_default_modes_apply_unless_the_client_asks_otherwise() {
+        let backend = MockBackend::default();
+        let fs = FtpFs::with_options(
+            backend.clone(),
+            FsOptions {
+                keepalive: None,
+                file_mode: Some(0o640),
+                dir_mode: Some(0o750),
+                ..FsOptions::default()
+            },
+        )
+        .unwrap();
+
+        // Los modos habituales de open(O_CREAT) y mkdir ceden al configurado
+        assert_eq!(fs.created_mode(0o100666, 0o022, false), 0o640);
+        assert_eq!(fs.created_mode(0o40777, 0o022, true), 0o750);
+        assert_eq!(fs.created_mode(0, 0o022, false), 0o640);
+        // Uno explícito gana, con la umask del cliente
+        assert_eq!(fs.created_mode(0o100600, 0o022, false), 0o600);
+        assert_eq!(fs.created_mode(0o40755, 0o077, true), 0o700);
+
+        // El modo aplicado se comprueba en el listado
+        let inode = fs.create_pending(ROOT_INODE, "new.txt", "/new.txt", 0o640);
+        let mut conn = backend.clone();
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .insert("/new.txt".to_string(), Vec::new());
+        fs.finish_pending_create(&mut conn, inode.ino, "/new.txt");
+        assert_eq!(fs.inodes.lock().unwrap()[&inode.ino].attr.perm, 0o640);
+        assert_eq!(backend.state.lock().unwrap().list_calls, 1);
+
+        // Solo se lista una vez por directorio: el resto confía en el resultado
+        let inode = fs.create_pending(ROOT_INODE, "more.txt", "/more.txt", 0o640);
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .insert("/more.txt".to_string(), Vec::new());
+        fs.finish_pending_create(&mut conn, inode.ino, "/more.txt");
+        assert_eq!(fs.inodes.lock().unwrap()[&inode.ino].attr.perm, 0o640);
+        assert_eq!(backend.state.lock().unwrap().list_calls, 1);
+
+        // Un servidor que ignora SITE CHMOD se detecta, y se recuerda el
+        // modo con el que crea
+        backend.state.lock().unwrap().chmod_ignored = true;
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .dirs
+            .insert("/sub".to_string());
+        for name in ["a.txt", "b.txt"] {
+            let path = format!("/sub/{}", name);
+            let inode = fs.create_pending(ROOT_INODE, name, &path, 0o640);
+            backend
+                .state
+                .lock()
+                .unwrap()
+                .files
+                .insert(path.clone(), Vec::new());
+            fs.finish_pending_create(&mut conn, inode.ino, &path);
+            assert_eq!(fs.inodes.lock().unwrap()[&inode.ino].attr.perm, 0o644);
+        }
+        assert_eq!(backend.state.lock().unwrap().list_calls, 2);
+    }
+
+    #[test]
+    fn test_file_mode_alone_does_not_list_after_mkdir() {
+        let backend = MockBackend::default();
+        let fs = FtpFs::with_options(
+            backend.clone(),
+            FsOptions {
+                keepalive: None,
+                file_mode: Some(0o640),
+                ..FsOptions::default()
+            },
+        )
+        .unwrap();
+
+        let mut conn = backend.clone();
+        conn.mkdir("/dir").unwrap();
+        assert_eq!(fs.chmod_created(&mut conn, "/dir", 0o750, 0, true), 0o750);
+        assert_eq!(backend.state.lock().unwrap().list_calls, 0);
+    }
+
+    #[test]
+    fn test_pathological_offsets_fail_with_errnos() {
+        assert_eq!(read_range(-1, 10), Err(EINVAL));
+        assert_eq!(read_range(i64::MIN, 10), Err(EINVAL));
+        let max = i64::MAX as u64;
+        assert_eq!(
+            read_range(i64::MAX, u32::MAX),
+            Ok((max, max + u32::MAX as u64))
+        );
+        assert_eq!(write_range(-1, 1), Err(EINVAL));
+        assert_eq!(write_range(i64::MAX, 0), Ok((max, max)));
+        assert_eq!(write_range(i64::MAX, 1), Err(libc::EFBIG));
+        assert_eq!(write_range(i64::MAX - 4, 4), Ok((max - 4, max)));
+
+        // Lo que queda más allá del final se lee vacío, sin desbordar índices
+        assert_eq!(slice_range(b"hello", 1, 3), b"el");
+        assert_eq!(slice_range(b"hello", 3, u64::MAX), b"lo");
+        assert_eq!(slice_range(b"hello", max, u64::MAX), b"");
+        assert_eq!(slice_range(b"hello", 4, 2), b"");
+
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"hello").unwrap();
+        assert!(buffer.read_at(u64::MAX, 10).unwrap().is_empty());
+        assert_eq!(buffer.read_at(3, usize::MAX).unwrap(), b"lo");
+        let err = buffer.write_at(u64::MAX, b"x").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+        assert_eq!(buffer.len(), 5);
+
+        // Una lectura secuencial al final del rango no desborda el siguiente offset
+        let backend = MockBackend::default();
+        let fs = mock_fs(&backend);
+        let fh = open_handle(&fs, ROOT_INODE, None);
+        fs.open_files
+            .lock()
+            .unwrap()
+            .get_mut(&fh)
+            .unwrap()
+            .next_read = max;
+        assert!(fs.track_sequential_read(fh, max, u32::MAX));
+        assert!(!fs.track_sequential_read(fh, 0, 1));
+    }
+
+    #[test]
+    fn test_writes_stop_short_of_the_quota_reserve() {
+        let backend = MockBackend::default();
+        backend.state.lock().unwrap().avbl = Some(1000);
+        let options = FsOptions {
+            keepalive: None,
+            quota_reserve: Some(100),
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+
+        assert_eq!(fs.claim_space(800), Ok(()));
+        // Quedan 200 bytes, 100 de ellos reservados
+        assert_eq!(fs.claim_space(101), Err(ENOSPC));
+        assert_eq!(fs.claim_space(100), Ok(()));
+        assert_eq!(fs.server_free_space(), Some(100));
+        assert_eq!(fs.claim_space(0), Ok(()));
+        assert_eq!(backend.state.lock().unwrap().avbl_calls, 1);
+
+        // Sin AVBL no hay capacidad con la que comparar: se comprueba (y se
+        // avisa) ya al montar
+        let backend = MockBackend::default();
+        let options = FsOptions {
+            keepalive: None,
+            quota_reserve: Some(100),
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        assert_eq!(backend.state.lock().unwrap().avbl_calls, 1);
+        assert_eq!(fs.claim_space(u64::MAX), Ok(()));
+        assert_eq!(fs.claim_space(1), Ok(()));
+        assert_eq!(backend.state.lock().unwrap().avbl_calls, 1);
+
+        // Ni sin --quota-reserve, que no pregunta nada
+        let fs = mock_fs(&backend);
+        assert_eq!(fs.claim_space(u64::MAX), Ok(()));
+        assert_eq!(backend.state.lock().unwrap().avbl_calls, 1);
+    }
+
+    #[test]
+    fn test_lseek_treats_the_whole_file_as_data() {
+        assert_eq!(seek_position(3, libc::SEEK_SET, 10), Ok(3));
+        assert_eq!(seek_position(-2, libc::SEEK_END, 10), Ok(8));
+        assert_eq!(seek_position(-11, libc::SEEK_END, 10), Err(EINVAL));
+        assert_eq!(seek_position(4, libc::SEEK_DATA, 10), Ok(4));
+        assert_eq!(seek_position(4, libc::SEEK_HOLE, 10), Ok(10));
+        assert_eq!(seek_position(10, libc::SEEK_DATA, 10), Err(ENXIO));
+        assert_eq!(seek_position(12, libc::SEEK_HOLE, 10), Err(ENXIO));
+        assert_eq!(seek_position(0, libc::SEEK_DATA, 0), Err(ENXIO));
+        assert_eq!(seek_position(0, 42, 10), Err(EINVAL));
+
+        // Con la caché de atributos caducada el tamaño se vuelve a pedir
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let options = FsOptions {
+            keepalive: None,
+            attr_ttl: Duration::ZERO,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .insert("/a.txt".to_string(), b"hello world".to_vec());
+        fs.size_cache.lock().unwrap().forget_children("/");
+        assert_eq!(fs.file_size(inode.ino, 0).unwrap(), 11);
+        assert!(fs.file_size(999, 0).is_err());
+    }
+
+    #[test]
+    fn test_stats_snapshot_counts_without_network() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let fs = mock_fs(&backend);
+
+        fs.list_ftp_directory_cached("/").unwrap();
+        fs.list_ftp_directory_cached("/").unwrap();
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+        fs.load_file_data(inode.ino, "/a.txt").unwrap();
+        fs.evict_file_data(inode.ino);
+
+        let calls = |b: &MockBackend| {
+            let state = b.state.lock().unwrap();
+            (state.list_calls, state.retrieve_calls)
+        };
+        let before = calls(&backend);
+        let json = fs.stats_json();
+        assert_eq!(calls(&backend), before);
+
+        assert!(json.contains("\"dir_cache\": {\"entries\": 1, \"hits\": 2, \"misses\": 1}"));
+        assert!(json.contains("\"read_cache\": {\"files\": 0, \"bytes\": 0, \"evictions\": 1}"));
+        assert!(json.contains("\"bytes_down\": 5,"));
+        assert!(json.contains("\"reconnects\": 0\n}"));
+        assert_eq!(fs.stats_attr(json.len()).perm, 0o444);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics_export_the_same_stats() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let fs = mock_fs(&backend);
+
+        fs.list_ftp_directory_cached("/").unwrap();
+        fs.list_ftp_directory_cached("/").unwrap();
+        fs.load_file_data(10, "/a.txt").unwrap();
+
+        let text = fs.metrics_text();
+        assert!(text.contains("rustftpfs_cache_hits_total{cache=\"dir\"} 1\n"));
+        assert!(text.contains("rustftpfs_cache_misses_total{cache=\"dir\"} 1\n"));
+        assert!(text.contains("rustftpfs_transferred_bytes_total{direction=\"down\"} 5\n"));
+        assert!(text.contains("# TYPE rustftpfs_operation_duration_seconds histogram\n"));
+        assert!(
+            text.contains("rustftpfs_operation_duration_seconds_count{operation=\"retrieve\"} 1\n")
+        );
+        assert!(text.contains(
+            "rustftpfs_operation_duration_seconds_bucket{operation=\"list\",le=\"+Inf\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn test_relative_link_targets_are_untouched() {
+        assert_eq!(
+            mount_link_target("/srv", "/srv/app/config", "../shared/config"),
+            "../shared/config"
+        );
+        assert_eq!(mount_link_target("/", "/latest", "v1.2.3"), "v1.2.3");
+    }
+
+    #[test]
+    fn test_absolute_link_targets_resolve_within_mount() {
+        // Montando /srv, /srv/data es `data` en la raíz del montaje
+        assert_eq!(
+            mount_link_target("/srv", "/srv/app/data", "/srv/data"),
+            "../data"
+        );
+        assert_eq!(
+            mount_link_target("/srv", "/srv/current", "/srv/releases/v2/"),
+            "releases/v2"
+        );
+        assert_eq!(mount_link_target("/srv", "/srv/a/b/up", "/srv/a"), "..");
+        assert_eq!(mount_link_target("/srv", "/srv/self", "/srv/./x/.."), ".");
+        assert_eq!(mount_link_target("/", "/pub/latest", "/pub/v2"), "v2");
+
+        // Fuera del montaje no hay destino equivalente
+        assert_eq!(
+            mount_link_target("/srv", "/srv/passwd", "/etc/passwd"),
+            "/etc/passwd"
+        );
+        assert_eq!(
+            mount_link_target("/srv", "/srv/x", "/srvother/x"),
+            "/srvother/x"
+        );
+    }
+
+    #[test]
+    fn test_paths_have_a_single_inode() {
+        let backend = MockBackend::default().with_file("/pub/a.txt", b"hi");
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .dirs
+            .insert("/pub".to_string());
+        let fs = mock_fs(&backend);
+
+        let files = fs.list_ftp_directory_cached("/pub/").unwrap();
+        fs.list_ftp_directory_cached("//pub").unwrap();
+        assert_eq!(backend.state.lock().unwrap().list_calls, 1);
+
+        let inode = fs.get_or_create_inode(ROOT_INODE, &files[0]);
+        let mut doubled = files[0].clone();
+        doubled.path = "//pub//a.txt".to_string();
+        assert_eq!(fs.get_or_create_inode(ROOT_INODE, &doubled).ino, inode.ino);
+    }
+
+    #[test]
+    fn test_file_replaced_by_directory_gets_a_new_inode() {
+        let backend = MockBackend::default().with_file("/x", b"data");
+        let fs = mock_fs(&backend);
+
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let file = fs.get_or_create_inode(ROOT_INODE, &info);
+        fs.load_file_data(file.ino, "/x").unwrap();
+        assert_eq!(fs.get_or_create_inode(ROOT_INODE, &info).ino, file.ino);
+
+        // Borrado y vuelto a crear como directorio por otro cliente
+        {
+            let mut state = backend.state.lock().unwrap();
+            state.files.remove("/x");
+            state.dirs.insert("/x".to_string());
+        }
+        fs.invalidate_dir_cache("/");
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let dir = fs.get_or_create_inode(ROOT_INODE, &info);
+        assert_ne!(dir.ino, file.ino);
+        assert_eq!(dir.attr.kind, FileType::Directory);
+        assert_eq!(fs.get_or_create_inode(ROOT_INODE, &info).ino, dir.ino);
+
+        // El inodo viejo desaparece con sus datos y atributos
+        assert!(!fs.inodes.lock().unwrap().contains_key(&file.ino));
+        assert!(fs.get_attr_cached(file.ino).is_none());
+        assert!(!fs.read_cache.lock().unwrap().contains_key(&file.ino));
+    }
+
+    #[test]
+    fn test_strict_listings_refresh_known_inodes() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let strict = FsOptions {
+            keepalive: None,
+            entry_ttl: Duration::ZERO,
+            dir_ttl: Duration::ZERO,
+            attr_ttl: Duration::ZERO,
+            negative_ttl: Duration::ZERO,
+            strict: true,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), strict).unwrap();
+        let cached = mock_fs(&backend);
+        let load = |fs: &FtpFs<MockBackend>| {
+            let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+            let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+            fs.load_file_data(inode.ino, "/a.txt").unwrap();
+            inode.ino
+        };
+        let (ino, cached_ino) = (load(&fs), load(&cached));
+
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .insert("/a.txt".to_string(), b"hello world".to_vec());
+
+        // Sin TTL el listado se repite y pone al día el inodo que ya existía
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+        assert_eq!(inode.ino, ino);
+        assert_eq!(inode.attr.size, 11);
+        assert_eq!(fs.load_file_data(ino, "/a.txt").unwrap(), b"hello world");
+
+        // En modo cached el listado sigue en caché y el inodo no cambia
+        let info = cached.list_ftp_directory_cached("/").unwrap().remove(0);
+        assert_eq!(cached.get_or_create_inode(ROOT_INODE, &info).attr.size, 5);
+        assert_eq!(
+            cached.load_file_data(cached_ino, "/a.txt").unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn test_followed_symlinks_become_their_target() {
+        assert_eq!(resolve_link_path("/pub/latest", "v2/file"), "/pub/v2/file");
+        assert_eq!(resolve_link_path("/pub/latest", "../etc/./x"), "/etc/x");
+        assert_eq!(resolve_link_path("/a/b", "/c/d/"), "/c/d");
+        assert_eq!(resolve_link_path("/up", "../.."), "/");
+
+        let backend = MockBackend::default().with_file("/data/a.txt", b"hello");
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .dirs
+            .insert("/data".to_string());
+        let options = FsOptions {
+            keepalive: None,
+            follow_symlinks: true,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend, options).unwrap();
+        let link = |path: &str, target: &str| FtpFileInfo {
+            name: path.trim_start_matches('/').to_string(),
+            path: path.to_string(),
+            size: target.len() as u64,
+            is_dir: false,
+            permissions: 0o777,
+            modified_time: None,
+            link_target: Some(target.to_string()),
+            raw_name: None,
+        };
+
+        let file = fs.get_or_create_inode(ROOT_INODE, &link("/current", "data/a.txt"));
+        assert_eq!(file.attr.kind, FileType::RegularFile);
+        assert_eq!(file.attr.size, 5);
+        assert_eq!(file.name, "current");
+        assert_eq!(file.ftp_path, "/data/a.txt");
+        assert_eq!(
+            fs.path_to_inode.lock().unwrap().get("/current"),
+            Some(&file.ino)
+        );
+
+        let dir = fs.get_or_create_inode(ROOT_INODE, &link("/d", "/data/"));
+        assert_eq!(dir.attr.kind, FileType::Directory);
+        assert_eq!(dir.ftp_path, "/data");
+
+        // Un enlace roto se queda como enlace
+        let broken = fs.get_or_create_inode(ROOT_INODE, &link("/broken", "nowhere"));
+        assert_eq!(broken.attr.kind, FileType::Symlink);
+        assert_eq!(broken.ftp_path, "/broken");
+    }
+
+    #[test]
+    fn test_file_info_uses_listed_kind() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .dirs
+            .insert("/src".to_string());
+        let fs = mock_fs(&backend);
+
+        // Sin listado previo hay que sondear el tipo
+        assert!(fs.get_ftp_file_info("/src").unwrap().is_dir);
+        assert_eq!(backend.state.lock().unwrap().is_dir_calls, 1);
+
+        fs.list_ftp_directory_cached("/").unwrap();
+        let info = fs.get_ftp_file_info("/a.txt").unwrap();
+        assert!(!info.is_dir);
+        assert_eq!(info.size, 5);
+        assert!(fs.get_ftp_file_info("/src").unwrap().is_dir);
+        assert_eq!(backend.state.lock().unwrap().is_dir_calls, 1);
+
+        // Invalidar el directorio olvida también los tipos de sus entradas
+        fs.invalidate_dir_cache("/");
+        fs.get_ftp_file_info("/a.txt").unwrap();
+        assert_eq!(backend.state.lock().unwrap().is_dir_calls, 2);
+    }
+
+    #[test]
+    fn test_remote_size_prefers_listing() {
+        let backend = MockBackend::default()
+            .with_file("/a.txt", b"hello")
+            .with_file("/b.txt", b"hi");
+        let fs = mock_fs(&backend);
+
+        // Sin listado hace falta SIZE, que queda cacheado
+        assert_eq!(fs.remote_size("/a.txt").unwrap(), 5);
+        assert_eq!(fs.remote_size("/a.txt").unwrap(), 5);
+        assert_eq!(fs.size_cache.lock().unwrap().misses, 1);
+
+        // El listado trae los tamaños de todas las entradas
+        fs.list_ftp_directory_cached("/").unwrap();
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .insert("/b.txt".to_string(), b"changed".to_vec());
+        assert_eq!(fs.remote_size("/b.txt").unwrap(), 2);
+        assert_eq!(fs.size_cache.lock().unwrap().misses, 1);
+
+        fs.invalidate_dir_cache("/");
+        assert_eq!(fs.remote_size("/b.txt").unwrap(), 7);
+        assert!(fs.remote_size("/missing").is_err());
+    }
+
+    #[test]
+    fn test_open_files_see_growth_without_waiting_for_the_attr_ttl() {
+        let backend = MockBackend::default().with_file("/app.log", b"one\n");
+        let fs = mock_fs(&backend);
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let inode = fs.get_or_create_inode(ROOT_INODE, &files[0]);
+        fs.load_file_data(inode.ino, "/app.log").unwrap();
+
+        // Cerrado, el tamaño cacheado vale hasta que caduque la caché
+        assert!(fs.open_file_attr(inode.ino).is_none());
+
+        let fh = open_handle(&fs, inode.ino, None);
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .insert("/app.log".to_string(), b"one\ntwo\n".to_vec());
+        assert_eq!(fs.open_file_attr(inode.ino).unwrap().size, 8);
+        assert_eq!(fs.inodes.lock().unwrap()[&inode.ino].attr.size, 8);
+        assert!(!fs.read_cache.lock().unwrap().contains_key(&inode.ino));
+
+        // Dentro de OPEN_SIZE_TTL no se repite el SIZE
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .insert("/app.log".to_string(), b"one\ntwo\nthree\n".to_vec());
+        assert_eq!(fs.open_file_attr(inode.ino).unwrap().size, 8);
+        fs.size_checked.lock().unwrap().clear();
+        assert_eq!(fs.refresh_size(inode.ino).unwrap(), 14);
+        assert_eq!(fs.get_attr_cached(inode.ino).unwrap().size, 14);
+
+        fs.release_handle(inode.ino, fh).unwrap();
+        assert!(fs.size_checked.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_readdirplus_attrs_expire_like_getattr() {
+        let backend = MockBackend::default().with_file("/app.log", b"one\n");
+        let fs = mock_fs(&backend);
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let inode = fs.get_or_create_inode(ROOT_INODE, &files[0]);
+
+        assert_eq!(fs.listed_attr_ttl(inode.ino), fs.kernel_attr_ttl());
+        assert_eq!(fs.listed_attr_ttl(STATS_INODE), Duration::ZERO);
+        // Abierto, un `ls -l` no puede fijar el tamaño más que `getattr`
+        let fh = open_handle(&fs, inode.ino, None);
+        assert_eq!(fs.listed_attr_ttl(inode.ino), OPEN_SIZE_TTL);
+        fs.release_handle(inode.ino, fh).unwrap();
+        assert_eq!(fs.listed_attr_ttl(inode.ino), fs.kernel_attr_ttl());
+    }
+
+    #[test]
+    fn test_prefetch_subdirs_respects_depth() {
+        let backend = MockBackend::default();
+        backend.state.lock().unwrap().dirs.extend([
+            "/a".to_string(),
+            "/a/b".to_string(),
+            "/a/b/c".to_string(),
+        ]);
+        let fs = mock_fs(&backend);
+        // La cola se atiende aquí en lugar de en el hilo, hasta vaciarla
+        let (sender, receiver) = mpsc::sync_channel(MAX_DIR_PREFETCH_QUEUE);
+        fs.dir_prefetch.open(sender);
+
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        fs.prefetch_subdirs(&files, 2);
+        while let Ok((path, depth)) = receiver.try_recv() {
+            fs.prefetch_dir(&path, depth);
+        }
+
+        assert!(fs.dir_cache_fresh("/a"));
+        assert!(fs.dir_cache_fresh("/a/b"));
+        assert!(!fs.dir_cache_fresh("/a/b/c"));
+        assert_eq!(backend.state.lock().unwrap().list_calls, 3);
+    }
+
+    #[test]
+    fn test_shutdown_stops_the_prefetchers() {
+        let options = FsOptions {
+            keepalive: None,
+            prefetch_depth: 1,
+            prefetch_workers: 2,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(MockBackend::default(), options).unwrap();
+        assert!(fs.dir_prefetch.is_open());
+        assert!(fs.file_prefetch.is_open());
+        assert_eq!(fs.file_prefetch.threads.lock().unwrap().len(), 2);
+
+        // Sin emisor los hilos salen de `recv`, y `shutdown` los espera
+        fs.shutdown().unwrap();
+        assert!(!fs.dir_prefetch.is_open());
+        assert!(!fs.file_prefetch.is_open());
+        assert!(fs.dir_prefetch.threads.lock().unwrap().is_empty());
+        assert!(fs.file_prefetch.threads.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_opened_files_are_prefetched_until_closed() {
+        let backend = MockBackend::default()
+            .with_file("/a.txt", b"hello")
+            .with_file("/b.txt", b"world");
+        let options = FsOptions {
+            keepalive: None,
+            prefetch_workers: 2,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let open = |name: &str| {
+            let inode = fs.get_or_create_inode(ROOT_INODE, find_entry(&files, name).unwrap());
+            let fh = open_handle(&fs, inode.ino, None);
+            (inode, fh)
+        };
+
+        let (a, _) = open("a.txt");
+        // Abrir solo para escribir no descarga nada
+        fs.queue_file_prefetch(a.ino, libc::O_WRONLY);
+        assert!(fs.file_prefetching.lock().unwrap().is_empty());
+
+        fs.queue_file_prefetch(a.ino, libc::O_RDONLY);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fs.file_prefetching.lock().unwrap().contains(&a.ino) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(fs.read_cache.lock().unwrap()[&a.ino], b"hello");
+        // El primer read ya es un acierto
+        fs.load_file_data(a.ino, "/a.txt").unwrap();
+        assert_eq!(backend.state.lock().unwrap().retrieve_calls, 1);
+
+        // Cerrado antes de que le llegue el turno: no se descarga
+        let (b, fh) = open("b.txt");
+        fs.file_prefetching.lock().unwrap().insert(b.ino);
+        fs.release_handle(b.ino, fh).unwrap();
+        fs.prefetch_file(b.ino, "/b.txt");
+        assert!(!fs.read_cache.lock().unwrap().contains_key(&b.ino));
+        assert!(fs.file_prefetching.lock().unwrap().is_empty());
+        assert_eq!(backend.state.lock().unwrap().retrieve_calls, 1);
+
+        // Quien esperaba la conexión mientras el prefetch descargaba no repite
+        let (b, _) = open("b.txt");
+        let conn = fs.ftp_conn.lock().unwrap();
+        let reader = {
+            let fs = fs.clone();
+            thread::spawn(move || fs.load_file_data(b.ino, "/b.txt").unwrap())
+        };
+        fs.read_cache
+            .lock()
+            .unwrap()
+            .insert(b.ino, b"world".to_vec());
+        drop(conn);
+        assert_eq!(reader.join().unwrap(), b"world");
+        assert_eq!(backend.state.lock().unwrap().retrieve_calls, 1);
+    }
+
+    #[test]
+    fn test_listing_a_file_is_not_a_directory() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let fs = mock_fs(&backend);
+
+        fs.list_ftp_directory_cached("/").unwrap();
+        let err = fs.list_ftp_directory_cached("/a.txt").unwrap_err();
+        assert_eq!(errno_for(&err), ENOTDIR);
+        // El tipo salió del listado del padre, sin otra consulta
+        assert_eq!(backend.state.lock().unwrap().list_calls, 1);
+
+        assert_eq!(errno_for(&anyhow::Error::new(FtpError::NotFound)), ENOENT);
+        assert_eq!(errno_for(&anyhow::anyhow!("421 closing")), EIO);
+    }
+
+    #[test]
+    fn test_directory_nlink_counts_subdirectories() {
+        let backend = MockBackend::default().with_file("/a.txt", b"");
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .dirs
+            .extend(["/src".to_string(), "/docs".to_string()]);
+        let fs = mock_fs(&backend);
+
+        assert_eq!(fs.get_attr_cached(ROOT_INODE).unwrap().nlink, 2);
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        assert_eq!(fs.get_attr_cached(ROOT_INODE).unwrap().nlink, 4);
+
+        // Un subdirectorio aún no listado se queda en 2
+        let src = files.iter().find(|f| f.name == "src").unwrap();
+        assert_eq!(fs.get_or_create_inode(ROOT_INODE, src).attr.nlink, 2);
+    }
+
+    #[test]
+    fn test_load_file_data_uses_read_cache() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let fs = mock_fs(&backend);
+
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+
+        assert_eq!(fs.load_file_data(inode.ino, "/a.txt").unwrap(), b"hello");
+        assert_eq!(fs.load_file_data(inode.ino, "/a.txt").unwrap(), b"hello");
+        assert_eq!(backend.state.lock().unwrap().retrieve_calls, 1);
+
+        assert!(fs.load_file_data(99, "/missing.txt").is_err());
+    }
+
+    #[test]
+    fn test_read_cache_stays_within_cache_size() {
+        let backend = MockBackend::default()
+            .with_file("/a.txt", b"hello")
+            .with_file("/b.txt", b"world");
+        let options = FsOptions {
+            keepalive: None,
+            cache_size: Some(8),
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        let listing = fs.list_ftp_directory_cached("/").unwrap();
+        let inode = |name: &str| {
+            let info = listing.iter().find(|info| info.name == name).unwrap();
+            fs.get_or_create_inode(ROOT_INODE, info)
+        };
+        let (a, b) = (inode("a.txt"), inode("b.txt"));
+
+        fs.load_file_data(a.ino, "/a.txt").unwrap();
+        fs.load_file_data(b.ino, "/b.txt").unwrap();
+        let cache = fs.read_cache.lock().unwrap();
+        assert!(!cache.contains_key(&a.ino));
+        assert_eq!(cache[&b.ino], b"world");
+    }
+
+    #[test]
+    fn test_no_cache_globs_bypass_caches_below_matches() {
+        let exclusions = CacheExclusions::new(&["/logs", "*.tmp"]).unwrap();
+        assert!(exclusions.matches("/logs"));
+        assert!(exclusions.matches("/logs/2024/app.log"));
+        assert!(exclusions.matches("/data/a.tmp"));
+        assert!(!exclusions.matches("/logs-old/app.log"));
+        assert!(!exclusions.matches("/"));
+        assert!(CacheExclusions::new(&["[unclosed"]).is_err());
+
+        let backend = MockBackend::default()
+            .with_file("/logs/app.log", b"line 1")
+            .with_file("/a.txt", b"hello");
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .dirs
+            .insert("/logs".to_string());
+        let options = FsOptions {
+            keepalive: None,
+            no_cache: exclusions,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+
+        fs.list_ftp_directory_cached("/").unwrap();
+        fs.list_ftp_directory_cached("/").unwrap();
+        assert_eq!(backend.state.lock().unwrap().list_calls, 1);
+        let files = fs.list_ftp_directory_cached("/logs").unwrap();
+        fs.list_ftp_directory_cached("/logs").unwrap();
+        assert_eq!(backend.state.lock().unwrap().list_calls, 3);
+
+        let log = fs.get_or_create_inode(ROOT_INODE, find_entry(&files, "app.log").unwrap());
+        fs.update_attr_cache(log.ino, log.attr);
+        assert!(fs.get_attr_cached(log.ino).is_none());
+
+        // Sin handles abiertos, cada lectura vuelve al servidor
+        fs.load_file_data(log.ino, "/logs/app.log").unwrap();
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .insert("/logs/app.log".to_string(), b"line 2".to_vec());
+        assert_eq!(
+            fs.load_file_data(log.ino, "/logs/app.log").unwrap(),
+            b"line 2"
+        );
+        assert_eq!(backend.state.lock().unwrap().retrieve_calls, 2);
+    }
+
+    #[test]
+    fn test_ascii_files_report_the_downloaded_size() {
+        let backend = MockBackend::default()
+            .with_file("/notes.txt", b"a\r\nb\r\n")
+            .with_file("/data.bin", b"a\nb\n");
+        let backend = MockBackend {
+            ascii_extensions: vec!["txt".to_string()],
+            ..backend
+        };
+        let options = FsOptions {
+            keepalive: None,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let inode = |name: &str| {
+            let info = files.iter().find(|f| f.name == name).unwrap();
+            fs.get_or_create_inode(ROOT_INODE, info)
+        };
+        let notes = inode("notes.txt");
+        let data = inode("data.bin");
+
+        // El servidor anuncia el tamaño con saltos de línea LF
+        fs.set_cached_size(notes.ino, 4);
+        assert_eq!(fs.ascii_data_size(notes.ino, "/notes.txt"), None);
+        fs.load_file_data(notes.ino, "/notes.txt").unwrap();
+        assert_eq!(fs.get_attr_cached(notes.ino).unwrap().size, 6);
+        assert_eq!(fs.ascii_data_size(notes.ino, "/notes.txt"), Some(6));
+
+        fs.load_file_data(data.ino, "/data.bin").unwrap();
+        assert_eq!(fs.ascii_data_size(data.ino, "/data.bin"), None);
+    }
+
+    #[test]
+    fn test_read_chunked_spans_windows() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello world");
+        let options = FsOptions {
+            keepalive: None,
+            read_ahead: 4,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+
+        let (data, eof) = fs.read_chunked(7, "/a.txt", 0, 6).unwrap();
+        assert_eq!((data.as_slice(), eof), (&b"hello "[..], false));
+
+        let (data, eof) = fs.read_chunked(7, "/a.txt", 6, 10).unwrap();
+        assert_eq!((data.as_slice(), eof), (&b"world"[..], true));
+
+        // Las ventanas anteriores a la lectura actual se descartan
+        let chunks = fs.ahead_cache.lock().unwrap()[&7]
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, vec![1, 2]);
+
+        let (data, eof) = fs.read_chunked(7, "/a.txt", 11, 4).unwrap();
+        assert!(data.is_empty() && eof);
+    }
+
+    #[test]
+    fn test_small_files_are_read_whole_until_reads_turn_sequential() {
+        let backend = MockBackend::default()
+            .with_file("/small.txt", b"hello")
+            .with_file("/large.bin", b"0123456789");
+        let options = FsOptions {
+            keepalive: None,
+            read_ahead: 8,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let small = fs.get_or_create_inode(ROOT_INODE, find_entry(&files, "small.txt").unwrap());
+        let large = fs.get_or_create_inode(ROOT_INODE, find_entry(&files, "large.bin").unwrap());
+
+        // Una primera lectura desde 0 de lo que cabe en una ventana: un solo RETR
+        let fh = open_handle(&fs, small.ino, None);
+        let sequential = fs.track_sequential_read(fh, 0, 4);
+        assert!(!sequential);
+        assert!(!fs.read_windowed(&small, 0, sequential));
+        let sequential = fs.track_sequential_read(fh, 4, 4);
+        assert!(sequential);
+        assert!(fs.read_windowed(&small, 4, sequential));
+
+        // Lo que no cabe va por ventanas desde la primera lectura
+        assert!(fs.read_windowed(&large, 0, false));
+        assert!(!fs.read_windowed(&large, 3, false));
+    }
+
+    #[test]
+    fn test_read_ahead_without_a_worker_drops_the_claim() {
+        let backend = MockBackend::default();
+        let options = FsOptions {
+            keepalive: None,
+            read_ahead: 0,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend, options).unwrap();
+        assert!(!fs.read_ahead.is_open());
+
+        fs.prefetch_after(7, "/a.txt", 10);
+        assert!(fs.prefetching.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_oversized_files_use_windows_without_read_ahead() {
+        let backend = MockBackend {
+            ascii_extensions: vec!["txt".to_string()],
+            ..MockBackend::default()
+        }
+        .with_file("/big.iso", b"0123456789")
+        .with_file("/big.txt", b"0123456789");
+        let options = FsOptions {
+            keepalive: None,
+            read_ahead: 0,
+            max_file_size: Some(8),
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        assert!(fs.oversized(10));
+        assert!(!fs.oversized(8));
+        assert_eq!(fs.read_window(), DEFAULT_READ_AHEAD);
+
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let inode = fs.get_or_create_inode(ROOT_INODE, find_entry(&files, "big.iso").unwrap());
+        assert_eq!(fs.check_size_limit(inode.ino), Ok(()));
+        assert_eq!(fs.check_size_limit(inode.ino), Ok(()));
+        assert_eq!(fs.oversized_warned.lock().unwrap().len(), 1);
+
+        // Un archivo ASCII no puede leerse por ventanas: se rechaza entero
+        let text = fs.get_or_create_inode(ROOT_INODE, find_entry(&files, "big.txt").unwrap());
+        assert_eq!(fs.check_size_limit(text.ino), Err(libc::EFBIG));
+        assert_eq!(backend.state.lock().unwrap().retrieve_calls, 0);
+
+        let (data, eof) = fs.read_chunked(inode.ino, "/big.iso", 4, 3).unwrap();
+        assert_eq!((data.as_slice(), eof), (&b"456"[..], false));
+        assert!(!fs.read_cache.lock().unwrap().contains_key(&inode.ino));
+    }
+
+    #[test]
+    fn test_write_buffer_resize_and_zero() {
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"abcdef").unwrap();
+
+        buffer.set_len(8).unwrap();
+        assert_eq!(buffer.read_at(0, 16).unwrap(), b"abcdef\0\0");
+
+        buffer.zero_range(1, 3).unwrap();
+        assert_eq!(buffer.read_at(0, 16).unwrap(), b"a\0\0def\0\0");
+
+        buffer.set_len(2).unwrap();
+        assert_eq!(buffer.read_at(0, 16).unwrap(), b"a\0");
+        assert!(buffer.dirty);
+    }
+
+    #[test]
+    fn test_sync_write_buffer_uploads_dirty_data() {
+        let backend = MockBackend::default().with_file("/a.txt", b"");
+        let fs = mock_fs(&backend);
+
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"new contents").unwrap();
+        let fh = open_handle(&fs, inode.ino, Some(buffer));
+        let buffer = fs.write_buffer_for(Some(fh), inode.ino).unwrap();
+
+        fs.sync_write_buffer(fh).unwrap();
+
+        assert_eq!(
+            backend.state.lock().unwrap().files["/a.txt"],
+            b"new contents"
+        );
+        assert!(!buffer.lock().unwrap().dirty);
+        assert_eq!(
+            fs.read_cache.lock().unwrap().get(&inode.ino).unwrap(),
+            b"new contents"
+        );
+    }
+
+    #[test]
+    fn test_reopened_file_is_written_over_its_contents() {
+        let big = vec![b'x'; WRITE_PRELOAD_MAX as usize + 1];
+        let backend = MockBackend::default()
+            .with_file("/a.txt", b"hello world")
+            .with_file("/big", &big);
+        let fs = mock_fs(&backend);
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let find = |name: &str| files.iter().find(|f| f.name == name).unwrap().clone();
+        let inode = fs.get_or_create_inode(ROOT_INODE, &find("a.txt"));
+
+        let mut buffer = fs.open_write_buffer(inode.ino, false).unwrap();
+        assert!(!buffer.dirty);
+        buffer.write_at(6, b"there").unwrap();
+        let fh = open_handle(&fs, inode.ino, Some(buffer));
+        fs.release_handle(inode.ino, fh).unwrap();
+        assert_eq!(
+            backend.state.lock().unwrap().files["/a.txt"],
+            b"hello there"
+        );
+
+        // O_TRUNC no descarga nada y sube el archivo vacío aunque no se escriba
+        let buffer = fs.open_write_buffer(inode.ino, true).unwrap();
+        assert!(buffer.dirty);
+        assert_eq!(buffer.len(), 0);
+
+        // Los grandes no se descargan: se escriben en su sitio
+        let big = fs.get_or_create_inode(ROOT_INODE, &find("big"));
+        let calls = backend.state.lock().unwrap().retrieve_calls;
+        let buffer = fs.open_write_buffer(big.ino, false).unwrap();
+        assert!(buffer.direct);
+        assert_eq!(backend.state.lock().unwrap().retrieve_calls, calls);
+    }
+
+    #[test]
+    fn test_direct_write_at_offset_zero_rewrites_the_head_in_place() {
+        let mut big = vec![b'x'; WRITE_PRELOAD_MAX as usize + 1];
+        let backend = MockBackend::default().with_file("/big", &big);
+        let fs = mock_fs(&backend);
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+
+        let buffer = Mutex::new(fs.open_write_buffer(inode.ino, false).unwrap());
+        let inode = fs.inodes.lock().unwrap()[&inode.ino].clone();
+        fs.write_buffered(&inode, &buffer, 0, b"head").unwrap();
+
+        // El resto del archivo sigue en el servidor y el buffer no recibe nada
+        big[..4].copy_from_slice(b"head");
+        assert!(backend.state.lock().unwrap().files["/big"] == big);
+        let buffer = buffer.lock().unwrap();
+        assert_eq!(buffer.len(), 0);
+        assert!(!buffer.dirty);
+        assert_eq!(
+            fs.inodes.lock().unwrap()[&inode.ino].attr.size,
+            big.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_synced_write_evicts_the_parent_listing() {
+        let backend = MockBackend::default().with_file("/dir/a.txt", b"");
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .dirs
+            .insert("/dir".to_string());
+        let fs = mock_fs(&backend);
+
+        let info = fs.list_ftp_directory_cached("/dir").unwrap().remove(0);
+        let dir = fs.get_or_create_inode(
+            ROOT_INODE,
+            &fs.list_ftp_directory_cached("/").unwrap().remove(0),
+        );
+        let inode = fs.get_or_create_inode(dir.ino, &info);
+        assert!(fs.dir_cache.lock().unwrap().contains_key("/dir"));
+
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"grown").unwrap();
+        let fh = open_handle(&fs, inode.ino, Some(buffer));
+        fs.sync_write_buffer(fh).unwrap();
+
+        assert!(!fs.dir_cache.lock().unwrap().contains_key("/dir"));
+        let listed = fs.list_ftp_directory_cached("/dir").unwrap();
+        assert_eq!(listed[0].size, 5);
+    }
+
+    #[test]
+    fn test_created_file_is_uploaded_once_on_flush() {
+        let backend = MockBackend::default();
+        let fs = mock_fs(&backend);
+
+        let inode = fs.create_pending(ROOT_INODE, "new.txt", "/new.txt", 0o640);
+        let mut buffer = WriteBuffer::new();
+        buffer.dirty = true;
+        let fh = open_handle(&fs, inode.ino, Some(buffer));
+        let buffer = fs.write_buffer_for(Some(fh), inode.ino).unwrap();
+
+        // Hasta el flush solo existe en local
+        assert!(!backend.state.lock().unwrap().files.contains_key("/new.txt"));
+        assert_eq!(
+            fs.pending_entries(ROOT_INODE),
+            vec![(inode.ino, "new.txt".to_string())]
+        );
+
+        buffer.lock().unwrap().write_at(0, b"written").unwrap();
+        fs.release_handle(inode.ino, fh).unwrap();
+
+        let state = backend.state.lock().unwrap();
+        assert_eq!(state.files["/new.txt"], b"written");
+        assert_eq!(state.chmod_calls, vec![("/new.txt".to_string(), 0o640)]);
+        drop(state);
+        assert!(fs.pending_entries(ROOT_INODE).is_empty());
+
+        // Un archivo vacío (touch) también llega al servidor
+        let inode = fs.create_pending(ROOT_INODE, "empty", "/empty", 0o644);
+        let mut buffer = WriteBuffer::new();
+        buffer.dirty = true;
+        let fh = open_handle(&fs, inode.ino, Some(buffer));
+        fs.release_handle(inode.ino, fh).unwrap();
+        assert_eq!(backend.state.lock().unwrap().files["/empty"], b"");
+
+        // Renombrado antes de subirse: sin RNFR, se sube con el nombre nuevo
+        let inode = fs.create_pending(ROOT_INODE, "draft", "/draft", 0o644);
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"final").unwrap();
+        let fh = open_handle(&fs, inode.ino, Some(buffer));
+        fs.move_local(
+            inode.ino,
+            "/draft",
+            "/final".to_string(),
+            ROOT_INODE,
+            "final".to_string(),
+        );
+        assert_eq!(
+            fs.pending_entries(ROOT_INODE),
+            vec![(inode.ino, "final".to_string())]
+        );
+        fs.release_handle(inode.ino, fh).unwrap();
+        let state = backend.state.lock().unwrap();
+        assert_eq!(state.files["/final"], b"final");
+        assert!(!state.files.contains_key("/draft"));
+    }
+
+    #[test]
+    fn test_listing_cut_mid_transfer_is_retried_not_cached_empty() {
+        let backend = MockBackend::default().with_file("/a.txt", b"a");
+        let fs = mock_fs(&backend);
+
+        backend.state.lock().unwrap().interrupted_listings = 2;
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(backend.state.lock().unwrap().list_calls, 3);
+
+        // Agotados los reintentos el error llega al llamador y no se cachea nada
+        fs.invalidate_dir_cache("/");
+        backend.state.lock().unwrap().interrupted_listings = usize::MAX;
+        assert!(fs.list_ftp_directory_cached("/").is_err());
+        assert!(!fs.dir_cache.lock().unwrap().contains_key("/"));
+
+        // Un directorio vacío de verdad sí se cachea
+        backend.state.lock().unwrap().interrupted_listings = 0;
+        backend.state.lock().unwrap().files.clear();
+        assert!(fs.list_ftp_directory_cached("/").unwrap().is_empty());
+        assert!(fs.dir_cache.lock().unwrap().contains_key("/"));
+    }
+
+    #[test]
+    fn test_mirror_serves_reads_and_queues_writes_while_down() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let backend = MockBackend::default().with_file("/a.txt", b"remote");
+        let options = FsOptions {
+            keepalive: None,
+            mirror: Some(Mirror::open(cache_dir.path()).unwrap()),
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+        fs.load_file_data(inode.ino, "/a.txt").unwrap();
+
+        // Sin servidor: listado y contenido salen del espejo
+        backend.state.lock().unwrap().down = true;
+        fs.invalidate_dir_cache("/");
+        fs.evict_file_data(inode.ino);
+        assert_eq!(fs.list_ftp_directory_cached("/").unwrap().len(), 1);
+        assert_eq!(fs.load_file_data(inode.ino, "/a.txt").unwrap(), b"remote");
+
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"edited").unwrap();
+        let fh = open_handle(&fs, inode.ino, Some(buffer));
+        fs.sync_write_buffer(fh).unwrap();
+        assert_eq!(backend.state.lock().unwrap().files["/a.txt"], b"remote");
+
+        // Al volver, la escritura encolada se sube
+        backend.state.lock().unwrap().down = false;
+        assert_eq!(fs.replay_pending_uploads(), 1);
+        assert_eq!(backend.state.lock().unwrap().files["/a.txt"], b"edited");
+        assert_eq!(fs.replay_pending_uploads(), 0);
+    }
+
+    #[test]
+    fn test_unlink_while_open_defers_delete_until_release() {
+        let backend = MockBackend::default()
+            .with_file("/a.txt", b"still here")
+            .with_file("/b.txt", b"other");
+        let fs = mock_fs(&backend);
+
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let info = find_entry(&files, "a.txt").unwrap();
+        let inode = fs.get_or_create_inode(ROOT_INODE, info);
+        let fh1 = open_handle(&fs, inode.ino, None);
+        let fh2 = open_handle(&fs, inode.ino, None);
+
+        fs.remove_file("/", "/a.txt").unwrap();
+
+        // La ruta ya no existe, pero los handles abiertos siguen leyendo
+        let names: Vec<String> = fs
+            .list_ftp_directory_cached("/")
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, vec!["b.txt"]);
+        assert_eq!(
+            fs.load_file_data(inode.ino, "/a.txt").unwrap(),
+            b"still here"
+        );
+
+        fs.release_handle(inode.ino, fh1).unwrap();
+        assert!(backend.state.lock().unwrap().files.contains_key("/a.txt"));
+        assert!(fs.inodes.lock().unwrap().contains_key(&inode.ino));
+
+        fs.release_handle(inode.ino, fh2).unwrap();
+        assert!(!backend.state.lock().unwrap().files.contains_key("/a.txt"));
+        assert!(!fs.inodes.lock().unwrap().contains_key(&inode.ino));
+    }
+
+    #[test]
+    fn test_truncated_upload_is_detected_and_retried() {
+        let backend = MockBackend::default();
+        let fs = mock_fs(&backend);
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"0123456789").unwrap();
+
+        backend.state.lock().unwrap().truncated_stores = 1;
+        let mut conn = backend.clone();
+        fs.upload_buffer(&mut conn, "/a.bin", &mut buffer).unwrap();
+        assert_eq!(backend.state.lock().unwrap().files["/a.bin"], b"0123456789");
+
+        // Si el servidor sigue sin tenerlo todo tras los reintentos, es un error
+        backend.state.lock().unwrap().truncated_stores = fs.options.retries as usize + 1;
+        assert!(matches!(
+            fs.upload_buffer(&mut conn, "/b.bin", &mut buffer),
+            Err(FtpError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_interrupted_upload_resumes_from_server_size() {
+        let backend = MockBackend::default();
+        let fs = mock_fs(&backend);
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"0123456789").unwrap();
+
+        backend.state.lock().unwrap().interrupted_stores = 1;
+        let mut conn = backend.clone();
+        fs.upload_buffer(&mut conn, "/a.bin", &mut buffer).unwrap();
+        assert_eq!(backend.state.lock().unwrap().files["/a.bin"], b"0123456789");
+        assert_eq!(Stats::get(&fs.stats.reconnects), 1);
+
+        // Un corte antes de enviar nada deja el archivo anterior: no es un prefijo
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .insert("/b.bin".to_string(), b"old".to_vec());
+        backend.state.lock().unwrap().refused_stores = 1;
+        fs.upload_buffer(&mut conn, "/b.bin", &mut buffer).unwrap();
+        assert_eq!(backend.state.lock().unwrap().files["/b.bin"], b"0123456789");
+    }
+
+    #[test]
+    fn test_disk_full_keeps_the_buffer_for_a_retry() {
+        let backend = MockBackend::default().with_file("/a.txt", b"");
+        let fs = mock_fs(&backend);
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"draft").unwrap();
+        let fh = open_handle(&fs, inode.ino, Some(buffer));
+        let buffer = fs.write_buffer_for(Some(fh), inode.ino).unwrap();
+
+        backend.state.lock().unwrap().full = true;
+        let err = fs.sync_write_buffer(fh).unwrap_err();
+        assert_eq!(errno_for(&err), ENOSPC);
+        assert!(buffer.lock().unwrap().dirty);
+
+        // Tras liberar espacio, el mismo buffer se sube
+        backend.state.lock().unwrap().full = false;
+        fs.sync_write_buffer(fh).unwrap();
+        assert_eq!(backend.state.lock().unwrap().files["/a.txt"], b"draft");
+    }
+
+    #[test]
+    fn test_atomic_save_lands_new_content_at_the_target() {
+        let backend = MockBackend::default()
+            .with_file("/file.ext", b"old")
+            .with_file("/file.ext.tmp", b"");
+        let fs = mock_fs(&backend);
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let inode_of = |name: &str| {
+            let info = files.iter().find(|f| f.name == name).unwrap();
+            fs.get_or_create_inode(ROOT_INODE, info)
+        };
+        let (target, temp) = (inode_of("file.ext"), inode_of("file.ext.tmp"));
+        fs.load_file_data(target.ino, "/file.ext").unwrap();
+
+        // El editor escribe el temporal y lo renombra sin cerrarlo
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"new content").unwrap();
+        let fh = open_handle(&fs, temp.ino, Some(buffer));
+        let buffer = fs.write_buffer_for(Some(fh), temp.ino).unwrap();
+        fs.rename_remote("/file.ext.tmp", "/file.ext", ROOT_INODE, "file.ext")
+            .unwrap();
+
+        let state = backend.state.lock().unwrap();
+        assert_eq!(state.files["/file.ext"], b"new content");
+        assert!(!state.files.contains_key("/file.ext.tmp"));
+        drop(state);
+        assert!(!buffer.lock().unwrap().dirty);
+
+        assert_eq!(fs.path_to_inode.lock().unwrap()["/file.ext"], temp.ino);
+        assert_eq!(fs.inodes.lock().unwrap()[&temp.ino].name, "file.ext");
+        assert!(!fs.inodes.lock().unwrap().contains_key(&target.ino));
+        assert!(!fs.read_cache.lock().unwrap().contains_key(&target.ino));
+    }
+
+    #[test]
+    fn test_flush_uploads_idle_buffers_of_open_files() {
+        let backend = MockBackend::default().with_file("/log.txt", b"");
+        let fs = mock_fs(&backend);
+
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+        let fh = open_handle(&fs, inode.ino, Some(WriteBuffer::new()));
+        let buffer = fs.write_buffer_for(Some(fh), inode.ino).unwrap();
+
+        // Recién escrito: todavía no toca subirlo
+        buffer.lock().unwrap().write_at(0, b"line 1\n").unwrap();
+        assert_eq!(fs.flush_idle_buffers(Duration::from_secs(60)), 0);
+        assert_eq!(backend.state.lock().unwrap().files["/log.txt"], b"");
+
+        assert_eq!(fs.flush_idle_buffers(Duration::ZERO), 1);
+        assert_eq!(backend.state.lock().unwrap().files["/log.txt"], b"line 1\n");
+        assert!(!buffer.lock().unwrap().dirty);
+
+        // Ya limpio: nada que subir
+        assert_eq!(fs.flush_idle_buffers(Duration::ZERO), 0);
+    }
+
+    #[test]
+    fn test_recreating_unlinked_file_cancels_deferred_delete() {
+        let backend = MockBackend::default().with_file("/a.txt", b"old");
+        let fs = mock_fs(&backend);
+
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+        let fh = open_handle(&fs, inode.ino, None);
+
+        fs.remove_file("/", "/a.txt").unwrap();
+        fs.cancel_deferred_unlink("/a.txt");
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .insert("/a.txt".to_string(), b"new".to_vec());
+        fs.invalidate_dir_cache("/");
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let recreated = fs.get_or_create_inode(ROOT_INODE, &info);
+        assert_ne!(recreated.ino, inode.ino);
+
+        fs.release_handle(inode.ino, fh).unwrap();
+        assert_eq!(backend.state.lock().unwrap().files["/a.txt"], b"new");
+        assert!(!fs.inodes.lock().unwrap().contains_key(&inode.ino));
+        assert!(fs.inodes.lock().unwrap().contains_key(&recreated.ino));
+    }
+
+    #[test]
+    fn test_ftp_path_xattr() {
+        let backend = MockBackend::default().with_file("/a.txt", b"");
+        let fs = mock_fs(&backend);
+
+        let info = fs.list_ftp_directory_cached("/").unwrap().remove(0);
+        let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+        let list_calls = backend.state.lock().unwrap().list_calls;
+
+        assert_eq!(
+            fs.synthetic_xattr(inode.ino, OsStr::new(FTP_PATH_XATTR)),
+            Some(b"/a.txt".to_vec())
+        );
+        assert_eq!(
+            fs.synthetic_xattr(inode.ino, OsStr::new("com.apple.FinderInfo")),
+            None
+        );
+        assert_eq!(backend.state.lock().unwrap().list_calls, list_calls);
+    }
+
+    #[test]
+    fn test_shadow_temp_file_is_uploaded_on_rename() {
+        let backend = MockBackend::default().with_file("/a.txt", b"old");
+        let fs = mock_fs(&backend);
+
+        let inode = fs.create_shadow(ROOT_INODE, ".a.txt.swp", "/.a.txt.swp", 0o644);
+        fs.shadow_buffer(inode.ino)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .write_at(0, b"new contents")
+            .unwrap();
+
+        // Visible localmente, nunca en el servidor
+        assert_eq!(
+            fs.shadow_entries(ROOT_INODE),
+            vec![(inode.ino, ".a.txt.swp".to_string())]
+        );
+        assert_eq!(fs.shadow_attr(inode.ino).unwrap().size, 12);
+        assert!(!backend
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .contains_key("/.a.txt.swp"));
+
+        fs.materialize_shadow(inode.ino, "/a.txt").unwrap();
+        assert_eq!(
+            backend.state.lock().unwrap().files["/a.txt"],
+            b"new contents"
+        );
+        assert!(fs.shadow_entries(ROOT_INODE).is_empty());
+    }
+
+    #[test]
+    fn test_removed_shadow_file_disappears() {
+        let backend = MockBackend::default();
+        let fs = mock_fs(&backend);
+
+        let inode = fs.create_shadow(ROOT_INODE, "x~", "/x~", 0o644);
+        fs.remove_shadow(inode.ino);
+
+        assert!(fs.shadow_entries(ROOT_INODE).is_empty());
+        assert!(!fs.inodes.lock().unwrap().contains_key(&inode.ino));
+        assert!(!fs.path_to_inode.lock().unwrap().contains_key("/x~"));
+    }
+
+    #[test]
+    fn test_flush_all_syncs_only_dirty_buffers() {
+        let backend = MockBackend::default()
+            .with_file("/a.txt", b"")
+            .with_file("/b.txt", b"old");
+        let fs = mock_fs(&backend);
+
+        for (name, contents) in [("a.txt", Some(&b"new"[..])), ("b.txt", None)] {
+            let info = fs
+                .list_ftp_directory_cached("/")
+                .unwrap()
+                .into_iter()
+                .find(|f| f.name == name)
+                .unwrap();
+            let inode = fs.get_or_create_inode(ROOT_INODE, &info);
+            let mut buffer = WriteBuffer::new();
+            if let Some(contents) = contents {
+                buffer.write_at(0, contents).unwrap();
+            }
+            let fh = open_handle(&fs, inode.ino, Some(buffer));
+        }
+
+        assert_eq!(fs.flush_all(), 1);
+        let state = backend.state.lock().unwrap();
+        assert_eq!(state.files["/a.txt"], b"new");
+        assert_eq!(state.files["/b.txt"], b"old");
+    }
+
+    #[test]
+    fn test_concurrent_operations_keep_one_inode_per_path() {
+        const THREADS: usize = 8;
+        let mut backend = MockBackend::default();
+        for i in 0..4 {
+            backend = backend.with_file(&format!("/f{}.txt", i), b"data");
+        }
+        let fs = mock_fs(&backend);
+
+        // Búsquedas, lecturas, escrituras, cierres e invalidaciones a la vez;
+        // en depuración, tomar los mutex fuera de orden haría fallar el hilo
+        let (done, finished) = mpsc::channel();
+        for t in 0..THREADS {
+            let fs = fs.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                for round in 0..50 {
+                    let files = fs.list_ftp_directory_cached("/").unwrap();
+                    for info in &files {
+                        let inode = fs.get_or_create_inode(ROOT_INODE, info);
+                        fs.get_attr_cached(inode.ino);
+                        fs.load_file_data(inode.ino, &inode.ftp_path).unwrap();
+                    }
+                    let info = &files[(t + round) % files.len()];
+                    let inode = fs.get_or_create_inode(ROOT_INODE, info);
+                    let mut buffer = WriteBuffer::new();
+                    buffer.write_at(0, &[t as u8]).unwrap();
+                    let fh = open_handle(&fs, inode.ino, Some(buffer));
+                    fs.release_handle(inode.ino, fh).unwrap();
+                    fs.invalidate_dir_cache("/");
+                    fs.flush_all();
+                }
+                done.send(()).unwrap();
+            });
+        }
+        drop(done);
+        for _ in 0..THREADS {
+            finished
+                .recv_timeout(Duration::from_secs(60))
+                .expect("a thread panicked or deadlocked");
+        }
+
+        let path_to_inode = fs.path_to_inode.lock().unwrap();
+        let inodes = fs.inodes.lock().unwrap();
+        for (path, ino) in path_to_inode.iter() {
+            assert_eq!(&inodes[ino].ftp_path, path);
+        }
+        let mut paths: Vec<&String> = inodes
+            .values()
+            .filter(|inode| inode.ino != ROOT_INODE)
+            .map(|inode| &inode.ftp_path)
+            .collect();
+        let count = paths.len();
+        paths.sort();
+        paths.dedup();
+        assert_eq!(paths.len(), count);
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn test_shutdown_flushes_and_quits_once() {
+        let backend = MockBackend::default().with_file("/a.txt", b"");
+        let fs = mock_fs(&backend);
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let inode = fs.get_or_create_inode(ROOT_INODE, find_entry(&files, "a.txt").unwrap());
+        let mut buffer = WriteBuffer::new();
+        buffer.write_at(0, b"new").unwrap();
+        let fh = open_handle(&fs, inode.ino, Some(buffer));
+
+        // Los clones (como los de los hilos de fondo) no cierran nada
+        drop(fs.clone());
+        assert_eq!(backend.state.lock().unwrap().quit_calls, 0);
+
+        fs.shutdown().unwrap();
+        {
+            let state = backend.state.lock().unwrap();
+            assert_eq!(state.files["/a.txt"], b"new");
+            assert_eq!(state.quit_calls, 1);
+        }
+        assert!(fs.dir_cache.lock().unwrap().is_empty());
+
+        // Ni una segunda llamada ni el Drop del handle original repiten QUIT
+        fs.shutdown().unwrap();
+        drop(fs);
+        assert_eq!(backend.state.lock().unwrap().quit_calls, 1);
+
+        // Sin llamarla, la cierra el Drop
+        let fs = mock_fs(&backend);
+        drop(fs);
+        assert_eq!(backend.state.lock().unwrap().quit_calls, 2);
+    }
+
+    #[test]
+    fn test_ignore_list_matches_whole_names() {
+        let defaults = IgnoreList::default();
+        assert!(defaults.matches(".main.rs.swp"));
+        assert!(defaults.matches("notes.txt~"));
+        assert!(defaults.matches(".#notes.txt"));
+        assert!(!defaults.matches("swap.swp.txt"));
+        assert!(!defaults.matches(".git"));
+        assert!(!defaults.matches("__pycache__"));
+
+        let ignore =
+            IgnoreList::new(&["*.tmp", "*.lock", "*.pid", ".DS_Store", ".attach_pid*"]).unwrap();
+        // Extensiones: solo sufijo exacto
+        assert!(ignore.matches("template.tmp"));
+        assert!(!ignore.matches("notes.tmplate"));
+        assert!(!ignore.matches("blocklist.txt"));
+        assert!(!ignore.matches("rapid.json"));
+        assert!(ignore.matches("Cargo.lock"));
+        // Nombres exactos y prefijos
+        assert!(ignore.matches(".DS_Store"));
+        assert!(!ignore.matches("my.DS_Store.bak"));
+        assert!(ignore.matches(".attach_pid12345"));
+        assert!(!ignore.matches("x.attach_pid"));
+
+        assert!(!IgnoreList::none().matches(".main.rs.swp"));
+        assert!(IgnoreList::new(&["[unclosed"]).is_err());
+    }
+
+    #[test]
+    fn test_check_access() {
+        let fs = mock_fs(&MockBackend::default());
+        let mut attr = fs.get_attr_cached(ROOT_INODE).unwrap();
+        attr.kind = FileType::RegularFile;
+        attr.perm = 0o640;
+        attr.uid = 1000;
+        attr.gid = 100;
+
+        // Propietario: lectura y escritura, sin ejecución
+        assert!(check_access(&attr, 1000, &[100], libc::R_OK | libc::W_OK));
+        assert!(!check_access(&attr, 1000, &[100], libc::X_OK));
+        // Grupo: solo lectura
+        assert!(check_access(&attr, 2000, &[100], libc::R_OK));
+        assert!(!check_access(&attr, 2000, &[100], libc::W_OK));
+        // También como grupo suplementario
+        assert!(check_access(&attr, 2000, &[200, 100], libc::R_OK));
+        // Otros: nada
+        assert!(!check_access(&attr, 2000, &[200], libc::R_OK));
+        // Root lee y escribe, pero no ejecuta sin bits x
+        assert!(check_access(&attr, 0, &[0], libc::R_OK | libc::W_OK));
+        assert!(!check_access(&attr, 0, &[0], libc::X_OK));
+    }
+
+    #[test]
+    fn test_parse_groups_from_proc_status() {
+        let status = "Name:\tcat\nUid:\t1000\t1000\t1000\t1000\n\
+                      Gid:\t1000\t1000\t1000\t1000\nGroups:\t4 24 27 1000 \nNSpid:\t42\n";
+        assert_eq!(parse_groups(status), vec![4, 24, 27, 1000]);
+        assert_eq!(parse_groups("Groups:\t\n"), Vec::<u32>::new());
+        assert_eq!(parse_groups("Name:\tcat\n"), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_read_only_mount_is_not_writable() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let options = FsOptions {
+            keepalive: None,
+            read_only: true,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        assert_eq!(fs.check_writable(), Err(EROFS));
+        assert_eq!(mock_fs(&backend).check_writable(), Ok(()));
+
+        // unlink se rechaza antes de llegar al servidor
+        let name = OsStr::new("a.txt");
+        assert_eq!(fs.unlink_entry(ROOT_INODE, name), Err(EROFS));
+        assert!(backend.state.lock().unwrap().files.contains_key("/a.txt"));
+        assert_eq!(mock_fs(&backend).unlink_entry(ROOT_INODE, name), Ok(()));
+        assert!(!backend.state.lock().unwrap().files.contains_key("/a.txt"));
+    }
+
+    #[test]
+    fn test_owner_and_umask_override() {
+        let backend = MockBackend::default().with_file("/a.txt", b"hello");
+        let options = FsOptions {
+            keepalive: None,
+            uid: Some(4242),
+            gid: Some(4343),
+            umask: 0o027,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend, options).unwrap();
+
+        let root = fs.get_attr_cached(ROOT_INODE).unwrap();
+        assert_eq!((root.uid, root.gid, root.perm), (4242, 4343, 0o750));
+
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let inode = fs.get_or_create_inode(ROOT_INODE, &files[0]);
+        assert_eq!(inode.attr.uid, 4242);
+        assert_eq!(inode.attr.gid, 4343);
+        assert_eq!(inode.attr.perm, 0o640);
+    }
+
+    #[test]
+    fn test_mount_subpath_as_root() {
+        let backend = MockBackend::default()
+            .with_file("/pub/data/a.txt", b"hello")
+            .with_file("/pub/readme", b"");
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .dirs
+            .extend(["/pub".to_string(), "/pub/data".to_string()]);
+        let with_root = |root: &str| {
+            let options = FsOptions {
+                keepalive: None,
+                root: root.to_string(),
+                ..FsOptions::default()
+            };
+            FtpFs::with_options(backend.clone(), options)
+        };
+
+        let fs = with_root("/pub/data/").unwrap();
+        let root = fs.inodes.lock().unwrap()[&ROOT_INODE].ftp_path.clone();
+        assert_eq!(root, "/pub/data");
+        let files = fs.list_ftp_directory_cached(&root).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "/pub/data/a.txt");
+
+        let err = with_root("/pub/missing").err().unwrap().to_string();
+        assert!(err.contains("does not exist"), "{}", err);
+        let err = with_root("/pub/readme").err().unwrap().to_string();
+        assert!(err.contains("not a directory"), "{}", err);
+
+        // Con --mkdir-root se crean los que falten, pero no a través de un archivo
+        let mkdir_root = |root: &str| {
+            let options = FsOptions {
+                keepalive: None,
+                root: root.to_string(),
+                mkdir_root: true,
+                ..FsOptions::default()
+            };
+            FtpFs::with_options(backend.clone(), options)
+        };
+        mkdir_root("/pub/new/deeper").unwrap();
+        let dirs = backend.state.lock().unwrap().dirs.clone();
+        assert!(dirs.contains("/pub/new") && dirs.contains("/pub/new/deeper"));
+        let err = mkdir_root("/pub/readme/sub").err().unwrap().to_string();
+        assert!(err.contains("/pub/readme is a file"), "{}", err);
+    }
+
+    #[test]
+    fn test_root_dot_entries_without_network() {
+        let backend = MockBackend::default();
+        let fs = mock_fs(&backend);
+        // Caché de atributos fría
+        fs.attr_cache.lock().unwrap().clear();
+
+        let root = fs.inodes.lock().unwrap()[&ROOT_INODE].clone();
+        let dot = fs.dot_entry_attr(&root, ".").unwrap();
+        let dotdot = fs.dot_entry_attr(&root, "..").unwrap();
+        assert_eq!(dot.ino, ROOT_INODE);
+        assert_eq!(dotdot.ino, ROOT_INODE);
+        assert_eq!(dotdot.kind, FileType::Directory);
+        assert!(fs.dot_entry_attr(&root, "a.txt").is_none());
+        assert_eq!(backend.state.lock().unwrap().list_calls, 0);
+    }
+
+    #[test]
+    fn test_directory_entries_carry_their_attributes() {
+        let backend = MockBackend::default()
+            .with_file("/a.txt", b"hello")
+            .with_file("/.main.rs.swp", b"x");
+        backend
+            .state
+            .lock()
+            .unwrap()
+            .dirs
+            .insert("/sub".to_string());
+        let options = FsOptions {
+            keepalive: None,
+            show_control: true,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        let root = fs.inodes.lock().unwrap()[&ROOT_INODE].clone();
+
+        let entries = fs.dir_entries(&root, 0).unwrap();
+        let mut names: Vec<&str> = entries.iter().map(|(_, _, name)| name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, [".", "..", STATS_FILE_NAME, "a.txt", "sub"]);
+
+        // Un solo listado da los atributos de todas las entradas
+        for (ino, kind, name) in &entries {
+            let attr = fs.entry_attr(&root, *ino, name).unwrap();
+            assert_eq!(attr.kind, *kind, "{}", name);
+            if name == "a.txt" {
+                assert_eq!(attr.size, 5);
+            }
+        }
+        assert_eq!(backend.state.lock().unwrap().list_calls, 1);
+    }
+
+    #[test]
+    fn test_utf8_name_round_trip() {
+        let listed = FtpConnection::parse_list_line(
+            "/pub",
+            "-rw-r--r--    1 ftp      ftp            12 Mar 14 10:00 café.txt",
+        )
+        .unwrap();
+        assert_eq!(listed.name, "café.txt");
+
+        // El kernel devuelve a lookup los mismos bytes que readdir entregó
+        let backend = MockBackend::default().with_file("/café.txt", b"bonjour");
+        let fs = mock_fs(&backend);
+        let listed = fs.list_ftp_directory_cached("/").unwrap();
+        let name = OsStr::from_bytes(find_entry(&listed, "café.txt").unwrap().name.as_bytes());
+        let (_, attr) = fs.lookup_entry(ROOT_INODE, name).unwrap();
+        assert_eq!(attr.size, 7);
+
+        // Otra forma Unicode del mismo nombre (NFD) no debe confundirse
+        let nfd = OsStr::new("cafe\u{301}.txt");
+        assert_eq!(fs.lookup_entry(ROOT_INODE, nfd).unwrap_err(), ENOENT);
+        // Los bytes que no son UTF-8 se rechazan sin consultar al servidor
+        let latin1 = OsStr::from_bytes(b"caf\xe9.txt");
+        assert_eq!(fs.lookup_entry(ROOT_INODE, latin1).unwrap_err(), EILSEQ);
+    }
+
+    #[test]
+    fn test_case_insensitive_lookup_finds_the_existing_inode() {
+        let backend = MockBackend::default().with_file("/File.txt", b"hello");
+        let options = FsOptions {
+            keepalive: None,
+            case_insensitive: true,
+            ..FsOptions::default()
+        };
+        let fs = FtpFs::with_options(backend.clone(), options).unwrap();
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        let inode = fs.get_or_create_inode(ROOT_INODE, find_entry(&files, "File.txt").unwrap());
+
+        // Otra grafía resuelve a la misma entrada y al mismo inodo, con el
+        // nombre tal como lo lista el servidor
+        let found = fs.listed_entry(&files, "FILE.TXT").unwrap();
+        assert_eq!(found.name, "File.txt");
+        assert_eq!(fs.get_or_create_inode(ROOT_INODE, found).ino, inode.ino);
+        let key = fs.path_key("/file.TXT");
+        assert_eq!(
+            fs.path_to_inode.lock().unwrap().get(key.as_ref()),
+            Some(&inode.ino)
+        );
+        assert!(fs.dir_cache_fresh("/"));
+        assert_eq!(backend.state.lock().unwrap().list_calls, 1);
+
+        fs.remember_missing("/Missing");
+        assert!(fs
+            .negative_cache
+            .lock()
+            .unwrap()
+            .get(&fs.path_key("/MISSING"))
+            .is_some());
+
+        // Sin la opción, las mayúsculas distinguen archivos
+        let fs = mock_fs(&backend);
+        let files = fs.list_ftp_directory_cached("/").unwrap();
+        assert!(fs.listed_entry(&files, "FILE.TXT").is_none());
     }
 }
@@ -2,15 +2,26 @@
 //!
 //! Handles FTP connections and operations using the suppaftp crate.
 
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt::Display;
 use std::io::{self, Read, Write};
-use std::path::Path;
-use std::time::SystemTime;
-
-use anyhow::{Context, Result};
-use log::{debug, error, info, warn};
-use suppaftp::native_tls::TlsConnector;
-use suppaftp::types::{FileType, Mode};
-use suppaftp::{FtpStream, NativeTlsConnector, NativeTlsFtpStream};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use encoding_rs::Encoding;
+use log::{debug, info, warn};
+use suppaftp::rustls::pki_types::ServerName;
+use suppaftp::rustls::{ClientConfig, ClientConnection, StreamOwned};
+use suppaftp::types::{FileType, FormatControl, Mode, Response};
+use suppaftp::{FtpStream, RustlsConnector, RustlsFtpStream, Status};
+
+use crate::throttle::{Throttle, ThrottledReader};
+use crate::tls;
 
 /// Information about a file or directory on the FTP server
 #[derive(Debug, Clone)]
@@ -21,6 +32,630 @@ pub struct FtpFileInfo {
     pub is_dir: bool,
     pub permissions: u32,
     pub modified_time: Option<SystemTime>,
+    /// Target of a symbolic link as listed by the server (None otherwise)
+    pub link_target: Option<String>,
+    /// Bytes of the name on the server when `--name-encoding` is set (None
+    /// otherwise, the name being sent as UTF-8)
+    pub raw_name: Option<Vec<u8>>,
+}
+
+/// Error returned by FTP operations
+///
+/// Replies the filesystem can act on are classified so it can answer with a
+/// precise errno; anything else is kept as a message.
+#[derive(Debug, thiserror::Error)]
+pub enum FtpError {
+    /// The path does not exist on the server (550)
+    #[error("No such file or directory")]
+    NotFound,
+    /// The server refused the operation (553, 530, or a 550 saying so)
+    #[error("Permission denied")]
+    PermissionDenied,
+    /// A directory operation was attempted on a file
+    #[error("Not a directory")]
+    NotADirectory,
+    /// A file operation was attempted on a directory
+    #[error("Is a directory")]
+    IsADirectory,
+    /// Temporary negative reply (4xx); retrying may succeed
+    #[error("Temporary failure")]
+    Transient,
+    /// The server has no room left for the file (452, 552)
+    #[error("No space left on server")]
+    NoSpace,
+    /// The name can't be written in the server's `--name-encoding`
+    #[error("Name not representable in the server's character set")]
+    InvalidName,
+    /// Any other unexpected reply or malformed response
+    #[error("{0}")]
+    Protocol(String),
+    /// Connection or socket failure
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl FtpError {
+    /// Whether the operation is worth retrying after a reconnect
+    ///
+    /// Connection failures and 4xx (transient negative) replies are
+    /// retryable; 5xx replies such as "file not found" are permanent.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, FtpError::Transient | FtpError::Io(_))
+    }
+}
+
+impl From<suppaftp::FtpError> for FtpError {
+    fn from(error: suppaftp::FtpError) -> Self {
+        let response = match error {
+            suppaftp::FtpError::ConnectionError(e) => return FtpError::Io(e),
+            suppaftp::FtpError::UnexpectedResponse(response) => response,
+            other => return FtpError::Protocol(other.to_string()),
+        };
+
+        let code = response.status.code();
+        let text = String::from_utf8_lossy(&response.body).trim().to_string();
+        debug!("Server replied {}", text);
+        match code {
+            452 | 552 => FtpError::NoSpace,
+            400..=499 => FtpError::Transient,
+            530 | 532 | 553 => FtpError::PermissionDenied,
+            550 if text.to_lowercase().contains("permission denied") => FtpError::PermissionDenied,
+            550 => FtpError::NotFound,
+            _ => FtpError::Protocol(text),
+        }
+    }
+}
+
+/// Result of an FTP operation
+pub type Result<T, E = FtpError> = std::result::Result<T, E>;
+
+/// Build an [`FtpError::Protocol`] from a format string
+macro_rules! protocol_error {
+    ($($arg:tt)*) => {
+        FtpError::Protocol(format!($($arg)*))
+    };
+}
+
+/// Describe what was being done when an operation failed
+///
+/// Messages and socket errors get the description prepended; classified
+/// errors keep their variant (so the errno survives) and the description is
+/// logged instead.
+trait Context<T> {
+    fn context<C: Display>(self, context: C) -> Result<T>;
+}
+
+impl<T, E: Into<FtpError>> Context<T> for Result<T, E> {
+    fn context<C: Display>(self, context: C) -> Result<T> {
+        self.map_err(|error| match error.into() {
+            FtpError::Protocol(message) => protocol_error!("{}: {}", context, message),
+            FtpError::Io(e) => {
+                FtpError::Io(io::Error::new(e.kind(), format!("{}: {}", context, e)))
+            }
+            other => {
+                debug!("{}: {}", context, other);
+                other
+            }
+        })
+    }
+}
+
+/// Data channel mode used for transfers and listings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DataMode {
+    #[default]
+    Passive,
+    Active,
+}
+
+/// Address family used to reach the server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpFamily {
+    /// Try every resolved address in resolver order
+    #[default]
+    Any,
+    /// Only IPv4 addresses
+    V4,
+    /// Only IPv6 addresses
+    V6,
+}
+
+impl IpFamily {
+    fn accepts(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpFamily::Any => true,
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Resolve `server:port`, keeping only addresses of the preferred family
+pub fn resolve(server: &str, port: u16, family: IpFamily) -> Result<Vec<SocketAddr>> {
+    let addr = format!("{}:{}", server, port);
+    let addrs: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .context(format!("Failed to resolve {}", addr))?
+        .filter(|a| family.accepts(a))
+        .collect();
+    if addrs.is_empty() {
+        return Err(match family {
+            IpFamily::Any => protocol_error!("No address found for {}", addr),
+            IpFamily::V4 => protocol_error!("No IPv4 address found for {}", addr),
+            IpFamily::V6 => protocol_error!("No IPv6 address found for {}", addr),
+        });
+    }
+    Ok(addrs)
+}
+
+/// Data channel mode to request for a control connection to `peer`
+///
+/// PASV replies can only carry an IPv4 address, so over IPv6 passive mode
+/// has to use EPSV (RFC 2428).
+fn data_channel_mode(mode: DataMode, peer: &SocketAddr) -> Mode {
+    match mode {
+        DataMode::Passive if peer.is_ipv6() => Mode::ExtendedPassive,
+        DataMode::Passive => Mode::Passive,
+        DataMode::Active => Mode::Active,
+    }
+}
+
+/// Classify the failure of a listing transfer
+///
+/// When the data connection breaks partway suppaftp discards the lines read
+/// so far and reports a bad response. That is a connection failure worth
+/// retrying, not a permanent error, and never an empty directory.
+fn listing_error(error: suppaftp::FtpError) -> FtpError {
+    match error {
+        suppaftp::FtpError::BadResponse => FtpError::Io(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "data connection failed during the listing",
+        )),
+        other => other.into(),
+    }
+}
+
+/// Parse a local port range for `--data-ports`: `50000-50100` or a single port
+pub fn parse_port_range(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let invalid = || format!("'{}' is not a port range like 50000-50100", value);
+    let port = |part: &str| match part.trim().parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(invalid()),
+    };
+    let (start, end) = match value.split_once('-') {
+        Some((start, end)) => (port(start)?, port(end)?),
+        None => (port(value)?, port(value)?),
+    };
+    if start > end {
+        return Err(invalid());
+    }
+    Ok(start..=end)
+}
+
+/// Parse a character set for `--name-encoding`: `shift_jis`, `latin1`, `gbk`...
+///
+/// Any WHATWG encoding label is accepted.
+pub fn parse_name_encoding(value: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(value.trim().as_bytes())
+        .ok_or_else(|| format!("'{}' is not a known character set", value))
+}
+
+/// Parse a comma-separated extension list for `--ascii-ext`: `.txt,.cfg`
+///
+/// Extensions are returned lowercased and without the leading dot.
+pub fn parse_extensions(value: &str) -> Result<Vec<String>, String> {
+    value
+        .split(',')
+        .map(|ext| {
+            let ext = ext.trim().trim_start_matches('.');
+            if ext.is_empty() || ext.contains('/') {
+                Err(format!(
+                    "'{}' is not an extension list like .txt,.cfg",
+                    value
+                ))
+            } else {
+                Ok(ext.to_lowercase())
+            }
+        })
+        .collect()
+}
+
+/// SITE subcommands known to leave the session's transfer type alone
+const SITE_KEEPS_TYPE: &[&str] = &["CHMOD", "UTIME", "UMASK", "HELP", "IDLE"];
+
+/// Whether `command` may change the transfer type behind our back
+///
+/// TYPE and REIN do; so might a SITE subcommand we know nothing about, but
+/// the ones we send ourselves don't, and forgetting the type after each of
+/// them would cost a TYPE before the next transfer.
+fn may_change_transfer_type(command: &str) -> bool {
+    let mut words = command.split(' ');
+    match words.next().unwrap_or_default().to_uppercase().as_str() {
+        "TYPE" | "REIN" => true,
+        "SITE" => {
+            let subcommand = words.next().unwrap_or_default().to_uppercase();
+            !SITE_KEEPS_TYPE.contains(&subcommand.as_str())
+        }
+        _ => false,
+    }
+}
+
+/// Whether `path` ends in one of `extensions` (as from [`parse_extensions`])
+pub fn is_ascii_path(extensions: &[String], path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => {
+            extensions.iter().any(|e| e.eq_ignore_ascii_case(ext))
+        }
+        _ => false,
+    }
+}
+
+/// Error for operations refused while the server can't be reached
+///
+/// It is a connection error, so callers treat it as transient (and fall
+/// back to the local mirror, if any).
+fn unavailable(detail: String) -> FtpError {
+    FtpError::Io(io::Error::new(
+        io::ErrorKind::NotConnected,
+        format!("Server unavailable, {}", detail),
+    ))
+}
+
+/// Finish a login that the server answered with 332 "need account"
+///
+/// `send` issues the ACCT command on the same control connection. Without
+/// an account the error says so, instead of a bare unexpected reply.
+fn complete_login(
+    login: suppaftp::FtpResult<()>,
+    account: Option<&str>,
+    send: impl FnOnce(String) -> suppaftp::FtpResult<Response>,
+) -> Result<()> {
+    match login {
+        Err(suppaftp::FtpError::UnexpectedResponse(response))
+            if response.status == Status::LoginNeedAccount =>
+        {
+            let account = account.ok_or_else(|| {
+                protocol_error!(
+                    "Server requires an account (ACCT) to log in; pass it with --account"
+                )
+            })?;
+            debug!("Server asked for an account, sending ACCT");
+            send(format!("ACCT {}", account))?;
+            Ok(())
+        }
+        other => Ok(other?),
+    }
+}
+
+/// Send USER and, if the server asks for it, PASS through `send`
+///
+/// Used instead of suppaftp's `login` so the exchange goes through the
+/// same traced path as every other command.
+fn send_login(
+    username: &str,
+    password: &str,
+    mut send: impl FnMut(&str, &[Status]) -> suppaftp::FtpResult<Response>,
+) -> suppaftp::FtpResult<()> {
+    let reply = send(
+        &format!("USER {}", username),
+        &[Status::LoggedIn, Status::NeedPassword],
+    )?;
+    if reply.status == Status::NeedPassword {
+        send(&format!("PASS {}", password), &[Status::LoggedIn])?;
+    }
+    Ok(())
+}
+
+/// Log a command sent on the control connection (`--verbose-ftp`)
+fn trace_command(verbose: bool, command: &str) {
+    if verbose {
+        info!("> {}", redact(command));
+    }
+}
+
+/// Log the server greeting, which suppaftp reads while connecting
+/// (`--verbose-ftp`)
+fn trace_greeting(verbose: bool, greeting: Option<&str>) {
+    if verbose {
+        for line in greeting.unwrap_or_default().lines() {
+            info!("< {}", line);
+        }
+    }
+}
+
+/// Log every line of a reply read on the control connection (`--verbose-ftp`)
+fn trace_reply(verbose: bool, response: &Response) {
+    if verbose {
+        for line in String::from_utf8_lossy(&response.body).lines() {
+            info!("< {}", line);
+        }
+    }
+}
+
+/// Log the reply carried by a refused command (`--verbose-ftp`)
+///
+/// For the exchanges suppaftp runs itself (data transfers, AUTH TLS) the
+/// replies are read internally, and only a refusal hands them back.
+fn trace_refusal<T>(verbose: bool, result: &suppaftp::FtpResult<T>) {
+    if let Err(suppaftp::FtpError::UnexpectedResponse(response)) = result {
+        trace_reply(verbose, response);
+    }
+}
+
+/// Send `command` through `send`, logging it and its reply (`--verbose-ftp`)
+fn traced(
+    verbose: bool,
+    command: &str,
+    send: impl FnOnce() -> suppaftp::FtpResult<Response>,
+) -> suppaftp::FtpResult<Response> {
+    trace_command(verbose, command);
+    let result = send();
+    match &result {
+        Ok(response) | Err(suppaftp::FtpError::UnexpectedResponse(response)) => {
+            trace_reply(verbose, response)
+        }
+        Err(_) => {}
+    }
+    result
+}
+
+/// Hide the password of a `PASS` command and the account of an `ACCT`
+fn redact(command: &str) -> String {
+    match command.split_once(' ') {
+        Some((verb, _))
+            if verb.eq_ignore_ascii_case("PASS") || verb.eq_ignore_ascii_case("ACCT") =>
+        {
+            format!("{} ****", verb)
+        }
+        _ => command.to_string(),
+    }
+}
+
+/// Port in `--data-ports` to try first for the next data listener
+///
+/// Rotating through the range avoids reusing the port of the connection
+/// that just closed, which may still be in TIME_WAIT.
+static NEXT_DATA_PORT: AtomicUsize = AtomicUsize::new(0);
+
+/// Listen on `ip` at a port in `ports` for an active-mode data connection
+///
+/// Ports already in use are skipped; any other error is returned at once.
+fn listen_in_range(ip: IpAddr, ports: &RangeInclusive<u16>) -> io::Result<TcpListener> {
+    let count = usize::from(*ports.end() - *ports.start()) + 1;
+    let first = NEXT_DATA_PORT.fetch_add(1, Ordering::Relaxed);
+    let mut last_error = None;
+    for i in 0..count {
+        let port = *ports.start() + ((first + i) % count) as u16;
+        match TcpListener::bind((ip, port)) {
+            Ok(listener) => {
+                debug!("Listening for the data connection on {}:{}", ip, port);
+                return Ok(listener);
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                ) =>
+            {
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!(
+            "no free local port in {}-{} for the data connection ({})",
+            ports.start(),
+            ports.end(),
+            last_error.map_or_else(String::new, |e| e.to_string())
+        ),
+    ))
+}
+
+/// Wait up to `timeout` for the server to open the data connection
+fn accept_within(listener: &TcpListener, timeout: Duration) -> io::Result<TcpStream> {
+    listener.set_nonblocking(true)?;
+    let started = Instant::now();
+    loop {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                debug!("Data connection from {}", peer);
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if started.elapsed() >= timeout {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "the server did not open the data connection",
+                    ));
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// PORT (EPRT over IPv6) command announcing a data listener at `addr`
+fn port_command(addr: SocketAddr) -> String {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let [a, b, c, d] = addr.ip().octets();
+            let port = addr.port();
+            format!("PORT {},{},{},{},{},{}", a, b, c, d, port >> 8, port & 0xff)
+        }
+        SocketAddr::V6(addr) => format!("EPRT |2|{}|{}|", addr.ip(), addr.port()),
+    }
+}
+
+/// Data port of a PASV reply: `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)`
+fn parse_pasv_port(reply: &str) -> Option<u16> {
+    let text = reply.get(4..)?;
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let fields: Vec<u16> = text[start..]
+        .split(|c: char| !c.is_ascii_digit() && c != ',')
+        .next()?
+        .split(',')
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    match fields[..] {
+        [_, _, _, _, high, low] if high < 256 && low < 256 => Some((high << 8) | low),
+        _ => None,
+    }
+}
+
+/// Data port of an EPSV reply: `229 Entering Extended Passive Mode (|||port|)`
+fn parse_epsv_port(reply: &str) -> Option<u16> {
+    let (_, rest) = reply.split_once("|||")?;
+    rest.split('|').next()?.parse().ok()
+}
+
+/// Data connection opened by [`FtpConnection::open_data`]
+enum DataConnection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for DataConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            DataConnection::Plain(stream) => stream.read(buf),
+            DataConnection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for DataConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            DataConnection::Plain(stream) => stream.write(buf),
+            DataConnection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            DataConnection::Plain(stream) => stream.flush(),
+            DataConnection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Drop for DataConnection {
+    fn drop(&mut self) {
+        // Without close_notify the server may take an upload for truncated
+        if let DataConnection::Tls(stream) = self {
+            let stream = &mut **stream;
+            let _ = stream.flush();
+            stream.conn.send_close_notify();
+            if let Err(e) = stream.conn.write_tls(&mut stream.sock) {
+                debug!("Failed to close the TLS data connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Directory listing format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListFormat {
+    /// MLSD when advertised, otherwise LIST guessing UNIX or MS-DOS per line
+    #[default]
+    Auto,
+    /// LIST in `ls -l` format
+    Unix,
+    /// LIST in MS-DOS/IIS format (`01-15-24  10:30AM  <DIR>  name`)
+    Dos,
+    /// MLSD machine-readable facts
+    Mlsd,
+}
+
+impl ListFormat {
+    fn name(self) -> &'static str {
+        match self {
+            ListFormat::Auto => "auto",
+            ListFormat::Unix => "unix",
+            ListFormat::Dos => "dos",
+            ListFormat::Mlsd => "mlsd",
+        }
+    }
+}
+
+/// Connection settings beyond host and credentials
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Use explicit TLS (AUTH TLS) on the control channel
+    pub use_tls: bool,
+    /// Negotiate TLS before the greeting (implicit FTPS, usually port 990)
+    /// instead of upgrading with AUTH TLS; implies `use_tls`
+    pub implicit_tls: bool,
+    /// Always perform a full TLS handshake on data connections instead of
+    /// resuming the control connection's session
+    pub no_tls_resume: bool,
+    /// Accept any server certificate instead of verifying it against the
+    /// system's root certificates
+    pub no_tls_verify: bool,
+    /// Data channel mode
+    pub mode: DataMode,
+    /// Timeout for connecting and for reads on the control channel
+    pub timeout: Option<Duration>,
+    /// Listing format; anything but `Auto` skips detection
+    pub list_format: ListFormat,
+    /// Bandwidth limit for uploads
+    pub limit_up: Option<Throttle>,
+    /// Bandwidth limit for downloads
+    pub limit_down: Option<Throttle>,
+    /// Address family to use when the host resolves to several
+    pub ip_family: IpFamily,
+    /// UTC offset in seconds of the times in LIST output (None = assume UTC)
+    ///
+    /// MLSD and MDTM times are always UTC and are not adjusted.
+    pub server_tz: Option<i32>,
+    /// Local ports to listen on for active-mode data connections (None =
+    /// any)
+    ///
+    /// Only applies to active mode: in passive mode the server picks the
+    /// port and the client connects to it.
+    pub data_ports: Option<RangeInclusive<u16>>,
+    /// Extensions of the files transferred in ASCII mode (TYPE A)
+    ///
+    /// The server may translate line endings, so the bytes transferred no
+    /// longer match SIZE or REST offsets: ranged reads and resumed uploads
+    /// of these files are refused, and interrupted downloads start over.
+    pub ascii_extensions: Vec<String>,
+    /// Account sent with ACCT when the server answers the login with 332
+    pub account: Option<String>,
+    /// Consecutive failed reconnects after which the delay jumps straight to
+    /// `reconnect_max_delay` (None = keep doubling it)
+    pub reconnect_attempts: Option<u32>,
+    /// Longest wait between reconnect attempts (None = one minute)
+    pub reconnect_max_delay: Option<Duration>,
+    /// Log every command and reply on the control connection at the info
+    /// level, with the `PASS` and `ACCT` arguments hidden
+    pub verbose: bool,
+    /// Character set of the names on the server (None = UTF-8)
+    ///
+    /// Listings are decoded and the paths in commands encoded with it, and
+    /// every transfer goes through our own data connection. Not available
+    /// with TLS, where the control connection only takes UTF-8.
+    pub name_encoding: Option<&'static Encoding>,
+}
+
+/// Wait after the first failed reconnect; it doubles with each further failure
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Default cap on the wait between reconnect attempts
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Wait for the server to open an active-mode data connection without `timeout`
+const ACTIVE_ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Wait before the next reconnect after `failures` consecutive failures
+fn reconnect_delay(failures: u32, max: Duration) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    (RECONNECT_BASE_DELAY * (1 << doublings)).min(max)
 }
 
 /// FTP Connection wrapper supporting both plain FTP and FTPS
@@ -29,15 +664,33 @@ pub struct FtpConnection {
     server: String,
     username: String,
     password: String,
-    use_tls: bool,
+    options: ConnectOptions,
     port: u16,
     current_dir: String,
+    /// Time of the last command sent on the control connection
+    last_activity: Instant,
+    /// Features advertised by FEAT (None if the server doesn't support FEAT)
+    features: Option<HashSet<String>>,
+    /// Transfer type last confirmed by the server (None = unknown)
+    transfer_type: Option<FileType>,
+    /// Consecutive failed reconnects; while above zero the session is dead
+    reconnect_failures: u32,
+    /// Earliest time the next reconnect may be attempted
+    next_reconnect: Option<Instant>,
+    /// Whether the "LIST times assumed UTC" warning was already logged
+    warned_list_tz: bool,
+    /// Whether SIZE and MDTM accept absolute paths (None = not seen yet);
+    /// some servers only answer for names in the current directory
+    absolute_paths: Option<bool>,
+    /// TLS configuration of the session, for the data connections opened
+    /// by [`FtpConnection::open_data`]
+    tls_config: Option<Arc<ClientConfig>>,
 }
 
 /// Enum to handle both plain and TLS FTP streams
 enum FtpStreamVariant {
     Plain(FtpStream),
-    Tls(NativeTlsFtpStream),
+    Tls(RustlsFtpStream),
 }
 
 impl FtpConnection {
@@ -49,86 +702,438 @@ impl FtpConnection {
         use_tls: bool,
         port: Option<u16>,
     ) -> Result<Self> {
-        let port = port.unwrap_or(21);
-        let addr = format!("{}:{}", server, port);
+        let options = ConnectOptions {
+            use_tls,
+            ..Default::default()
+        };
+        Self::with_options(server, username, password, port, options)
+    }
+
+    /// Create a new FTP connection with explicit connection options
+    pub fn with_options(
+        server: String,
+        username: String,
+        password: String,
+        port: Option<u16>,
+        options: ConnectOptions,
+    ) -> Result<Self> {
+        let port = port.unwrap_or(if options.implicit_tls { 990 } else { 21 });
+        let addrs = resolve(&server, port, options.ip_family)?;
+
+        info!("Connecting to FTP server at {}:{}", server, port);
+        if let Some(limit) = &options.limit_up {
+            info!("Uploads limited to {} bytes/s", limit.rate());
+        }
+        if let Some(limit) = &options.limit_down {
+            info!("Downloads limited to {} bytes/s", limit.rate());
+        }
+        if let Some(ports) = &options.data_ports {
+            info!(
+                "Data connections accepted on local ports {}-{}",
+                ports.start(),
+                ports.end()
+            );
+        }
+        if let Some(encoding) = options.name_encoding {
+            if options.use_tls || options.implicit_tls {
+                return Err(protocol_error!(
+                    "Names in {} can't be sent over TLS, whose control connection only takes UTF-8",
+                    encoding.name()
+                ));
+            }
+            info!("File names on the server are in {}", encoding.name());
+        }
+        let mut tls_config = None;
+        let verbose = options.verbose;
 
-        info!("Connecting to FTP server at {}", addr);
+        let stream = if options.implicit_tls {
+            let config = Self::tls_config(&options)?;
+            let connector = RustlsConnector::from(Arc::clone(&config));
+            tls_config = Some(config);
 
-        let stream = if use_tls {
-            // Create TLS connector
-            let connector = TlsConnector::builder()
-                .danger_accept_invalid_certs(true) // For development; should be configurable
-                .build()
-                .context("Failed to create TLS connector")?;
-            let native_connector = NativeTlsConnector::from(connector);
+            // TLS handshake happens before the server greeting
+            let mut ftp_stream =
+                RustlsFtpStream::connect_secure_implicit(&addrs[..], connector, &server)
+                    .context("Failed to connect to implicit FTPS server")?;
+            ftp_stream.get_ref().set_read_timeout(options.timeout)?;
+            trace_greeting(verbose, ftp_stream.get_welcome_msg());
+
+            let mut send = |command: &str, expected: &[Status]| {
+                traced(verbose, command, || {
+                    ftp_stream.custom_command(command, expected)
+                })
+            };
+            let login = send_login(&username, &password, &mut send);
+            complete_login(login, options.account.as_deref(), |command| {
+                send(&command, &[Status::LoggedIn, Status::CommandNotImplemented])
+            })
+            .context("Failed to login to FTPS server")?;
+
+            // Protect the data channel too; servers that already do reply 200 anyway
+            for command in ["PBSZ 0", "PROT P"] {
+                send(command, &[Status::CommandOk]).context(format!("{} failed", command))?;
+            }
+            info!("Data connections encrypted (PROT P)");
 
-            // Connect with TLS
-            let ftp_stream =
-                NativeTlsFtpStream::connect(&addr).context("Failed to connect to FTPS server")?;
-            let mut ftp_stream = ftp_stream
-                .into_secure(native_connector, &server)
-                .context("Failed to establish TLS connection")?;
+            FtpStreamVariant::Tls(ftp_stream)
+        } else if options.use_tls {
+            let config = Self::tls_config(&options)?;
+            let connector = RustlsConnector::from(Arc::clone(&config));
+            tls_config = Some(config);
 
-            ftp_stream
-                .login(&username, &password)
-                .context("Failed to login to FTPS server")?;
+            // Connect with TLS
+            let tcp = Self::connect_tcp(&addrs, options.timeout)
+                .context("Failed to connect to FTPS server")?;
+            let ftp_stream = RustlsFtpStream::connect_with_stream(tcp)
+                .context("Failed to connect to FTPS server")?;
+            trace_greeting(verbose, ftp_stream.get_welcome_msg());
+            // into_secure also sends PBSZ 0 and PROT P and fails unless the
+            // server accepts both, so file data never crosses in cleartext.
+            // There is no PROT C: suppaftp wraps every data connection of a
+            // secured session in TLS.
+            for command in ["AUTH TLS", "PBSZ 0", "PROT P"] {
+                trace_command(verbose, command);
+            }
+            let secured = ftp_stream.into_secure(connector, &server);
+            trace_refusal(verbose, &secured);
+            let mut ftp_stream = secured.context("Failed to establish TLS connection")?;
+            info!("Data connections encrypted (PROT P)");
+
+            let mut send = |command: &str, expected: &[Status]| {
+                traced(verbose, command, || {
+                    ftp_stream.custom_command(command, expected)
+                })
+            };
+            let login = send_login(&username, &password, &mut send);
+            complete_login(login, options.account.as_deref(), |command| {
+                send(&command, &[Status::LoggedIn, Status::CommandNotImplemented])
+            })
+            .context("Failed to login to FTPS server")?;
 
             FtpStreamVariant::Tls(ftp_stream)
         } else {
             // Connect without TLS
+            let tcp = Self::connect_tcp(&addrs, options.timeout)
+                .context("Failed to connect to FTP server")?;
             let mut ftp_stream =
-                FtpStream::connect(&addr).context("Failed to connect to FTP server")?;
-
-            ftp_stream
-                .login(&username, &password)
-                .context("Failed to login to FTP server")?;
+                FtpStream::connect_with_stream(tcp).context("Failed to connect to FTP server")?;
+            trace_greeting(verbose, ftp_stream.get_welcome_msg());
+
+            let mut send = |command: &str, expected: &[Status]| {
+                traced(verbose, command, || {
+                    ftp_stream.custom_command(command, expected)
+                })
+            };
+            let login = send_login(&username, &password, &mut send);
+            complete_login(login, options.account.as_deref(), |command| {
+                send(&command, &[Status::LoggedIn, Status::CommandNotImplemented])
+            })
+            .context("Failed to login to FTP server")?;
 
             FtpStreamVariant::Plain(ftp_stream)
         };
 
-        info!("Successfully connected to FTP server");
+        let peer = match &stream {
+            FtpStreamVariant::Plain(stream) => stream.get_ref().peer_addr()?,
+            FtpStreamVariant::Tls(stream) => stream.get_ref().peer_addr()?,
+        };
+        info!(
+            event = "connect", server = server.as_str();
+            "Successfully connected to FTP server at {} ({})",
+            peer,
+            if peer.is_ipv6() { "IPv6" } else { "IPv4" }
+        );
 
         let mut conn = FtpConnection {
             stream,
             server,
             username,
             password,
-            use_tls,
+            options,
             port,
             current_dir: "/".to_string(),
+            last_activity: Instant::now(),
+            features: None,
+            transfer_type: None,
+            reconnect_failures: 0,
+            next_reconnect: None,
+            warned_list_tz: false,
+            absolute_paths: None,
+            tls_config,
         };
 
+        // Detect server capabilities
+        match conn.feat() {
+            Ok(features) => {
+                let mut list: Vec<&String> = features.iter().collect();
+                list.sort();
+                info!("Server features: {:?}", list);
+                conn.features = Some(features);
+            }
+            Err(e) => info!("Server does not support FEAT: {}", e),
+        }
+
+        // Ask for UTF-8 pathnames so non-ASCII names round-trip unchanged,
+        // unless the names are known to be in another character set
+        if conn.options.name_encoding.is_none()
+            && conn.features.as_ref().is_some_and(|f| f.contains("UTF8"))
+        {
+            match conn.custom_command(
+                "OPTS UTF8 ON",
+                &[Status::CommandOk, Status::CommandNotImplemented],
+            ) {
+                Ok(_) => debug!("Enabled UTF-8 pathnames"),
+                Err(e) => warn!("OPTS UTF8 ON failed: {}", e),
+            }
+        }
+
         // Set transfer type to binary
         conn.set_transfer_type(FileType::Binary)?;
 
-        // Set passive mode
-        conn.set_mode(Mode::Passive)?;
+        // Set data channel mode
+        let mode = data_channel_mode(conn.options.mode, &peer);
+        if mode == Mode::ExtendedPassive {
+            debug!("IPv6 control connection, using extended passive mode (EPSV)");
+        }
+        conn.set_mode(mode)?;
 
         Ok(conn)
     }
 
+    /// TLS configuration shared by the control connection and its data
+    /// connections
+    fn tls_config(options: &ConnectOptions) -> Result<Arc<ClientConfig>> {
+        if options.no_tls_resume {
+            debug!("TLS session resumption disabled");
+        }
+        if options.no_tls_verify {
+            warn!("TLS certificate verification disabled");
+        }
+        tls::client_config(!options.no_tls_resume, !options.no_tls_verify)
+            .map_err(|e| protocol_error!("Failed to create TLS connector: {}", e))
+    }
+
+    /// Open the control connection TCP socket, honoring the configured timeout
+    ///
+    /// Addresses are tried in order; the error of the last one is returned.
+    fn connect_tcp(addrs: &[SocketAddr], timeout: Option<Duration>) -> Result<TcpStream> {
+        let mut last_error = None;
+        for addr in addrs {
+            let connected = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match connected {
+                Ok(stream) => {
+                    stream.set_read_timeout(timeout)?;
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    debug!("Failed to connect to {}: {}", addr, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.map_or_else(|| protocol_error!("No address to connect to"), FtpError::Io))
+    }
+
+    /// Connect and read the server greeting without logging in
+    ///
+    /// Used to tell whether a server is a public archive before giving up
+    /// for lack of credentials. The greeting comes before any TLS
+    /// negotiation, except with implicit TLS, where this returns None.
+    pub fn greeting(
+        server: &str,
+        port: Option<u16>,
+        options: &ConnectOptions,
+    ) -> Result<Option<String>> {
+        if options.implicit_tls {
+            return Ok(None);
+        }
+        let addrs = resolve(server, port.unwrap_or(21), options.ip_family)?;
+        let tcp = Self::connect_tcp(&addrs, options.timeout)?;
+        let mut stream =
+            FtpStream::connect_with_stream(tcp).context("Failed to read server greeting")?;
+        let greeting = stream.get_welcome_msg().map(str::to_string);
+        if let Err(e) = stream.quit() {
+            debug!("QUIT after reading the greeting failed: {}", e);
+        }
+        Ok(greeting)
+    }
+
     /// Reconnect to the FTP server (useful after connection loss)
+    ///
+    /// The new session goes through the same setup as the first one (FEAT,
+    /// `OPTS UTF8 ON`, binary type, data channel mode) and then returns to
+    /// the directory the old one was in. If that directory is gone (removed
+    /// while disconnected) the new session continues from `/` with a
+    /// warning: every path the filesystem sends is absolute, so the old
+    /// directory only mattered to relative commands.
+    ///
+    /// After a failed attempt the next one waits, doubling the delay up to
+    /// `reconnect_max_delay`; calls made before then fail at once with a
+    /// "server unavailable" error instead of waiting for another connect
+    /// timeout. After `reconnect_attempts` consecutive failures the server
+    /// is only tried once per `reconnect_max_delay`, so the mount recovers
+    /// on its own once it is back.
     pub fn reconnect(&mut self) -> Result<()> {
+        self.check_backoff()?;
         info!("Reconnecting to FTP server...");
 
-        let new_conn = Self::new(
+        let new_conn = match Self::with_options(
             self.server.clone(),
             self.username.clone(),
             self.password.clone(),
-            self.use_tls,
             Some(self.port),
-        )?;
+            self.options.clone(),
+        ) {
+            Ok(conn) => conn,
+            Err(e) => {
+                self.reconnect_failed();
+                return Err(e);
+            }
+        };
+        self.reconnect_failures = 0;
+        self.next_reconnect = None;
 
+        let previous_dir = std::mem::replace(&mut self.current_dir, new_conn.current_dir);
         self.stream = new_conn.stream;
-        self.current_dir = new_conn.current_dir;
+        self.tls_config = new_conn.tls_config;
+        self.features = new_conn.features;
+        self.transfer_type = new_conn.transfer_type;
+
+        if let Err(e) = self.cwd(&previous_dir) {
+            warn!(
+                "Could not return to {} after reconnecting, continuing from /: {}",
+                previous_dir, e
+            );
+            self.cwd("/")
+                .context("Failed to enter / after reconnecting")?;
+        }
+
+        info!(event = "reconnect", server = self.server.as_str(); "Reconnected successfully");
+        Ok(())
+    }
+
+    /// Fail fast while waiting to retry a failed reconnect
+    fn check_backoff(&self) -> Result<()> {
+        if self.reconnect_failures == 0 {
+            return Ok(());
+        }
+        match self.next_reconnect {
+            Some(at) if at > Instant::now() => Err(unavailable(format!(
+                "{} reconnect attempts failed, retrying in {}s",
+                self.reconnect_failures,
+                (at - Instant::now()).as_secs_f64().ceil()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `reconnect_attempts` consecutive reconnects have failed
+    fn reconnects_exhausted(&self) -> bool {
+        self.options
+            .reconnect_attempts
+            .is_some_and(|max| self.reconnect_failures >= max)
+    }
+
+    /// Record a failed reconnect and schedule the next attempt
+    fn reconnect_failed(&mut self) {
+        self.reconnect_failures += 1;
+        let max = self
+            .options
+            .reconnect_max_delay
+            .unwrap_or(RECONNECT_MAX_DELAY);
+        let delay = match self.reconnects_exhausted() {
+            true => max,
+            false => reconnect_delay(self.reconnect_failures, max),
+        };
+        self.next_reconnect = Some(Instant::now() + delay);
+        warn!(
+            "Reconnect attempt {} failed, next one in {}s",
+            self.reconnect_failures,
+            delay.as_secs()
+        );
+    }
+
+    /// Reconnect first if the session was lost and could not be re-established
+    ///
+    /// Called before every operation, so that while the server is down they
+    /// fail at once instead of each waiting on the dead connection.
+    fn ensure_connected(&mut self) -> Result<()> {
+        if self.reconnect_failures == 0 {
+            return Ok(());
+        }
+        self.reconnect()
+    }
+
+    /// Access the underlying stream, recording control connection activity
+    fn stream(&mut self) -> &mut FtpStreamVariant {
+        self.last_activity = Instant::now();
+        &mut self.stream
+    }
+
+    /// Time since the last command was sent to the server
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Send a NOOP to keep the control connection alive
+    pub fn noop(&mut self) -> Result<()> {
+        self.custom_command("NOOP", &[Status::CommandOk])
+            .context("NOOP failed")?;
+        Ok(())
+    }
 
-        info!("Reconnected successfully");
+    /// End the session with QUIT
+    ///
+    /// The connection is unusable afterwards until the next reconnect.
+    pub fn quit(&mut self) -> Result<()> {
+        self.custom_command("QUIT", &[Status::Closing])
+            .context("QUIT failed")?;
         Ok(())
     }
 
+    /// Query the features advertised by the server (FEAT)
+    ///
+    /// Feature names are upper-cased; features with parameters are also
+    /// recorded with them, e.g. both `REST` and `REST STREAM`.
+    pub fn feat(&mut self) -> Result<HashSet<String>> {
+        let lines = self
+            .command_lines("FEAT", &[Status::System])
+            .context("FEAT failed")?;
+
+        // The first and last lines are the "Features:" / "End" frame
+        let mut set = HashSet::new();
+        for line in lines.iter().skip(1).take(lines.len().saturating_sub(2)) {
+            let (name, value) = match line.split_once(' ') {
+                Some((name, value)) => (name, Some(value)),
+                None => (line.as_str(), None),
+            };
+            let name = name.to_uppercase();
+            if let Some(value) = value {
+                set.insert(format!("{} {}", name, value.trim().to_uppercase()));
+            }
+            set.insert(name);
+        }
+
+        Ok(set)
+    }
+
+    /// Whether the server advertised `feature` in its FEAT reply
+    ///
+    /// Servers without FEAT are assumed to support everything, so commands
+    /// are still attempted and fail normally.
+    pub fn supports(&self, feature: &str) -> bool {
+        match &self.features {
+            Some(features) => features.contains(&feature.to_uppercase()),
+            None => true,
+        }
+    }
+
     /// Set FTP mode (Passive, Active, ExtendedPassive)
     fn set_mode(&mut self, mode: Mode) -> Result<()> {
-        match &mut self.stream {
+        match self.stream() {
             FtpStreamVariant::Plain(stream) => {
                 stream.set_mode(mode);
             }
@@ -141,30 +1146,74 @@ impl FtpConnection {
 
     /// Set transfer type (Binary or ASCII)
     fn set_transfer_type(&mut self, file_type: FileType) -> Result<()> {
-        match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => {
-                stream
-                    .transfer_type(file_type)
-                    .context("Failed to set transfer type")?;
-            }
-            FtpStreamVariant::Tls(stream) => {
-                stream
-                    .transfer_type(file_type)
-                    .context("Failed to set transfer type")?;
+        self.custom_command(&format!("TYPE {}", file_type), &[Status::CommandOk])
+            .context("Failed to set transfer type")?;
+        self.transfer_type = Some(file_type);
+        Ok(())
+    }
+
+    /// Make sure the connection is in binary mode before a transfer or SIZE
+    ///
+    /// In ASCII mode servers may convert line endings, so SIZE and the bytes
+    /// transferred no longer match the file and reads come out truncated.
+    /// Costs nothing while the type is known to be binary.
+    fn ensure_binary(&mut self) -> Result<()> {
+        if self.transfer_type != Some(FileType::Binary) {
+            debug!(
+                "Transfer type is {:?}, switching back to binary",
+                self.transfer_type
+            );
+            self.set_transfer_type(FileType::Binary)?;
+        }
+        Ok(())
+    }
+
+    /// Set the transfer type for a RETR or STOR of `path`
+    ///
+    /// Returns whether ASCII was chosen; the caller switches back to binary
+    /// with [`Self::end_transfer`] once the transfer is over.
+    fn begin_transfer(&mut self, path: &str) -> Result<bool> {
+        if is_ascii_path(&self.options.ascii_extensions, path) {
+            debug!("Transferring {} in ASCII mode", path);
+            self.set_transfer_type(FileType::Ascii(FormatControl::Default))?;
+            Ok(true)
+        } else {
+            self.ensure_binary()?;
+            Ok(false)
+        }
+    }
+
+    /// Restore binary mode after an ASCII transfer, keeping the transfer's error
+    fn end_transfer<T>(&mut self, ascii: bool, result: Result<T>) -> Result<T> {
+        if ascii {
+            if let Err(e) = self.ensure_binary() {
+                // The next transfer or SIZE retries the switch
+                debug!("Failed to restore binary mode: {}", e);
             }
         }
+        result
+    }
+
+    /// Refuse a REST offset on a file transferred in ASCII mode
+    fn check_ranged(&self, path: &str) -> Result<()> {
+        if is_ascii_path(&self.options.ascii_extensions, path) {
+            return Err(protocol_error!(
+                "{} is transferred in ASCII mode, where offsets don't match the file",
+                path
+            ));
+        }
         Ok(())
     }
 
     /// Get current working directory
     pub fn pwd(&mut self) -> Result<String> {
-        let path = match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => {
-                stream.pwd().context("Failed to get current directory")?
-            }
-            FtpStreamVariant::Tls(stream) => {
-                stream.pwd().context("Failed to get current directory")?
-            }
+        let reply = self
+            .custom_command("PWD", &[Status::PathCreated])
+            .context("Failed to get current directory")?;
+        let text = self.decode_name(&reply.body);
+        let path = match (text.find('"'), text.rfind('"')) {
+            (Some(begin), Some(end)) if begin < end => text[begin + 1..end].to_string(),
+            _ => return Err(protocol_error!("Unexpected PWD reply: {}", text.trim_end())),
         };
         self.current_dir = path.clone();
         Ok(path)
@@ -174,14 +1223,8 @@ impl FtpConnection {
     pub fn cwd(&mut self, path: &str) -> Result<()> {
         debug!("Changing directory to: {}", path);
 
-        match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => stream
-                .cwd(path)
-                .context(format!("Failed to change directory to {}", path))?,
-            FtpStreamVariant::Tls(stream) => stream
-                .cwd(path)
-                .context(format!("Failed to change directory to {}", path))?,
-        }
+        self.custom_command(&format!("CWD {}", path), &[Status::RequestedFileActionOk])
+            .context(format!("Failed to change directory to {}", path))?;
 
         self.current_dir = path.to_string();
         Ok(())
@@ -191,14 +1234,8 @@ impl FtpConnection {
     pub fn cdup(&mut self) -> Result<()> {
         debug!("Changing to parent directory");
 
-        match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => stream
-                .cdup()
-                .context("Failed to change to parent directory")?,
-            FtpStreamVariant::Tls(stream) => stream
-                .cdup()
-                .context("Failed to change to parent directory")?,
-        }
+        self.custom_command("CDUP", &[Status::CommandOk, Status::RequestedFileActionOk])
+            .context("Failed to change to parent directory")?;
 
         // Update current directory
         let _ = self.pwd();
@@ -206,81 +1243,728 @@ impl FtpConnection {
     }
 
     /// List files in current directory
+    ///
+    /// With an explicit `list_format`, a line that doesn't parse is an error
+    /// (carrying the raw line); in `Auto` mode such lines are skipped.
     pub fn list(&mut self) -> Result<Vec<FtpFileInfo>> {
         debug!("Listing directory contents");
 
-        let list = match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => {
-                stream.list(None).context("Failed to list directory")?
-            }
-            FtpStreamVariant::Tls(stream) => {
-                stream.list(None).context("Failed to list directory")?
-            }
+        let format = self.options.list_format;
+        let use_mlsd = match format {
+            ListFormat::Mlsd => true,
+            ListFormat::Auto => self.features.as_ref().is_some_and(|f| f.contains("MLST")),
+            _ => false,
         };
 
-        let mut files = Vec::new();
-        for entry in list {
-            if let Ok(file_info) = self.parse_list_line(&entry) {
-                files.push(file_info);
-            } else {
-                debug!("Failed to parse line: {}", entry);
+        if use_mlsd {
+            match self.listing_lines("MLSD") {
+                Ok(lines) => return self.parse_lines(&lines, ListFormat::Mlsd, format),
+                // A transfer that broke off says nothing about MLSD support
+                Err(e) if format == ListFormat::Auto && !e.is_transient() => {
+                    debug!("MLSD failed, falling back to LIST: {}", e);
+                }
+                Err(e) => return Err(e).context("Failed to list directory"),
             }
         }
 
-        Ok(files)
-    }
+        let lines = self
+            .listing_lines("LIST")
+            .context("Failed to list directory")?;
 
-    /// List files in a specific directory
-    pub fn list_dir(&mut self, path: &str) -> Result<Vec<FtpFileInfo>> {
-        let original_dir = self.pwd()?;
-        self.cwd(path)?;
-        let files = self.list()?;
-        self.cwd(&original_dir)?;
-        Ok(files)
+        self.parse_lines(&lines, format, format)
+    }
+
+    /// Lines of an MLSD or LIST transfer of the current directory
+    fn listing_lines(&mut self, command: &str) -> Result<Vec<String>> {
+        if self.own_data_path() {
+            let mut data = self.open_data(command)?;
+            let mut bytes = Vec::new();
+            let read = data.read_to_end(&mut bytes);
+            self.finish_data(data)?;
+            read.map_err(|e| listing_error(suppaftp::FtpError::ConnectionError(e)))?;
+            return Ok(bytes
+                .split(|&byte| byte == b'\n')
+                .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                .filter(|line| !line.is_empty())
+                .map(|line| self.decode_listing_line(line))
+                .collect());
+        }
+        let mlsd = command == "MLSD";
+        trace_command(self.options.verbose, command);
+        let lines = match self.stream() {
+            FtpStreamVariant::Plain(stream) if mlsd => stream.mlsd(None),
+            FtpStreamVariant::Plain(stream) => stream.list(None),
+            FtpStreamVariant::Tls(stream) if mlsd => stream.mlsd(None),
+            FtpStreamVariant::Tls(stream) => stream.list(None),
+        };
+        trace_refusal(self.options.verbose, &lines);
+        lines.map_err(listing_error)
+    }
+
+    /// Parse listing lines in `format`, failing on bad lines unless `requested`
+    /// is `Auto`
+    fn parse_lines(
+        &mut self,
+        lines: &[String],
+        format: ListFormat,
+        requested: ListFormat,
+    ) -> Result<Vec<FtpFileInfo>> {
+        let mut files = Vec::new();
+        for line in lines {
+            match Self::parse_listing_line(&self.current_dir, format, line) {
+                Ok(Some(mut file_info)) => {
+                    if let Some(encoding) = self.options.name_encoding {
+                        let (bytes, _, _) = encoding.encode(&file_info.name);
+                        file_info.raw_name = Some(bytes.into_owned());
+                    }
+                    // LIST shows the server's local time; MLSD facts are UTC
+                    if format != ListFormat::Mlsd {
+                        file_info.modified_time = file_info
+                            .modified_time
+                            .map(|time| self.list_time_to_utc(time));
+                    }
+                    files.push(file_info)
+                }
+                Ok(None) => {}
+                Err(e) if requested == ListFormat::Auto => {
+                    debug!("Failed to parse line {:?}: {}", line, e)
+                }
+                Err(e) => {
+                    return Err(e).context(format!(
+                        "Unparseable {} listing line: {:?}",
+                        requested.name(),
+                        line
+                    ))
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Parse one listing line; `Ok(None)` for lines that carry no entry
+    /// (blank and summary lines, `.` and `..`, entries without a name)
+    pub(crate) fn parse_listing_line(
+        current_dir: &str,
+        format: ListFormat,
+        line: &str,
+    ) -> Result<Option<FtpFileInfo>> {
+        if is_summary_line(line) {
+            return Ok(None);
+        }
+
+        let entry = match format {
+            ListFormat::Unix => Self::parse_list_line(current_dir, line).map(Some),
+            ListFormat::Dos => Self::parse_dos_line(current_dir, line).map(Some),
+            ListFormat::Mlsd => Self::parse_mlsd_line(current_dir, line),
+            ListFormat::Auto => Self::parse_list_line(current_dir, line)
+                .or_else(|_| Self::parse_dos_line(current_dir, line))
+                .map(Some),
+        }?;
+        match entry {
+            // `ls -a` style listings include them; readdir adds its own
+            Some(info) if info.name == "." || info.name == ".." => Ok(None),
+            // Its path would be the directory's own
+            Some(info) if !is_entry_name(&info.name) => {
+                warn!("Skipping listing entry without a name: {:?}", line);
+                Ok(None)
+            }
+            entry => Ok(entry),
+        }
+    }
+
+    /// List files in a specific directory
+    ///
+    /// If the directory can't be entered the error tells a missing path
+    /// ([`FtpError::NotFound`]) from a file ([`FtpError::NotADirectory`]).
+    pub fn list_dir(&mut self, path: &str) -> Result<Vec<FtpFileInfo>> {
+        let mut entered = false;
+        let result = self.with_cwd(path, |conn| {
+            entered = true;
+            conn.list()
+        });
+        match result {
+            Err(e) if !entered => Err(self.cwd_error(path, e)),
+            result => result,
+        }
+    }
+
+    /// Convert a time read from LIST in the server's zone to UTC
+    fn list_time_to_utc(&mut self, time: SystemTime) -> SystemTime {
+        match self.options.server_tz {
+            Some(offset) => shift_time(time, -offset),
+            None => {
+                if !self.warned_list_tz {
+                    self.warned_list_tz = true;
+                    warn!(
+                        "Assuming LIST times are UTC; use --server-tz if the server lists local times"
+                    );
+                }
+                time
+            }
+        }
+    }
+
+    /// Classify a failure to enter `path`
+    ///
+    /// A permanent (5xx) reply means the path is missing or is not a
+    /// directory, which SIZE tells apart; other failures are kept as they are.
+    fn cwd_error(&mut self, path: &str, error: FtpError) -> FtpError {
+        match error {
+            FtpError::NotFound | FtpError::Protocol(_) => {
+                debug!("Failed to enter {}: {}", path, error);
+                if self.size(path).is_ok() {
+                    FtpError::NotADirectory
+                } else {
+                    FtpError::NotFound
+                }
+            }
+            other => other,
+        }
+    }
+
+    /// Run `f` inside `path`, always changing back to the previous directory
+    ///
+    /// The previous directory is restored even when `f` fails. If the restore
+    /// itself fails, `current_dir` is re-read from the server so listings never
+    /// build paths against a directory we are not actually in.
+    fn with_cwd<T>(&mut self, path: &str, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let original_dir = self.pwd()?;
+        self.cwd(path)?;
+
+        let result = f(self);
+
+        if let Err(e) = self.cwd(&original_dir) {
+            warn!("Failed to return to {}: {}", original_dir, e);
+            self.pwd()?;
+        }
+
+        result
+    }
+
+    /// Run `op` on an absolute `path`, or on its bare name from inside its
+    /// parent directory if the server only answers for the current one
+    ///
+    /// A 550 for the absolute path is retried relatively until one form is
+    /// seen to work; from then on only that form is used, so a missing file
+    /// costs no extra round trips on servers that accept absolute paths.
+    /// The previous directory is restored even when the retry fails.
+    fn relative_fallback<T>(
+        &mut self,
+        path: &str,
+        op: impl Fn(&mut Self, &str) -> Result<T>,
+    ) -> Result<T> {
+        let (parent, name) = match path.rsplit_once('/') {
+            Some(("", name)) if path.starts_with('/') => ("/", name),
+            Some((parent, name)) if path.starts_with('/') => (parent, name),
+            _ => return op(self, path),
+        };
+        if name.is_empty() {
+            return op(self, path);
+        }
+
+        if self.absolute_paths != Some(false) {
+            match op(self, path) {
+                Err(FtpError::NotFound) if self.absolute_paths.is_none() => {
+                    debug!("{} not found, retrying it from {}", path, parent)
+                }
+                Ok(value) => {
+                    self.absolute_paths = Some(true);
+                    return Ok(value);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let value = self.with_cwd(parent, |conn| op(conn, name))?;
+        if self.absolute_paths.is_none() {
+            info!("Server only answers SIZE/MDTM in the current directory, using relative names");
+            self.absolute_paths = Some(false);
+        }
+        Ok(value)
+    }
+
+    /// Whether transfers go through [`Self::open_data`] rather than
+    /// suppaftp's own data channel
+    ///
+    /// suppaftp's active mode always listens on a port the OS picks, so a
+    /// `--data-ports` range needs a listener of our own; and suppaftp sends
+    /// the path of RETR or STOR as UTF-8, which a `--name-encoding` server
+    /// would not recognize.
+    fn own_data_path(&self) -> bool {
+        (self.options.mode == DataMode::Active && self.options.data_ports.is_some())
+            || self.options.name_encoding.is_some()
+    }
+
+    /// Start `command` over a data connection of our own
+    ///
+    /// In active mode the listener is announced with PORT (EPRT over IPv6)
+    /// on the address of the control connection, on a port within
+    /// `--data-ports` if given. In passive mode the port from PASV (EPSV
+    /// over IPv6) is connected to at the server's address. In a TLS session
+    /// the data connection is secured with the session's configuration, as
+    /// suppaftp would. Finish the transfer with [`Self::finish_data`].
+    fn open_data(&mut self, command: &str) -> Result<DataConnection> {
+        let (local, peer) = match &self.stream {
+            FtpStreamVariant::Plain(stream) => (
+                stream.get_ref().local_addr()?,
+                stream.get_ref().peer_addr()?,
+            ),
+            FtpStreamVariant::Tls(stream) => (
+                stream.get_ref().local_addr()?,
+                stream.get_ref().peer_addr()?,
+            ),
+        };
+        let stream = match self.options.mode {
+            DataMode::Active => {
+                // Port 0 lets the OS pick one, as suppaftp does
+                let ports = self.options.data_ports.clone().unwrap_or(0..=0);
+                let listener = listen_in_range(local.ip(), &ports)?;
+                self.custom_command(&port_command(listener.local_addr()?), &[Status::CommandOk])
+                    .context("Failed to announce the data connection")?;
+                self.custom_command(command, &[Status::AboutToSend, Status::AlreadyOpen])?;
+
+                let timeout = self.options.timeout.unwrap_or(ACTIVE_ACCEPT_TIMEOUT);
+                accept_within(&listener, timeout)?
+            }
+            DataMode::Passive => {
+                // The address in a PASV reply is often a private one behind
+                // NAT; the server is reached where the control connection is
+                let addr = SocketAddr::new(peer.ip(), self.passive_port(peer.is_ipv6())?);
+                let stream = match self.options.timeout {
+                    Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                    None => TcpStream::connect(addr),
+                }
+                .context(format!("Failed to open the data connection to {}", addr))?;
+                self.custom_command(command, &[Status::AboutToSend, Status::AlreadyOpen])?;
+                stream
+            }
+        };
+        stream.set_read_timeout(self.options.timeout)?;
+        let Some(config) = &self.tls_config else {
+            return Ok(DataConnection::Plain(stream));
+        };
+        let name = ServerName::try_from(self.server.clone())
+            .map_err(|e| protocol_error!("Invalid TLS server name {}: {}", self.server, e))?;
+        let connection = ClientConnection::new(Arc::clone(config), name)
+            .map_err(|e| protocol_error!("Failed to secure the data connection: {}", e))?;
+        Ok(DataConnection::Tls(Box::new(StreamOwned::new(
+            connection, stream,
+        ))))
+    }
+
+    /// Port the server listens on for the next passive data connection
+    /// (PASV, or EPSV over IPv6)
+    fn passive_port(&mut self, extended: bool) -> Result<u16> {
+        let (command, status) = if extended {
+            ("EPSV", Status::ExtendedPassiveMode)
+        } else {
+            ("PASV", Status::PassiveMode)
+        };
+        let reply = self
+            .custom_command(command, &[status])
+            .context("Failed to enter passive mode")?;
+        let text = String::from_utf8_lossy(&reply.body);
+        let port = if extended {
+            parse_epsv_port(&text)
+        } else {
+            parse_pasv_port(&text)
+        };
+        port.ok_or_else(|| protocol_error!("Unexpected {} reply: {}", command, text.trim_end()))
+    }
+
+    /// Close a data connection from [`Self::open_data`] and read the
+    /// transfer's final reply
+    fn finish_data(&mut self, data: DataConnection) -> Result<()> {
+        let finished = match self.stream() {
+            FtpStreamVariant::Plain(stream) => stream.finalize_retr_stream(data),
+            FtpStreamVariant::Tls(stream) => stream.finalize_retr_stream(data),
+        };
+        trace_refusal(self.options.verbose, &finished);
+        Ok(finished?)
+    }
+
+    /// Send a raw command and wait for one of the expected reply codes
+    fn custom_command(&mut self, command: &str, expected: &[Status]) -> Result<Response> {
+        let encoded = self.encode_command(command)?;
+        Ok(self.raw_command(command, encoded.as_deref(), expected)?)
+    }
+
+    /// `command` in `--name-encoding`, or None if that is its UTF-8
+    fn encode_command(&self, command: &str) -> Result<Option<Vec<u8>>> {
+        let Some(encoding) = self.options.name_encoding else {
+            return Ok(None);
+        };
+        match encoding.encode(command) {
+            (_, _, true) => {
+                debug!("{} can't represent {:?}", encoding.name(), command);
+                Err(FtpError::InvalidName)
+            }
+            (Cow::Borrowed(_), _, _) => Ok(None),
+            (Cow::Owned(bytes), _, _) => Ok(Some(bytes)),
+        }
+    }
+
+    /// Send a raw command, keeping suppaftp's error so the reply can be inspected
+    ///
+    /// `encoded` goes on the wire instead of the UTF-8 of `command`.
+    fn raw_command(
+        &mut self,
+        command: &str,
+        encoded: Option<&[u8]>,
+        expected: &[Status],
+    ) -> std::result::Result<Response, suppaftp::FtpError> {
+        if may_change_transfer_type(command) {
+            self.transfer_type = None;
+        }
+        let verbose = self.options.verbose;
+        traced(verbose, command, || match (self.stream(), encoded) {
+            // suppaftp only sends strings: the bytes go out on the bare
+            // socket first, and an empty command ends the line and reads the
+            // reply
+            (FtpStreamVariant::Plain(stream), Some(bytes)) => {
+                let mut socket = stream.get_ref();
+                socket
+                    .write_all(bytes)
+                    .map_err(suppaftp::FtpError::ConnectionError)?;
+                stream.custom_command("", expected)
+            }
+            (FtpStreamVariant::Plain(stream), None) => stream.custom_command(command, expected),
+            // Never encoded: with_options refuses --name-encoding over TLS
+            (FtpStreamVariant::Tls(stream), _) => stream.custom_command(command, expected),
+        })
+    }
+
+    /// Decode a reply or listing from the server's `--name-encoding`
+    /// (UTF-8 otherwise); undecodable bytes become U+FFFD
+    fn decode_name<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match self.options.name_encoding {
+            Some(encoding) => encoding.decode_without_bom_handling(bytes).0,
+            None => String::from_utf8_lossy(bytes),
+        }
+    }
+
+    /// Decode one listing line, warning when its names wouldn't encode back
+    /// to the bytes the server listed (so they can't be opened)
+    fn decode_listing_line(&self, line: &[u8]) -> String {
+        let text = self.decode_name(line);
+        if let Some(encoding) = self.options.name_encoding {
+            if *encoding.encode(&text).0 != *line {
+                warn!(
+                    "Listing line is not valid {}, its name may be unreachable: {:?}",
+                    encoding.name(),
+                    text
+                );
+            }
+        }
+        text.into_owned()
+    }
+
+    /// Send a raw command and collect every line of its reply
+    ///
+    /// suppaftp stops reading a 211 reply after its first line when 211 is
+    /// among the expected codes, leaving the rest to corrupt the next reply.
+    /// The command is therefore sent expecting nothing, so the whole
+    /// `code-` ... `code ` block is always read, and the code checked here.
+    fn command_lines(&mut self, command: &str, expected: &[Status]) -> Result<Vec<String>> {
+        let encoded = self.encode_command(command)?;
+        let response = match self.raw_command(command, encoded.as_deref(), &[]) {
+            Ok(response) => response,
+            Err(suppaftp::FtpError::UnexpectedResponse(response))
+                if expected.contains(&response.status) =>
+            {
+                response
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(reply_lines(&response.body))
+    }
+
+    /// Send `SITE <command>` and return the text of the reply
+    ///
+    /// SITE commands are server specific; multi-line replies (such as
+    /// `SITE HELP`) are returned one line per line, without status codes.
+    pub fn site(&mut self, command: &str) -> Result<String> {
+        debug!("Sending SITE {}", command);
+
+        let lines = self
+            .command_lines(
+                &format!("SITE {}", command),
+                &[
+                    Status::CommandOk,
+                    Status::System,
+                    Status::Help,
+                    Status::RequestedFileActionOk,
+                ],
+            )
+            .context(format!("SITE {} failed", command))?;
+
+        Ok(lines.join("\n"))
+    }
+
+    /// Get file modification time (MDTM)
+    pub fn mdtm(&mut self, path: &str) -> Result<SystemTime> {
+        if !self.supports("MDTM") {
+            return Err(protocol_error!("Server does not support MDTM"));
+        }
+
+        let response = self
+            .relative_fallback(path, |conn, path| {
+                conn.custom_command(&format!("MDTM {}", path), &[Status::File])
+            })
+            .context(format!("Failed to get modification time of {}", path))?;
+        let reply = String::from_utf8_lossy(&response.body);
+
+        parse_mdtm_reply(&reply)
+            .ok_or_else(|| protocol_error!("Invalid MDTM reply: {}", reply.trim()))
+    }
+
+    /// Free space in bytes for uploads to `path` (`AVBL`)
+    ///
+    /// AVBL comes from a draft extension few servers implement; without it
+    /// the server replies 500/502, returned as [`FtpError::Protocol`].
+    pub fn available_space(&mut self, path: &str) -> Result<u64> {
+        if !self.supports("AVBL") {
+            return Err(protocol_error!("Server does not support AVBL"));
+        }
+
+        let response = self
+            .custom_command(&format!("AVBL {}", path), &[Status::File])
+            .context(format!("Failed to get free space of {}", path))?;
+        let reply = String::from_utf8_lossy(&response.body);
+
+        parse_size_reply(&reply)
+            .ok_or_else(|| protocol_error!("Invalid AVBL reply: {}", reply.trim()))
+    }
+
+    /// Set file modification time with `MFMT`, or `SITE UTIME` without it
+    ///
+    /// Both are extensions; a server implementing neither replies 500/502,
+    /// returned as [`FtpError::Protocol`].
+    pub fn set_mtime(&mut self, path: &str, time: SystemTime) -> Result<()> {
+        let timeval = format_timeval(time);
+        debug!("Setting modification time of {} to {}", path, timeval);
+
+        if self.supports("MFMT") {
+            match self.custom_command(&format!("MFMT {} {}", timeval, path), &[Status::File]) {
+                Ok(_) => return Ok(()),
+                // Without FEAT there was no telling whether MFMT exists
+                Err(e @ FtpError::Protocol(_)) if self.features.is_none() => {
+                    debug!("MFMT failed, trying SITE UTIME: {}", e)
+                }
+                Err(e) => {
+                    return Err(e).context(format!("Failed to set modification time of {}", path))
+                }
+            }
+        }
+        self.site(&format!("UTIME {} {}", timeval, path))
+            .context(format!("Failed to set modification time of {}", path))?;
+        Ok(())
     }
 
     /// Get file size
     pub fn size(&mut self, path: &str) -> Result<u64> {
-        let size = match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => stream
-                .size(path)
-                .context(format!("Failed to get size of {}", path))?,
-            FtpStreamVariant::Tls(stream) => stream
-                .size(path)
-                .context(format!("Failed to get size of {}", path))?,
-        };
+        if !self.supports("SIZE") {
+            return Err(protocol_error!("Server does not support SIZE"));
+        }
+        if self.transfer_type != Some(FileType::Binary) {
+            warn!(
+                "SIZE {} requested in transfer type {:?}; the reply would not match the bytes read",
+                path, self.transfer_type
+            );
+        }
+        self.ensure_binary()?;
 
-        Ok(size as u64)
+        let response = self
+            .relative_fallback(path, |conn, path| {
+                conn.custom_command(&format!("SIZE {}", path), &[Status::File])
+            })
+            .context(format!("Failed to get size of {}", path))?;
+        let reply = String::from_utf8_lossy(&response.body);
+
+        parse_size_reply(&reply)
+            .ok_or_else(|| protocol_error!("Invalid SIZE reply: {}", reply.trim()))
     }
 
     /// Download file contents
     pub fn retrieve(&mut self, path: &str) -> Result<Vec<u8>> {
         debug!("Retrieving file: {}", path);
 
-        let data = match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => {
-                let mut reader = stream
-                    .retr_as_buffer(path)
-                    .context(format!("Failed to retrieve file {}", path))?;
-                let mut data = Vec::new();
-                reader
-                    .read_to_end(&mut data)
-                    .context("Failed to read file data")?;
-                data
+        let mut data = Vec::new();
+        self.retrieve_into(path, &mut data)?;
+
+        debug!("Retrieved {} bytes from {}", data.len(), path);
+        Ok(data)
+    }
+
+    /// Download file contents, appending to `data`
+    ///
+    /// When `data` is not empty the transfer resumes at `data.len()` via REST.
+    /// Bytes received before a failure stay in `data`, so the caller can
+    /// reconnect and call again to continue where it left off.
+    ///
+    /// ASCII-mode files can't be resumed at a byte offset and are downloaded
+    /// again from the start.
+    ///
+    /// A data connection closed early looks like the end of the file, so
+    /// binary downloads are checked against a SIZE taken before the RETR: a
+    /// short one fails with a transient error, keeping the bytes received,
+    /// instead of passing for the whole file. Without SIZE only the reply
+    /// closing the transfer (226, not 426) vouches for it.
+    pub fn retrieve_into(&mut self, path: &str, data: &mut Vec<u8>) -> Result<()> {
+        let mut offset = data.len();
+        if offset > 0 && self.check_ranged(path).is_err() {
+            debug!("Restarting ASCII download of {} from the start", path);
+            data.clear();
+            offset = 0;
+        }
+        if offset > 0 && !self.supports("REST STREAM") {
+            return Err(protocol_error!("Server does not support REST STREAM"));
+        }
+        let ascii = self.begin_transfer(path)?;
+        let expected = if ascii { None } else { self.size(path).ok() };
+        let result = self
+            .retr_into(path, offset, data)
+            .and_then(|()| match expected {
+                Some(expected) if (data.len() as u64) < expected => {
+                    Err(FtpError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "Transfer of {} ended after {} of {} bytes",
+                            path,
+                            data.len(),
+                            expected
+                        ),
+                    )))
+                }
+                _ => Ok(()),
+            });
+        self.end_transfer(ascii, result)
+    }
+
+    /// RETR `path` from `offset` in the current transfer type, appending to `data`
+    fn retr_into(&mut self, path: &str, offset: usize, data: &mut Vec<u8>) -> Result<()> {
+        let throttle = self.options.limit_down.clone();
+        let mut read_all = |reader: &mut dyn Read| {
+            let mut chunk = [0u8; 64 * 1024];
+            let mut reader = ThrottledReader::new(reader, throttle.clone());
+            loop {
+                match reader.read(&mut chunk) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => data.extend_from_slice(&chunk[..n]),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(suppaftp::FtpError::ConnectionError(e)),
+                }
             }
-            FtpStreamVariant::Tls(stream) => {
-                let mut reader = stream
-                    .retr_as_buffer(path)
-                    .context(format!("Failed to retrieve file {}", path))?;
-                let mut data = Vec::new();
-                reader
-                    .read_to_end(&mut data)
-                    .context("Failed to read file data")?;
-                data
+        };
+
+        if offset > 0 {
+            self.custom_command(&format!("REST {}", offset), &[Status::RequestFilePending])
+                .context(format!("Failed to resume {} at offset {}", path, offset))?;
+        }
+
+        if self.own_data_path() {
+            let mut reader = self
+                .open_data(&format!("RETR {}", path))
+                .context(format!("Failed to retrieve file {}", path))?;
+            let read = read_all(&mut reader);
+            self.finish_data(reader)
+                .context(format!("Failed to retrieve file {}", path))?;
+            return read.context(format!("Failed to retrieve file {}", path));
+        }
+
+        trace_command(self.options.verbose, &format!("RETR {}", path));
+        let retrieved = match self.stream() {
+            FtpStreamVariant::Plain(stream) => stream.retr(path, read_all),
+            FtpStreamVariant::Tls(stream) => stream.retr(path, read_all),
+        };
+        trace_refusal(self.options.verbose, &retrieved);
+        retrieved.context(format!("Failed to retrieve file {}", path))
+    }
+
+    /// Download up to `len` bytes starting at `offset` (REST + RETR)
+    ///
+    /// The transfer is aborted once `len` bytes have arrived, so only the
+    /// requested window crosses the network.
+    pub fn retrieve_range(&mut self, path: &str, offset: u64, len: usize) -> Result<Vec<u8>> {
+        debug!("Retrieving {} bytes at offset {} of {}", len, offset, path);
+        self.check_ranged(path)?;
+        self.ensure_binary()?;
+
+        if offset > 0 {
+            if !self.supports("REST STREAM") {
+                return Err(protocol_error!("Server does not support REST STREAM"));
+            }
+            self.custom_command(&format!("REST {}", offset), &[Status::RequestFilePending])?;
+        }
+
+        // Read one byte past the window to know whether the file continues:
+        // if it does the transfer must be aborted, otherwise it completed
+        let mut data = Vec::with_capacity(len + 1);
+        let throttle = self.options.limit_down.clone();
+        let verbose = self.options.verbose;
+        let aborted = if self.own_data_path() {
+            let mut reader = self.open_data(&format!("RETR {}", path))?;
+            ThrottledReader::new(&mut reader, throttle)
+                .take(len as u64 + 1)
+                .read_to_end(&mut data)?;
+            if data.len() > len {
+                trace_command(verbose, "ABOR");
+                match self.stream() {
+                    FtpStreamVariant::Plain(stream) => stream.abort(reader),
+                    FtpStreamVariant::Tls(stream) => stream.abort(reader),
+                }
+            } else {
+                self.finish_data(reader)?;
+                Ok(())
+            }
+        } else {
+            trace_command(verbose, &format!("RETR {}", path));
+            match self.stream() {
+                FtpStreamVariant::Plain(stream) => {
+                    let reader = stream.retr_as_stream(path);
+                    trace_refusal(verbose, &reader);
+                    let mut reader = reader?;
+                    ThrottledReader::new(&mut reader, throttle)
+                        .take(len as u64 + 1)
+                        .read_to_end(&mut data)?;
+                    if data.len() > len {
+                        trace_command(verbose, "ABOR");
+                        stream.abort(reader)
+                    } else {
+                        stream
+                            .finalize_retr_stream(reader)
+                            .map_err(FtpError::from)?;
+                        Ok(())
+                    }
+                }
+                FtpStreamVariant::Tls(stream) => {
+                    let reader = stream.retr_as_stream(path);
+                    trace_refusal(verbose, &reader);
+                    let mut reader = reader?;
+                    ThrottledReader::new(&mut reader, throttle)
+                        .take(len as u64 + 1)
+                        .read_to_end(&mut data)?;
+                    if data.len() > len {
+                        trace_command(verbose, "ABOR");
+                        stream.abort(reader)
+                    } else {
+                        stream
+                            .finalize_retr_stream(reader)
+                            .map_err(FtpError::from)?;
+                        Ok(())
+                    }
+                }
             }
         };
 
-        debug!("Retrieved {} bytes from {}", data.len(), path);
+        trace_refusal(verbose, &aborted);
+        // Servers disagree on the replies to ABOR; resync with a fresh session
+        if let Err(e) = aborted {
+            debug!("ABOR after ranged download failed, reconnecting: {}", e);
+            self.reconnect()?;
+        }
+
+        data.truncate(len);
         Ok(data)
     }
 
@@ -288,21 +1972,71 @@ impl FtpConnection {
     pub fn store(&mut self, path: &str, data: &[u8]) -> Result<()> {
         debug!("Storing file: {} ({} bytes)", path, data.len());
 
-        match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => {
-                let mut reader = io::Cursor::new(data);
-                stream
-                    .put_file(path, &mut reader)
-                    .context(format!("Failed to store file {}", path))?;
-            }
-            FtpStreamVariant::Tls(stream) => {
-                let mut reader = io::Cursor::new(data);
-                stream
-                    .put_file(path, &mut reader)
-                    .context(format!("Failed to store file {}", path))?;
-            }
+        let mut reader = io::Cursor::new(data);
+        self.store_from(path, &mut reader)
+    }
+
+    /// Upload file contents streamed from a reader
+    pub fn store_from(&mut self, path: &str, reader: &mut dyn Read) -> Result<()> {
+        debug!("Streaming file: {}", path);
+        let ascii = self.begin_transfer(path)?;
+        let result = self.put_from(path, reader);
+        self.end_transfer(ascii, result)
+    }
+
+    /// STOR `path` from `reader` in the current transfer type
+    fn put_from(&mut self, path: &str, reader: &mut dyn Read) -> Result<()> {
+        let mut reader = ThrottledReader::new(reader, self.options.limit_up.clone());
+        let written = self
+            .put_data(path, &mut reader)
+            .context(format!("Failed to store file {}", path))?;
+
+        debug!("Stored {} bytes to {}", written, path);
+        Ok(())
+    }
+
+    /// Send STOR `path` and upload `reader` over the data channel,
+    /// returning the bytes sent
+    fn put_data(&mut self, path: &str, mut reader: &mut dyn Read) -> Result<u64> {
+        if self.own_data_path() {
+            let mut data = self.open_data(&format!("STOR {}", path))?;
+            let copied = io::copy(reader, &mut data);
+            self.finish_data(data)?;
+            return Ok(copied?);
+        }
+        trace_command(self.options.verbose, &format!("STOR {}", path));
+        let written = match self.stream() {
+            FtpStreamVariant::Plain(stream) => stream.put_file(path, &mut reader),
+            FtpStreamVariant::Tls(stream) => stream.put_file(path, &mut reader),
+        };
+        trace_refusal(self.options.verbose, &written);
+        Ok(written?)
+    }
+
+    /// Upload data at a byte offset of an existing file (REST + STOR)
+    ///
+    /// Offset 0 also sends `REST 0`: a plain STOR would truncate the rest of
+    /// the file.
+    pub fn store_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<()> {
+        self.check_ranged(path)?;
+        if !self.supports("REST STREAM") {
+            return Err(protocol_error!("Server does not support REST STREAM"));
         }
 
+        debug!(
+            "Storing {} bytes at offset {} of {}",
+            data.len(),
+            offset,
+            path
+        );
+        self.ensure_binary()?;
+
+        let mut reader = ThrottledReader::new(io::Cursor::new(data), self.options.limit_up.clone());
+        self.custom_command(&format!("REST {}", offset), &[Status::RequestFilePending])
+            .context(format!("Failed to set restart offset for {}", path))?;
+        self.put_data(path, &mut reader)
+            .context(format!("Failed to store file {}", path))?;
+
         Ok(())
     }
 
@@ -310,14 +2044,8 @@ impl FtpConnection {
     pub fn delete(&mut self, path: &str) -> Result<()> {
         debug!("Deleting file: {}", path);
 
-        match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => stream
-                .rm(path)
-                .context(format!("Failed to delete file {}", path))?,
-            FtpStreamVariant::Tls(stream) => stream
-                .rm(path)
-                .context(format!("Failed to delete file {}", path))?,
-        }
+        self.custom_command(&format!("DELE {}", path), &[Status::RequestedFileActionOk])
+            .context(format!("Failed to delete file {}", path))?;
 
         Ok(())
     }
@@ -326,14 +2054,8 @@ impl FtpConnection {
     pub fn mkdir(&mut self, path: &str) -> Result<()> {
         debug!("Creating directory: {}", path);
 
-        match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => stream
-                .mkdir(path)
-                .context(format!("Failed to create directory {}", path))?,
-            FtpStreamVariant::Tls(stream) => stream
-                .mkdir(path)
-                .context(format!("Failed to create directory {}", path))?,
-        }
+        self.custom_command(&format!("MKD {}", path), &[Status::PathCreated])
+            .context(format!("Failed to create directory {}", path))?;
 
         Ok(())
     }
@@ -342,14 +2064,8 @@ impl FtpConnection {
     pub fn rmdir(&mut self, path: &str) -> Result<()> {
         debug!("Removing directory: {}", path);
 
-        match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => stream
-                .rmdir(path)
-                .context(format!("Failed to remove directory {}", path))?,
-            FtpStreamVariant::Tls(stream) => stream
-                .rmdir(path)
-                .context(format!("Failed to remove directory {}", path))?,
-        }
+        self.custom_command(&format!("RMD {}", path), &[Status::RequestedFileActionOk])
+            .context(format!("Failed to remove directory {}", path))?;
 
         Ok(())
     }
@@ -358,30 +2074,66 @@ impl FtpConnection {
     pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
         debug!("Renaming {} to {}", from, to);
 
-        match &mut self.stream {
-            FtpStreamVariant::Plain(stream) => stream
-                .rename(from, to)
-                .context(format!("Failed to rename {} to {}", from, to))?,
-            FtpStreamVariant::Tls(stream) => stream
-                .rename(from, to)
-                .context(format!("Failed to rename {} to {}", from, to))?,
-        }
+        self.custom_command(&format!("RNFR {}", from), &[Status::RequestFilePending])
+            .and_then(|_| {
+                self.custom_command(&format!("RNTO {}", to), &[Status::RequestedFileActionOk])
+            })
+            .context(format!("Failed to rename {} to {}", from, to))?;
+
+        Ok(())
+    }
+
+    /// Change permission bits with `SITE CHMOD`
+    ///
+    /// Not part of RFC 959, but most Unix servers implement it; the others
+    /// reply 500/502, which is returned as [`FtpError::Protocol`].
+    pub fn chmod(&mut self, path: &str, mode: u32) -> Result<()> {
+        debug!("Changing mode of {} to {:o}", path, mode);
+
+        self.site(&format!("CHMOD {:o} {}", mode & 0o7777, path))
+            .context(format!("Failed to change mode of {}", path))?;
 
         Ok(())
     }
 
     /// Check if path is a directory
+    ///
+    /// Tries, in order: the `type` fact from MLST (one round trip), the entry
+    /// in the parent directory listing, and finally a CWD probe that always
+    /// returns to the original directory.
     pub fn is_dir(&mut self, path: &str) -> Result<bool> {
-        // Try to change to the directory - if it succeeds, it's a directory
-        let original_dir = self.pwd()?;
+        if path == "/" {
+            return Ok(true);
+        }
+
+        if self.supports("MLST") {
+            // The facts are on the second line, between the reply's frame
+            let facts = self
+                .custom_command(&format!("MLST {}", path), &[Status::RequestedFileActionOk])
+                .map(|reply| reply_lines(&reply.body).get(1).cloned().unwrap_or_default());
+            match facts.map(|line| parse_mlst_type(&line)) {
+                Ok(Some(kind)) => return Ok(matches!(kind.as_str(), "dir" | "cdir" | "pdir")),
+                Ok(None) => debug!("MLST reply for {} has no type fact", path),
+                Err(e) => debug!("MLST failed for {}: {}", path, e),
+            }
+        }
 
-        match self.cwd(path) {
-            Ok(_) => {
-                self.cwd(&original_dir)?;
-                Ok(true)
+        let (parent, name) = match path.trim_end_matches('/').rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((parent, name)) => (parent, name),
+            None => (".", path),
+        };
+        match self.list_dir(parent) {
+            Ok(files) => {
+                if let Some(file) = files.iter().find(|f| f.name == name) {
+                    return Ok(file.is_dir);
+                }
+                // Hidden entries may be missing from LIST, so keep probing
             }
-            Err(_) => Ok(false),
+            Err(e) => debug!("Failed to list {} while probing {}: {}", parent, path, e),
         }
+
+        Ok(self.with_cwd(path, |_| Ok(())).is_ok())
     }
 
     /// Check if file exists
@@ -396,16 +2148,30 @@ impl FtpConnection {
     }
 
     /// Parse a directory listing line (UNIX format)
-    fn parse_list_line(&self, line: &str) -> Result<FtpFileInfo> {
+    pub(crate) fn parse_list_line(current_dir: &str, line: &str) -> Result<FtpFileInfo> {
         // Parse UNIX ls -l format:
         // drwxr-xr-x 2 user group 4096 Jan 01 00:00 filename
         // -rw-r--r-- 1 user group 1234 Jan 01 00:00 filename
-
-        let parts: Vec<&str> = line.split_whitespace().collect();
-
-        if parts.len() < 9 {
-            return Err(anyhow::anyhow!("Invalid listing format"));
+        // lrwxrwxrwx 1 user group    7 Jan 01 00:00 link -> target
+
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        // Locate the first eight columns; the name is everything after the
+        // single separator that follows the time/year column, so internal,
+        // leading and trailing spaces in the name are preserved.
+        let mut parts = Vec::with_capacity(8);
+        let mut rest = line;
+        for _ in 0..8 {
+            let trimmed = rest.trim_start();
+            let end = trimmed
+                .find(char::is_whitespace)
+                .ok_or_else(|| protocol_error!("Invalid listing format"))?;
+            parts.push(&trimmed[..end]);
+            rest = &trimmed[end..];
         }
+        let raw_name = rest
+            .strip_prefix(' ')
+            .ok_or_else(|| protocol_error!("Invalid listing format"))?;
 
         let permissions_str = parts[0];
         let is_dir = permissions_str.starts_with('d');
@@ -413,30 +2179,118 @@ impl FtpConnection {
         // Parse size (5th field)
         let size = parts[4].parse::<u64>().unwrap_or(0);
 
-        // Parse date (fields 5-7) and filename (rest)
-        let name_parts = &parts[8..];
-        let name = name_parts.join(" ");
+        // Symlinks are listed as "name -> target"; other names may contain "->"
+        let (name, link_target) = match raw_name.split_once(" -> ") {
+            Some((name, target)) if permissions_str.starts_with('l') => {
+                (name.to_string(), Some(target.to_string()))
+            }
+            _ => (raw_name.to_string(), None),
+        };
 
         // Build full path
-        let path = if self.current_dir.ends_with('/') {
-            format!("{}{}", self.current_dir, name)
-        } else {
-            format!("{}/{}", self.current_dir, name)
-        };
+        let path = join_ftp_path(current_dir, &name);
 
         // Parse permissions
         let permissions = Self::parse_permissions(permissions_str);
 
+        // Unparseable dates are left unknown rather than rejecting the entry
+        let modified_time = parse_unix_timestamp(parts[5], parts[6], parts[7], SystemTime::now());
+
         Ok(FtpFileInfo {
             name,
             path,
             size,
             is_dir,
             permissions,
-            modified_time: None, // Parsing time is complex and may vary by server
+            modified_time,
+            link_target,
+            raw_name: None,
+        })
+    }
+
+    /// Parse a directory listing line (MS-DOS/IIS format)
+    fn parse_dos_line(current_dir: &str, line: &str) -> Result<FtpFileInfo> {
+        // 01-15-24  10:30AM       <DIR>          dirname
+        // 01-15-2024  10:30PM             1234 file name.txt
+        let invalid = || protocol_error!("Invalid MS-DOS listing format");
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        let mut parts = Vec::with_capacity(3);
+        let mut rest = line;
+        for _ in 0..3 {
+            let trimmed = rest.trim_start();
+            let end = trimmed.find(char::is_whitespace).ok_or_else(invalid)?;
+            parts.push(&trimmed[..end]);
+            rest = &trimmed[end..];
+        }
+        let name = rest.trim_start().to_string();
+
+        let modified_time = parse_dos_timestamp(parts[0], parts[1]).ok_or_else(invalid)?;
+        let (is_dir, size) = if parts[2].eq_ignore_ascii_case("<DIR>") {
+            (true, 0)
+        } else {
+            (false, parts[2].parse::<u64>().map_err(|_| invalid())?)
+        };
+
+        Ok(FtpFileInfo {
+            path: join_ftp_path(current_dir, &name),
+            name,
+            size,
+            is_dir,
+            permissions: if is_dir { 0o040755 } else { 0o644 },
+            modified_time: Some(modified_time),
+            link_target: None,
+            raw_name: None,
         })
     }
 
+    /// Parse a machine-readable MLSD entry (RFC 3659)
+    fn parse_mlsd_line(current_dir: &str, line: &str) -> Result<Option<FtpFileInfo>> {
+        // type=file;size=1234;modify=20240115103000;UNIX.mode=0644; name
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (facts, name) = line
+            .split_once("; ")
+            .or_else(|| line.split_once(' '))
+            .ok_or_else(|| protocol_error!("Invalid MLSD entry"))?;
+
+        let mut kind = None;
+        let mut size = 0;
+        let mut modified_time = None;
+        let mut mode = None;
+        for fact in facts.split(';') {
+            let Some((key, value)) = fact.split_once('=') else {
+                continue;
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "type" => kind = Some(value.to_ascii_lowercase()),
+                "size" | "sizd" => size = value.parse().unwrap_or(0),
+                "modify" => modified_time = parse_timeval(value),
+                "unix.mode" => mode = u32::from_str_radix(value, 8).ok(),
+                _ => {}
+            }
+        }
+
+        let is_dir = match kind.as_deref() {
+            Some("cdir") | Some("pdir") => return Ok(None),
+            Some("dir") => true,
+            Some(_) => false,
+            None => return Err(protocol_error!("MLSD entry has no type fact")),
+        };
+        let default_mode = if is_dir { 0o755 } else { 0o644 };
+        let permissions = mode.unwrap_or(default_mode) & 0o7777 | if is_dir { 0o040000 } else { 0 };
+
+        Ok(Some(FtpFileInfo {
+            path: join_ftp_path(current_dir, name),
+            name: name.to_string(),
+            size,
+            is_dir,
+            permissions,
+            modified_time,
+            link_target: None,
+            raw_name: None,
+        }))
+    }
+
     /// Parse UNIX permission string to numeric mode
     fn parse_permissions(perm_str: &str) -> u32 {
         let mut mode: u32 = 0;
@@ -485,19 +2339,1756 @@ impl FtpConnection {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Remote operations used by the FUSE layer
+///
+/// Implemented by [`FtpConnection`]; tests substitute an in-memory backend so
+/// the filesystem logic can be exercised without a live server.
+pub trait FtpBackend: Send + 'static {
+    /// List files in a directory
+    fn list_dir(&mut self, path: &str) -> Result<Vec<FtpFileInfo>>;
+
+    /// Download file contents, appending to `data` (resuming at `data.len()`)
+    fn retrieve_into(&mut self, path: &str, data: &mut Vec<u8>) -> Result<()>;
+
+    /// Download up to `len` bytes starting at `offset`
+    fn retrieve_range(&mut self, path: &str, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let data = self.retrieve(path)?;
+        let start = (offset as usize).min(data.len());
+        let end = data.len().min(start + len);
+        Ok(data[start..end].to_vec())
+    }
 
-    #[test]
-    fn test_parse_permissions() {
-        let perm = FtpConnection::parse_permissions("drwxr-xr-x");
-        assert_eq!(perm, 0o040755);
+    /// Upload file contents streamed from a reader
+    fn store_from(&mut self, path: &str, reader: &mut dyn Read) -> Result<()>;
 
-        let perm = FtpConnection::parse_permissions("-rw-r--r--");
-        assert_eq!(perm, 0o0644);
+    /// Upload data at a byte offset of an existing file
+    fn store_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<()>;
 
-        let perm = FtpConnection::parse_permissions("-rwxrwxrwx");
-        assert_eq!(perm, 0o777);
+    /// Get file size
+    fn size(&mut self, path: &str) -> Result<u64>;
+
+    /// Get file modification time
+    fn mdtm(&mut self, path: &str) -> Result<SystemTime>;
+
+    /// Check if path is a directory
+    fn is_dir(&mut self, path: &str) -> Result<bool>;
+
+    /// Delete file
+    fn delete(&mut self, path: &str) -> Result<()>;
+
+    /// Create directory
+    fn mkdir(&mut self, path: &str) -> Result<()>;
+
+    /// Remove directory
+    fn rmdir(&mut self, path: &str) -> Result<()>;
+
+    /// Rename file or directory
+    fn rename(&mut self, from: &str, to: &str) -> Result<()>;
+
+    /// Re-establish the connection after a failure
+    fn reconnect(&mut self) -> Result<()>;
+
+    /// Download file contents
+    fn retrieve(&mut self, path: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.retrieve_into(path, &mut data)?;
+        Ok(data)
+    }
+
+    /// Upload file contents
+    fn store(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        self.store_from(path, &mut io::Cursor::new(data))
+    }
+
+    /// Check if file exists
+    fn exists(&mut self, path: &str) -> Result<bool> {
+        match self.size(path) {
+            Ok(_) => Ok(true),
+            Err(_) => self.is_dir(path),
+        }
+    }
+
+    /// Time since the last command was sent
+    fn idle_time(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Extensions of the files transferred in ASCII mode (`--ascii-ext`)
+    fn ascii_extensions(&self) -> &[String] {
+        &[]
+    }
+
+    /// Keep the connection alive
+    fn noop(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Close the session before the filesystem goes away
+    fn quit(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Change permission bits (`SITE CHMOD`, an optional extension)
+    fn chmod(&mut self, _path: &str, _mode: u32) -> Result<()> {
+        Err(protocol_error!("SITE CHMOD is not supported"))
+    }
+
+    /// Set the modification time (`MFMT` or `SITE UTIME`, optional extensions)
+    fn set_mtime(&mut self, _path: &str, _time: SystemTime) -> Result<()> {
+        Err(protocol_error!("MFMT is not supported"))
+    }
+
+    /// Free space for uploads to `path` (`AVBL`, an optional extension)
+    fn available_space(&mut self, _path: &str) -> Result<u64> {
+        Err(protocol_error!("AVBL is not supported"))
+    }
+}
+
+impl FtpBackend for FtpConnection {
+    fn list_dir(&mut self, path: &str) -> Result<Vec<FtpFileInfo>> {
+        self.ensure_connected()?;
+        FtpConnection::list_dir(self, path)
+    }
+
+    fn retrieve_into(&mut self, path: &str, data: &mut Vec<u8>) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::retrieve_into(self, path, data)
+    }
+
+    fn retrieve_range(&mut self, path: &str, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.ensure_connected()?;
+        FtpConnection::retrieve_range(self, path, offset, len)
+    }
+
+    fn store_from(&mut self, path: &str, reader: &mut dyn Read) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::store_from(self, path, reader)
+    }
+
+    fn store_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::store_at(self, path, offset, data)
+    }
+
+    fn size(&mut self, path: &str) -> Result<u64> {
+        self.ensure_connected()?;
+        FtpConnection::size(self, path)
+    }
+
+    fn mdtm(&mut self, path: &str) -> Result<SystemTime> {
+        self.ensure_connected()?;
+        FtpConnection::mdtm(self, path)
+    }
+
+    fn is_dir(&mut self, path: &str) -> Result<bool> {
+        self.ensure_connected()?;
+        FtpConnection::is_dir(self, path)
+    }
+
+    fn delete(&mut self, path: &str) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::delete(self, path)
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::mkdir(self, path)
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::rmdir(self, path)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::rename(self, from, to)
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        FtpConnection::reconnect(self)
+    }
+
+    fn retrieve(&mut self, path: &str) -> Result<Vec<u8>> {
+        self.ensure_connected()?;
+        FtpConnection::retrieve(self, path)
+    }
+
+    fn store(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::store(self, path, data)
+    }
+
+    fn exists(&mut self, path: &str) -> Result<bool> {
+        self.ensure_connected()?;
+        FtpConnection::exists(self, path)
+    }
+
+    fn idle_time(&self) -> Duration {
+        FtpConnection::idle_time(self)
+    }
+
+    fn ascii_extensions(&self) -> &[String] {
+        &self.options.ascii_extensions
+    }
+
+    fn noop(&mut self) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::noop(self)
+    }
+
+    /// A session that is already lost has nothing to close
+    fn quit(&mut self) -> Result<()> {
+        if self.reconnect_failures > 0 {
+            return Ok(());
+        }
+        FtpConnection::quit(self)
+    }
+
+    fn chmod(&mut self, path: &str, mode: u32) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::chmod(self, path, mode)
+    }
+
+    fn set_mtime(&mut self, path: &str, time: SystemTime) -> Result<()> {
+        self.ensure_connected()?;
+        FtpConnection::set_mtime(self, path, time)
+    }
+
+    fn available_space(&mut self, path: &str) -> Result<u64> {
+        self.ensure_connected()?;
+        FtpConnection::available_space(self, path)
+    }
+}
+
+/// Split a raw reply into its lines of text, without status codes
+///
+/// Multi-line replies look like `211-Features:\r\n MDTM\r\n211 End`: the
+/// first line carries `code-`, the last `code `, and the lines in between
+/// may or may not repeat the code.
+fn reply_lines(body: &[u8]) -> Vec<String> {
+    let body = String::from_utf8_lossy(body);
+    let code = body.get(..3).unwrap_or_default();
+    body.lines()
+        .map(|line| {
+            let text = match line.strip_prefix(code) {
+                Some(rest) if rest.starts_with(['-', ' ']) => &rest[1..],
+                _ => line,
+            };
+            text.trim().to_string()
+        })
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Extract the lowercased `type` fact from an MLST/MLSD entry
+///
+/// Entries look like `type=dir;modify=20240101000000; /pub/dir`.
+fn parse_mlst_type(entry: &str) -> Option<String> {
+    let facts = entry
+        .trim_start()
+        .split_once(' ')
+        .map_or(entry, |(facts, _)| facts);
+    facts.split(';').find_map(|fact| {
+        let (key, value) = fact.split_once('=')?;
+        key.eq_ignore_ascii_case("type")
+            .then(|| value.to_lowercase())
+    })
+}
+
+/// Parse an MDTM reply (`213 YYYYMMDDhhmmss[.fff]`, always UTC)
+fn parse_mdtm_reply(reply: &str) -> Option<SystemTime> {
+    parse_timeval(reply.trim().strip_prefix("213")?.trim())
+}
+
+/// Parse a SIZE or AVBL reply (`213 <bytes>`)
+fn parse_size_reply(reply: &str) -> Option<u64> {
+    reply.trim().strip_prefix("213")?.trim().parse().ok()
+}
+
+/// Parse an RFC 3659 time-val (`YYYYMMDDHHMMSS[.sss]`, UTC)
+fn parse_timeval(value: &str) -> Option<SystemTime> {
+    let (whole, fraction) = match value.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (value, None),
+    };
+
+    if whole.len() != 14 || !whole.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let field = |range: std::ops::Range<usize>| whole[range].parse::<u32>().ok();
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(8..10)?, field(10..12)?, field(12..14)?);
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    // Leap seconds are reported as :60 by some servers
+    let second = second.min(59);
+
+    let nanos = match fraction {
+        Some(digits) if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) => {
+            let digits = &digits[..digits.len().min(9)];
+            digits.parse::<u32>().ok()? * 10u32.pow(9 - digits.len() as u32)
+        }
+        Some(_) => return None,
+        None => 0,
+    };
+
+    let secs = days_from_civil(year as i64, month, day) * 86_400
+        + (hour * 3600 + minute * 60 + second) as i64;
+    if secs < 0 {
+        return None;
+    }
+
+    Some(UNIX_EPOCH + Duration::new(secs as u64, nanos))
+}
+
+/// Parse the date and time columns of an MS-DOS listing (`01-15-24`, `10:30AM`)
+fn parse_dos_timestamp(date: &str, time: &str) -> Option<SystemTime> {
+    let mut date_parts = date.split('-');
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+    let year_str = date_parts.next()?;
+    let year: i64 = year_str.parse().ok()?;
+    // Two-digit years follow the usual pivot: 70-99 -> 19xx, 00-69 -> 20xx
+    let year = match year_str.len() {
+        2 if year >= 70 => 1900 + year,
+        2 => 2000 + year,
+        4 => year,
+        _ => return None,
+    };
+
+    let upper = time.to_ascii_uppercase();
+    let (clock, pm) = match (upper.strip_suffix("AM"), upper.strip_suffix("PM")) {
+        (Some(clock), _) => (clock.to_string(), Some(false)),
+        (_, Some(clock)) => (clock.to_string(), Some(true)),
+        _ => (upper, None),
+    };
+    let (hour, minute) = clock.split_once(':')?;
+    let (mut hour, minute): (u32, u32) = (hour.parse().ok()?, minute.parse().ok()?);
+    match pm {
+        Some(true) if hour < 12 => hour += 12,
+        Some(false) if hour == 12 => hour = 0,
+        _ => {}
+    }
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86_400 + (hour * 3600 + minute * 60) as i64;
+    (secs >= 0).then(|| UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// Whether a server greeting invites anonymous logins
+///
+/// Public archives usually say so ("Anonymous access granted", "Please
+/// login with USER anonymous"); greetings that mention it only to refuse it
+/// don't count.
+pub fn greeting_allows_anonymous(greeting: &str) -> bool {
+    const REFUSALS: [&str; 6] = [
+        "no anonymous",
+        "not allowed",
+        "not permitted",
+        "not available",
+        "denied",
+        "disabled",
+    ];
+    let greeting = greeting.to_ascii_lowercase();
+    greeting.contains("anonymous") && !REFUSALS.iter().any(|refusal| greeting.contains(refusal))
+}
+
+/// Whether a listing line is blank or a summary rather than an entry
+///
+/// `ls -l` output starts with `total 48` (`Total: 48` or `total 1.2M` on
+/// some servers), and MS-DOS style listings can end with `3 File(s) 1234
+/// bytes` and `2 Dir(s)` lines. Entries never start with these words, so a
+/// file named `total` is still listed.
+fn is_summary_line(line: &str) -> bool {
+    let mut words = line.split_whitespace();
+    let first = match words.next() {
+        Some(first) => first,
+        None => return true,
+    };
+
+    if first.trim_end_matches(':').eq_ignore_ascii_case("total") {
+        return match (words.next(), words.next()) {
+            (Some(count), None) => count.starts_with(|c: char| c.is_ascii_digit()),
+            _ => false,
+        };
+    }
+    first.bytes().all(|b| b.is_ascii_digit() || b == b',')
+        && words.next().is_some_and(|word| {
+            word.eq_ignore_ascii_case("File(s)") || word.eq_ignore_ascii_case("Dir(s)")
+        })
+}
+
+/// Parse the date columns of an `ls -l` listing (`Jan 15 10:30` or `Jan 15 2023`)
+///
+/// Recent files show a time instead of the year: the year is the one that
+/// puts the date no later than `now` (plus a day of slack for zone
+/// differences), as `ls` only omits it for dates within the last months.
+fn parse_unix_timestamp(
+    month: &str,
+    day: &str,
+    time_or_year: &str,
+    now: SystemTime,
+) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as u32 + 1;
+    let day: u32 = day.parse().ok()?;
+    if !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let to_secs = |year: i64, hour: u32, minute: u32| {
+        days_from_civil(year, month, day) * 86_400 + (hour * 3600 + minute * 60) as i64
+    };
+    let secs = match time_or_year.split_once(':') {
+        Some((hour, minute)) => {
+            let (hour, minute): (u32, u32) = (hour.parse().ok()?, minute.parse().ok()?);
+            if hour > 23 || minute > 59 {
+                return None;
+            }
+            let now = now.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+            let (this_year, _, _) = civil_from_days(now.div_euclid(86_400));
+            let secs = to_secs(this_year, hour, minute);
+            if secs > now + 86_400 {
+                to_secs(this_year - 1, hour, minute)
+            } else {
+                secs
+            }
+        }
+        None => to_secs(time_or_year.parse().ok()?, 0, 0),
+    };
+
+    (secs >= 0).then(|| UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// Parse a UTC offset for `--server-tz`: `UTC`, `+02:00`, `-0530`, `UTC+1`...
+///
+/// Returns the offset in seconds east of UTC. Named zones with daylight
+/// saving rules are not supported; use the offset currently in effect.
+pub fn parse_utc_offset(value: &str) -> Result<i32, String> {
+    let invalid = || {
+        format!(
+            "'{}' is not a UTC offset like UTC, +02:00, -0530 or UTC+1",
+            value
+        )
+    };
+    let upper = value.trim().to_ascii_uppercase();
+    let offset = upper
+        .strip_prefix("UTC")
+        .or_else(|| upper.strip_prefix("GMT"))
+        .unwrap_or(&upper);
+    if offset.is_empty() || offset == "Z" {
+        return Ok(0);
+    }
+
+    let (sign, digits) = match offset.split_at(1) {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return Err(invalid()),
+    };
+    let digits = digits.replace(':', "");
+    if digits.is_empty() || digits.len() > 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let (hours, minutes) = match digits.len() {
+        1 | 2 => (digits.parse::<i32>().unwrap(), 0),
+        len => (
+            digits[..len - 2].parse::<i32>().unwrap(),
+            digits[len - 2..].parse::<i32>().unwrap(),
+        ),
+    };
+    if hours > 14 || minutes > 59 {
+        return Err(invalid());
+    }
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Move `time` by `secs` seconds (negative = earlier)
+fn shift_time(time: SystemTime, secs: i32) -> SystemTime {
+    let delta = Duration::from_secs(secs.unsigned_abs() as u64);
+    let shifted = if secs >= 0 {
+        time.checked_add(delta)
+    } else {
+        time.checked_sub(delta)
+    };
+    shifted.unwrap_or(time)
+}
+
+/// Join a directory and an entry name into a server path
+///
+/// Slashes are normalized so each file has a single spelling: `/` + `a` is
+/// `/a`, and `/pub//` + `b/` is `/pub/b`, never `//a` or `/pub/b/`.
+pub fn join_ftp_path(parent: &str, name: &str) -> String {
+    normalize_ftp_path(&format!("{}/{}", parent, name))
+}
+
+/// Whether a listed name can be an entry of its directory
+///
+/// Empty and whitespace-only names can't be told apart from a line padded
+/// after the date, and `.` and `..` are not children of the directory.
+pub fn is_entry_name(name: &str) -> bool {
+    !name.trim().is_empty() && name != "." && name != ".."
+}
+
+/// Collapse repeated slashes and drop trailing ones; the root stays `/`
+pub fn normalize_ftp_path(path: &str) -> String {
+    let joined = path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .collect::<Vec<_>>()
+        .join("/");
+    if path.starts_with('/') || joined.is_empty() {
+        format!("/{}", joined)
+    } else {
+        joined
+    }
+}
+
+/// Format a time as an RFC 3659 time-val (`YYYYMMDDHHMMSS`, UTC) for MFMT
+///
+/// Fractions of a second are dropped; times before 1970 become the epoch.
+fn format_timeval(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()) as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let seconds_of_day = secs.rem_euclid(86_400);
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Proleptic Gregorian date for a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::sync::mpsc;
+    use suppaftp::rustls::ServerConnection;
+
+    /// Start a scripted control-connection server that answers each command
+    /// with `respond(command)`, and connect to it
+    fn connect_scripted(respond: fn(&str) -> String) -> FtpConnection {
+        connect_scripted_on("127.0.0.1", respond)
+    }
+
+    /// Like [`connect_scripted`], with the server listening on `host`
+    fn connect_scripted_on(host: &str, respond: fn(&str) -> String) -> FtpConnection {
+        let port = serve_scripted(host, respond);
+        FtpConnection::with_options(
+            host.to_string(),
+            "user".to_string(),
+            "pass".to_string(),
+            Some(port),
+            ConnectOptions::default(),
+        )
+        .unwrap()
+    }
+
+    /// Start the scripted server on `host` and return its port
+    fn serve_scripted(host: &str, respond: fn(&str) -> String) -> u16 {
+        let listener = TcpListener::bind(format!("{}:0", host)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Every connection (including reconnects) gets the same script
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut writer = stream;
+                    writer.write_all(b"220 ready\r\n").unwrap();
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                        let reply = match line.trim_end() {
+                            command if command.starts_with("USER") => "331 password".to_string(),
+                            command if command.starts_with("PASS") => "230 logged in".to_string(),
+                            command if command.starts_with("TYPE") => "200 ok".to_string(),
+                            command => respond(command),
+                        };
+                        if writer
+                            .write_all(format!("{}\r\n", reply).as_bytes())
+                            .is_err()
+                        {
+                            break;
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_is_dir_failure_keeps_current_dir() {
+        let mut conn = connect_scripted(|command| match command {
+            "PWD" => "257 \"/pub\" is current directory".to_string(),
+            "CWD /pub" => "250 ok".to_string(),
+            _ => "550 no such file or command".to_string(),
+        });
+        conn.pwd().unwrap();
+
+        assert!(!conn.is_dir("/pub/missing").unwrap());
+        assert_eq!(conn.current_dir, "/pub");
+    }
+
+    #[test]
+    fn test_is_dir_uses_mlst_type() {
+        let mut conn = connect_scripted(|command| match command {
+            "MLST /pub/dir" => {
+                "250-Listing /pub/dir\r\n type=dir;modify=20240101000000; /pub/dir\r\n250 End"
+                    .to_string()
+            }
+            "MLST /pub/file" => {
+                "250-Listing /pub/file\r\n Type=file;Size=3; /pub/file\r\n250 End".to_string()
+            }
+            // CWD must not be needed when MLST answers
+            _ => "550 unexpected".to_string(),
+        });
+
+        assert!(conn.is_dir("/pub/dir").unwrap());
+        assert!(!conn.is_dir("/pub/file").unwrap());
+        assert_eq!(conn.current_dir, "/");
+    }
+
+    #[test]
+    fn test_size_and_mdtm_fall_back_to_relative_names() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Mutex;
+        // A server that only answers SIZE and MDTM for names in its current directory
+        static CWD: Mutex<String> = Mutex::new(String::new());
+
+        let mut conn = connect_scripted(|command| {
+            let mut cwd = CWD.lock().unwrap();
+            match command.split_once(' ') {
+                Some(("CWD", dir)) if dir == "/" || dir == "/pub" => {
+                    *cwd = dir.to_string();
+                    "250 ok".to_string()
+                }
+                None if command == "PWD" => {
+                    let dir = if cwd.is_empty() { "/" } else { cwd.as_str() };
+                    format!("257 \"{}\" is current directory", dir)
+                }
+                Some(("SIZE", "a.txt")) if *cwd == "/pub" => "213 5".to_string(),
+                Some(("MDTM", "a.txt")) if *cwd == "/pub" => "213 20240125103000".to_string(),
+                _ => "550 No such file".to_string(),
+            }
+        });
+
+        assert_eq!(conn.size("/pub/a.txt").unwrap(), 5);
+        assert_eq!(conn.absolute_paths, Some(false));
+        assert_eq!(
+            conn.mdtm("/pub/a.txt").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1_706_178_600)
+        );
+        assert!(matches!(conn.size("/pub/missing"), Err(FtpError::NotFound)));
+        // Back where it was after every attempt, failed or not
+        assert_eq!(conn.current_dir, "/");
+        assert_eq!(*CWD.lock().unwrap(), "/");
+
+        // Once absolute paths are seen to work, a missing file costs no CWD
+        static CWDS: AtomicUsize = AtomicUsize::new(0);
+        let mut conn = connect_scripted(|command| match command {
+            "SIZE /pub/a.txt" => "213 5".to_string(),
+            command if command.starts_with("CWD ") => {
+                CWDS.fetch_add(1, Ordering::SeqCst);
+                "250 ok".to_string()
+            }
+            _ => "550 No such file".to_string(),
+        });
+        assert_eq!(conn.size("/pub/a.txt").unwrap(), 5);
+        assert!(matches!(conn.size("/pub/missing"), Err(FtpError::NotFound)));
+        assert_eq!(CWDS.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_parse_permissions() {
+        let perm = FtpConnection::parse_permissions("drwxr-xr-x");
+        assert_eq!(perm, 0o040755);
+
+        let perm = FtpConnection::parse_permissions("-rw-r--r--");
+        assert_eq!(perm, 0o0644);
+
+        let perm = FtpConnection::parse_permissions("-rwxrwxrwx");
+        assert_eq!(perm, 0o777);
+    }
+
+    #[test]
+    fn test_parse_mdtm_reply() {
+        let time = parse_mdtm_reply("213 20240125103000").unwrap();
+        assert_eq!(time, UNIX_EPOCH + Duration::from_secs(1_706_178_600));
+
+        let time = parse_mdtm_reply("213 20240125103000.250\r\n").unwrap();
+        assert_eq!(time, UNIX_EPOCH + Duration::new(1_706_178_600, 250_000_000));
+
+        assert!(parse_mdtm_reply("500 Unknown command").is_none());
+        assert!(parse_mdtm_reply("213 2024").is_none());
+    }
+
+    #[test]
+    fn test_parse_size_reply() {
+        assert_eq!(parse_size_reply("213 1048576\r\n"), Some(1_048_576));
+        assert_eq!(parse_size_reply("213 0"), Some(0));
+        assert!(parse_size_reply("550 Not a directory").is_none());
+        assert!(parse_size_reply("213 lots").is_none());
+    }
+
+    #[test]
+    fn test_parse_list_line_names_with_spaces() {
+        let info = FtpConnection::parse_list_line(
+            "/docs",
+            "-rw-r--r--   1 user  group     1234 Jan 01 00:00 my report.txt",
+        )
+        .unwrap();
+        assert_eq!(info.name, "my report.txt");
+        assert_eq!(info.path, "/docs/my report.txt");
+        assert_eq!(info.size, 1234);
+
+        let info = FtpConnection::parse_list_line(
+            "/",
+            "-rw-r--r-- 1 user group 10 Jan 01  2023 a  b.txt ",
+        )
+        .unwrap();
+        assert_eq!(info.name, "a  b.txt ");
+        assert_eq!(info.path, "/a  b.txt ");
+    }
+
+    #[test]
+    fn test_parse_list_line_symlink() {
+        let info = FtpConnection::parse_list_line(
+            "/",
+            "lrwxrwxrwx 1 user group 7 Jan 01 00:00 latest -> v1.2.3",
+        )
+        .unwrap();
+        assert_eq!(info.name, "latest");
+        assert_eq!(info.link_target.as_deref(), Some("v1.2.3"));
+
+        // Regular files may legitimately contain an arrow
+        let info = FtpConnection::parse_list_line(
+            "/",
+            "-rw-r--r-- 1 user group 7 Jan 01 00:00 a -> b.txt",
+        )
+        .unwrap();
+        assert_eq!(info.name, "a -> b.txt");
+        assert_eq!(info.link_target, None);
+    }
+
+    #[test]
+    fn test_parse_dos_line() {
+        let info = FtpConnection::parse_dos_line(
+            "/pub",
+            "01-15-24  10:30PM       <DIR>          old stuff",
+        )
+        .unwrap();
+        assert!(info.is_dir);
+        assert_eq!(info.name, "old stuff");
+        assert_eq!(info.path, "/pub/old stuff");
+
+        let info =
+            FtpConnection::parse_dos_line("/", "12-31-1999  12:05AM             1234 y2k.txt")
+                .unwrap();
+        assert!(!info.is_dir);
+        assert_eq!(info.size, 1234);
+        // 1999-12-31 00:05 UTC
+        assert_eq!(
+            info.modified_time,
+            Some(UNIX_EPOCH + Duration::from_secs(946_598_700))
+        );
+
+        assert!(
+            FtpConnection::parse_dos_line("/", "-rw-r--r-- 1 user group 7 Jan 01 00:00 a").is_err()
+        );
+    }
+
+    #[test]
+    fn test_join_ftp_path() {
+        assert_eq!(join_ftp_path("/", "a"), "/a");
+        assert_eq!(join_ftp_path("", "a"), "/a");
+        assert_eq!(join_ftp_path("/pub", "a"), "/pub/a");
+        assert_eq!(join_ftp_path("/pub/", "a"), "/pub/a");
+        assert_eq!(join_ftp_path("/pub//sub", "/a/"), "/pub/sub/a");
+        assert_eq!(join_ftp_path("//", ""), "/");
+
+        assert_eq!(normalize_ftp_path("/pub/"), "/pub");
+        assert_eq!(normalize_ftp_path("//"), "/");
+        assert_eq!(normalize_ftp_path(""), "/");
+        assert_eq!(normalize_ftp_path("pub//a/"), "pub/a");
+    }
+
+    #[test]
+    fn test_parse_unix_timestamps() {
+        // 2024-06-15 12:00 UTC
+        let now = UNIX_EPOCH + Duration::from_secs(1_718_452_800);
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+
+        // 2023-01-15 00:00
+        assert_eq!(
+            parse_unix_timestamp("Jan", "15", "2023", now),
+            at(1_673_740_800)
+        );
+        // Recent: this year, 2024-03-01 10:30
+        assert_eq!(
+            parse_unix_timestamp("Mar", "1", "10:30", now),
+            at(1_709_289_000)
+        );
+        // A "future" time belongs to last year: 2023-12-24 18:00
+        assert_eq!(
+            parse_unix_timestamp("dec", "24", "18:00", now),
+            at(1_703_440_800)
+        );
+        assert_eq!(parse_unix_timestamp("Foo", "1", "2023", now), None);
+        assert_eq!(parse_unix_timestamp("Jan", "1", "25:00", now), None);
+
+        assert_eq!(civil_from_days(19_889), (2024, 6, 15));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+
+        let info =
+            FtpConnection::parse_list_line("/", "-rw-r--r-- 1 user group 10 Jan 15 2023 notes.txt")
+                .unwrap();
+        assert_eq!(info.modified_time, at(1_673_740_800));
+    }
+
+    #[test]
+    fn test_format_timeval() {
+        let time = UNIX_EPOCH + Duration::new(1_705_314_600, 500_000_000);
+        assert_eq!(format_timeval(time), "20240115103000");
+        assert_eq!(
+            parse_timeval(&format_timeval(time)),
+            Some(UNIX_EPOCH + Duration::from_secs(1_705_314_600))
+        );
+        assert_eq!(format_timeval(UNIX_EPOCH), "19700101000000");
+        assert_eq!(
+            format_timeval(UNIX_EPOCH - Duration::from_secs(86_400)),
+            "19700101000000"
+        );
+        assert_eq!(
+            format_timeval(UNIX_EPOCH + Duration::from_secs(951_868_799)),
+            "20000229235959"
+        );
+    }
+
+    #[test]
+    fn test_parse_utc_offset() {
+        assert_eq!(parse_utc_offset("UTC"), Ok(0));
+        assert_eq!(parse_utc_offset("z"), Ok(0));
+        assert_eq!(parse_utc_offset("+02:00"), Ok(7200));
+        assert_eq!(parse_utc_offset("-0530"), Ok(-19_800));
+        assert_eq!(parse_utc_offset("UTC+1"), Ok(3600));
+        assert_eq!(parse_utc_offset("gmt-3"), Ok(-10_800));
+        assert!(parse_utc_offset("Europe/Madrid").is_err());
+        assert!(parse_utc_offset("+25").is_err());
+        assert!(parse_utc_offset("+1:75").is_err());
+
+        // 10:00 at UTC+2 is 08:00 UTC
+        let listed = UNIX_EPOCH + Duration::from_secs(36_000);
+        assert_eq!(
+            shift_time(listed, -7200),
+            UNIX_EPOCH + Duration::from_secs(28_800)
+        );
+    }
+
+    #[test]
+    fn test_parse_mlsd_line() {
+        let info = FtpConnection::parse_mlsd_line(
+            "/pub",
+            "type=file;size=42;modify=20240115103000;UNIX.mode=0640; notes; final.txt",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(info.name, "notes; final.txt");
+        assert_eq!(info.size, 42);
+        assert_eq!(info.permissions, 0o640);
+        assert!(info.modified_time.is_some());
+
+        let info = FtpConnection::parse_mlsd_line("/pub", "Type=dir;Modify=20240115103000; src")
+            .unwrap()
+            .unwrap();
+        assert!(info.is_dir);
+
+        assert!(FtpConnection::parse_mlsd_line("/pub", "type=cdir; .")
+            .unwrap()
+            .is_none());
+        assert!(FtpConnection::parse_mlsd_line("/pub", "size=1; orphan").is_err());
+    }
+
+    #[test]
+    fn test_listed_dot_entries_are_not_children() {
+        let mut conn = connect_scripted(|command| match command {
+            "PASV" => {
+                let data = TcpListener::bind("127.0.0.1:0").unwrap();
+                let port = data.local_addr().unwrap().port();
+                thread::spawn(move || {
+                    let (mut stream, _) = data.accept().unwrap();
+                    stream
+                        .write_all(
+                            b"drwxr-xr-x  2 ftp ftp 4096 Jan 15  2023 .\r\n\
+                              drwxr-xr-x  9 ftp ftp 4096 Jan 15  2023 ..\r\n\
+                              -rw-r--r--  1 ftp ftp    5 Jan 15  2023 a.txt\r\n\
+                              drwxr-xr-x  2 ftp ftp 4096 Jan 15  2023 sub\r\n",
+                        )
+                        .unwrap();
+                });
+                format!(
+                    "227 Entering Passive Mode (127,0,0,1,{},{})",
+                    port >> 8,
+                    port & 0xff
+                )
+            }
+            "PWD" => "257 \"/pub\" is current directory".to_string(),
+            "LIST" => "150 Opening data connection\r\n226 Transfer complete".to_string(),
+            command if command.starts_with("CWD ") => "250 ok".to_string(),
+            _ => "500 unknown command".to_string(),
+        });
+
+        let names: Vec<String> = conn
+            .list_dir("/pub")
+            .unwrap()
+            .into_iter()
+            .map(|info| info.path)
+            .collect();
+        assert_eq!(names, ["/pub/a.txt", "/pub/sub"]);
+
+        for line in [
+            "type=cdir; .",
+            "type=pdir; ..",
+            "type=dir; .",
+            "type=dir; ..",
+        ] {
+            assert!(
+                FtpConnection::parse_listing_line("/pub", ListFormat::Mlsd, line)
+                    .unwrap()
+                    .is_none()
+            );
+        }
+    }
+
+    #[test]
+    fn test_forced_list_format_reports_bad_lines() {
+        let line = "01-15-24  10:30AM       <DIR>          dos-dir";
+
+        // Auto detects the MS-DOS line
+        let info = FtpConnection::parse_listing_line("/", ListFormat::Auto, line)
+            .unwrap()
+            .unwrap();
+        assert!(info.is_dir);
+
+        // Forcing UNIX rejects it; summary lines are still skipped
+        assert!(FtpConnection::parse_listing_line("/", ListFormat::Unix, line).is_err());
+        assert!(
+            FtpConnection::parse_listing_line("/", ListFormat::Unix, "total 12")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_greeting_allows_anonymous() {
+        assert!(greeting_allows_anonymous(
+            "220 Welcome to the archive. Anonymous logins are welcome."
+        ));
+        assert!(greeting_allows_anonymous(
+            "220-Please login with USER anonymous\n220 ready"
+        ));
+        assert!(!greeting_allows_anonymous("220 ProFTPD Server ready."));
+        assert!(!greeting_allows_anonymous(
+            "220 Anonymous access is not allowed"
+        ));
+        assert!(!greeting_allows_anonymous("220 No anonymous logins"));
+    }
+
+    #[test]
+    fn test_greeting_is_read_without_login() {
+        let port = serve_scripted("127.0.0.1", |command| match command {
+            "QUIT" => "221 bye".to_string(),
+            _ => "530 not logged in".to_string(),
+        });
+        let options = ConnectOptions::default();
+        let greeting = FtpConnection::greeting("127.0.0.1", Some(port), &options)
+            .unwrap()
+            .unwrap();
+        assert!(greeting.starts_with("220"));
+    }
+
+    #[test]
+    fn test_summary_and_blank_lines_are_skipped() {
+        let listing = "total 48\r\n\
+                       drwxr-xr-x  2 ftp ftp 4096 Jan 15  2023 pub\r\n\
+                       \r\n\
+                       -rw-r--r--  1 ftp ftp  512 Jan 15  2023 total\r\n\
+                       -rw-r--r--  1 ftp ftp  100 Feb  1  2023 48\r\n\
+                       Total: 1024\r\n\
+                       \x20\x20\r\n";
+        let names: Vec<String> = listing
+            .split("\r\n")
+            .filter_map(|line| {
+                FtpConnection::parse_listing_line("/", ListFormat::Auto, line).unwrap()
+            })
+            .map(|info| info.name)
+            .collect();
+        assert_eq!(names, ["pub", "total", "48"]);
+
+        assert!(is_summary_line("total 1.2M"));
+        assert!(is_summary_line("   3 File(s)      1,234 bytes"));
+        assert!(is_summary_line("   2 Dir(s)  1,000,000 bytes free"));
+        assert!(!is_summary_line("total recall.mkv"));
+        assert!(FtpConnection::parse_listing_line(
+            "/",
+            ListFormat::Dos,
+            "   3 File(s)   1,234 bytes"
+        )
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    fn test_dot_and_nameless_entries_are_skipped() {
+        let parse = |format, line: &str| FtpConnection::parse_listing_line("/pub", format, line);
+        for line in [
+            "drwxr-xr-x  2 ftp ftp 4096 Jan 15  2023 .",
+            "drwxr-xr-x  9 ftp ftp 4096 Jan 15  2023 ..",
+            "-rw-r--r--  1 ftp ftp    0 Jan 15  2023 ",
+            "-rw-r--r--  1 ftp ftp    0 Jan 15  2023    ",
+            "01-15-24  10:30AM       <DIR>          .",
+        ] {
+            assert!(parse(ListFormat::Auto, line).unwrap().is_none(), "{}", line);
+        }
+        assert!(
+            parse(ListFormat::Unix, "-rw-r--r-- 1 ftp ftp 0 Jan 15 2023 ")
+                .unwrap()
+                .is_none()
+        );
+        assert!(parse(ListFormat::Mlsd, "type=file;size=0; ")
+            .unwrap()
+            .is_none());
+
+        // Only the separator after the date is dropped: leading spaces and
+        // dot-prefixed names are kept
+        let info = parse(
+            ListFormat::Unix,
+            "-rw-r--r--  1 ftp ftp    3 Jan 15  2023   notes",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(info.name, "  notes");
+        assert_eq!(info.path, "/pub/  notes");
+        for name in ["...", ".hidden", "..bak"] {
+            let line = format!("-rw-r--r-- 1 ftp ftp 3 Jan 15 2023 {}", name);
+            let info = parse(ListFormat::Unix, &line).unwrap().unwrap();
+            assert_eq!(info.name, name);
+        }
+        assert!(!is_entry_name(""));
+        assert!(!is_entry_name(" \t"));
+        assert!(is_entry_name(" a"));
+    }
+
+    #[test]
+    fn test_binary_reasserted_after_type_change() {
+        let mut conn = connect_scripted(|command| match command {
+            "SIZE /notes.txt" => "213 42".to_string(),
+            _ => "500 unknown command".to_string(),
+        });
+        assert_eq!(conn.transfer_type, Some(FileType::Binary));
+
+        conn.custom_command("TYPE A", &[Status::CommandOk]).unwrap();
+        assert_eq!(conn.transfer_type, None);
+
+        assert_eq!(conn.size("/notes.txt").unwrap(), 42);
+        assert_eq!(conn.transfer_type, Some(FileType::Binary));
+    }
+
+    #[test]
+    fn test_ascii_extensions() {
+        let exts = parse_extensions(".txt, CFG,.ini").unwrap();
+        assert_eq!(exts, ["txt", "cfg", "ini"]);
+        assert!(parse_extensions(".txt,,.cfg").is_err());
+        assert!(parse_extensions(".").is_err());
+
+        assert!(is_ascii_path(&exts, "/etc/app.CFG"));
+        assert!(is_ascii_path(&exts, "notes.tar.txt"));
+        assert!(!is_ascii_path(&exts, "/pub/image.png"));
+        assert!(!is_ascii_path(&exts, "/pub/.txt"));
+        assert!(!is_ascii_path(&exts, "/pub.txt/readme"));
+        assert!(!is_ascii_path(&[], "/notes.txt"));
+
+        // REST offsets are meaningless in ASCII mode: no ranges, no resumed uploads
+        let mut conn = connect_scripted(|_| "500 unknown command".to_string());
+        conn.options.ascii_extensions = exts;
+        assert!(conn.retrieve_range("/notes.txt", 10, 5).is_err());
+        assert!(conn.store_at("/notes.txt", 10, b"x").is_err());
+        assert_eq!(conn.transfer_type, Some(FileType::Binary));
+    }
+
+    #[test]
+    fn test_store_at_offset_zero_keeps_the_tail() {
+        use std::sync::Mutex;
+        static COMMANDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        let mut conn = connect_scripted(|command| {
+            COMMANDS.lock().unwrap().push(command.to_string());
+            match command {
+                "REST 0" => "350 Restarting at 0".to_string(),
+                _ => "500 unknown command".to_string(),
+            }
+        });
+
+        // The data connection is refused: only the commands sent matter
+        assert!(conn.store_at("/big.bin", 0, b"head").is_err());
+        let commands = COMMANDS.lock().unwrap();
+        assert!(commands.iter().any(|command| command == "REST 0"));
+        assert!(!commands.iter().any(|command| command.starts_with("STOR")));
+    }
+
+    #[test]
+    fn test_login_needing_an_account_sends_acct() {
+        let need_account = || {
+            Err(suppaftp::FtpError::UnexpectedResponse(Response::new(
+                Status::LoginNeedAccount,
+                b"332 Need account for login".to_vec(),
+            )))
+        };
+        let logged_in = || Ok(Response::new(Status::LoggedIn, b"230 ok".to_vec()));
+
+        let mut sent = Vec::new();
+        complete_login(need_account(), Some("dept42"), |command| {
+            sent.push(command);
+            logged_in()
+        })
+        .unwrap();
+        assert_eq!(sent, ["ACCT dept42"]);
+
+        let err = complete_login(need_account(), None, |_| logged_in()).unwrap_err();
+        assert!(err.to_string().contains("ACCT"));
+
+        // Logins that don't ask for an account never send ACCT
+        complete_login(Ok(()), Some("dept42"), |_| panic!("ACCT sent")).unwrap();
+        let denied = Response::new(Status::NotLoggedIn, b"530 Login incorrect".to_vec());
+        let err = complete_login(
+            Err(suppaftp::FtpError::UnexpectedResponse(denied)),
+            Some("dept42"),
+            |_| panic!("ACCT sent"),
+        )
+        .unwrap_err();
+        assert!(!err.to_string().contains("ACCT"));
+    }
+
+    #[test]
+    fn test_login_sends_pass_only_when_asked() {
+        let mut sent = Vec::new();
+        send_login("alice", "s3cret", |command, _| {
+            sent.push(command.to_string());
+            Ok(match command {
+                "USER alice" => Response::new(Status::NeedPassword, b"331 password".to_vec()),
+                _ => Response::new(Status::LoggedIn, b"230 ok".to_vec()),
+            })
+        })
+        .unwrap();
+        assert_eq!(sent, ["USER alice", "PASS s3cret"]);
+
+        sent.clear();
+        send_login("anonymous", "guest", |command, _| {
+            sent.push(command.to_string());
+            Ok(Response::new(Status::LoggedIn, b"230 welcome".to_vec()))
+        })
+        .unwrap();
+        assert_eq!(sent, ["USER anonymous"]);
+    }
+
+    #[test]
+    fn test_redact_hides_pass_and_acct() {
+        assert_eq!(redact("USER alice"), "USER alice");
+        assert_eq!(redact("PASS s3cret"), "PASS ****");
+        assert_eq!(redact("pass s3cret"), "pass ****");
+        assert_eq!(redact("ACCT dept42"), "ACCT ****");
+        assert_eq!(redact("CWD /pass word"), "CWD /pass word");
+    }
+
+    #[test]
+    fn test_reconnect_delay_doubles_up_to_the_cap() {
+        let max = Duration::from_secs(60);
+        assert_eq!(reconnect_delay(1, max), Duration::from_secs(1));
+        assert_eq!(reconnect_delay(2, max), Duration::from_secs(2));
+        assert_eq!(reconnect_delay(4, max), Duration::from_secs(8));
+        assert_eq!(reconnect_delay(7, max), max);
+        assert_eq!(reconnect_delay(1000, max), max);
+    }
+
+    #[test]
+    fn test_failed_reconnects_back_off_and_fail_fast() {
+        let mut conn = connect_scripted(|_| "500 unknown command".to_string());
+        // Nothing listens on the discard port: every reconnect is refused
+        conn.server = "127.0.0.1".to_string();
+        conn.port = 9;
+        conn.options.reconnect_attempts = Some(2);
+
+        assert!(conn.reconnect().is_err());
+        assert_eq!(conn.reconnect_failures, 1);
+        // Within the backoff window nothing is attempted
+        let err = FtpBackend::noop(&mut conn).unwrap_err();
+        assert!(err.is_transient());
+        assert!(err.to_string().contains("retrying in"));
+        assert_eq!(conn.reconnect_failures, 1);
+
+        conn.next_reconnect = Some(Instant::now());
+        assert!(FtpBackend::list_dir(&mut conn, "/").is_err());
+        assert_eq!(conn.reconnect_failures, 2);
+        // Past the limit the server is still tried, at the ceiling
+        let wait = conn.next_reconnect.unwrap() - Instant::now();
+        assert!(wait > Duration::from_secs(59));
+        conn.next_reconnect = Some(Instant::now());
+        assert!(conn.reconnect().is_err());
+        assert_eq!(conn.reconnect_failures, 3);
+    }
+
+    #[test]
+    fn test_list_dir_classifies_cwd_failures() {
+        let mut conn = connect_scripted(|command| match command {
+            "PWD" => "257 \"/\" is current directory".to_string(),
+            "SIZE /notes.txt" => "213 5".to_string(),
+            "CWD /busy" => "450 try again later".to_string(),
+            _ => "550 no such file or directory".to_string(),
+        });
+
+        assert!(matches!(
+            conn.list_dir("/notes.txt"),
+            Err(FtpError::NotADirectory)
+        ));
+        assert!(matches!(conn.list_dir("/missing"), Err(FtpError::NotFound)));
+
+        let err = conn.list_dir("/busy").unwrap_err();
+        assert!(matches!(err, FtpError::Transient));
+        assert!(err.is_transient());
+    }
+
+    #[test]
+    fn test_reconnect_returns_to_current_dir() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static CWD_PUB: AtomicUsize = AtomicUsize::new(0);
+
+        let mut conn = connect_scripted(|command| match command {
+            "CWD /pub" => {
+                CWD_PUB.fetch_add(1, Ordering::SeqCst);
+                "250 ok".to_string()
+            }
+            "CWD /gone" => "550 No such directory".to_string(),
+            "CWD /" => "250 ok".to_string(),
+            _ => "500 unknown command".to_string(),
+        });
+        conn.cwd("/pub").unwrap();
+
+        conn.reconnect().unwrap();
+        assert_eq!(conn.current_dir, "/pub");
+        assert_eq!(CWD_PUB.load(Ordering::SeqCst), 2);
+        assert_eq!(conn.transfer_type, Some(FileType::Binary));
+
+        // A directory removed while disconnected falls back to the root
+        conn.current_dir = "/gone".to_string();
+        conn.reconnect().unwrap();
+        assert_eq!(conn.current_dir, "/");
+        assert_eq!(conn.reconnect_failures, 0);
+    }
+
+    #[test]
+    fn test_multi_line_feat_reply() {
+        let mut conn = connect_scripted(|command| match command {
+            "FEAT" => "211-Features:\r\n MDTM\r\n REST STREAM\r\n211-SIZE\r\n \
+                       MLST type*;size*;modify*;\r\n211 End"
+                .to_string(),
+            "NOOP" => "200 ok".to_string(),
+            _ => "500 unknown command".to_string(),
+        });
+
+        let features = conn.feat().unwrap();
+        for feature in ["MDTM", "REST", "REST STREAM", "SIZE", "MLST"] {
+            assert!(features.contains(feature), "missing {}", feature);
+        }
+        assert!(!features.contains("FEATURES:"));
+        assert!(!features.contains("END"));
+
+        // The whole reply was consumed: the next command gets its own answer
+        conn.noop().unwrap();
+    }
+
+    #[test]
+    fn test_site_returns_reply_text() {
+        let mut conn = connect_scripted(|command| match command {
+            "SITE HELP" => "214-The following SITE commands are recognized\r\n \
+                            CHMOD UMASK\r\n214 Direct comments to root"
+                .to_string(),
+            "SITE CHMOD 600 /key" => "200 SITE CHMOD command successful".to_string(),
+            _ => "500 unknown command".to_string(),
+        });
+
+        assert_eq!(
+            conn.site("HELP").unwrap(),
+            "The following SITE commands are recognized\nCHMOD UMASK\nDirect comments to root"
+        );
+        conn.chmod("/key", 0o100600).unwrap();
+        // Neither needs a TYPE before the next transfer
+        assert_eq!(conn.transfer_type, Some(FileType::Binary));
+        assert!(matches!(
+            conn.site("QUOTA"),
+            Err(FtpError::Protocol(message)) if message.contains("unknown command")
+        ));
+        // An unknown SITE command might have switched it
+        assert_eq!(conn.transfer_type, None);
+    }
+
+    #[test]
+    fn test_resolve_filters_address_family() {
+        let addrs = resolve("127.0.0.1", 21, IpFamily::V4).unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:21".parse().unwrap()]);
+        assert!(resolve("127.0.0.1", 21, IpFamily::V6).is_err());
+        assert!(resolve("[::1]", 21, IpFamily::V6).unwrap()[0].is_ipv6());
+    }
+
+    #[test]
+    fn test_passive_mode_uses_epsv_over_ipv6() {
+        let v4 = "192.0.2.1:21".parse().unwrap();
+        let v6 = "[2001:db8::1]:21".parse().unwrap();
+        assert_eq!(data_channel_mode(DataMode::Passive, &v4), Mode::Passive);
+        assert_eq!(
+            data_channel_mode(DataMode::Passive, &v6),
+            Mode::ExtendedPassive
+        );
+        assert_eq!(data_channel_mode(DataMode::Active, &v6), Mode::Active);
+    }
+
+    #[test]
+    fn test_broken_listing_transfer_is_transient() {
+        assert!(listing_error(suppaftp::FtpError::BadResponse).is_transient());
+        let aborted = Response::new(Status::TransferAborted, b"426 Connection closed".to_vec());
+        assert!(listing_error(suppaftp::FtpError::UnexpectedResponse(aborted)).is_transient());
+        let missing = Response::new(Status::FileUnavailable, b"550 No such directory".to_vec());
+        assert!(matches!(
+            listing_error(suppaftp::FtpError::UnexpectedResponse(missing)),
+            FtpError::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("50000-50100"), Ok(50000..=50100));
+        assert_eq!(parse_port_range(" 2020 - 2021 "), Ok(2020..=2021));
+        assert_eq!(parse_port_range("2020"), Ok(2020..=2020));
+        for invalid in ["", "0-10", "10-5", "1-70000", "a-b", "10-"] {
+            assert!(parse_port_range(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_data_listener_skips_busy_ports() {
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        let busy = TcpListener::bind((localhost, 0)).unwrap();
+        let port = busy.local_addr().unwrap().port();
+
+        let err = listen_in_range(localhost, &(port..=port)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        drop(busy);
+        let listener = listen_in_range(localhost, &(port..=port)).unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn test_active_listing_connects_to_the_announced_port() {
+        static DATA_PORT: AtomicUsize = AtomicUsize::new(0);
+        let mut conn = connect_scripted(|command| match command {
+            command if command.starts_with("PORT 127,0,0,1,") => {
+                let fields: Vec<usize> = command[5..]
+                    .split(',')
+                    .map(|field| field.parse().unwrap())
+                    .collect();
+                DATA_PORT.store((fields[4] << 8) | fields[5], Ordering::SeqCst);
+                "200 PORT ok".to_string()
+            }
+            "LIST" => {
+                let port = DATA_PORT.load(Ordering::SeqCst) as u16;
+                thread::spawn(move || {
+                    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                    stream
+                        .write_all(b"-rw-r--r-- 1 user group 5 Jan 01 2024 a.txt\r\n")
+                        .unwrap();
+                });
+                "150 Opening data connection\r\n226 Transfer complete".to_string()
+            }
+            _ => "500 unknown command".to_string(),
+        });
+        let range = 50000..=50100;
+        conn.options.mode = DataMode::Active;
+        conn.options.data_ports = Some(range.clone());
+
+        let files = conn.list().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "a.txt");
+        assert!(range.contains(&(DATA_PORT.load(Ordering::SeqCst) as u16)));
+    }
+
+    #[test]
+    fn test_parse_passive_replies() {
+        assert_eq!(
+            parse_pasv_port("227 Entering Passive Mode (192,168,1,2,195,80)."),
+            Some(50000)
+        );
+        assert_eq!(parse_pasv_port("227 =10,0,0,1,0,21"), Some(21));
+        assert_eq!(
+            parse_pasv_port("227 Entering Passive Mode (1,2,3,4,300,1)"),
+            None
+        );
+        assert_eq!(parse_pasv_port("227 Entering Passive Mode"), None);
+        assert_eq!(
+            parse_epsv_port("229 Entering Extended Passive Mode (|||6446|)"),
+            Some(6446)
+        );
+        assert_eq!(parse_epsv_port("229 Entering Extended Passive Mode"), None);
+    }
+
+    #[test]
+    fn test_parse_name_encoding() {
+        assert_eq!(parse_name_encoding("Shift_JIS"), Ok(encoding_rs::SHIFT_JIS));
+        assert_eq!(parse_name_encoding(" gbk "), Ok(encoding_rs::GBK));
+        assert_eq!(parse_name_encoding("latin1"), Ok(encoding_rs::WINDOWS_1252));
+        assert!(parse_name_encoding("klingon").is_err());
+    }
+
+    #[test]
+    fn test_name_encoding_decodes_shift_jis_listing() {
+        let mut conn = connect_scripted(|command| match command {
+            "PASV" => {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let port = listener.local_addr().unwrap().port();
+                thread::spawn(move || {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    // 日本.txt in Shift-JIS
+                    stream
+                        .write_all(
+                            b"-rw-r--r-- 1 user group 5 Jan 01 2024 \x93\xfa\x96\x7b.txt\r\n",
+                        )
+                        .unwrap();
+                });
+                format!(
+                    "227 Entering Passive Mode (127,0,0,1,{},{})",
+                    port >> 8,
+                    port & 0xff
+                )
+            }
+            "LIST" => "150 Opening data connection\r\n226 Transfer complete".to_string(),
+            _ => "500 unknown command".to_string(),
+        });
+        conn.options.name_encoding = Some(encoding_rs::SHIFT_JIS);
+
+        let files = conn.list().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "日本.txt");
+        assert_eq!(files[0].path, "/日本.txt");
+        assert_eq!(
+            files[0].raw_name.as_deref(),
+            Some(&b"\x93\xfa\x96\x7b.txt"[..])
+        );
+    }
+
+    /// Serve one plain session, sending the bytes of every command after
+    /// the login through the channel
+    fn serve_raw() -> (u16, mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sent, received) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220 ready\r\n").unwrap();
+            let mut line = Vec::new();
+            while reader.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+                let reply: &[u8] = match &line[..] {
+                    command if command.starts_with(b"USER") => b"331 password\r\n",
+                    command if command.starts_with(b"PASS") => b"230 logged in\r\n",
+                    command if command.starts_with(b"TYPE") => b"200 ok\r\n",
+                    command if command.starts_with(b"FEAT") => b"500 unknown command\r\n",
+                    command => {
+                        sent.send(command.to_vec()).unwrap();
+                        if command.starts_with(b"RNFR") {
+                            b"350 pending\r\n"
+                        } else {
+                            b"250 ok\r\n"
+                        }
+                    }
+                };
+                if writer.write_all(reply).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+        });
+        (port, received)
+    }
+
+    #[test]
+    fn test_name_encoding_sends_shift_jis_names() {
+        let (port, received) = serve_raw();
+        let options = ConnectOptions {
+            name_encoding: Some(encoding_rs::SHIFT_JIS),
+            ..Default::default()
+        };
+        let mut conn = FtpConnection::with_options(
+            "127.0.0.1".to_string(),
+            "user".to_string(),
+            "pass".to_string(),
+            Some(port),
+            options,
+        )
+        .unwrap();
+
+        conn.rename("/a.txt", "/日本.txt").unwrap();
+        assert_eq!(received.recv().unwrap(), b"RNFR /a.txt\r\n");
+        assert_eq!(received.recv().unwrap(), b"RNTO /\x93\xfa\x96\x7b.txt\r\n");
+
+        // No Shift-JIS for a snowman: refused before anything is sent
+        let err = conn.cwd("/\u{2603}").unwrap_err();
+        assert!(matches!(err, FtpError::InvalidName), "{:?}", err);
+        conn.cwd("/").unwrap();
+        assert_eq!(received.recv().unwrap(), b"CWD /\r\n");
+    }
+
+    #[test]
+    fn test_name_encoding_refused_over_tls() {
+        let options = ConnectOptions {
+            use_tls: true,
+            name_encoding: Some(encoding_rs::SHIFT_JIS),
+            ..Default::default()
+        };
+        let result = FtpConnection::with_options(
+            "127.0.0.1".to_string(),
+            "user".to_string(),
+            "pass".to_string(),
+            Some(1),
+            options,
+        );
+        assert!(matches!(result, Err(FtpError::Protocol(_))));
+    }
+
+    /// Serve one explicit FTPS session on a self-signed certificate
+    ///
+    /// Data connections are accepted only through a TLS handshake: RETR
+    /// sends `hello` over it and STOR's bytes are returned through the
+    /// channel, so a cleartext data connection fails the transfer.
+    fn serve_ftps() -> (u16, mpsc::Receiver<Vec<u8>>) {
+        let config = tls::self_signed_server();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (stored, received) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut control, _) = listener.accept().unwrap();
+            control.write_all(b"220 ready\r\n").unwrap();
+            // The client waits for 234 before its ClientHello, so nothing
+            // past this line is buffered
+            let mut line = String::new();
+            BufReader::new(&control).read_line(&mut line).unwrap();
+            assert_eq!(line.trim_end(), "AUTH TLS");
+            control.write_all(b"234 ok\r\n").unwrap();
+
+            let session = ServerConnection::new(Arc::clone(&config)).unwrap();
+            let mut control = BufReader::new(StreamOwned::new(session, control));
+            let data = TcpListener::bind("127.0.0.1:0").unwrap();
+            let data_port = data.local_addr().unwrap().port();
+            let accept_tls = || {
+                let (stream, _) = data.accept().unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                let session = ServerConnection::new(Arc::clone(&config)).unwrap();
+                StreamOwned::new(session, stream)
+            };
+            line.clear();
+            while control.read_line(&mut line).unwrap_or(0) > 0 {
+                let reply = match line.trim_end() {
+                    command if command.starts_with("USER") => "331 password".to_string(),
+                    command if command.starts_with("PASS") => "230 logged in".to_string(),
+                    "PBSZ 0" | "PROT P" | "TYPE I" => "200 ok".to_string(),
+                    "PASV" => format!(
+                        "227 Entering Passive Mode (127,0,0,1,{},{})",
+                        data_port >> 8,
+                        data_port & 0xff
+                    ),
+                    "RETR /secret.txt" => {
+                        control.get_mut().write_all(b"150 opening\r\n").unwrap();
+                        let mut stream = accept_tls();
+                        stream.write_all(b"hello").unwrap();
+                        stream.conn.send_close_notify();
+                        stream.flush().unwrap();
+                        "226 done".to_string()
+                    }
+                    "STOR /upload.txt" => {
+                        control.get_mut().write_all(b"150 opening\r\n").unwrap();
+                        let mut bytes = Vec::new();
+                        // Bytes read before an unclean close are kept
+                        let _ = accept_tls().read_to_end(&mut bytes);
+                        stored.send(bytes).unwrap();
+                        "226 done".to_string()
+                    }
+                    _ => "500 unknown command".to_string(),
+                };
+                let reply = format!("{}\r\n", reply);
+                if control.get_mut().write_all(reply.as_bytes()).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+        });
+        (port, received)
+    }
+
+    #[test]
+    fn test_ftps_transfers_use_encrypted_data_connections() {
+        let (port, stored) = serve_ftps();
+        let options = ConnectOptions {
+            use_tls: true,
+            no_tls_verify: true,
+            timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let mut conn = FtpConnection::with_options(
+            "127.0.0.1".to_string(),
+            "user".to_string(),
+            "pass".to_string(),
+            Some(port),
+            options,
+        )
+        .unwrap();
+
+        assert_eq!(conn.retrieve("/secret.txt").unwrap(), b"hello");
+        conn.store("/upload.txt", b"classified").unwrap();
+        assert_eq!(stored.recv().unwrap(), b"classified");
+    }
+
+    #[test]
+    fn test_retrieve_over_ipv6() {
+        // Nothing to test where the host has no IPv6 loopback
+        if TcpListener::bind("[::1]:0").is_err() {
+            return;
+        }
+        let mut conn = connect_scripted_on("[::1]", |command| match command {
+            "EPSV" => {
+                let data = TcpListener::bind("[::1]:0").unwrap();
+                let port = data.local_addr().unwrap().port();
+                thread::spawn(move || {
+                    let (mut stream, _) = data.accept().unwrap();
+                    stream.write_all(b"hello").unwrap();
+                });
+                format!("229 Entering Extended Passive Mode (|||{}|)", port)
+            }
+            "RETR /hello.txt" => "150 Opening data connection\r\n226 Transfer complete".to_string(),
+            // PASV cannot describe an IPv6 data address
+            _ => "500 unknown command".to_string(),
+        });
+
+        assert_eq!(conn.retrieve("/hello.txt").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_truncated_download_is_a_transient_error() {
+        let mut conn = connect_scripted(|command| match command {
+            "PASV" => {
+                // The data connection always closes after three bytes
+                let data = TcpListener::bind("127.0.0.1:0").unwrap();
+                let port = data.local_addr().unwrap().port();
+                thread::spawn(move || {
+                    let (mut stream, _) = data.accept().unwrap();
+                    stream.write_all(b"hel").unwrap();
+                });
+                format!(
+                    "227 Entering Passive Mode (127,0,0,1,{},{})",
+                    port >> 8,
+                    port & 0xff
+                )
+            }
+            "SIZE /hello.txt" => "213 5".to_string(),
+            "SIZE /hel.txt" => "213 3".to_string(),
+            "RETR /hello.txt" | "RETR /hel.txt" | "RETR /nosize.txt" => {
+                "150 Opening data connection\r\n226 Transfer complete".to_string()
+            }
+            _ => "550 No such file".to_string(),
+        });
+
+        let mut data = Vec::new();
+        let err = conn.retrieve_into("/hello.txt", &mut data).unwrap_err();
+        assert!(err.is_transient(), "{:?}", err);
+        assert_eq!(data, b"hel");
+
+        // Complete downloads, and those the server can't size, are accepted
+        assert_eq!(conn.retrieve("/hel.txt").unwrap(), b"hel");
+        assert_eq!(conn.retrieve("/nosize.txt").unwrap(), b"hel");
+    }
+
+    #[test]
+    fn test_reply_codes_map_to_errors() {
+        let mut conn = connect_scripted(|command| match command {
+            "DELE /locked" => "550 Permission denied".to_string(),
+            "DELE /gone" => "550 No such file".to_string(),
+            "MKD /bad:name" => "553 File name not allowed".to_string(),
+            "RMD /full" => "451 Local error".to_string(),
+            _ => "502 Command not implemented".to_string(),
+        });
+
+        assert!(matches!(
+            conn.delete("/locked"),
+            Err(FtpError::PermissionDenied)
+        ));
+        assert!(matches!(conn.delete("/gone"), Err(FtpError::NotFound)));
+        assert!(matches!(
+            conn.mkdir("/bad:name"),
+            Err(FtpError::PermissionDenied)
+        ));
+        assert!(matches!(conn.rmdir("/full"), Err(FtpError::Transient)));
+        match conn.rename("/a", "/b") {
+            Err(FtpError::Protocol(message)) => assert!(message.contains("not implemented")),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // A full disk is reported on the data transfer reply
+        for status in [Status::ExceededStorage, Status::RequestedActionNotTaken] {
+            let reply = Response::new(status, b"552 Exceeded storage allocation".to_vec());
+            let err = FtpError::from(suppaftp::FtpError::UnexpectedResponse(reply));
+            assert!(matches!(err, FtpError::NoSpace) && !err.is_transient());
+        }
     }
 }
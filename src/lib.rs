@@ -2,9 +2,29 @@
 //!
 //! This crate provides functionality to mount FTP servers as local filesystems
 //! using FUSE (Filesystem in Userspace), similar to the curlftpfs utility.
+//!
+//! With the `tokio` feature, [`async_ftp::AsyncFtp`] offers awaitable FTP
+//! operations for embedding in an async service without mounting anything.
+//! With the `metrics` feature, `--metrics-addr` serves the mount's
+//! statistics to Prometheus.
 
-pub mod ftp;
+#[cfg(feature = "tokio")]
+pub mod async_ftp;
+pub mod config;
 pub mod filesystem;
+pub mod ftp;
+pub mod lock_order;
+pub mod locks;
+pub mod log_format;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod mirror;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod multihost;
+pub mod netrc;
+pub mod throttle;
+pub mod tls;
 
-pub use ftp::{FtpConnection, FtpFileInfo};
-pub use filesystem::FtpFs;
\ No newline at end of file
+pub use filesystem::FtpFs;
+pub use ftp::{FtpBackend, FtpConnection, FtpError, FtpFileInfo};
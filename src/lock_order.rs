@@ -0,0 +1,197 @@
+//! Lock Order Module
+//!
+//! A mutex with a rank in a global lock order. The filesystem keeps its
+//! state in several mutexes, and two threads taking two of them in opposite
+//! orders can deadlock; taking them only in ascending rank rules that out.
+//!
+//! Debug builds check the order: each thread records the ranks it holds,
+//! and locking a mutex whose rank is not above all of them panics, naming
+//! both locks. Release builds skip the bookkeeping.
+
+#[cfg(debug_assertions)]
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{LockResult, Mutex, MutexGuard, PoisonError, TryLockError};
+
+#[cfg(debug_assertions)]
+thread_local! {
+    /// Ranked locks held by this thread: (rank, name)
+    static HELD: RefCell<Vec<(u8, &'static str)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A [`Mutex`] that must be taken after every lock of lower rank
+pub struct OrderedMutex<T> {
+    rank: u8,
+    name: &'static str,
+    inner: Mutex<T>,
+}
+
+impl<T> OrderedMutex<T> {
+    /// Create a mutex; `name` only appears in order violation panics
+    pub fn new(rank: u8, name: &'static str, value: T) -> Self {
+        OrderedMutex {
+            rank,
+            name,
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// Acquire the mutex, like [`Mutex::lock`]
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, if this thread already holds a lock of equal or
+    /// higher rank.
+    pub fn lock(&self) -> LockResult<OrderedGuard<'_, T>> {
+        self.enter();
+        match self.inner.lock() {
+            Ok(guard) => Ok(self.guard(guard)),
+            Err(poisoned) => Err(PoisonError::new(self.guard(poisoned.into_inner()))),
+        }
+    }
+
+    /// Acquire the mutex only if no other thread holds it
+    ///
+    /// A poisoned mutex is returned like a free one. The order is checked
+    /// as in [`Self::lock`].
+    pub fn try_lock(&self) -> Option<OrderedGuard<'_, T>> {
+        self.enter();
+        match self.inner.try_lock() {
+            Ok(guard) => Some(self.guard(guard)),
+            Err(TryLockError::Poisoned(poisoned)) => Some(self.guard(poisoned.into_inner())),
+            Err(TryLockError::WouldBlock) => {
+                leave(self.rank);
+                None
+            }
+        }
+    }
+
+    fn guard<'a>(&self, guard: MutexGuard<'a, T>) -> OrderedGuard<'a, T> {
+        OrderedGuard {
+            guard,
+            #[cfg(debug_assertions)]
+            rank: self.rank,
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn enter(&self) {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(&(rank, name)) = held.iter().find(|(rank, _)| *rank >= self.rank) {
+                panic!(
+                    "lock order violation: {} (rank {}) taken while holding {} (rank {})",
+                    self.name, self.rank, name, rank
+                );
+            }
+            held.push((self.rank, self.name));
+        });
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn enter(&self) {}
+}
+
+impl<T: fmt::Debug> fmt::Debug for OrderedMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OrderedMutex")
+            .field("rank", &self.rank)
+            .field("name", &self.name)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+/// Guard of an [`OrderedMutex`]; the rank is released when it is dropped
+pub struct OrderedGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    #[cfg(debug_assertions)]
+    rank: u8,
+}
+
+impl<T> Deref for OrderedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for OrderedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<T> Drop for OrderedGuard<'_, T> {
+    fn drop(&mut self) {
+        leave(self.rank);
+    }
+}
+
+/// Forget the most recent lock of `rank` held by this thread
+#[cfg(debug_assertions)]
+fn leave(rank: u8) {
+    // Guards need not be dropped in reverse order; at thread exit the list
+    // may already be gone
+    let _ = HELD.try_with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(i) = held.iter().rposition(|(held_rank, _)| *held_rank == rank) {
+            held.remove(i);
+        }
+    });
+}
+
+#[cfg(not(debug_assertions))]
+fn leave(_rank: u8) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_locks_taken_in_rank_order() {
+        let low = OrderedMutex::new(1, "low", 0);
+        let high = OrderedMutex::new(2, "high", 0);
+
+        let a = low.lock().unwrap();
+        let b = high.lock().unwrap();
+        // Released out of order, the ranks are still forgotten
+        drop(a);
+        drop(b);
+        *high.lock().unwrap() += 1;
+        *low.lock().unwrap() += 1;
+        assert_eq!(*high.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_try_lock_fails_while_held() {
+        let low = OrderedMutex::new(1, "low", ());
+        let high = OrderedMutex::new(2, "high", ());
+
+        let guard = high.lock().unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                assert!(high.try_lock().is_none());
+                // The failed attempt left no rank behind to check against
+                let _low = low.lock().unwrap();
+            });
+        });
+        drop(guard);
+        assert!(high.try_lock().is_some());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "low (rank 1) taken while holding high (rank 2)")]
+    fn test_out_of_order_lock_panics() {
+        let low = OrderedMutex::new(1, "low", ());
+        let high = OrderedMutex::new(2, "high", ());
+
+        let _high = high.lock().unwrap();
+        let _low = low.lock();
+    }
+}
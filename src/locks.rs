@@ -0,0 +1,282 @@
+//! Locks Module
+//!
+//! Advisory byte-range locks (`fcntl` F_GETLK/F_SETLK/F_SETLKW) for files in
+//! the mount. The table lives in this process only: it coordinates the
+//! processes using this mount, not other mounts of the same server or other
+//! FTP clients, which see no locks at all.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+use libc::{F_RDLCK, F_UNLCK, F_WRLCK};
+
+/// A lock held on a byte range of one file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lock {
+    /// First byte of the range
+    pub start: u64,
+    /// Last byte of the range, inclusive (`u64::MAX`-like values reach EOF)
+    pub end: u64,
+    /// `F_RDLCK` or `F_WRLCK` (`F_UNLCK` to release the range)
+    pub typ: i32,
+    /// Process that took the lock, reported to F_GETLK callers
+    pub pid: u32,
+    /// Lock owner from the kernel; locks of the same owner never conflict
+    pub owner: u64,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    /// Whether `other`, held by another owner, keeps this lock from being set
+    fn conflicts_with(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+/// F_SETLKW callers blocked on one (inode, owner)
+#[derive(Debug, Default)]
+struct Waiters {
+    /// Callers still waiting
+    count: usize,
+    /// Bumped by `release_owner`; a waiter that sees it change gives up
+    cancelled: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Locks held per inode
+    held: HashMap<u64, Vec<Lock>>,
+    /// Pending F_SETLKW calls per (inode, owner)
+    waiting: HashMap<(u64, u64), Waiters>,
+}
+
+/// Locks held per inode
+#[derive(Debug, Default)]
+pub struct LockTable {
+    state: Mutex<State>,
+    /// Signalled whenever a range is released, to wake F_SETLKW waiters
+    released: Condvar,
+}
+
+impl LockTable {
+    /// First lock held by another owner that conflicts with `lock`
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        let state = self.state.lock().unwrap();
+        Self::find_conflict(&state.held, ino, lock)
+    }
+
+    /// Set or release `lock` (F_SETLK), or return the lock in the way
+    ///
+    /// The owner's own locks on the range are replaced, splitting them where
+    /// they extend past it, as POSIX locks behave.
+    pub fn set(&self, ino: u64, lock: Lock) -> Result<(), Lock> {
+        let mut state = self.state.lock().unwrap();
+        if lock.typ != F_UNLCK {
+            if let Some(conflict) = Self::find_conflict(&state.held, ino, &lock) {
+                return Err(conflict);
+            }
+        }
+        self.apply(&mut state.held, ino, lock);
+        Ok(())
+    }
+
+    /// Set `lock`, waiting until no other owner holds a conflicting one
+    /// (F_SETLKW)
+    ///
+    /// Returns false without setting it when `release_owner` is called for
+    /// the same owner meanwhile: the process closed the file (or died), so
+    /// nobody is left to hold the lock. Deadlocks between owners waiting on
+    /// each other are not detected.
+    pub fn set_wait(&self, ino: u64, lock: Lock) -> bool {
+        let key = (ino, lock.owner);
+        let mut state = self.state.lock().unwrap();
+        let waiters = state.waiting.entry(key).or_default();
+        waiters.count += 1;
+        let generation = waiters.cancelled;
+
+        let mut granted = true;
+        while lock.typ != F_UNLCK && Self::find_conflict(&state.held, ino, &lock).is_some() {
+            state = self.released.wait(state).unwrap();
+            if state.waiting[&key].cancelled != generation {
+                granted = false;
+                break;
+            }
+        }
+
+        let waiters = state.waiting.get_mut(&key).unwrap();
+        waiters.count -= 1;
+        if waiters.count == 0 {
+            state.waiting.remove(&key);
+        }
+        if granted {
+            self.apply(&mut state.held, ino, lock);
+        }
+        granted
+    }
+
+    /// Release every lock `owner` holds on `ino`, as closing a file does,
+    /// and cancel its pending F_SETLKW calls on it
+    pub fn release_owner(&self, ino: u64, owner: u64) {
+        let mut state = self.state.lock().unwrap();
+        let mut wake = false;
+        if let Some(waiters) = state.waiting.get_mut(&(ino, owner)) {
+            waiters.cancelled += 1;
+            wake = true;
+        }
+        if let Some(held) = state.held.get_mut(&ino) {
+            let before = held.len();
+            held.retain(|lock| lock.owner != owner);
+            if held.len() != before {
+                if held.is_empty() {
+                    state.held.remove(&ino);
+                }
+                wake = true;
+            }
+        }
+        if wake {
+            self.released.notify_all();
+        }
+    }
+
+    /// Number of F_SETLKW calls still waiting, across all files
+    pub fn waiting(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.waiting.values().map(|waiters| waiters.count).sum()
+    }
+
+    fn find_conflict(locks: &HashMap<u64, Vec<Lock>>, ino: u64, lock: &Lock) -> Option<Lock> {
+        locks
+            .get(&ino)?
+            .iter()
+            .find(|held| lock.conflicts_with(held))
+            .copied()
+    }
+
+    /// Replace the owner's locks on the range with `lock`
+    fn apply(&self, locks: &mut HashMap<u64, Vec<Lock>>, ino: u64, lock: Lock) {
+        let held = locks.entry(ino).or_default();
+        let mut kept = Vec::with_capacity(held.len() + 1);
+        for old in held.drain(..) {
+            if old.owner != lock.owner || !old.overlaps(lock.start, lock.end) {
+                kept.push(old);
+                continue;
+            }
+            // Keep whatever lies outside the new range
+            if old.start < lock.start {
+                kept.push(Lock {
+                    end: lock.start - 1,
+                    ..old
+                });
+            }
+            if old.end > lock.end {
+                kept.push(Lock {
+                    start: lock.end + 1,
+                    ..old
+                });
+            }
+        }
+        if lock.typ == F_RDLCK || lock.typ == F_WRLCK {
+            kept.push(lock);
+        }
+        if kept.is_empty() {
+            locks.remove(&ino);
+        } else {
+            *held = kept;
+        }
+        // A downgrade or unlock may let a waiter in
+        self.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn lock(owner: u64, start: u64, end: u64, typ: i32) -> Lock {
+        Lock {
+            start,
+            end,
+            typ,
+            pid: owner as u32,
+            owner,
+        }
+    }
+
+    #[test]
+    fn test_write_locks_exclude_other_owners() {
+        let table = LockTable::default();
+        table.set(1, lock(1, 0, 99, F_WRLCK)).unwrap();
+
+        assert_eq!(
+            table.set(1, lock(2, 50, 60, F_RDLCK)),
+            Err(lock(1, 0, 99, F_WRLCK))
+        );
+        // Other ranges, other files and the same owner are unaffected
+        assert!(table.set(1, lock(2, 100, 199, F_WRLCK)).is_ok());
+        assert!(table.set(2, lock(2, 0, 99, F_WRLCK)).is_ok());
+        assert!(table.set(1, lock(1, 0, 49, F_RDLCK)).is_ok());
+
+        // Read locks only exclude writers
+        table.set(3, lock(1, 0, 9, F_RDLCK)).unwrap();
+        assert!(table.set(3, lock(2, 0, 9, F_RDLCK)).is_ok());
+        assert!(table.conflict(3, &lock(4, 5, 5, F_WRLCK)).is_some());
+    }
+
+    #[test]
+    fn test_unlocking_part_of_a_range_splits_it() {
+        let table = LockTable::default();
+        table.set(1, lock(1, 0, 99, F_WRLCK)).unwrap();
+        table.set(1, lock(1, 40, 59, F_UNLCK)).unwrap();
+
+        assert!(table.conflict(1, &lock(2, 40, 59, F_WRLCK)).is_none());
+        assert_eq!(
+            table.conflict(1, &lock(2, 30, 30, F_RDLCK)),
+            Some(lock(1, 0, 39, F_WRLCK))
+        );
+        assert_eq!(
+            table.conflict(1, &lock(2, 60, 60, F_RDLCK)),
+            Some(lock(1, 60, 99, F_WRLCK))
+        );
+
+        table.release_owner(1, 1);
+        assert!(table.conflict(1, &lock(2, 0, 99, F_WRLCK)).is_none());
+    }
+
+    #[test]
+    fn test_releasing_an_owner_cancels_its_waits() {
+        let table = Arc::new(LockTable::default());
+        table.set(1, lock(1, 0, 99, F_WRLCK)).unwrap();
+
+        let waiter = {
+            let table = Arc::clone(&table);
+            thread::spawn(move || table.set_wait(1, lock(2, 0, 9, F_WRLCK)))
+        };
+        while table.waiting() == 0 {
+            thread::yield_now();
+        }
+        // Closing the file gives up the wait instead of leaving it parked
+        table.release_owner(1, 2);
+        assert!(!waiter.join().unwrap());
+        assert_eq!(table.waiting(), 0);
+        assert!(table.conflict(1, &lock(3, 0, 9, F_RDLCK)).is_some());
+
+        // An unlock by the holder still grants the wait
+        let waiter = {
+            let table = Arc::clone(&table);
+            thread::spawn(move || table.set_wait(1, lock(2, 0, 9, F_WRLCK)))
+        };
+        while table.waiting() == 0 {
+            thread::yield_now();
+        }
+        table.release_owner(1, 1);
+        assert!(waiter.join().unwrap());
+        assert!(table.conflict(1, &lock(3, 0, 9, F_RDLCK)).is_some());
+    }
+}
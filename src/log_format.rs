@@ -0,0 +1,117 @@
+//! Log Format Module
+//!
+//! One JSON object per log record, for feeding a service's logs into a log
+//! pipeline. Besides `timestamp`, `level`, `target` and `message`, the
+//! key-value pairs attached to a record become fields of the object; key
+//! operations attach an `event` (`connect`, `reconnect`, `store`,
+//! `retrieve`, `cache_evict`) and, where they apply, `server`, `ino`,
+//! `path` and `bytes`. `store`, `retrieve` and `cache_evict` are debug
+//! records, so they only show up with `-d` or `RUST_LOG=debug`.
+
+use std::fmt::{Display, Write as _};
+use std::io::{self, Write};
+
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+
+/// Write `record` as a single line of JSON
+pub fn write_json(out: &mut dyn Write, record: &Record, timestamp: impl Display) -> io::Result<()> {
+    let mut line = String::from("{");
+    push_field(&mut line, "timestamp", &timestamp.to_string());
+    line.push(',');
+    push_field(&mut line, "level", record.level().as_str());
+    line.push(',');
+    push_field(&mut line, "target", record.target());
+    line.push(',');
+    push_field(&mut line, "message", &record.args().to_string());
+
+    let mut fields = Fields(&mut line);
+    record
+        .key_values()
+        .visit(&mut fields)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    line.push('}');
+    writeln!(out, "{}", line)
+}
+
+/// Appends each key-value pair as a JSON field
+struct Fields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push(',');
+        push_string(self.0, key.as_str());
+        self.0.push(':');
+        // Numbers and booleans stay unquoted so they can be queried as such
+        if let Some(n) = value.to_u64() {
+            let _ = write!(self.0, "{}", n);
+        } else if let Some(n) = value.to_i64() {
+            let _ = write!(self.0, "{}", n);
+        } else if let Some(b) = value.to_bool() {
+            let _ = write!(self.0, "{}", b);
+        } else {
+            push_string(self.0, &value.to_string());
+        }
+        Ok(())
+    }
+}
+
+fn push_field(line: &mut String, key: &str, value: &str) {
+    push_string(line, key);
+    line.push(':');
+    push_string(line, value);
+}
+
+/// Append `value` as a quoted, escaped JSON string
+fn push_string(line: &mut String, value: &str) {
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_records_become_json_lines() {
+        let kvs: [(&str, Value); 3] = [
+            ("event", Value::from("store")),
+            ("path", Value::from("/a \"b\".txt")),
+            ("bytes", Value::from(42u64)),
+        ];
+        let mut out = Vec::new();
+        write_json(
+            &mut out,
+            &Record::builder()
+                .level(Level::Info)
+                .target("rustftpfs::filesystem")
+                .args(format_args!("Stored\tfile"))
+                .key_values(&kvs)
+                .build(),
+            "2024-01-25T10:00:00Z",
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"timestamp\":\"2024-01-25T10:00:00Z\",\"level\":\"INFO\",\
+             \"target\":\"rustftpfs::filesystem\",\"message\":\"Stored\\tfile\",\
+             \"event\":\"store\",\"path\":\"/a \\\"b\\\".txt\",\"bytes\":42}\n"
+        );
+    }
+}
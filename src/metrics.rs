@@ -0,0 +1,251 @@
+//! Metrics Module
+//!
+//! A minimal HTTP endpoint serving Prometheus text-format metrics (built with
+//! the `metrics` feature). It answers `GET /metrics` and nothing else, one
+//! request at a time, so it needs no HTTP dependencies.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+/// Time a scraper gets to send its request before it is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Metrics in the Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    /// Add a counter; each sample is a label set (`cache="dir"`, or empty)
+    /// and its value
+    pub fn counter(&mut self, name: &str, help: &str, samples: &[(&str, u64)]) {
+        self.header(name, help, "counter");
+        self.samples(name, samples);
+    }
+
+    /// Add a gauge; samples as in [`Exposition::counter`]
+    pub fn gauge(&mut self, name: &str, help: &str, samples: &[(&str, u64)]) {
+        self.header(name, help, "gauge");
+        self.samples(name, samples);
+    }
+
+    /// Add one series of a histogram
+    ///
+    /// `buckets` are cumulative `(upper bound, count)` pairs; the `+Inf`
+    /// bucket is `count`. Call [`Exposition::histogram_header`] once before
+    /// the series of a metric.
+    pub fn histogram(
+        &mut self,
+        name: &str,
+        labels: &str,
+        buckets: &[(f64, u64)],
+        sum: f64,
+        count: u64,
+    ) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (le, n) in buckets {
+            let _ = writeln!(
+                self.text,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, le, n
+            );
+        }
+        let _ = writeln!(
+            self.text,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(self.text, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(self.text, "{}_count{} {}", name, labels, count);
+    }
+
+    /// `# HELP` and `# TYPE` lines of a histogram
+    pub fn histogram_header(&mut self, name: &str, help: &str) {
+        self.header(name, help, "histogram");
+    }
+
+    /// The text to serve
+    pub fn into_string(self) -> String {
+        self.text
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    fn samples(&mut self, name: &str, samples: &[(&str, u64)]) {
+        for (labels, value) in samples {
+            if labels.is_empty() {
+                let _ = writeln!(self.text, "{} {}", name, value);
+            } else {
+                let _ = writeln!(self.text, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+}
+
+/// A running metrics endpoint, returned by [`serve`]
+pub struct Server {
+    addr: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Server {
+    /// The address actually bound (useful with port 0)
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Close the listener and wait for the serving thread to exit
+    ///
+    /// The thread is blocked in `accept`, so it is woken with a connection
+    /// of our own once it has been told to stop.
+    pub fn stop(self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        match TcpStream::connect_timeout(&wake, REQUEST_TIMEOUT) {
+            Ok(_) => {
+                let _ = self.thread.join();
+            }
+            Err(e) => warn!("Failed to stop the metrics endpoint: {}", e),
+        }
+    }
+}
+
+/// Serve `GET /metrics` on `addr` from a background thread
+///
+/// Binding happens before returning, so a taken port is reported to the
+/// caller. `render` is called for every scrape; the thread runs until
+/// [`Server::stop`].
+pub fn serve<F>(addr: SocketAddr, render: F) -> io::Result<Server>
+where
+    F: Fn() -> String + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    info!("Serving metrics on http://{}/metrics", local);
+
+    let stopping = Arc::new(AtomicBool::new(false));
+    let stop = Arc::clone(&stopping);
+    let thread = thread::spawn(move || {
+        for stream in listener.incoming() {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            let result = stream.and_then(|stream| answer(stream, &render));
+            if let Err(e) = result {
+                debug!("Metrics request failed: {}", e);
+            }
+        }
+        debug!("Metrics endpoint stopped");
+    });
+    Ok(Server {
+        addr: local,
+        stopping,
+        thread,
+    })
+}
+
+/// Read one request and answer it
+fn answer(mut stream: TcpStream, render: &dyn Fn() -> String) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers are not needed, but must be consumed before replying
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            render(),
+        ),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_metrics_are_served_over_http() {
+        let server = serve("127.0.0.1:0".parse().unwrap(), || {
+            let mut metrics = Exposition::default();
+            metrics.counter("rustftpfs_reconnects_total", "Reconnects", &[("", 2)]);
+            metrics.histogram_header("rustftpfs_op_seconds", "Latency");
+            metrics.histogram(
+                "rustftpfs_op_seconds",
+                "op=\"list\"",
+                &[(0.1, 1), (1.0, 3)],
+                1.5,
+                4,
+            );
+            metrics.into_string()
+        })
+        .unwrap();
+        let addr = server.local_addr();
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP rustftpfs_reconnects_total Reconnects\n"));
+        assert!(response.contains("rustftpfs_reconnects_total 2\n"));
+        assert!(response.contains("rustftpfs_op_seconds_bucket{op=\"list\",le=\"1\"} 3\n"));
+        assert!(response.contains("rustftpfs_op_seconds_bucket{op=\"list\",le=\"+Inf\"} 4\n"));
+        assert!(response.contains("rustftpfs_op_seconds_count{op=\"list\"} 4\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+
+        // Stopping closes the listener
+        server.stop();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,432 @@
+//! Mirror Module
+//!
+//! On-disk copy of downloaded files and directory listings, so reads keep
+//! working while the server is unreachable, plus a queue of uploads that
+//! could not be sent and are replayed once the connection is back.
+//!
+//! Layout under the cache directory, with every entry named by a hash of
+//! its server path (the path itself is stored alongside to rule out
+//! collisions):
+//!
+//! - `files/<key>` and `files/<key>.meta`: contents and the MDTM they were
+//!   downloaded at. A copy is only trusted online if the server still
+//!   reports the same MDTM.
+//! - `listings/<key>`: the last listing of a directory.
+//! - `pending/<key>` and `pending/<key>.path`: contents waiting to be
+//!   uploaded. They are newer than anything on the server, so reads prefer
+//!   them over `files/`.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use crate::ftp::{join_ftp_path, FtpBackend, FtpError, FtpFileInfo, Result};
+
+/// Local mirror rooted at a cache directory
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    root: PathBuf,
+}
+
+impl Mirror {
+    /// Open (creating if needed) the mirror in `dir`
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        for sub in ["files", "listings", "pending"] {
+            fs::create_dir_all(dir.join(sub))?;
+        }
+        Ok(Mirror {
+            root: dir.to_path_buf(),
+        })
+    }
+
+    /// Directory holding the mirror
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Save the contents of `path` as downloaded at `mtime` (None if the
+    /// server doesn't support MDTM; such copies are only used offline)
+    pub fn store_file(&self, path: &str, mtime: Option<SystemTime>, data: &[u8]) -> io::Result<()> {
+        let entry = self.entry("files", path);
+        write_atomic(&entry, data)?;
+        let meta = format!("{}\n{}\n", format_mtime(mtime), path);
+        write_atomic(&with_suffix(&entry, "meta"), meta.as_bytes())
+    }
+
+    /// Contents of `path`: a pending upload if there is one, otherwise the
+    /// mirrored copy if it was downloaded at `mtime` (any copy when `mtime`
+    /// is None)
+    pub fn load_file(&self, path: &str, mtime: Option<SystemTime>) -> Option<Vec<u8>> {
+        if let Some(data) = self.pending_data(path) {
+            return Some(data);
+        }
+
+        let stored = self.stored_mtime(path)?;
+        if mtime.is_some() && stored != mtime {
+            debug!("Mirrored copy of {} is stale", path);
+            return None;
+        }
+        fs::read(self.entry("files", path)).ok()
+    }
+
+    /// MDTM a mirrored copy was downloaded at (`Some(None)` if unknown)
+    fn stored_mtime(&self, path: &str) -> Option<Option<SystemTime>> {
+        let meta = fs::read_to_string(with_suffix(&self.entry("files", path), "meta")).ok()?;
+        let mut lines = meta.lines();
+        let mtime = parse_mtime(lines.next()?)?;
+        (lines.next()? == path).then_some(mtime)
+    }
+
+    /// Save the listing of directory `path`
+    pub fn store_listing(&self, path: &str, files: &[FtpFileInfo]) -> io::Result<()> {
+        let mut text = format!("{}\n", path);
+        // Names are written last so tabs survive; newlines can't be stored,
+        // and neither can link targets with tabs
+        let storable = |f: &&FtpFileInfo| {
+            !f.name.contains('\n')
+                && !f
+                    .link_target
+                    .as_ref()
+                    .is_some_and(|t| t.contains(['\t', '\n']))
+        };
+        for file in files.iter().filter(storable) {
+            let kind = match (&file.link_target, file.is_dir) {
+                (Some(_), _) => 'l',
+                (None, true) => 'd',
+                (None, false) => 'f',
+            };
+            text.push_str(&format!(
+                "{}\t{}\t{:o}\t{}\t{}\t{}\n",
+                kind,
+                file.size,
+                file.permissions,
+                format_mtime(file.modified_time),
+                file.link_target.as_deref().unwrap_or_default(),
+                file.name
+            ));
+        }
+        write_atomic(&self.entry("listings", path), text.as_bytes())
+    }
+
+    /// Last saved listing of directory `path`
+    pub fn load_listing(&self, path: &str) -> Option<Vec<FtpFileInfo>> {
+        let text = fs::read_to_string(self.entry("listings", path)).ok()?;
+        let mut lines = text.lines();
+        if lines.next()? != path {
+            return None;
+        }
+
+        let mut files = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.splitn(6, '\t').collect();
+            let [kind, size, permissions, mtime, target, name] = fields[..] else {
+                continue;
+            };
+            files.push(FtpFileInfo {
+                name: name.to_string(),
+                path: join_ftp_path(path, name),
+                size: size.parse().ok()?,
+                is_dir: kind == "d",
+                permissions: u32::from_str_radix(permissions, 8).ok()?,
+                modified_time: parse_mtime(mtime)?,
+                link_target: (kind == "l").then(|| target.to_string()),
+                raw_name: None,
+            });
+        }
+        Some(files)
+    }
+
+    /// Queue the contents read from `reader` to be uploaded to `path`,
+    /// replacing any earlier pending upload of the same path
+    pub fn queue_upload(&self, path: &str, reader: &mut dyn Read) -> io::Result<()> {
+        let entry = self.entry("pending", path);
+        let tmp = with_suffix(&entry, "tmp");
+        let mut file = File::create(&tmp)?;
+        io::copy(reader, &mut file)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &entry)?;
+        write_atomic(&with_suffix(&entry, "path"), path.as_bytes())
+    }
+
+    /// Server paths with a pending upload
+    pub fn pending(&self) -> Vec<String> {
+        let dir = match fs::read_dir(self.root.join("pending")) {
+            Ok(dir) => dir,
+            Err(e) => {
+                warn!("Failed to read pending uploads: {}", e);
+                return Vec::new();
+            }
+        };
+        dir.filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "path"))
+            .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+            .collect()
+    }
+
+    /// Open the pending upload of `path` for reading
+    pub fn open_pending(&self, path: &str) -> io::Result<File> {
+        File::open(self.entry("pending", path))
+    }
+
+    fn pending_data(&self, path: &str) -> Option<Vec<u8>> {
+        let entry = self.entry("pending", path);
+        let queued = fs::read_to_string(with_suffix(&entry, "path")).ok()?;
+        (queued == path).then(|| fs::read(&entry).ok()).flatten()
+    }
+
+    /// Drop the pending upload of `path` once it reached the server
+    pub fn finish_upload(&self, path: &str) -> io::Result<()> {
+        let entry = self.entry("pending", path);
+        fs::remove_file(with_suffix(&entry, "path"))?;
+        fs::remove_file(entry)
+    }
+
+    /// Forget everything mirrored for `path` (after it is deleted or renamed)
+    pub fn forget(&self, path: &str) {
+        for sub in ["files", "pending"] {
+            let entry = self.entry(sub, path);
+            for file in [
+                with_suffix(&entry, "meta"),
+                with_suffix(&entry, "path"),
+                entry,
+            ] {
+                if let Err(e) = fs::remove_file(&file) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        warn!("Failed to remove {:?} from the mirror: {}", file, e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn entry(&self, sub: &str, path: &str) -> PathBuf {
+        self.root.join(sub).join(format!("{:016x}", fnv1a(path)))
+    }
+}
+
+/// Backend for `--offline`: answers from the mirror and never touches the
+/// network
+///
+/// Uploads are queued in the mirror to be replayed by the next online
+/// mount; other changes are refused.
+pub struct OfflineBackend {
+    mirror: Mirror,
+    /// `--ascii-ext` of the mount: the mirrored copies of these files hold
+    /// what their ASCII downloads returned
+    ascii_extensions: Vec<String>,
+}
+
+impl OfflineBackend {
+    pub fn new(mirror: Mirror, ascii_extensions: Vec<String>) -> Self {
+        OfflineBackend {
+            mirror,
+            ascii_extensions,
+        }
+    }
+
+    /// Entry for `path` in its parent's mirrored listing
+    fn listed(&self, path: &str) -> Result<FtpFileInfo> {
+        let (parent, name) = match path.rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((parent, name)) => (parent, name),
+            None => return Err(FtpError::NotFound),
+        };
+        self.mirror
+            .load_listing(parent)
+            .and_then(|files| files.into_iter().find(|f| f.name == name))
+            .ok_or(FtpError::NotFound)
+    }
+
+    fn unavailable(operation: &str) -> FtpError {
+        FtpError::Protocol(format!("{} is not available offline", operation))
+    }
+}
+
+impl FtpBackend for OfflineBackend {
+    fn list_dir(&mut self, path: &str) -> Result<Vec<FtpFileInfo>> {
+        self.mirror.load_listing(path).ok_or(FtpError::NotFound)
+    }
+
+    fn retrieve_into(&mut self, path: &str, data: &mut Vec<u8>) -> Result<()> {
+        let contents = self
+            .mirror
+            .load_file(path, None)
+            .ok_or_else(|| FtpError::Protocol(format!("{} is not in the local mirror", path)))?;
+        data.extend_from_slice(&contents[data.len().min(contents.len())..]);
+        Ok(())
+    }
+
+    fn store_from(&mut self, path: &str, reader: &mut dyn Read) -> Result<()> {
+        debug!("Offline: queueing upload of {}", path);
+        Ok(self.mirror.queue_upload(path, reader)?)
+    }
+
+    fn store_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<()> {
+        let mut contents = self.mirror.load_file(path, None).unwrap_or_default();
+        let end = offset as usize + data.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[offset as usize..end].copy_from_slice(data);
+        self.store_from(path, &mut io::Cursor::new(contents))
+    }
+
+    fn size(&mut self, path: &str) -> Result<u64> {
+        if let Some(data) = self.mirror.pending_data(path) {
+            return Ok(data.len() as u64);
+        }
+        match self.listed(path)? {
+            file if file.is_dir => Err(FtpError::IsADirectory),
+            file => Ok(file.size),
+        }
+    }
+
+    fn mdtm(&mut self, path: &str) -> Result<SystemTime> {
+        self.mirror
+            .stored_mtime(path)
+            .flatten()
+            .or(self.listed(path)?.modified_time)
+            .ok_or_else(|| Self::unavailable("MDTM"))
+    }
+
+    fn is_dir(&mut self, path: &str) -> Result<bool> {
+        if path == "/" {
+            return Ok(true);
+        }
+        Ok(self.listed(path)?.is_dir)
+    }
+
+    fn delete(&mut self, _path: &str) -> Result<()> {
+        Err(Self::unavailable("Deleting"))
+    }
+
+    fn mkdir(&mut self, _path: &str) -> Result<()> {
+        Err(Self::unavailable("Creating directories"))
+    }
+
+    fn rmdir(&mut self, _path: &str) -> Result<()> {
+        Err(Self::unavailable("Removing directories"))
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<()> {
+        Err(Self::unavailable("Renaming"))
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn ascii_extensions(&self) -> &[String] {
+        &self.ascii_extensions
+    }
+}
+
+/// FNV-1a: stable across Rust releases, unlike `DefaultHasher`
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Write through a temporary file so a crash never leaves half an entry
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = with_suffix(path, "tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+fn format_mtime(mtime: Option<SystemTime>) -> String {
+    match mtime.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        Some(since_epoch) => since_epoch.as_secs().to_string(),
+        None => "-".to_string(),
+    }
+}
+
+/// Inverse of `format_mtime`; None if the field is malformed
+fn parse_mtime(field: &str) -> Option<Option<SystemTime>> {
+    match field {
+        "-" => Some(None),
+        secs => secs
+            .parse()
+            .ok()
+            .map(|secs| Some(UNIX_EPOCH + Duration::from_secs(secs))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, is_dir: bool, mtime: Option<u64>) -> FtpFileInfo {
+        FtpFileInfo {
+            name: name.to_string(),
+            path: format!("/docs/{}", name),
+            size: if is_dir { 0 } else { 42 },
+            is_dir,
+            permissions: if is_dir { 0o755 } else { 0o640 },
+            modified_time: mtime.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+            link_target: None,
+            raw_name: None,
+        }
+    }
+
+    #[test]
+    fn test_listing_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mirror = Mirror::open(dir.path()).unwrap();
+        let files = vec![
+            file("a\tb.txt", false, Some(1_700_000_000)),
+            file("sub", true, None),
+            FtpFileInfo {
+                link_target: Some("../shared".to_string()),
+                ..file("link", false, None)
+            },
+        ];
+        mirror.store_listing("/docs", &files).unwrap();
+
+        let loaded = mirror.load_listing("/docs").unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded[0].name, "a\tb.txt");
+        assert_eq!(loaded[0].path, "/docs/a\tb.txt");
+        assert_eq!(loaded[0].permissions, 0o640);
+        assert_eq!(loaded[0].modified_time, files[0].modified_time);
+        assert!(loaded[1].is_dir);
+        assert_eq!(loaded[1].link_target, None);
+        assert_eq!(loaded[2].link_target.as_deref(), Some("../shared"));
+        assert!(mirror.load_listing("/other").is_none());
+    }
+
+    #[test]
+    fn test_file_staleness_and_pending_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let mirror = Mirror::open(dir.path()).unwrap();
+        let old = UNIX_EPOCH + Duration::from_secs(1_000);
+        let new = UNIX_EPOCH + Duration::from_secs(2_000);
+        mirror.store_file("/a.txt", Some(old), b"old").unwrap();
+
+        assert_eq!(mirror.load_file("/a.txt", Some(old)).unwrap(), b"old");
+        assert!(mirror.load_file("/a.txt", Some(new)).is_none());
+        assert_eq!(mirror.load_file("/a.txt", None).unwrap(), b"old");
+
+        // Local edits win until they are uploaded
+        mirror.queue_upload("/a.txt", &mut &b"edited"[..]).unwrap();
+        assert_eq!(mirror.pending(), vec!["/a.txt".to_string()]);
+        assert_eq!(mirror.load_file("/a.txt", Some(new)).unwrap(), b"edited");
+
+        mirror.finish_upload("/a.txt").unwrap();
+        assert!(mirror.pending().is_empty());
+        assert!(mirror.load_file("/a.txt", Some(new)).is_none());
+    }
+}
@@ -0,0 +1,260 @@
+//! Mock Backend Module
+//!
+//! In-memory [`FtpBackend`] shared by the unit tests of the filesystem, the
+//! multi-host aggregator and the async API, so they exercise one simulated
+//! server instead of each growing its own. Directories are explicit
+//! (`dirs`); a file or directory belongs to the directory its path names
+//! as parent. The state sits behind an `Arc<Mutex<_>>`, so a test keeps a
+//! clone of the backend to inspect the server or inject failures after
+//! handing the original over.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::ftp::{FtpBackend, FtpError, FtpFileInfo, Result};
+
+/// State of the simulated server
+#[derive(Default)]
+pub(crate) struct MockState {
+    pub files: HashMap<String, Vec<u8>>,
+    pub dirs: HashSet<String>,
+    /// Symbolic links: path of the link to its target
+    pub links: HashMap<String, String>,
+    pub list_calls: usize,
+    pub retrieve_calls: usize,
+    pub is_dir_calls: usize,
+    pub reconnect_calls: usize,
+    /// SITE CHMOD received: (path, mode)
+    pub chmod_calls: Vec<(String, u32)>,
+    /// Simulate a server that accepts SITE CHMOD without applying it
+    pub chmod_ignored: bool,
+    /// Next uploads that break off halfway with a transient error
+    pub interrupted_stores: usize,
+    /// Next uploads that store only half and report success
+    pub truncated_stores: usize,
+    /// Next uploads that are refused before anything is sent
+    pub refused_stores: usize,
+    /// Next listings whose data connection breaks off halfway
+    pub interrupted_listings: usize,
+    /// Simulate a full server: uploads fail with 552
+    pub full: bool,
+    /// Simulate an unreachable server
+    pub down: bool,
+    /// QUIT received
+    pub quit_calls: usize,
+    /// Free space answered by AVBL (None = no AVBL)
+    pub avbl: Option<u64>,
+    /// AVBL received
+    pub avbl_calls: usize,
+}
+
+/// In-memory FTP server for tests that need no network
+#[derive(Clone, Default)]
+pub(crate) struct MockBackend {
+    pub state: Arc<Mutex<MockState>>,
+    /// Extensions reported as transferred in ASCII mode
+    pub ascii_extensions: Vec<String>,
+}
+
+impl MockBackend {
+    pub fn with_file(self, path: &str, data: &[u8]) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .insert(path.to_string(), data.to_vec());
+        self
+    }
+
+    pub fn with_dir(self, path: &str) -> Self {
+        self.state.lock().unwrap().dirs.insert(path.to_string());
+        self
+    }
+}
+
+fn parent_of(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) => "/",
+        Some(i) => &path[..i],
+        None => "/",
+    }
+}
+
+impl FtpBackend for MockBackend {
+    fn list_dir(&mut self, path: &str) -> Result<Vec<FtpFileInfo>> {
+        let mut state = self.state.lock().unwrap();
+        state.list_calls += 1;
+        if state.down {
+            return Err(FtpError::Transient);
+        }
+        if path != "/" && !state.dirs.contains(path) {
+            return Err(FtpError::NotFound);
+        }
+        if state.interrupted_listings > 0 {
+            state.interrupted_listings -= 1;
+            return Err(FtpError::Io(io::ErrorKind::ConnectionReset.into()));
+        }
+
+        let files = state.files.iter().map(|(p, d)| (p, d.len() as u64, false));
+        let dirs = state.dirs.iter().map(|p| (p, 0, true));
+        Ok(files
+            .chain(dirs)
+            .filter(|(p, _, _)| parent_of(p) == path)
+            .map(|(p, size, is_dir)| FtpFileInfo {
+                name: p.rsplit('/').next().unwrap().to_string(),
+                path: p.clone(),
+                size,
+                is_dir,
+                permissions: match state.chmod_calls.iter().rfind(|(c, _)| c == p) {
+                    Some(&(_, mode)) if !state.chmod_ignored => mode,
+                    _ if is_dir => 0o755,
+                    _ => 0o644,
+                },
+                modified_time: None,
+                link_target: state.links.get(p).cloned(),
+                raw_name: None,
+            })
+            .collect())
+    }
+
+    fn retrieve_into(&mut self, path: &str, data: &mut Vec<u8>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.retrieve_calls += 1;
+        if state.down {
+            return Err(FtpError::Transient);
+        }
+        let contents = state.files.get(path).ok_or(FtpError::NotFound)?;
+        data.extend_from_slice(&contents[data.len().min(contents.len())..]);
+        Ok(())
+    }
+
+    fn store_from(&mut self, path: &str, reader: &mut dyn Read) -> Result<()> {
+        if self.state.lock().unwrap().down {
+            return Err(FtpError::Transient);
+        }
+        if self.state.lock().unwrap().full {
+            return Err(FtpError::NoSpace);
+        }
+        if self.state.lock().unwrap().refused_stores > 0 {
+            self.state.lock().unwrap().refused_stores -= 1;
+            return Err(FtpError::Transient);
+        }
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut state = self.state.lock().unwrap();
+        if state.interrupted_stores > 0 {
+            state.interrupted_stores -= 1;
+            data.truncate(data.len() / 2);
+            state.files.insert(path.to_string(), data);
+            return Err(FtpError::Transient);
+        }
+        if state.truncated_stores > 0 {
+            state.truncated_stores -= 1;
+            data.truncate(data.len() / 2);
+        }
+        state.files.insert(path.to_string(), data);
+        Ok(())
+    }
+
+    fn store_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let file = state.files.entry(path.to_string()).or_default();
+        let end = offset as usize + data.len();
+        if file.len() < end {
+            file.resize(end, 0);
+        }
+        file[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn size(&mut self, path: &str) -> Result<u64> {
+        let state = self.state.lock().unwrap();
+        let file = state.files.get(path).ok_or(FtpError::NotFound)?;
+        Ok(file.len() as u64)
+    }
+
+    fn mdtm(&mut self, _path: &str) -> Result<SystemTime> {
+        Err(FtpError::NotFound)
+    }
+
+    fn is_dir(&mut self, path: &str) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        state.is_dir_calls += 1;
+        Ok(path == "/" || state.dirs.contains(path))
+    }
+
+    fn delete(&mut self, path: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or(FtpError::NotFound)
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<()> {
+        self.state.lock().unwrap().dirs.insert(path.to_string());
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let prefix = format!("{}/", path);
+        let mut contents = state.files.keys().chain(state.dirs.iter());
+        if contents.any(|child| child.starts_with(&prefix)) {
+            return Err(FtpError::Protocol(format!(
+                "550 {}: Directory not empty",
+                path
+            )));
+        }
+        if state.dirs.remove(path) {
+            Ok(())
+        } else {
+            Err(FtpError::NotFound)
+        }
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let data = state.files.remove(from).ok_or(FtpError::NotFound)?;
+        state.files.insert(to.to_string(), data);
+        Ok(())
+    }
+
+    fn ascii_extensions(&self) -> &[String] {
+        &self.ascii_extensions
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.reconnect_calls += 1;
+        match state.down {
+            true => Err(FtpError::Transient),
+            false => Ok(()),
+        }
+    }
+
+    fn quit(&mut self) -> Result<()> {
+        self.state.lock().unwrap().quit_calls += 1;
+        Ok(())
+    }
+
+    fn available_space(&mut self, _path: &str) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        state.avbl_calls += 1;
+        state
+            .avbl
+            .ok_or_else(|| FtpError::Protocol("502 AVBL not implemented".to_string()))
+    }
+
+    fn chmod(&mut self, path: &str, mode: u32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.down {
+            return Err(FtpError::Transient);
+        }
+        state.chmod_calls.push((path.to_string(), mode));
+        Ok(())
+    }
+}
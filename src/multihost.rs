@@ -0,0 +1,403 @@
+//! Multi-Host Module
+//!
+//! Aggregates several FTP servers under one mount, each shown as a top-level
+//! directory (`mnt/hostA`, `mnt/hostB`). [`MultiHost`] is an [`FtpBackend`]
+//! that routes every path to the connection named by its first component,
+//! so the filesystem layer works unchanged: its caches are keyed by paths
+//! such as `/hostA/pub/file`, which can't collide between hosts.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::time::{Duration, SystemTime};
+
+use log::debug;
+
+use crate::ftp::{
+    is_entry_name, join_ftp_path, normalize_ftp_path, FtpBackend, FtpConnection, FtpError,
+    FtpFileInfo, Result,
+};
+
+/// One aggregated server
+struct Host<B> {
+    conn: B,
+    /// Server directory shown as the host's top-level directory
+    root: String,
+}
+
+/// Backend serving several FTP servers as top-level directories
+///
+/// The hosts share the filesystem's connection lock, so operations on
+/// different hosts are still served one at a time.
+pub struct MultiHost<B: FtpBackend = FtpConnection> {
+    hosts: BTreeMap<String, Host<B>>,
+    /// Host used by the last operation, the one `reconnect` re-establishes
+    last: Option<String>,
+}
+
+impl<B: FtpBackend> Default for MultiHost<B> {
+    fn default() -> Self {
+        MultiHost {
+            hosts: BTreeMap::new(),
+            last: None,
+        }
+    }
+}
+
+impl<B: FtpBackend> MultiHost<B> {
+    /// Create an aggregate without hosts
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `conn` as the top-level directory `name`, serving `root`
+    pub fn add(&mut self, name: &str, conn: B, root: &str) -> Result<()> {
+        if !is_entry_name(name) || name.contains('/') {
+            return Err(FtpError::Protocol(format!("Invalid host name {:?}", name)));
+        }
+        if self.hosts.contains_key(name) {
+            return Err(FtpError::Protocol(format!("Host {} given twice", name)));
+        }
+        self.hosts.insert(
+            name.to_string(),
+            Host {
+                conn,
+                root: normalize_ftp_path(root),
+            },
+        );
+        Ok(())
+    }
+
+    /// Names of the hosts, in the order they are listed
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.hosts.keys().map(String::as_str)
+    }
+
+    /// Run `op` on the host owning `path` with the path on that server
+    ///
+    /// The top level itself belongs to no host and is refused.
+    fn on_host<T>(&mut self, path: &str, op: impl FnOnce(&mut B, &str) -> Result<T>) -> Result<T> {
+        let (name, rest) = split(path).ok_or(FtpError::PermissionDenied)?;
+        let host = self.hosts.get_mut(&name).ok_or(FtpError::NotFound)?;
+        let server_path = join_ftp_path(&host.root, &rest);
+        self.last = Some(name);
+        op(&mut host.conn, &server_path)
+    }
+
+    /// Like [`on_host`](Self::on_host), refusing the hosts' own directories,
+    /// which come from the command line and can't be created or removed
+    fn below_host<T>(
+        &mut self,
+        path: &str,
+        op: impl FnOnce(&mut B, &str) -> Result<T>,
+    ) -> Result<T> {
+        match split(path) {
+            Some((_, rest)) if !rest.is_empty() => self.on_host(path, op),
+            _ => Err(FtpError::PermissionDenied),
+        }
+    }
+
+    /// Entries of the top level: one directory per host
+    fn host_entries(&self) -> Vec<FtpFileInfo> {
+        self.names()
+            .map(|name| FtpFileInfo {
+                name: name.to_string(),
+                path: format!("/{}", name),
+                size: 0,
+                is_dir: true,
+                permissions: 0o040755,
+                modified_time: None,
+                link_target: None,
+                raw_name: None,
+            })
+            .collect()
+    }
+}
+
+/// Split a mount path into host name and the rest; None for the top level
+fn split(path: &str) -> Option<(String, String)> {
+    let path = normalize_ftp_path(path);
+    let relative = path.strip_prefix('/').unwrap_or(&path);
+    if relative.is_empty() {
+        return None;
+    }
+    let (name, rest) = relative.split_once('/').unwrap_or((relative, ""));
+    Some((name.to_string(), rest.to_string()))
+}
+
+/// Mount path of `server_path` on host `name`; None if it lies outside `root`
+fn to_mount(name: &str, root: &str, server_path: &str) -> Option<String> {
+    let rest = if root == "/" {
+        server_path
+    } else {
+        server_path
+            .strip_prefix(root)
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))?
+    };
+    Some(join_ftp_path(&format!("/{}", name), rest))
+}
+
+impl<B: FtpBackend> FtpBackend for MultiHost<B> {
+    fn list_dir(&mut self, path: &str) -> Result<Vec<FtpFileInfo>> {
+        let Some((name, _)) = split(path) else {
+            return Ok(self.host_entries());
+        };
+        let mount_dir = normalize_ftp_path(path);
+        let mut files = self.on_host(path, |conn, path| conn.list_dir(path))?;
+        let root = &self.hosts[&name].root;
+        for file in &mut files {
+            file.path = to_mount(&name, root, &file.path)
+                .unwrap_or_else(|| join_ftp_path(&mount_dir, &file.name));
+            // Absolute targets are server paths: point them inside the mount
+            if let Some(target) = file.link_target.as_mut().filter(|t| t.starts_with('/')) {
+                if let Some(mounted) = to_mount(&name, root, target) {
+                    *target = mounted;
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn retrieve_into(&mut self, path: &str, data: &mut Vec<u8>) -> Result<()> {
+        self.on_host(path, |conn, path| conn.retrieve_into(path, data))
+    }
+
+    fn retrieve_range(&mut self, path: &str, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.on_host(path, |conn, path| conn.retrieve_range(path, offset, len))
+    }
+
+    fn store_from(&mut self, path: &str, reader: &mut dyn Read) -> Result<()> {
+        self.below_host(path, |conn, path| conn.store_from(path, reader))
+    }
+
+    fn store_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<()> {
+        self.below_host(path, |conn, path| conn.store_at(path, offset, data))
+    }
+
+    fn size(&mut self, path: &str) -> Result<u64> {
+        match split(path) {
+            Some(_) => self.on_host(path, |conn, path| conn.size(path)),
+            None => Err(FtpError::NotFound),
+        }
+    }
+
+    fn mdtm(&mut self, path: &str) -> Result<SystemTime> {
+        match split(path) {
+            Some(_) => self.on_host(path, |conn, path| conn.mdtm(path)),
+            None => Err(FtpError::NotFound),
+        }
+    }
+
+    fn is_dir(&mut self, path: &str) -> Result<bool> {
+        match split(path) {
+            Some((name, _)) if !self.hosts.contains_key(&name) => Ok(false),
+            Some(_) => self.on_host(path, |conn, path| conn.is_dir(path)),
+            None => Ok(true),
+        }
+    }
+
+    fn delete(&mut self, path: &str) -> Result<()> {
+        self.below_host(path, |conn, path| conn.delete(path))
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<()> {
+        self.below_host(path, |conn, path| conn.mkdir(path))
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<()> {
+        self.below_host(path, |conn, path| conn.rmdir(path))
+    }
+
+    /// Renames stay on one server; across hosts the error is EXDEV, so `mv`
+    /// falls back to copying
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let (from_host, to_host) = match (split(from), split(to)) {
+            (Some((from_host, from_rest)), Some((to_host, to_rest)))
+                if !from_rest.is_empty() && !to_rest.is_empty() =>
+            {
+                (from_host, to_host)
+            }
+            _ => return Err(FtpError::PermissionDenied),
+        };
+        if from_host != to_host {
+            return Err(FtpError::Io(io::Error::new(
+                io::ErrorKind::CrossesDevices,
+                format!("Cannot rename from {} to {}", from_host, to_host),
+            )));
+        }
+        let to_path = {
+            let host = self.hosts.get(&to_host).ok_or(FtpError::NotFound)?;
+            join_ftp_path(&host.root, &split(to).unwrap().1)
+        };
+        self.on_host(from, |conn, from| conn.rename(from, &to_path))
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        let Some(name) = self.last.clone() else {
+            return Ok(());
+        };
+        debug!("Reconnecting host {}", name);
+        match self.hosts.get_mut(&name) {
+            Some(host) => host.conn.reconnect(),
+            None => Ok(()),
+        }
+    }
+
+    /// The longest idle host decides when a keepalive is due
+    fn idle_time(&self) -> Duration {
+        self.hosts
+            .values()
+            .map(|host| host.conn.idle_time())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Every host is connected with the same `--ascii-ext`
+    fn ascii_extensions(&self) -> &[String] {
+        match self.hosts.values().next() {
+            Some(host) => host.conn.ascii_extensions(),
+            None => &[],
+        }
+    }
+
+    fn noop(&mut self) -> Result<()> {
+        for (name, host) in &mut self.hosts {
+            if let Err(e) = host.conn.noop() {
+                self.last = Some(name.clone());
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Every host is closed; the first failure is returned
+    fn quit(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for (name, host) in &mut self.hosts {
+            if let Err(e) = host.conn.quit() {
+                debug!("QUIT to host {} failed: {}", name, e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    fn chmod(&mut self, path: &str, mode: u32) -> Result<()> {
+        self.below_host(path, |conn, path| conn.chmod(path, mode))
+    }
+
+    fn set_mtime(&mut self, path: &str, time: SystemTime) -> Result<()> {
+        self.below_host(path, |conn, path| conn.set_mtime(path, time))
+    }
+
+    fn available_space(&mut self, path: &str) -> Result<u64> {
+        self.on_host(path, |conn, path| conn.available_space(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+
+    fn two_hosts() -> MultiHost<MockBackend> {
+        let mut hosts = MultiHost::new();
+        let a = MockBackend::default()
+            .with_dir("/pub")
+            .with_file("/pub/readme", b"host a");
+        let b = MockBackend::default()
+            .with_dir("/srv")
+            .with_dir("/srv/pub")
+            .with_file("/srv/pub/readme", b"host b")
+            .with_file("/srv/pub/v1", b"1")
+            .with_file("/srv/pub/latest", b"")
+            .with_file("/outside", b"hidden");
+        b.state
+            .lock()
+            .unwrap()
+            .links
+            .insert("/srv/pub/latest".to_string(), "/srv/pub/v1".to_string());
+        hosts.add("a", a, "/").unwrap();
+        hosts.add("b", b, "/srv/").unwrap();
+        hosts
+    }
+
+    #[test]
+    fn test_top_level_lists_the_hosts() {
+        let mut hosts = two_hosts();
+        let top = hosts.list_dir("/").unwrap();
+        let names: Vec<(&str, &str, bool)> = top
+            .iter()
+            .map(|f| (f.name.as_str(), f.path.as_str(), f.is_dir))
+            .collect();
+        assert_eq!(names, [("a", "/a", true), ("b", "/b", true)]);
+        assert!(hosts.is_dir("/").unwrap());
+        assert!(hosts.is_dir("/b").unwrap());
+        assert!(!hosts.is_dir("/c").unwrap());
+
+        assert!(hosts.add("a", MockBackend::default(), "/").is_err());
+        assert!(hosts.add("..", MockBackend::default(), "/").is_err());
+        assert!(hosts.add("x/y", MockBackend::default(), "/").is_err());
+    }
+
+    #[test]
+    fn test_paths_are_routed_by_their_first_component() {
+        let mut hosts = two_hosts();
+
+        // Same path on both servers, kept apart by the host prefix
+        assert_eq!(hosts.retrieve("/a/pub/readme").unwrap(), b"host a");
+        assert_eq!(hosts.retrieve("/b/pub/readme").unwrap(), b"host b");
+        assert!(matches!(
+            hosts.retrieve("/c/pub/readme"),
+            Err(FtpError::NotFound)
+        ));
+        // Host b is rooted at /srv: the rest of its server stays out of reach
+        assert!(hosts.retrieve("/b/outside").is_err());
+
+        let mut listing = hosts.list_dir("/b/pub").unwrap();
+        listing.sort_by(|x, y| x.name.cmp(&y.name));
+        let paths: Vec<&str> = listing.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["/b/pub/latest", "/b/pub/readme", "/b/pub/v1"]);
+        assert_eq!(listing[0].link_target.as_deref(), Some("/b/pub/v1"));
+
+        hosts.store("/a/pub/new", b"x").unwrap();
+        hosts.mkdir("/b/pub/dir").unwrap();
+        hosts.rename("/b/pub/v1", "/b/pub/v2").unwrap();
+        let server_a = hosts.hosts["a"].conn.state.lock().unwrap();
+        let server_b = hosts.hosts["b"].conn.state.lock().unwrap();
+        assert_eq!(server_a.files["/pub/new"], b"x");
+        assert!(server_b.dirs.contains("/srv/pub/dir"));
+        assert!(server_b.files.contains_key("/srv/pub/v2"));
+    }
+
+    #[test]
+    fn test_top_level_and_cross_host_changes_are_refused() {
+        let mut hosts = two_hosts();
+        assert!(matches!(hosts.mkdir("/c"), Err(FtpError::PermissionDenied)));
+        assert!(matches!(hosts.rmdir("/a"), Err(FtpError::PermissionDenied)));
+        assert!(matches!(
+            hosts.store("/file", b"x"),
+            Err(FtpError::PermissionDenied)
+        ));
+        assert!(matches!(
+            hosts.rename("/a", "/z"),
+            Err(FtpError::PermissionDenied)
+        ));
+        match hosts.rename("/a/pub/readme", "/b/pub/readme") {
+            Err(FtpError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::CrossesDevices),
+            other => panic!("expected a cross-device error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reconnect_targets_the_last_host_used() {
+        let mut hosts = two_hosts();
+        hosts.reconnect().unwrap();
+        hosts.size("/b/pub/readme").unwrap();
+        hosts.reconnect().unwrap();
+        let reconnects = |host: &str| hosts.hosts[host].conn.state.lock().unwrap().reconnect_calls;
+        assert_eq!(reconnects("a"), 0);
+        assert_eq!(reconnects("b"), 1);
+    }
+}
@@ -0,0 +1,159 @@
+//! Netrc Module
+//!
+//! Looks up FTP credentials in a `.netrc` file so passwords don't have to be
+//! passed on the command line, mirroring curlftpfs and lftp.
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+/// Credentials for a single machine (or the `default` entry)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetrcEntry {
+    pub login: Option<String>,
+    pub password: Option<String>,
+    pub account: Option<String>,
+}
+
+/// Parsed contents of a netrc file
+#[derive(Debug, Default)]
+pub struct Netrc {
+    machines: Vec<(String, NetrcEntry)>,
+    default: Option<NetrcEntry>,
+}
+
+impl Netrc {
+    /// Parse netrc text
+    pub fn parse(text: &str) -> Self {
+        let mut netrc = Netrc::default();
+        let mut current: Option<(Option<String>, NetrcEntry)> = None;
+        let mut lines = text.lines();
+
+        while let Some(line) = lines.next() {
+            let mut tokens = line.split_whitespace();
+            while let Some(token) = tokens.next() {
+                match token {
+                    "machine" => {
+                        netrc.push(current.take());
+                        let host = tokens.next().unwrap_or_default().to_string();
+                        current = Some((Some(host), NetrcEntry::default()));
+                    }
+                    "default" => {
+                        netrc.push(current.take());
+                        current = Some((None, NetrcEntry::default()));
+                    }
+                    "login" | "password" | "account" => {
+                        let value = tokens.next().map(|v| v.to_string());
+                        if let Some((_, entry)) = current.as_mut() {
+                            match token {
+                                "login" => entry.login = value,
+                                "password" => entry.password = value,
+                                _ => entry.account = value,
+                            }
+                        }
+                    }
+                    "macdef" => {
+                        // Macro bodies run until the next blank line
+                        for body in lines.by_ref() {
+                            if body.trim().is_empty() {
+                                break;
+                            }
+                        }
+                        break;
+                    }
+                    _ if token.starts_with('#') => break,
+                    _ => {}
+                }
+            }
+        }
+        netrc.push(current.take());
+
+        netrc
+    }
+
+    fn push(&mut self, entry: Option<(Option<String>, NetrcEntry)>) {
+        match entry {
+            Some((Some(host), entry)) => self.machines.push((host, entry)),
+            Some((None, entry)) => self.default = Some(entry),
+            None => {}
+        }
+    }
+
+    /// Load the netrc file pointed to by `$NETRC`, or `~/.netrc`
+    ///
+    /// Returns `Ok(None)` when no netrc file exists.
+    pub fn load() -> Result<Option<Self>> {
+        let path = match std::env::var_os("NETRC") {
+            Some(path) => PathBuf::from(path),
+            None => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".netrc"),
+                None => return Ok(None),
+            },
+        };
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let text =
+            fs::read_to_string(&path).context(format!("Failed to read netrc file {:?}", path))?;
+        Ok(Some(Self::parse(&text)))
+    }
+
+    /// Find credentials for `host`, optionally restricted to `login`
+    ///
+    /// Falls back to the `default` entry when no machine matches.
+    pub fn lookup(&self, host: &str, login: Option<&str>) -> Option<&NetrcEntry> {
+        let login_matches = |entry: &NetrcEntry| match (login, entry.login.as_deref()) {
+            (Some(wanted), Some(found)) => wanted == found,
+            _ => true,
+        };
+
+        self.machines
+            .iter()
+            .find(|(machine, entry)| machine.eq_ignore_ascii_case(host) && login_matches(entry))
+            .map(|(_, entry)| entry)
+            .or_else(|| self.default.as_ref().filter(|entry| login_matches(entry)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+machine ftp.example.com login alice password s3cret
+# comment line
+machine mirror.example.org
+    login bob
+    password hunter2
+macdef init
+cd /pub
+
+default login anonymous password guest@
+";
+
+    #[test]
+    fn test_lookup_machine() {
+        let netrc = Netrc::parse(SAMPLE);
+
+        let entry = netrc.lookup("ftp.example.com", None).unwrap();
+        assert_eq!(entry.login.as_deref(), Some("alice"));
+        assert_eq!(entry.password.as_deref(), Some("s3cret"));
+
+        let entry = netrc.lookup("mirror.example.org", Some("bob")).unwrap();
+        assert_eq!(entry.password.as_deref(), Some("hunter2"));
+    }
+
+    #[test]
+    fn test_lookup_default() {
+        let netrc = Netrc::parse(SAMPLE);
+
+        let entry = netrc.lookup("unknown.example.net", None).unwrap();
+        assert_eq!(entry.login.as_deref(), Some("anonymous"));
+
+        // A login mismatch on the machine entry falls back to default only if it matches
+        assert!(netrc.lookup("ftp.example.com", Some("carol")).is_none());
+    }
+}
@@ -0,0 +1,158 @@
+//! Throttle Module
+//!
+//! Token-bucket bandwidth limiting for FTP data transfers, so a large upload
+//! or download doesn't saturate a shared or metered link.
+
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Token bucket shared by every transfer in one direction
+///
+/// Clones share the same bucket, so the limit holds across reconnects and
+/// across connections. Bursts of up to one second worth of bytes pass
+/// without waiting.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second
+    rate: u64,
+    /// Available bytes; negative while a consumer is paying off a debt
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Throttle {
+    /// Create a throttle allowing `bytes_per_sec` on average
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1);
+        Throttle {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// Bytes per second allowed
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Account for `bytes` just transferred and sleep until they are paid for
+    ///
+    /// The bucket lock is released before sleeping, so other transfers can
+    /// queue their own debt meanwhile.
+    pub fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            let rate = bucket.rate as f64;
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Reader that paces the bytes it returns with a [`Throttle`]
+pub struct ThrottledReader<R> {
+    inner: R,
+    throttle: Option<Throttle>,
+}
+
+impl<R> ThrottledReader<R> {
+    /// Wrap `inner`; with `None` reads pass through untouched
+    pub fn new(inner: R, throttle: Option<Throttle>) -> Self {
+        ThrottledReader { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(throttle) = &self.throttle {
+            throttle.consume(n);
+        }
+        Ok(n)
+    }
+}
+
+/// Parse a rate such as `500000`, `512K` or `2M` (binary multiples) into
+/// bytes per second
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    parse_bytes(value, "a rate like 500000, 512K or 2M bytes/s")
+}
+
+/// Parse a size such as `1048576`, `512M` or `2G` (binary multiples) into bytes
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    parse_bytes(value, "a size like 1048576, 512M or 2G")
+}
+
+/// Parse a positive byte count with an optional K, M or G suffix
+fn parse_bytes(value: &str, expected: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1024),
+        Some((i, 'm' | 'M')) => (&value[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&value[..i], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("'{}' is too large", value)),
+        _ => Err(format!("'{}' is not {}", value, expected)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1500"), Ok(1500));
+        assert_eq!(parse_rate("512K"), Ok(512 * 1024));
+        assert_eq!(parse_rate("2m"), Ok(2 * 1024 * 1024));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("K").is_err());
+        assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("big").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn test_throttled_reader_paces_transfer() {
+        // The first second's worth passes as a burst, the rest is paced
+        let data = vec![0u8; 3000];
+        let throttle = Throttle::new(10_000);
+        throttle.consume(10_000);
+
+        let start = Instant::now();
+        let mut reader = ThrottledReader::new(&data[..], Some(throttle));
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+
+        assert_eq!(out.len(), 3000);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+}
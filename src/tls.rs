@@ -0,0 +1,307 @@
+//! TLS Module
+//!
+//! Client configuration for FTPS. Server certificates are verified against
+//! the system's root certificates unless verification is turned off
+//! (`--no-tls-verify`). Data connections are opened to the same
+//! server name as the control connection, so with resumption enabled they
+//! offer the session cached from it (or from an earlier data connection)
+//! instead of performing a full handshake each time. Servers that don't
+//! accept the session simply complete a full handshake.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use log::{debug, warn};
+use suppaftp::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use suppaftp::rustls::client::{
+    ClientSessionMemoryCache, ClientSessionStore, Resumption, Tls12ClientSessionValue,
+    Tls13ClientSessionValue,
+};
+use suppaftp::rustls::crypto::{self, CryptoProvider};
+use suppaftp::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use suppaftp::rustls::{
+    ClientConfig, DigitallySignedStruct, NamedGroup, RootCertStore, SignatureScheme,
+};
+
+/// Sessions kept per connection; one server name is all it ever talks to
+const SESSION_CACHE_SIZE: usize = 32;
+
+/// Build the client configuration for one FTP connection
+///
+/// With `resume` false every handshake is a full one, for servers that
+/// mishandle resumed sessions. With `verify` false any certificate is
+/// accepted, for self-signed servers.
+pub fn client_config(
+    resume: bool,
+    verify: bool,
+) -> Result<Arc<ClientConfig>, suppaftp::rustls::Error> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = if verify {
+        builder.with_root_certificates(system_roots())
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+    };
+    let mut config = builder.with_no_client_auth();
+    config.resumption = if resume {
+        Resumption::store(Arc::new(SessionStats::default()))
+    } else {
+        Resumption::disabled()
+    };
+    Ok(Arc::new(config))
+}
+
+/// Root certificates of the system's trust store
+///
+/// Certificates that fail to load are skipped; with none at all every
+/// handshake fails with an unknown issuer error.
+fn system_roots() -> RootCertStore {
+    let loaded = rustls_native_certs::load_native_certs();
+    for error in &loaded.errors {
+        warn!("Failed to load system root certificates: {}", error);
+    }
+    let mut roots = RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(loaded.certs);
+    debug!(
+        "Loaded {} system root certificates ({} ignored)",
+        added, ignored
+    );
+    roots
+}
+
+/// Certificate verifier that accepts any server certificate
+///
+/// Handshake signatures are still checked, so the peer must hold the key of
+/// the certificate it presents.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, suppaftp::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, suppaftp::rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, suppaftp::rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Session cache that counts how many handshakes could offer a session
+///
+/// rustls looks for a TLS 1.3 ticket first and falls back to a TLS 1.2
+/// session, so every handshake ends in either a hit or a TLS 1.2 miss.
+#[derive(Debug)]
+struct SessionStats {
+    cache: ClientSessionMemoryCache,
+    resumed: AtomicUsize,
+    full: AtomicUsize,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self {
+            cache: ClientSessionMemoryCache::new(SESSION_CACHE_SIZE),
+            resumed: AtomicUsize::new(0),
+            full: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl SessionStats {
+    /// Record the outcome of a session lookup for a new handshake
+    fn record(&self, server_name: &ServerName<'_>, found: bool) {
+        let counter = if found { &self.resumed } else { &self.full };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let resumed = self.resumed.load(Ordering::Relaxed);
+        let full = self.full.load(Ordering::Relaxed);
+        debug!(
+            "TLS handshake with {}: {} ({} of {} handshakes offered a cached session)",
+            server_name.to_str(),
+            if found {
+                "resuming session"
+            } else {
+                "full handshake"
+            },
+            resumed,
+            resumed + full
+        );
+    }
+}
+
+impl ClientSessionStore for SessionStats {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.cache.set_kx_hint(server_name, group)
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        self.cache.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.cache.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        let session = self.cache.tls12_session(server_name);
+        self.record(server_name, session.is_some());
+        session
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.cache.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: ServerName<'static>,
+        value: Tls13ClientSessionValue,
+    ) {
+        self.cache.insert_tls13_ticket(server_name, value)
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &ServerName<'static>,
+    ) -> Option<Tls13ClientSessionValue> {
+        let ticket = self.cache.take_tls13_ticket(server_name);
+        if ticket.is_some() {
+            self.record(server_name, true);
+        }
+        ticket
+    }
+}
+
+/// Server configuration with a fresh self-signed certificate for
+/// `localhost`, for tests that need a TLS peer
+#[cfg(test)]
+pub(crate) fn self_signed_server() -> Arc<suppaftp::rustls::ServerConfig> {
+    use suppaftp::rustls::pki_types::PrivatePkcs8KeyDer;
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    let provider = Arc::new(crypto::ring::default_provider());
+    let config = suppaftp::rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![certified.cert.der().clone()], key.into())
+        .unwrap();
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use suppaftp::rustls::{ClientConnection, HandshakeKind, ServerConnection};
+
+    #[test]
+    fn test_session_lookups_are_counted() {
+        let stats = SessionStats::default();
+        let server = ServerName::try_from("ftp.example.com").unwrap();
+
+        assert!(stats.take_tls13_ticket(&server).is_none());
+        assert!(stats.tls12_session(&server).is_none());
+        assert_eq!(stats.resumed.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.full.load(Ordering::Relaxed), 1);
+    }
+
+    /// Complete a handshake between `client` and `server` in memory
+    fn handshake(client: &mut ClientConnection, server: &mut ServerConnection) {
+        while client.is_handshaking() || server.is_handshaking() || server.wants_write() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut &buf[..]).unwrap();
+            server.process_new_packets().unwrap();
+
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut &buf[..]).unwrap();
+            client.process_new_packets().unwrap();
+        }
+    }
+
+    /// Handshake kinds of two consecutive connections made with one client
+    /// configuration
+    fn second_handshake(resume: bool) -> (HandshakeKind, HandshakeKind) {
+        let server_config = self_signed_server();
+        let client_config = client_config(resume, false).unwrap();
+
+        let mut kinds = Vec::new();
+        for _ in 0..2 {
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut client = ClientConnection::new(Arc::clone(&client_config), name).unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            handshake(&mut client, &mut server);
+            kinds.push(client.handshake_kind().unwrap());
+        }
+        (kinds[0], kinds[1])
+    }
+
+    #[test]
+    fn test_second_connection_resumes_the_session() {
+        assert_eq!(
+            second_handshake(true),
+            (HandshakeKind::Full, HandshakeKind::Resumed)
+        );
+        assert_eq!(
+            second_handshake(false),
+            (HandshakeKind::Full, HandshakeKind::Full)
+        );
+    }
+
+    #[test]
+    fn test_verified_config_rejects_unknown_certificates() {
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut client = ClientConnection::new(client_config(true, true).unwrap(), name).unwrap();
+        let mut server = ServerConnection::new(self_signed_server()).unwrap();
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
+        server.read_tls(&mut &buf[..]).unwrap();
+        server.process_new_packets().unwrap();
+        buf.clear();
+        server.write_tls(&mut buf).unwrap();
+        client.read_tls(&mut &buf[..]).unwrap();
+        // The self-signed certificate is not among the system roots
+        assert!(client.process_new_packets().is_err());
+    }
+}